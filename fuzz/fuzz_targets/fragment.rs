@@ -0,0 +1,21 @@
+//! Parses arbitrary bytes as a fragment, run with `cargo +nightly fuzz run fragment`.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sshconfgen::sshconf::{parse_sections, SECTIONS};
+use std::path::Path;
+
+fuzz_target!(|data: &[u8]| {
+    let contents = String::from_utf8_lossy(data);
+    let sections = parse_sections(Path::new("fuzz.sshconf"), &contents);
+
+    // A section never runs over the marker of another one.
+    let markers: Vec<String> = SECTIONS
+        .iter()
+        .flat_map(|name| [format!("# {name} BEGIN"), format!("# {name} END")])
+        .collect();
+    for section in &sections {
+        assert!(section.lines().all(|line| !markers.iter().any(|marker| line.trim() == marker)));
+    }
+});
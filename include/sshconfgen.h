@@ -0,0 +1,50 @@
+/*
+ * sshconfgen C API
+ *
+ * Link against the sshconfgen cdylib (libsshconfgen.so, libsshconfgen.dylib or sshconfgen.dll).
+ *
+ * Strings are UTF-8 and NUL-terminated. Strings returned as `char *` are owned by the caller and
+ * must be released with sshconfgen_string_free(). Failing functions record a message that
+ * sshconfgen_last_error() returns on the same thread. Status codes are 0 on success, otherwise
+ * the exit code of the sshconfgen binary for the error (sysexits.h values).
+ */
+
+#ifndef SSHCONFGEN_H
+#define SSHCONFGEN_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The library version, a static string that must not be freed. */
+const char *sshconfgen_version(void);
+
+/* Load the user's settings file, returns a status code. Only the first call has an effect. */
+int sshconfgen_init(int verbose);
+
+/* Generate the SSH config of the current user and write it, returns a status code. */
+int sshconfgen_run(void);
+
+/* Generate the SSH config from the fragments in config_dir (the user's config.d if NULL)
+ * without writing it. Returns NULL on error. */
+char *sshconfgen_generate(const char *config_dir);
+
+/* The fingerprint of the current network as JSON, NULL on error. */
+char *sshconfgen_fingerprint(void);
+
+/* Evaluate the conditions of a fragment given by its contents: 1 if its local rules apply,
+ * 0 if its remote rules do, -1 on error. */
+int sshconfgen_evaluate(const char *contents);
+
+/* The message of the last error on this thread, or NULL. Valid until the next call on the
+ * same thread, must not be freed. */
+const char *sshconfgen_last_error(void);
+
+/* Release a string returned by the library, NULL is ignored. */
+void sshconfgen_string_free(char *string);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* SSHCONFGEN_H */
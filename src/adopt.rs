@@ -0,0 +1,85 @@
+//! # Adopt
+//!
+//! This module guards a hand-written SSH config against being overwritten. The config files
+//! sshconfgen wrote are recorded in the state file, and a generation refuses to replace a
+//! non-empty config that isn't recorded until it is adopted: the original is archived in the backup
+//! directory, optionally converted into a fragment with its rules as global rules, and the config
+//! recorded.
+
+use crate::error::{Error, Result};
+use crate::import::{Definition, Definitions, Rules};
+use crate::paths::Paths;
+use crate::{is_verbose, verbose_println};
+use std::path::PathBuf;
+
+/// The name of the fragment an adopted config is converted into
+pub const FRAGMENT_NAME: &str = "adopted";
+
+/// What adopting a config did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Adoption {
+    /// Where the original config was archived
+    pub archive: PathBuf,
+    /// The fragment the original config was converted into, if asked to
+    pub fragment: Option<PathBuf>,
+}
+
+/// Check whether sshconfgen may replace the SSH config of `paths`: it is missing, empty, holds a
+/// managed region, or was written by sshconfgen before. Returns whether it is recorded in the
+/// state file.
+pub fn check(paths: &Paths) -> Result<bool> {
+    let config_file = &paths.config_file;
+    if crate::state::read(&paths.state_dir).managed.contains(config_file) {
+        return Ok(true);
+    }
+
+    match std::fs::metadata(config_file) {
+        Ok(metadata) if metadata.len() > 0 => match crate::file::read_file(config_file) {
+            Ok(contents) if crate::region::has_region(&contents) => Ok(false),
+            _ => Err(Error::Unmanaged(config_file.to_path_buf())),
+        },
+        _ => Ok(false),
+    }
+}
+
+/// Record in the state file that sshconfgen manages the SSH config of `paths`, forgetting the
+/// recorded files that no longer exist
+pub fn record(paths: &Paths) -> Result<()> {
+    crate::state::update(&paths.state_dir, |state| {
+        state.managed.retain(|path| path.exists());
+        state.managed.insert(paths.config_file.clone());
+    })
+}
+
+/// Adopt the SSH config of `paths` if it was not written by sshconfgen, archiving it, converting
+/// it into the `adopted` fragment with `fragment`, and recording it. The config is left in place
+/// for the next generation to replace. Returns `None` if there was nothing to adopt.
+pub fn adopt(paths: &Paths, fragment: bool) -> Result<Option<Adoption>> {
+    let config_file = &paths.config_file;
+    let _lock = paths.lock()?;
+    if check(paths).is_ok() {
+        verbose_println!("{} is already managed by sshconfgen", config_file.display());
+        return Ok(None);
+    }
+
+    let original = crate::file::read_file(config_file).map_err(Error::io("read", config_file))?;
+    let fragment = if fragment {
+        let definition = Definition {
+            name: FRAGMENT_NAME.to_string(),
+            conditions: Default::default(),
+            global: Rules::Text(original.clone()),
+            local: Rules::default(),
+            remote: Rules::default(),
+        };
+        let fragments = Definitions { fragments: vec![definition] }
+            .to_fragments(&paths.config_dir)
+            .map_err(|e| Error::Invalid(format!("config: {}", e)))?;
+        crate::import::write_fragments(&fragments, false)?.into_iter().next()
+    } else {
+        None
+    };
+
+    let archive = crate::backup::backup_adopted(paths, &original)?;
+    record(paths)?;
+    Ok(Some(Adoption { archive, fragment }))
+}
@@ -0,0 +1,60 @@
+//! # Age
+//!
+//! This module decrypts the `(encrypted)` sections of fragments with the `age` tool, so fragments
+//! holding sensitive hostnames or tokens can be kept in public dotfile repositories. Sections are
+//! encrypted to the user's key, e.g. `age -a -R ~/.ssh/id_ed25519.pub`, and decrypted with the
+//! matching identity when they are used.
+
+use crate::error::{Error, Result};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+/// The SSH keys used as the identity when the `age_identity` setting is not set, the first one
+/// existing in the `.ssh` directory is used
+const DEFAULT_IDENTITIES: &[&str] = &["id_ed25519", "id_rsa"];
+
+/// Get the identity sections are decrypted with: the `age_identity` setting, or the user's SSH key
+pub fn identity() -> Option<PathBuf> {
+    if let Some(identity) = &crate::settings::get().age_identity {
+        return Some(crate::platform::expand_home(identity));
+    }
+
+    let ssh_dir = crate::platform::ssh_dir()?;
+    DEFAULT_IDENTITIES.iter().map(|name| ssh_dir.join(name)).find(|path| path.is_file())
+}
+
+/// Decrypt age ciphertext, armored or not
+pub fn decrypt(ciphertext: &str) -> Result<String> {
+    if !crate::backend::is_in_path("age") {
+        return Err(Error::ToolUnavailable("age".to_string()));
+    }
+
+    let identity = identity().ok_or(Error::NotFound("age identity"))?;
+    let mut child = crate::platform::command("age")
+        .arg("--decrypt")
+        .arg("--identity")
+        .arg(&identity)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| Error::ToolUnavailable("age".to_string()))?;
+
+    // Written from another thread, so a large plaintext can't fill the output pipe while the
+    // ciphertext is still being written.
+    let Some(mut stdin) = child.stdin.take() else {
+        return Err(Error::ToolUnavailable("age".to_string()));
+    };
+    let ciphertext = format!("{}\n", ciphertext.trim());
+    let writer = std::thread::spawn(move || stdin.write_all(ciphertext.as_bytes()));
+
+    let output = child.wait_with_output().map_err(|_| Error::ToolUnavailable("age".to_string()))?;
+    let _ = writer.join();
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Invalid(format!("ciphertext ({})", stderr.trim())));
+    }
+
+    String::from_utf8(output.stdout).map_err(|_| Error::Parse("age"))
+}
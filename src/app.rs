@@ -0,0 +1,556 @@
+//! # App
+//!
+//! This module runs the actions of the binary, as parsed by the [`crate::cli`] module, through the
+//! public API of the library, and prints what they did. Errors are reported by `main`.
+
+use crate::cli::{Action, Adopt, Format, Import, Installer, Options};
+use crate::color::{paint, Color};
+use sshconfgen::generator::{Generation, Generator, Monitor, Outcome, Planned, Report, Trigger};
+use sshconfgen::paths::Paths;
+use sshconfgen::provider::{Connection, Providers};
+use sshconfgen::{is_verbose, verbose_println, Error, Overrides, Preferences, Result, VERSION};
+use std::path::Path;
+
+/// Run the binary
+pub fn run() -> Result<()> {
+    let options = match crate::cli::parse(std::env::args().skip(1))? {
+        Action::Help => {
+            crate::help::print_help();
+            return Ok(());
+        }
+        Action::Version => {
+            println!("{}", VERSION);
+            return Ok(());
+        }
+        Action::Validate(options) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            check_dirs(generator.paths(), &options)?;
+            let validation = generator.validate();
+            if validation.warnings > 0 {
+                return Err(Error::InvalidFragments(validation.warnings));
+            }
+            println!("{} fragments are valid", validation.fragments);
+            return Ok(());
+        }
+        Action::Fingerprint(options) => {
+            init(&options)?;
+            let fingerprint = Generator::new(Paths::resolve()?).fingerprint();
+            println!("{}", serde_json::to_string_pretty(&fingerprint).unwrap_or_default());
+            return Ok(());
+        }
+        Action::Export(options, export) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            check_dirs(generator.paths(), &options)?;
+
+            let exported = generator.export(export.evaluate);
+            let output = match export.format {
+                Format::Json => serde_json::to_string_pretty(&exported).unwrap_or_default(),
+                Format::Yaml => serde_yaml_ng::to_string(&exported).unwrap_or_default(),
+            };
+            println!("{}", output.trim_end());
+            return Ok(());
+        }
+        Action::Import(options, import) => {
+            init(&options)?;
+            return import_definitions(&import, &options);
+        }
+        Action::Sync(options) => {
+            let preferences = init(&options)?;
+            let paths = Paths::resolve()?;
+            if options.init_dirs {
+                paths.init_dirs()?;
+            }
+            let mut generator = generator(paths, Providers::default(), &options);
+            generator.sync()?;
+            check_dirs(generator.paths(), &options)?;
+            let report = generator.generate()?;
+            print_report(&report, &options, &preferences);
+            return Ok(());
+        }
+        Action::Install(options, installer) => {
+            init(&options)?;
+            let installed = match installer {
+                Installer::NmDispatcher => {
+                    sshconfgen::generator::install_nm_dispatcher()?.display().to_string()
+                }
+                Installer::TaskScheduler => {
+                    format!("the {} task", sshconfgen::generator::install_task_scheduler()?)
+                }
+                Installer::Cron(schedule) => {
+                    let entry = sshconfgen::generator::install_cron(&schedule)?;
+                    format!("the crontab entry {}", entry)
+                }
+            };
+            println!("Installed {}", installed);
+            return Ok(());
+        }
+        Action::MatchHelper(options, network) => {
+            init(&options)?;
+            // Match exec only looks at the exit status.
+            if !Generator::new(Paths::resolve()?).is_network_active(&network)? {
+                return Err(Error::Exit(1));
+            }
+            return Ok(());
+        }
+        Action::Current(options) => {
+            init(&options)?;
+            let profile = Generator::new(Paths::resolve()?).profile()?;
+            println!("{}", profile.ok_or(Error::NotFound("Profile"))?);
+            return Ok(());
+        }
+        Action::Status(options, format) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            let generation = generator.status().ok_or(Error::NotFound("Generation"))?;
+            let output = match format {
+                None => render_status(&generator.paths().config_file, &generation),
+                Some(Format::Json) => serde_json::to_string_pretty(&generation).unwrap_or_default(),
+                Some(Format::Yaml) => serde_yaml_ng::to_string(&generation).unwrap_or_default(),
+            };
+            println!("{}", output.trim_end());
+            return Ok(());
+        }
+        Action::Hosts(options, format) => {
+            init(&options)?;
+            let hosts = Generator::new(Paths::resolve()?).hosts()?;
+            match format {
+                None => hosts.iter().for_each(|host| println!("{}", host.line())),
+                Some(Format::Json) => {
+                    println!("{}", serde_json::to_string_pretty(&hosts).unwrap_or_default())
+                }
+                Some(Format::Yaml) => {
+                    print!("{}", serde_yaml_ng::to_string(&hosts).unwrap_or_default())
+                }
+            }
+            return Ok(());
+        }
+        Action::Test(options, hosts) => {
+            init(&options)?;
+            let outcomes = Generator::new(Paths::resolve()?).smoke_test(&hosts);
+            if outcomes.is_empty() {
+                let message =
+                    "arguments: test needs a host, or [smoke_test] hosts in the settings file";
+                return Err(Error::Invalid(message.to_string()));
+            }
+
+            let mut failed = Vec::new();
+            for outcome in outcomes {
+                if outcome.connected {
+                    println!("{}  {}", paint("ok    ", Color::Green), outcome.host);
+                } else {
+                    let status = paint("failed", Color::Red);
+                    println!("{}  {}: {}", status, outcome.host, outcome.message);
+                    failed.push(outcome.host);
+                }
+            }
+            if !failed.is_empty() {
+                return Err(Error::SmokeTest(failed.join(", ")));
+            }
+            return Ok(());
+        }
+        Action::Restore(options, restore) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            if restore.list {
+                for (position, backup) in generator.backups().iter().enumerate() {
+                    println!(
+                        "{}\t{}",
+                        position + 1,
+                        backup.file_name().unwrap_or_default().to_string_lossy()
+                    );
+                }
+                return Ok(());
+            }
+
+            let backup = generator.restore(restore.backup.as_deref())?;
+            let config_file = &generator.paths().config_file;
+            println!("Restored {} from {}", config_file.display(), backup.display());
+            return Ok(());
+        }
+        Action::Migrate(options) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            check_dirs(generator.paths(), &options)?;
+            let migrations = generator.migrate()?;
+            for migration in &migrations {
+                let (path, backup) = (migration.path.display(), migration.backup.display());
+                println!(
+                    "Migrated {} from format {}, the original is kept as {}",
+                    path, migration.from, backup
+                );
+            }
+            if migrations.is_empty() {
+                let version = sshconfgen::generator::FORMAT_VERSION;
+                println!("The fragments are in format {}", version);
+            }
+            return Ok(());
+        }
+        Action::MigrateLayout(options, symlink) => {
+            init(&options)?;
+            let generator = Generator::new(Paths::resolve()?);
+            let migration = generator.migrate_layout(symlink)?;
+            let paths = generator.paths();
+            for (from, to) in &migration.moved {
+                println!("Moved {} to {}", from.display(), to.display());
+            }
+            for conflict in &migration.conflicts {
+                let config_dir = paths.config_dir.display();
+                eprintln!(
+                    "Warning: {} is left in place, {} has one of the same name.",
+                    conflict.display(),
+                    config_dir
+                );
+            }
+            if let Some(link) = &migration.link {
+                println!("Linked {} to {}", link.display(), paths.config_dir.display());
+            }
+            if migration.moved.is_empty() && migration.conflicts.is_empty() {
+                let legacy_dir = paths.ssh_dir.join(sshconfgen::paths::LEGACY_CONFIG_DIR);
+                println!("No fragments in {}", legacy_dir.display());
+            }
+            return Ok(());
+        }
+        Action::SelfUpdate(options, check) => {
+            init(&options)?;
+            let release = if check {
+                sshconfgen::generator::check_update()?
+            } else {
+                sshconfgen::generator::self_update()?
+            };
+            match release {
+                Some(release) if check => {
+                    println!("{} is available, running {}", release.version, VERSION)
+                }
+                Some(release) => println!("Updated from {} to {}", VERSION, release.version),
+                None => println!("{} is the latest release", VERSION),
+            }
+            return Ok(());
+        }
+        Action::Plugin(options, name, args) => {
+            init(&options)?;
+            let code = Generator::new(Paths::resolve()?).run_plugin(&name, &args)?;
+            if code != 0 {
+                return Err(Error::Exit(code));
+            }
+            return Ok(());
+        }
+        Action::Generate(options) => options,
+    };
+
+    let preferences = init(&options)?;
+
+    // Check that .ssh directory exists and .ssh/config.d directories exists
+    let paths = Paths::resolve()?;
+    check_dirs(&paths, &options)?;
+
+    let providers = Providers::default();
+    let mut generator = generator(paths, providers.clone(), &options);
+    if let Some(adopt) = options.adopt {
+        if let Some(adoption) = generator.adopt(adopt == Adopt::Fragment)? {
+            let config_file = &generator.paths().config_file;
+            println!("Archived {} as {}", config_file.display(), adoption.archive.display());
+            if let Some(fragment) = adoption.fragment {
+                println!("Converted it into {}", fragment.display());
+            }
+        }
+    }
+
+    if options.stdout {
+        let config = generator.preview()?;
+        if !config.is_empty() {
+            print!("{}{}", config, preferences.newline);
+        }
+        return Ok(());
+    }
+    if !options.monitor {
+        let report = generator.generate()?;
+        print_report(&report, &options, &preferences);
+        return Ok(());
+    }
+
+    let sleep_time = options
+        .monitor_interval
+        .or(preferences.monitor_interval)
+        .unwrap_or(crate::cli::DEFAULT_MONITOR_INTERVAL);
+    monitor_ssid(generator, providers, sleep_time, &options, &preferences)
+}
+
+/// Create the generator of the config of `paths`, writing as `options` say
+fn generator(paths: Paths, providers: Providers, options: &Options) -> Generator {
+    let generator = Generator::new(paths)
+        .with_providers(providers)
+        .with_dry_run(options.dry_run)
+        .with_force(options.force);
+    if options.interactive {
+        generator.with_interactive(approve)
+    } else {
+        generator
+    }
+}
+
+/// Print what the generation of `profile` would change and ask whether to write it, for
+/// `--interactive`
+fn approve(planned: &[Planned], profile: &str) -> bool {
+    print_changes(planned, profile);
+    let approved = confirm("Write the new config?");
+    if !approved {
+        println!("Nothing was written");
+    }
+    approved
+}
+
+/// Print the hosts each of `planned` adds, removes and changes, then the diff of each
+fn print_changes(planned: &[Planned], profile: &str) {
+    println!("{}", paint(format!("Generated the {} profile", profile), Color::Bold));
+    for file in planned {
+        println!("{}: {}", file.path.display(), file.summary());
+    }
+    for file in planned {
+        let name = file.path.display().to_string();
+        let new = format!("{} (new)", name);
+        print!("{}", crate::diff::unified(&name, &new, &file.current, &file.new));
+    }
+}
+
+/// Print what a generation did: the changes of a dry run, with `--timings` how long each
+/// fragment and condition took, and in verbose mode how long each phase took
+fn print_report(report: &Report, options: &Options, preferences: &Preferences) {
+    // The generated config is the only output on stdout with --stdout.
+    let timings = (options.timings || preferences.timings) && !options.stdout;
+    if timings {
+        print_timings(&report.timings);
+    }
+    if report.outcome == Outcome::Previewed {
+        if report.changes.is_empty() {
+            println!("Generated the {} profile, no file would change", report.profile);
+        } else {
+            print_changes(&report.changes, &report.profile);
+        }
+    }
+    verbose_println!("Phases:");
+    for line in report.timings.lines(report.elapsed) {
+        verbose_println!("{}", line);
+    }
+    if timings {
+        println!("Generation took {:.3}s", report.elapsed.as_secs_f64());
+    }
+}
+
+/// Print how long each fragment and condition took, slowest first
+///
+/// Conditions cancelled by a match or still running once the budget ran out are not listed.
+fn print_timings(timings: &sshconfgen::generator::Timings) {
+    let mut fragments: Vec<_> = timings.fragments.iter().collect();
+    fragments.sort_by_key(|timing| {
+        std::cmp::Reverse(timing.elapsed.unwrap_or(std::time::Duration::MAX))
+    });
+
+    println!("{}", paint("Fragment timings:", Color::Bold));
+    for timing in fragments {
+        match (timing.matched, timing.elapsed) {
+            (Some(matched), Some(elapsed)) => println!(
+                "{:>9.3}s  {}  {}",
+                elapsed.as_secs_f64(),
+                if matched {
+                    paint("local   ", Color::Green)
+                } else {
+                    paint("remote  ", Color::Red)
+                },
+                timing.path.display()
+            ),
+            _ => println!(
+                "{:>10}  {}  {}",
+                "-",
+                paint("cut off ", Color::Yellow),
+                timing.path.display()
+            ),
+        }
+    }
+
+    let mut conditions: Vec<_> = timings.conditions.iter().collect();
+    conditions.sort_by_key(|timing| std::cmp::Reverse(timing.elapsed));
+
+    println!("{}", paint("Condition timings:", Color::Bold));
+    for timing in conditions {
+        println!(
+            "{:>9.3}s  {}  {} ({})",
+            timing.elapsed.as_secs_f64(),
+            if timing.matched {
+                paint("match   ", Color::Green)
+            } else {
+                paint("no match", Color::Red)
+            },
+            timing.condition,
+            timing.path.display()
+        );
+    }
+}
+
+/// Describe a generation for `sshconfgen status`
+fn render_status(config_file: &Path, generation: &Generation) -> String {
+    let time = chrono::DateTime::from_timestamp(generation.time as i64, 0)
+        .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S %z").to_string())
+        .unwrap_or_default();
+    let fingerprint = &generation.fingerprint;
+    let mut network: Vec<String> = fingerprint.wifi.iter().map(|wifi| wifi.ssid.clone()).collect();
+    if let Some(gateway) = fingerprint.gateway {
+        let interface =
+            fingerprint.interface.as_deref().map(|interface| format!(" ({})", interface));
+        network.push(format!("gateway {}{}", gateway, interface.unwrap_or_default()));
+    }
+    if network.is_empty() {
+        network.push("offline".to_string());
+    }
+
+    format!(
+        "Config: {}{}\nGenerated: {}\nProfile: {}\nNetwork: {}\nFragments: {}\n",
+        config_file.display(),
+        if generation.modified() {
+            paint(" (modified since)", Color::Yellow)
+        } else {
+            String::new()
+        },
+        time,
+        generation.profile,
+        network.join(", "),
+        generation.fragments.len()
+    )
+}
+
+/// Write the fragments of a file of rule definitions to `config.d`
+fn import_definitions(import: &Import, options: &Options) -> Result<()> {
+    let generator = Generator::new(Paths::resolve()?);
+    check_dirs(generator.paths(), options)?;
+
+    let extension = import.file.extension().and_then(|extension| extension.to_str());
+    let format = match (import.format, extension) {
+        (Some(format), _) => format,
+        (None, Some("json")) => Format::Json,
+        (None, Some("yaml" | "yml")) => Format::Yaml,
+        (None, _) => {
+            let message = format!(
+                "import: can't infer the format of {}, use --format",
+                import.file.display()
+            );
+            return Err(Error::Invalid(message));
+        }
+    };
+
+    for path in generator.import(&import.file, format, import.force)? {
+        verbose_println!("Wrote {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Check that the `.ssh` and fragment directories exist. Missing ones are created with
+/// `--init-dirs`, or once the user agrees to when asked on a terminal, but never on a dry run.
+fn check_dirs(paths: &Paths, options: &Options) -> Result<()> {
+    let create = |dir: &Path| {
+        !options.dry_run && confirm(&format!("{} does not exist, create it?", dir.display()))
+    };
+    match paths.check() {
+        Err(Error::MissingDirectory(dir)) if options.init_dirs || create(&dir) => {
+            paths.init_dirs()?;
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Ask a yes or no question on the terminal, answered no when stdin or stderr isn't a terminal
+fn confirm(question: &str) -> bool {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return false;
+    }
+
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim(), "y" | "Y" | "yes" | "Yes")
+}
+
+/// Load the settings and apply them, the options taking precedence
+fn init(options: &Options) -> Result<Preferences> {
+    let overrides = Overrides {
+        ssh_config: options.output.clone(),
+        config_dir: options.config_dir.clone(),
+        verbose: options.verbose,
+        log_to_stderr: options.stdout,
+    };
+    let preferences = sshconfgen::load_settings_with(&overrides)?;
+    if options.no_color {
+        crate::color::disable();
+    }
+    if options.interactive {
+        use std::io::IsTerminal;
+        if !std::io::stdin().is_terminal() {
+            return Err(Error::Invalid("arguments: --interactive needs a terminal".to_string()));
+        }
+    }
+    Ok(preferences)
+}
+
+/// Generate the SSH config with `generator`, then again whenever the connected SSIDs, polled
+/// every `sleep_time` seconds, change, or the HTTP listener or D-Bus service set in `options` is
+/// asked to. Generations run one at a time on this thread.
+fn monitor_ssid(
+    mut generator: Generator,
+    providers: Providers,
+    sleep_time: u64,
+    options: &Options,
+    preferences: &Preferences,
+) -> Result<()> {
+    let monitor = Monitor::new();
+    if let Some(address) = options.listen {
+        monitor.listen(address, &providers)?;
+    }
+    if options.dbus {
+        monitor.publish()?;
+    }
+    monitor.trigger(Trigger::Requested);
+
+    let poller = monitor.clone();
+    let ssid = providers.ssid.clone();
+    std::thread::spawn(move || {
+        let mut current_connections = ssid.connections();
+        verbose_println!("Current SSIDs: {}", format_connections(&current_connections));
+
+        loop {
+            verbose_println!("<<>>");
+            std::thread::sleep(std::time::Duration::from_secs(sleep_time));
+            let new_connections = ssid.connections();
+            if !same_connections(&new_connections, &current_connections) {
+                current_connections = new_connections;
+                verbose_println!("New SSIDs: {}", format_connections(&current_connections));
+                poller.trigger(Trigger::Network);
+            }
+        }
+    });
+
+    monitor.run_generations(&mut generator, |report| print_report(report, options, preferences))
+}
+
+/// Format the connected SSIDs for display
+fn format_connections(connections: &Result<Vec<Connection>>) -> String {
+    match connections {
+        Ok(connections) => connections
+            .iter()
+            .map(|connection| connection.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Check whether two SSID polls report the same connections, or fail the same way
+fn same_connections(a: &Result<Vec<Connection>>, b: &Result<Vec<Connection>>) -> bool {
+    match (a, b) {
+        (Ok(a), Ok(b)) => a == b,
+        (Err(a), Err(b)) => a.to_string() == b.to_string(),
+        _ => false,
+    }
+}
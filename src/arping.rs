@@ -0,0 +1,45 @@
+//! # ARP Ping
+//!
+//! This module contains the function to check whether a host on the local segment answers ARP
+//! requests, which works even when the host drops ICMP.
+
+use crate::platform::{command, run};
+use std::{
+    net::{Ipv4Addr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+/// Get whether a host on the local segment answers an ARP request
+///
+/// Uses `arping` when installed. Otherwise a datagram is sent to the host so the kernel resolves
+/// its address, and the neighbor table is checked for the resulting entry.
+pub fn get_arp_pingable(ip_address: &str) -> bool {
+    let Ok(ip) = ip_address.parse::<Ipv4Addr>() else {
+        crate::platform::warn_once(
+            &format!("arping {}", ip_address),
+            &format!("LocalArpPing {} is not an IPv4 address, skipping it.", ip_address),
+        );
+        return false;
+    };
+
+    if crate::backend::is_in_path("arping") {
+        return run(command("arping").args(["-c", "1", "-w", "1", ip_address]), "LocalArpPing")
+            .is_ok_and(|output| output.status.success());
+    }
+
+    // The discard port, nothing is expected to answer. Addresses off the local segment resolve
+    // the gateway instead, so no entry for the host itself appears.
+    if let Ok(socket) = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)) {
+        let _ = socket.send_to(&[0], (ip, 9));
+    }
+
+    for _i in 0..5 {
+        thread::sleep(Duration::from_millis(200));
+        if crate::hwaddr::get_hw_address(ip_address).is_ok() {
+            return true;
+        }
+    }
+
+    false
+}
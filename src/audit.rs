@@ -0,0 +1,113 @@
+//! # Audit
+//!
+//! This module appends to the audit log, `audit.log` next to the state file, a JSON object per
+//! generation and per restored backup, so when and why the SSH config changed can be
+//! reconstructed later. Generations record what triggered them, the network fingerprint, the
+//! conditions of each fragment and whether its local rules were used, and the checksum of the
+//! config written:
+//!
+//! ```text
+//! {"time":"2024-01-31T09:30:00Z","event":"generation","triggers":["network"],...}
+//! {"time":"2024-01-31T09:42:10Z","event":"restore","config":"/home/me/.ssh/config",...}
+//! ```
+//!
+//! The log is only ever appended to, and only written with `audit_log = true`.
+
+use crate::error::{Error, Result};
+use crate::fingerprint::Fingerprint;
+use crate::paths::Paths;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+/// An event of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Event {
+    /// A generation wrote the SSH config
+    Generation {
+        /// What requested the generation, `command` when sshconfgen was run for it
+        triggers: Vec<String>,
+        profile: String,
+        fingerprint: Box<Fingerprint>,
+        fragments: Vec<Decision>,
+        config: PathBuf,
+        /// The SHA-256 checksum of the generated config
+        sha256: String,
+    },
+    /// The SSH config was restored from a backup
+    Restore {
+        config: PathBuf,
+        backup: PathBuf,
+        /// The SHA-256 checksum of the restored config
+        sha256: String,
+    },
+}
+
+/// Which rules of a fragment a generation used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub path: PathBuf,
+    /// The condition lines of the fragment
+    pub conditions: Vec<String>,
+    /// Whether any condition matched, so its local rules were used
+    pub local: bool,
+}
+
+/// A line of the audit log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the event happened, in RFC 3339 format
+    pub time: String,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Get the path of the audit log of `paths`, `audit.log` next to the state file
+pub fn audit_path(paths: &Paths) -> PathBuf {
+    paths.state_dir.join("audit.log")
+}
+
+/// Append `event` to the audit log of `paths`, if it is enabled
+pub fn append(paths: &Paths, event: Event) -> Result<()> {
+    if !crate::settings::get().audit_log {
+        return Ok(());
+    }
+
+    let path = audit_path(paths);
+    std::fs::create_dir_all(&paths.state_dir).map_err(Error::io("create", &paths.state_dir))?;
+
+    let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+    let mut line = serde_json::to_string(&Entry { time, event }).unwrap_or_default();
+    line.push('\n');
+
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path).map_err(Error::io("open", &path))?;
+    // A single write, so lines of concurrent processes don't interleave.
+    file.write_all(line.as_bytes()).map_err(Error::io("write", &path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_one_tagged_object_per_line() {
+        let event = Event::Restore {
+            config: "config".into(),
+            backup: "config.gz".into(),
+            sha256: "ab".to_string(),
+        };
+        let entry = Entry { time: "2024-01-31T09:30:00Z".to_string(), event: event.clone() };
+        let line = serde_json::to_string(&entry).unwrap();
+        assert!(!line.contains('\n'));
+        assert!(line
+            .starts_with(r#"{"time":"2024-01-31T09:30:00Z","event":"restore","config":"config""#));
+
+        let entry: Entry = serde_json::from_str(&line).unwrap();
+        assert_eq!(entry.event, event);
+    }
+}
@@ -0,0 +1,69 @@
+//! # Backend
+//!
+//! This module decides whether a detection capability is served by external commands or by the
+//! native (pure Rust) implementation in the `native` module.
+
+use serde::Deserialize;
+use std::path::Path;
+
+/// The implementation serving a detection capability
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Use the external command when it is installed, the native backend otherwise
+    #[default]
+    Auto,
+    /// Always use the external command
+    Command,
+    /// Always use the native backend
+    Native,
+}
+
+/// The backend selected for each capability in the settings file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendSettings {
+    pub ssid: Backend,
+    pub neighbor: Backend,
+    pub ping: Backend,
+    pub routes: Backend,
+}
+
+/// Get whether the native backend should serve a capability
+///
+/// `tool` is the external command the capability relies on, and `native_supported` whether a
+/// native implementation exists for the current platform.
+pub fn use_native(backend: Backend, tool: &str, native_supported: bool) -> bool {
+    match backend {
+        Backend::Auto => native_supported && !is_in_path(tool),
+        Backend::Command => false,
+        Backend::Native => true,
+    }
+}
+
+/// Get whether an executable is found in any `PATH` directory
+pub fn is_in_path(program: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+
+    std::env::split_paths(&path).any(|dir| {
+        is_executable(&dir.join(program))
+            || (cfg!(windows) && is_executable(&dir.join(format!("{}.exe", program))))
+    })
+}
+
+/// Get whether a path is an executable file
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
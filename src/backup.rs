@@ -0,0 +1,192 @@
+//! # Backup
+//!
+//! This module keeps gzip-compressed copies of the SSH config it replaces in the backup directory,
+//! `~/.local/state/sshconfgen/backups` by default, rather than next to the config in `~/.ssh`.
+//! Backups are named after the config file, a hash of its path, so configs of other `.ssh`
+//! directories don't mix, and the time they were taken, e.g.
+//! `config-1a2b3c4d-20240131T093000.123.gz`. The original of an adopted config is kept there too,
+//! with an `-adopted` suffix, and is never pruned.
+
+use crate::error::{Error, Result};
+use crate::journal::Intent;
+use crate::paths::Paths;
+use crate::review::Planned;
+use crate::{is_verbose, verbose_println};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The suffix of the backups of adopted configs
+const ADOPTED_SUFFIX: &str = "-adopted";
+
+/// How the replaced SSH configs are kept
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackupSettings {
+    /// The number of backups kept of each config, 0 (the default) disables them
+    pub keep: usize,
+    /// The backup directory, `backups` next to the state file by default
+    pub dir: Option<PathBuf>,
+}
+
+impl BackupSettings {
+    /// Check that the backup directory is absolute
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match &self.dir {
+            Some(dir) if !crate::platform::expand_home(dir).is_absolute() => {
+                Err(format!("backups: dir {} must be absolute or start with ~/", dir.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Get the backup directory of `paths`
+pub fn backup_dir(paths: &Paths) -> PathBuf {
+    match &crate::settings::get().backups.dir {
+        Some(dir) => crate::platform::expand_home(dir),
+        None => paths.state_dir.join("backups"),
+    }
+}
+
+/// Get the start of the names of the backups of `config_file`
+fn prefix(config_file: &Path) -> String {
+    let hash = crate::source::sha256_hex(config_file.to_string_lossy().as_bytes());
+    format!("{}-{}-", config_file.file_name().unwrap_or_default().to_string_lossy(), &hash[..8])
+}
+
+/// Get the backups of the SSH config of `paths`, newest first
+pub fn list(paths: &Paths) -> Vec<PathBuf> {
+    let prefix = prefix(&paths.config_file);
+    let mut backups: Vec<PathBuf> = std::fs::read_dir(backup_dir(paths))
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.file_name().unwrap_or_default().to_string_lossy().starts_with(&prefix))
+        .collect();
+    backups.sort();
+    backups.reverse();
+    backups
+}
+
+/// Read a backup
+pub fn read(backup: &Path) -> Result<String> {
+    let file = std::fs::File::open(backup).map_err(Error::io("read", backup))?;
+    let mut contents = String::new();
+    GzDecoder::new(file).read_to_string(&mut contents).map_err(Error::io("decompress", backup))?;
+    Ok(contents)
+}
+
+/// Keep a backup of the SSH config of `paths`, if backups are enabled and it differs from the
+/// last one. The oldest backups are only removed by [`prune`], once the config was replaced.
+pub fn backup(paths: &Paths) -> Result<Option<PathBuf>> {
+    if crate::settings::get().backups.keep == 0 {
+        return Ok(None);
+    }
+
+    let Ok(contents) = crate::file::read_file(&paths.config_file) else {
+        return Ok(None);
+    };
+    let last = list(paths).into_iter().find(|backup| !is_adopted(backup));
+    if contents.is_empty()
+        || last.is_some_and(|last| read(&last).is_ok_and(|last| last == contents))
+    {
+        return Ok(None);
+    }
+
+    write(paths, &contents, "").map(Some)
+}
+
+/// Keep the original of an adopted config, whether or not backups are enabled
+pub fn backup_adopted(paths: &Paths, contents: &str) -> Result<PathBuf> {
+    write(paths, contents, ADOPTED_SUFFIX)
+}
+
+/// Check whether a backup is the original of an adopted config
+fn is_adopted(backup: &Path) -> bool {
+    backup
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .ends_with(&format!("{}.gz", ADOPTED_SUFFIX))
+}
+
+/// Write a compressed backup of `contents`, the contents of the SSH config of `paths`
+fn write(paths: &Paths, contents: &str, suffix: &str) -> Result<PathBuf> {
+    let (config_file, dir) = (&paths.config_file, backup_dir(paths));
+    crate::file::create_private_dir(&dir).map_err(Error::io("create", &dir))?;
+
+    let timestamp = chrono::Local::now().format("%Y%m%dT%H%M%S%.3f");
+    let backup = dir.join(format!("{}{}{}.gz", prefix(config_file), timestamp, suffix));
+    verbose_println!("Backing up {} to {}", config_file.display(), backup.display());
+    crate::file::replace_file(&backup, |out| {
+        let mut encoder = GzEncoder::new(out, Compression::default());
+        encoder.write_all(contents.as_bytes())?;
+        encoder.finish().map(|_| ())
+    })
+    .map_err(Error::io("write", &backup))?;
+    Ok(backup)
+}
+
+/// Remove the oldest backups of the SSH config of `paths` beyond the number kept, adopted
+/// originals aside
+pub fn prune(paths: &Paths) {
+    let keep = crate::settings::get().backups.keep;
+    if keep == 0 {
+        return;
+    }
+
+    for backup in list(paths).into_iter().filter(|backup| !is_adopted(backup)).skip(keep) {
+        verbose_println!("Removing the backup {}", backup.display());
+        if let Err(e) = std::fs::remove_file(&backup) {
+            verbose_println!("Unable to remove {}: {}", backup.display(), e);
+        }
+    }
+}
+
+/// Restore the SSH config of `paths` from a backup, the newest one by default, backing up the
+/// current config first. `name` selects a backup by its file name, or by its position in [`list`]
+/// with 1 being the newest. Returns the backup restored.
+pub fn restore(paths: &Paths, name: Option<&str>) -> Result<PathBuf> {
+    let config_file = &paths.config_file;
+    let backups = list(paths);
+    let selected = match name {
+        None => backups.first(),
+        Some(name) => match name.parse::<usize>() {
+            Ok(position) => position.checked_sub(1).and_then(|index| backups.get(index)),
+            Err(_) => {
+                backups.iter().find(|backup| backup.file_name().is_some_and(|file| file == name))
+            }
+        },
+    };
+    let selected = selected.cloned().ok_or(Error::NotFound("Backup"))?;
+    let contents = read(&selected)?;
+
+    let _lock = paths.lock()?;
+    crate::journal::repair(paths)?;
+    let kept = backup(paths)?;
+    let current = crate::file::read_file(config_file).unwrap_or_default();
+    let planned = Planned { path: config_file.clone(), current, new: contents.clone() };
+    crate::journal::begin(paths, Intent::Restore, kept.as_deref(), &[planned])?;
+    verbose_println!("Restoring {} from {}", config_file.display(), selected.display());
+    crate::file::replace_file(config_file, |out| out.write_all(contents.as_bytes()))
+        .map_err(Error::io("write", config_file))?;
+    crate::journal::finish(paths)?;
+    prune(paths);
+    crate::adopt::record(paths)?;
+
+    let sha256 = crate::source::sha256_hex(contents.as_bytes());
+    let event = crate::audit::Event::Restore {
+        config: config_file.clone(),
+        backup: selected.clone(),
+        sha256,
+    };
+    if let Err(e) = crate::audit::append(paths, event) {
+        crate::platform::warn_once("audit", &format!("Unable to write the audit log: {}", e));
+    }
+    Ok(selected)
+}
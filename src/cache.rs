@@ -0,0 +1,180 @@
+//! # Cache
+//!
+//! This module contains the cache of a long-running process (monitor mode), which keeps the
+//! parsed fragments and their condition outcomes so an unchanged fragment on an unchanged network
+//! is neither re-read nor re-probed.
+
+use crate::context::Context;
+use crate::fingerprint::Fingerprint;
+use crate::sshconf::{discover_fragments, Fragment};
+use crate::{is_verbose, verbose_println};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The fragments and condition outcomes of the previous generations
+#[derive(Default)]
+pub struct Cache {
+    fingerprint: Option<Fingerprint>,
+    entries: HashMap<PathBuf, Entry>,
+    local: BTreeSet<PathBuf>,
+    profile: Option<String>,
+}
+
+/// A fragment as it was when last read, along with whether its conditions matched
+struct Entry {
+    modified: SystemTime,
+    /// The modification times of the snippets the fragment includes
+    includes: Vec<Option<SystemTime>>,
+    fragment: Fragment,
+    matched: Option<bool>,
+}
+
+/// Get the modification time of a file
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Get the modification times of the snippets a fragment includes
+fn includes_modified(fragment: &Fragment) -> Vec<Option<SystemTime>> {
+    fragment.includes.iter().map(|path| modified(path)).collect()
+}
+
+impl Cache {
+    /// Read and parse the fragments in a directory, skipping empty or unreadable files
+    ///
+    /// Fragments whose modification time, and that of the snippets they include, didn't change
+    /// since the previous call are not re-read.
+    pub fn read_fragments(&mut self, ssh_config_dir: &Path) -> Vec<Fragment> {
+        let mut entries = HashMap::new();
+        let mut fragments = Vec::new();
+
+        for config_file_path in
+            crate::phases::time("discovery", || discover_fragments(ssh_config_dir))
+        {
+            let modified = modified(&config_file_path);
+            let cached = self.entries.remove(&config_file_path).filter(|entry| {
+                Some(entry.modified) == modified
+                    && entry.includes == includes_modified(&entry.fragment)
+            });
+
+            let entry = match cached {
+                Some(entry) => entry,
+                None => {
+                    let read = || crate::sshconf::read_fragment(ssh_config_dir, &config_file_path);
+                    let Some(fragment) = crate::phases::time("parsing", read) else {
+                        continue;
+                    };
+
+                    // Without a modification time the fragment is read again next time.
+                    let Some(modified) = modified else {
+                        fragments.push(fragment);
+                        continue;
+                    };
+
+                    Entry {
+                        modified,
+                        includes: includes_modified(&fragment),
+                        fragment,
+                        matched: None,
+                    }
+                }
+            };
+
+            fragments.push(entry.fragment.clone());
+            entries.insert(config_file_path, entry);
+        }
+
+        self.entries = entries;
+        self.local.retain(|path| fragments.iter().any(|fragment| fragment.path == *path));
+        crate::sshconf::sort_fragments(&mut fragments);
+        fragments
+    }
+
+    /// Get the fingerprint of the network the last evaluation happened on
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.fingerprint.as_ref()
+    }
+
+    /// Get the paths of the fragments whose local rules were selected by the last evaluation
+    pub fn local_fragments(&self) -> &BTreeSet<PathBuf> {
+        &self.local
+    }
+
+    /// Get the profile selected by the last generation: the active named network, or `local` or
+    /// `remote` depending on whether any fragment uses its local rules
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Record the profile selected by a generation
+    pub(crate) fn set_profile(&mut self, profile: String) {
+        self.profile = Some(profile);
+    }
+
+    /// Get whether the conditions of each fragment match, only probing the fragments that changed
+    /// since the previous call unless the network fingerprint changed
+    pub fn evaluate(&mut self, fragments: &[Fragment], context: &Context) -> Vec<bool> {
+        let fingerprint = context.fingerprint();
+        if self.fingerprint.as_ref() != Some(&fingerprint) {
+            verbose_println!("Network changed, evaluating the conditions of every fragment");
+            for entry in self.entries.values_mut() {
+                entry.matched = None;
+            }
+            self.fingerprint = Some(fingerprint);
+        }
+
+        let previous: Vec<Option<bool>> = fragments
+            .iter()
+            .map(|fragment| {
+                self.entries
+                    .get(&fragment.path)
+                    .filter(|entry| entry.fragment == *fragment)
+                    .and_then(|entry| entry.matched)
+            })
+            .collect();
+
+        let pending: Vec<Fragment> = fragments
+            .iter()
+            .zip(&previous)
+            .filter(|(_, matched)| matched.is_none())
+            .map(|(fragment, _)| fragment.clone())
+            .collect();
+        let mut results =
+            crate::engine::evaluate(&pending, context, crate::engine::budget()).into_iter();
+
+        let mut matches = Vec::with_capacity(fragments.len());
+        for (fragment, matched) in fragments.iter().zip(previous) {
+            let matched = match matched {
+                Some(matched) => {
+                    verbose_println!(
+                        "Reusing the previous outcome for {}",
+                        fragment.path.display()
+                    );
+                    matched
+                }
+                None => {
+                    // Fragments cut off by the probe budget are probed again next time.
+                    let matched = results.next().flatten();
+                    if let Some(entry) = self.entries.get_mut(&fragment.path) {
+                        entry.matched = matched;
+                    }
+                    matched.unwrap_or(false)
+                }
+            };
+
+            matches.push(matched);
+        }
+
+        self.local = fragments
+            .iter()
+            .zip(&matches)
+            .filter(|(_, matched)| **matched)
+            .map(|(fragment, _)| fragment.path.clone())
+            .collect();
+        matches
+    }
+}
@@ -0,0 +1,778 @@
+//! # CLI
+//!
+//! This module contains the command line parser of the binary. Every argument is validated before
+//! anything is read or generated.
+
+use sshconfgen::generator::{is_plugin_name, parse_cron_schedule};
+use sshconfgen::{Error, Result};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// The default interval of `--monitor-ssid`, in seconds
+pub const DEFAULT_MONITOR_INTERVAL: u64 = 20;
+
+/// What the binary was asked to do
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    Help,
+    Version,
+    /// Generate the SSH config, and keep monitoring the SSIDs with `monitor` or `--monitor-ssid`
+    Generate(Options),
+    /// Check the fragments and the rules they would generate without writing anything
+    Validate(Options),
+    /// Print the detected network fingerprint as JSON
+    Fingerprint(Options),
+    /// Print the parsed fragments, and optionally whether they match
+    Export(Options, Export),
+    /// Write the fragments of a file of rule definitions to `config.d`
+    Import(Options, Import),
+    /// Pull the repository of shared fragments and fetch the remote bundles, then generate
+    Sync(Options),
+    /// Install a hook generating the SSH config when the network changes
+    Install(Options, Installer),
+    /// Exit with status 0 if the named network is active, for `Match exec` lines
+    MatchHelper(Options, String),
+    /// Print the profile of the last generation
+    Current(Options),
+    /// Print the last generation recorded in the state file, in the given format if any
+    Status(Options, Option<Format>),
+    /// Print the hosts of the SSH config in use, in the given format if any
+    Hosts(Options, Option<Format>),
+    /// Connect to the given hosts, or the canary hosts of the settings, with the SSH config in use
+    Test(Options, Vec<String>),
+    /// Restore the SSH config from a backup, or list the backups
+    Restore(Options, Restore),
+    /// Upgrade the fragments written for an older format
+    Migrate(Options),
+    /// Move the fragments of the legacy `conf.d` directory to `config.d`, leaving a symbolic link
+    /// in its place if asked
+    MigrateLayout(Options, bool),
+    /// Replace the binary with the latest release, or only report whether there is one
+    SelfUpdate(Options, bool),
+    /// Run the `sshconfgen-<name>` executable with the remaining arguments
+    Plugin(Options, String, Vec<String>),
+}
+
+/// The options of a generation
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub verbose: bool,
+    pub timings: bool,
+    /// Whether to print reports without colors, even on a terminal
+    pub no_color: bool,
+    /// Whether to keep monitoring the SSIDs after generating
+    pub monitor: bool,
+    /// The SSID polling interval in seconds, defaults to the `monitor_interval` setting
+    pub monitor_interval: Option<u64>,
+    /// The loopback address the HTTP listener of monitor mode serves
+    pub listen: Option<SocketAddr>,
+    /// Whether to publish monitor mode on the session bus
+    pub dbus: bool,
+    /// How to take over a hand-written SSH config before generating
+    pub adopt: Option<Adopt>,
+    /// Whether to create the `.ssh` and fragment directories if they are missing
+    pub init_dirs: bool,
+    /// Whether to write the SSH config even though sessions use the hosts it changes
+    pub force: bool,
+    /// Whether to show what would change and ask for confirmation before writing
+    pub interactive: bool,
+    /// Whether to only print what would change, writing nothing
+    pub dry_run: bool,
+    /// Whether to print the generated config instead of writing it
+    pub stdout: bool,
+    /// The SSH config generated, overriding the `ssh_config` setting
+    pub output: Option<PathBuf>,
+    /// The directory of the fragments, overriding the `config_dir` setting
+    pub config_dir: Option<PathBuf>,
+}
+
+/// How `--adopt` takes over a hand-written SSH config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adopt {
+    /// Archive it
+    Archive,
+    /// Archive it and convert it into a fragment of global rules
+    Fragment,
+}
+
+/// The options of `export`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Export {
+    pub format: Format,
+    /// Whether to evaluate the conditions of each fragment
+    pub evaluate: bool,
+}
+
+/// The options of `import`
+#[derive(Debug, PartialEq, Eq)]
+pub struct Import {
+    pub file: PathBuf,
+    /// The format of the file, inferred from its extension if not given
+    pub format: Option<Format>,
+    /// Whether to replace existing fragments
+    pub force: bool,
+}
+
+/// The options of `restore`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Restore {
+    /// The backup to restore, by file name or position, the newest by default
+    pub backup: Option<String>,
+    /// Whether to list the backups instead
+    pub list: bool,
+}
+
+/// The hook `install` installs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Installer {
+    /// A NetworkManager dispatcher script
+    NmDispatcher,
+    /// A Windows scheduled task
+    TaskScheduler,
+    /// A crontab entry with the given schedule
+    Cron(String),
+}
+
+/// The options selecting an installer, in the order they are listed
+const INSTALLER_FLAGS: [&str; 3] = ["--nm-dispatcher", "--task-scheduler", "--cron"];
+
+impl Installer {
+    /// Get the option selecting the installer
+    pub fn flag(&self) -> &'static str {
+        match self {
+            Installer::NmDispatcher => "--nm-dispatcher",
+            Installer::TaskScheduler => "--task-scheduler",
+            Installer::Cron(_) => "--cron",
+        }
+    }
+}
+
+/// A structured data format given with `--format`
+pub use sshconfgen::generator::Format;
+
+/// The options taking a value, given as `--name value` or `--name=value`
+const VALUE_OPTIONS: [&str; 7] =
+    ["--format", "--network", "--listen", "--cron", "--interval", "--output", "--config-dir"];
+
+/// The short names of options taking a value, given as `-n value`
+const SHORT_VALUE_OPTIONS: [(&str, &str); 1] = [("-o", "--output")];
+
+/// Parse the command line arguments, without the binary name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Action> {
+    let mut options = Options::default();
+    let mut help = false;
+    let mut version = false;
+    let mut command: Option<String> = None;
+    let mut format: Option<Format> = None;
+    let mut evaluate = false;
+    let mut force = false;
+    let mut file: Option<PathBuf> = None;
+    let mut restore = Restore::default();
+    let mut installer: Option<Installer> = None;
+    let mut network: Option<String> = None;
+    let mut interval: Option<u64> = None;
+    let mut check = false;
+    let mut symlink = false;
+    let mut hosts: Vec<String> = Vec::new();
+    let mut values: Vec<&str> = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some((name, value)) = option_value(&arg, &mut args)? {
+            if values.contains(&name) {
+                return Err(Error::Invalid(format!("arguments: {} is given more than once", name)));
+            } else if name == "--cron" && installer.is_some() {
+                let message =
+                    format!("arguments: install takes only one of {}", INSTALLER_FLAGS.join(", "));
+                return Err(Error::Invalid(message));
+            }
+            values.push(name);
+
+            match name {
+                "--format" => format = Some(parse_format(&value)?),
+                "--network" => network = Some(value),
+                "--listen" => options.listen = Some(parse_listen(&value)?),
+                "--cron" => installer = Some(Installer::Cron(parse_cron_schedule(&value)?)),
+                "--output" | "--config-dir" if value.is_empty() => {
+                    return Err(Error::Invalid(format!("argument: {} needs a path", name)));
+                }
+                "--output" => options.output = Some(PathBuf::from(value)),
+                "--config-dir" => options.config_dir = Some(PathBuf::from(value)),
+                _ => interval = Some(parse_interval(name, &value)?),
+            }
+            continue;
+        }
+
+        match arg.as_str() {
+            "-h" | "--help" => help = true,
+            "-V" | "--version" => version = true,
+            "-v" | "--verbose" => options.verbose = true,
+            "--timings" => options.timings = true,
+            "--no-color" => options.no_color = true,
+            "--dbus" => options.dbus = true,
+            "--init-dirs" => options.init_dirs = true,
+            "-i" | "--interactive" => options.interactive = true,
+            "--dry-run" => options.dry_run = true,
+            "--stdout" => options.stdout = true,
+            "--adopt" if options.adopt.is_none() => options.adopt = Some(Adopt::Archive),
+            "--adopt=fragment" if options.adopt.is_none() => options.adopt = Some(Adopt::Fragment),
+            "--evaluate" => evaluate = true,
+            "--force" => force = true,
+            "--list" => restore.list = true,
+            "--check" => check = true,
+            "--symlink" => symlink = true,
+            "--nm-dispatcher" if installer.is_none() => installer = Some(Installer::NmDispatcher),
+            "--task-scheduler" if installer.is_none() => installer = Some(Installer::TaskScheduler),
+            "generate" | "diff" | "monitor" | "validate" | "fingerprint" | "export" | "import"
+            | "sync" | "install" | "match-helper" | "current" | "restore" | "status" | "hosts"
+            | "test" | "self-update" | "migrate" | "migrate-layout"
+                if command.is_none() =>
+            {
+                command = Some(arg)
+            }
+            _ if command.is_none() && is_plugin_name(&arg) => {
+                let plugin_args = args.collect();
+                if help {
+                    return Ok(Action::Help);
+                } else if version {
+                    return Ok(Action::Version);
+                } else if options.monitor || options.listen.is_some() || options.dbus {
+                    return Err(Error::Invalid(format!(
+                        "arguments: --monitor-ssid can't be combined with {}",
+                        arg
+                    )));
+                } else if options.adopt.is_some() {
+                    return Err(Error::Invalid(format!(
+                        "arguments: --adopt can't be combined with {}",
+                        arg
+                    )));
+                }
+                return Ok(Action::Plugin(options, arg, plugin_args));
+            }
+            _ if command.as_deref() == Some("test") && !arg.starts_with('-') => hosts.push(arg),
+            _ if command.as_deref() == Some("import")
+                && file.is_none()
+                && !arg.starts_with('-') =>
+            {
+                file = Some(PathBuf::from(arg))
+            }
+            _ if command.as_deref() == Some("restore")
+                && restore.backup.is_none()
+                && !arg.starts_with('-') =>
+            {
+                restore.backup = Some(arg)
+            }
+            _ => {
+                let Some(interval) = arg.strip_prefix("--monitor-ssid") else {
+                    return Err(Error::Invalid(format!("argument: {}", arg)));
+                };
+
+                if options.monitor {
+                    return Err(Error::Invalid(
+                        "arguments: --monitor-ssid is given more than once".to_string(),
+                    ));
+                }
+
+                options.monitor = true;
+                options.monitor_interval = match interval.strip_prefix('=') {
+                    Some(interval) => Some(parse_interval("--monitor-ssid", interval)?),
+                    None if interval.is_empty() => None,
+                    None => return Err(Error::Invalid(format!("argument: {}", arg))),
+                };
+            }
+        }
+    }
+
+    if help {
+        return Ok(Action::Help);
+    } else if version {
+        return Ok(Action::Version);
+    }
+
+    // The options only some commands accept, with those commands
+    let command_name = command.as_deref().unwrap_or("generate");
+    let installer_flag = installer.as_ref().map_or("--cron", Installer::flag);
+    let restricted: [(&str, bool, &[&str]); 14] = [
+        ("--monitor-ssid", options.monitor, &["generate"]),
+        ("--stdout", options.stdout, &["generate"]),
+        ("--dry-run", options.dry_run, &["generate", "diff"]),
+        ("--interval", interval.is_some(), &["monitor"]),
+        ("--adopt", options.adopt.is_some(), &["generate", "monitor"]),
+        ("--interactive", options.interactive, &["generate", "sync"]),
+        ("--force", force, &["generate", "import"]),
+        ("--evaluate", evaluate, &["export"]),
+        ("--format", format.is_some(), &["export", "import", "status", "hosts"]),
+        ("--list", restore.list, &["restore"]),
+        ("--check", check, &["self-update"]),
+        ("--symlink", symlink, &["migrate-layout"]),
+        ("--network", network.is_some(), &["match-helper"]),
+        (installer_flag, installer.is_some(), &["install"]),
+    ];
+    for (flag, _, commands) in restricted.iter().filter(|(_, given, _)| *given) {
+        if !commands.contains(&command_name) {
+            return Err(Error::Invalid(format!(
+                "arguments: {} is only valid for {}",
+                flag,
+                list(commands)
+            )));
+        }
+    }
+
+    if command_name == "monitor" {
+        options.monitor = true;
+        options.monitor_interval = interval;
+    } else if command_name == "diff" {
+        options.dry_run = true;
+    }
+
+    if options.listen.is_some() && !options.monitor {
+        return Err(Error::Invalid(
+            "arguments: --listen needs monitor or --monitor-ssid".to_string(),
+        ));
+    } else if options.dbus && !options.monitor {
+        return Err(Error::Invalid(
+            "arguments: --dbus needs monitor or --monitor-ssid".to_string(),
+        ));
+    } else if options.interactive && options.monitor {
+        return Err(Error::Invalid(
+            "arguments: --interactive can't be combined with --monitor-ssid".to_string(),
+        ));
+    } else if let Some(flag) = options.dry_run.then(|| write_conflict(&options)).flatten() {
+        return Err(Error::Invalid(format!(
+            "arguments: --dry-run can't be combined with {}",
+            flag
+        )));
+    } else if options.stdout && (options.dry_run || options.timings || options.output.is_some()) {
+        let message = "arguments: --stdout can't be combined with --dry-run, --timings or --output";
+        return Err(Error::Invalid(message.to_string()));
+    } else if let Some(flag) = options.stdout.then(|| write_conflict(&options)).flatten() {
+        return Err(Error::Invalid(format!("arguments: --stdout can't be combined with {}", flag)));
+    } else if restore.list && restore.backup.is_some() {
+        return Err(Error::Invalid(
+            "arguments: --list can't be combined with a backup".to_string(),
+        ));
+    }
+
+    options.force = force && command_name != "import";
+    Ok(match command_name {
+        "validate" => Action::Validate(options),
+        "fingerprint" => Action::Fingerprint(options),
+        "export" => {
+            Action::Export(options, Export { format: format.unwrap_or_default(), evaluate })
+        }
+        "import" => {
+            let file =
+                file.ok_or_else(|| Error::Invalid("arguments: import needs a file".to_string()))?;
+            Action::Import(options, Import { file, format, force })
+        }
+        "sync" => Action::Sync(options),
+        "current" => Action::Current(options),
+        "restore" => Action::Restore(options, restore),
+        "status" => Action::Status(options, format),
+        "hosts" => Action::Hosts(options, format),
+        "test" => Action::Test(options, hosts),
+        "self-update" => Action::SelfUpdate(options, check),
+        "migrate" => Action::Migrate(options),
+        "migrate-layout" => Action::MigrateLayout(options, symlink),
+        "install" => {
+            let Some(installer) = installer else {
+                let message =
+                    format!("arguments: install needs one of {}", INSTALLER_FLAGS.join(", "));
+                return Err(Error::Invalid(message));
+            };
+            Action::Install(options, installer)
+        }
+        "match-helper" => match network {
+            Some(network) if !network.is_empty() => Action::MatchHelper(options, network),
+            _ => return Err(Error::Invalid("arguments: match-helper needs --network".to_string())),
+        },
+        _ => Action::Generate(options),
+    })
+}
+
+/// Get the option that writes files, which `--dry-run` and `--stdout` can't be combined with, if
+/// any is given
+fn write_conflict(options: &Options) -> Option<&'static str> {
+    [
+        ("--monitor-ssid", options.monitor),
+        ("--interactive", options.interactive),
+        ("--adopt", options.adopt.is_some()),
+        ("--init-dirs", options.init_dirs),
+    ]
+    .into_iter()
+    .find_map(|(flag, given)| given.then_some(flag))
+}
+
+/// Get the name and value of `arg` if it is one of [`VALUE_OPTIONS`], taking the value from
+/// `args` unless it is given as `--name=value`
+fn option_value(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<Option<(&'static str, String)>> {
+    let arg =
+        SHORT_VALUE_OPTIONS.iter().find(|(short, _)| arg == *short).map_or(arg, |(_, name)| name);
+    for name in VALUE_OPTIONS {
+        if arg == name {
+            let value = args
+                .next()
+                .ok_or_else(|| Error::Invalid(format!("argument: {} needs a value", name)))?;
+            return Ok(Some((name, value)));
+        } else if let Some(value) = arg.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')) {
+            return Ok(Some((name, value.to_string())));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Join `commands` as `a, b and c`
+fn list(commands: &[&str]) -> String {
+    match commands {
+        [] => String::new(),
+        [command] => command.to_string(),
+        [init @ .., last] => format!("{} and {}", init.join(", "), last),
+    }
+}
+
+/// Parse the value of `--format`
+fn parse_format(format: &str) -> Result<Format> {
+    match format {
+        "json" => Ok(Format::Json),
+        "yaml" => Ok(Format::Yaml),
+        _ => Err(Error::Invalid(format!("format: {}, expected json or yaml", format))),
+    }
+}
+
+/// Parse the address of `--listen`, which must be a loopback address
+fn parse_listen(address: &str) -> Result<SocketAddr> {
+    match address.parse::<SocketAddr>() {
+        Ok(address) if address.ip().is_loopback() => Ok(address),
+        _ => Err(Error::Invalid(format!(
+            "listen address: {}, expected a loopback IP and port",
+            address
+        ))),
+    }
+}
+
+/// Parse the interval of `--monitor-ssid` or `--interval`, a positive number of seconds
+fn parse_interval(flag: &str, interval: &str) -> Result<u64> {
+    match interval.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(seconds),
+        _ => Err(Error::Invalid(format!("duration specified for {}: {}", flag, interval))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Action> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(parse_args(&[]).unwrap(), Action::Generate(Options::default()));
+        assert_eq!(
+            parse_args(&["-v", "--timings", "--monitor-ssid=5"]).unwrap(),
+            Action::Generate(Options {
+                verbose: true,
+                timings: true,
+                no_color: false,
+                monitor: true,
+                monitor_interval: Some(5),
+                listen: None,
+                dbus: false,
+                adopt: None,
+                init_dirs: false,
+                force: false,
+                interactive: false,
+                dry_run: false,
+                stdout: false,
+                output: None,
+                config_dir: None
+            })
+        );
+        assert_eq!(
+            parse_args(&["generate", "--force"]).unwrap(),
+            Action::Generate(Options { force: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["-i", "sync"]).unwrap(),
+            Action::Sync(Options { interactive: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["--monitor-ssid"]).unwrap(),
+            Action::Generate(Options { monitor: true, ..Options::default() })
+        );
+        assert_eq!(parse_args(&["--monitor-ssid", "-h"]).unwrap(), Action::Help);
+        assert_eq!(
+            parse_args(&["--monitor-ssid", "--listen", "127.0.0.1:7422"]).unwrap(),
+            Action::Generate(Options {
+                monitor: true,
+                listen: "127.0.0.1:7422".parse().ok(),
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse_args(&["--dbus", "--monitor-ssid"]).unwrap(),
+            Action::Generate(Options { monitor: true, dbus: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["-v", "hosts-json", "--out", "-v"]).unwrap(),
+            Action::Plugin(
+                Options { verbose: true, ..Options::default() },
+                "hosts-json".to_string(),
+                vec!["--out".to_string(), "-v".to_string()]
+            )
+        );
+        assert_eq!(parse_args(&["-h", "hosts-json"]).unwrap(), Action::Help);
+        assert_eq!(
+            parse_args(&["monitor", "--interval", "5", "--dbus"]).unwrap(),
+            Action::Generate(Options {
+                monitor: true,
+                monitor_interval: Some(5),
+                dbus: true,
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse_args(&["monitor", "--listen=127.0.0.1:7422"]).unwrap(),
+            Action::Generate(Options {
+                monitor: true,
+                listen: "127.0.0.1:7422".parse().ok(),
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse_args(&["validate", "-v"]).unwrap(),
+            Action::Validate(Options { verbose: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["diff"]).unwrap(),
+            Action::Generate(Options { dry_run: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["--dry-run", "-v"]).unwrap(),
+            Action::Generate(Options { dry_run: true, verbose: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["generate", "--stdout"]).unwrap(),
+            Action::Generate(Options { stdout: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["-o", "config.generated", "diff"]).unwrap(),
+            Action::Generate(Options {
+                dry_run: true,
+                output: Some("config.generated".into()),
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse_args(&["status", "--output=/etc/ssh/ssh_config"]).unwrap(),
+            Action::Status(
+                Options { output: Some("/etc/ssh/ssh_config".into()), ..Options::default() },
+                None
+            )
+        );
+        assert_eq!(
+            parse_args(&["export", "--config-dir", "~/dotfiles/ssh"]).unwrap(),
+            Action::Export(
+                Options { config_dir: Some("~/dotfiles/ssh".into()), ..Options::default() },
+                Export::default()
+            )
+        );
+        assert_eq!(parse_args(&["current"]).unwrap(), Action::Current(Options::default()));
+        assert_eq!(parse_args(&["status"]).unwrap(), Action::Status(Options::default(), None));
+        assert_eq!(
+            parse_args(&["test", "nas", "-v", "git"]).unwrap(),
+            Action::Test(
+                Options { verbose: true, ..Options::default() },
+                vec!["nas".to_string(), "git".to_string()]
+            )
+        );
+        assert_eq!(
+            parse_args(&["hosts", "--format", "json"]).unwrap(),
+            Action::Hosts(Options::default(), Some(Format::Json))
+        );
+        assert_eq!(
+            parse_args(&["status", "--format=json"]).unwrap(),
+            Action::Status(Options::default(), Some(Format::Json))
+        );
+        assert_eq!(
+            parse_args(&["restore"]).unwrap(),
+            Action::Restore(Options::default(), Restore::default())
+        );
+        assert_eq!(
+            parse_args(&["restore", "2"]).unwrap(),
+            Action::Restore(
+                Options::default(),
+                Restore { backup: Some("2".to_string()), list: false }
+            )
+        );
+        assert_eq!(
+            parse_args(&["--list", "restore"]).unwrap(),
+            Action::Restore(Options::default(), Restore { backup: None, list: true })
+        );
+        assert_eq!(
+            parse_args(&["self-update", "--check"]).unwrap(),
+            Action::SelfUpdate(Options::default(), true)
+        );
+        assert_eq!(
+            parse_args(&["migrate", "-v"]).unwrap(),
+            Action::Migrate(Options { verbose: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["migrate-layout", "--symlink"]).unwrap(),
+            Action::MigrateLayout(Options::default(), true)
+        );
+        assert!(parse_args(&["migrate", "--symlink"]).is_err());
+        assert_eq!(
+            parse_args(&["generate", "--adopt=fragment"]).unwrap(),
+            Action::Generate(Options { adopt: Some(Adopt::Fragment), ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["-v", "fingerprint"]).unwrap(),
+            Action::Fingerprint(Options { verbose: true, ..Options::default() })
+        );
+        assert_eq!(
+            parse_args(&["export", "--format", "yaml", "--evaluate"]).unwrap(),
+            Action::Export(Options::default(), Export { format: Format::Yaml, evaluate: true })
+        );
+        assert_eq!(
+            parse_args(&["--format=json", "export"]).unwrap(),
+            Action::Export(Options::default(), Export::default())
+        );
+        assert_eq!(
+            parse_args(&["import", "rules.yaml", "--force"]).unwrap(),
+            Action::Import(
+                Options::default(),
+                Import { file: "rules.yaml".into(), format: None, force: true }
+            )
+        );
+        assert_eq!(
+            parse_args(&["import", "--format", "json", "rules"]).unwrap(),
+            Action::Import(
+                Options::default(),
+                Import { file: "rules".into(), format: Some(Format::Json), force: false }
+            )
+        );
+        assert_eq!(
+            parse_args(&["generate", "--monitor-ssid=5"]).unwrap(),
+            Action::Generate(Options {
+                monitor: true,
+                monitor_interval: Some(5),
+                ..Options::default()
+            })
+        );
+        assert_eq!(
+            parse_args(&["install", "--nm-dispatcher"]).unwrap(),
+            Action::Install(Options::default(), Installer::NmDispatcher)
+        );
+        assert_eq!(
+            parse_args(&["--task-scheduler", "install"]).unwrap(),
+            Action::Install(Options::default(), Installer::TaskScheduler)
+        );
+        assert_eq!(
+            parse_args(&["install", "--cron", "*/5 * * * *"]).unwrap(),
+            Action::Install(Options::default(), Installer::Cron("*/5 * * * *".to_string()))
+        );
+        assert_eq!(
+            parse_args(&["install", "--cron=@hourly"]).unwrap(),
+            Action::Install(Options::default(), Installer::Cron("@hourly".to_string()))
+        );
+        assert_eq!(
+            parse_args(&["match-helper", "--network", "office"]).unwrap(),
+            Action::MatchHelper(Options::default(), "office".to_string())
+        );
+        assert_eq!(
+            parse_args(&["sync", "--timings", "--no-color", "--init-dirs"]).unwrap(),
+            Action::Sync(Options {
+                timings: true,
+                no_color: true,
+                init_dirs: true,
+                ..Options::default()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        for args in [
+            &["--verbos"][..],
+            &["30"],
+            &["--monitor-ssidx"],
+            &["--monitor-ssid=abc"],
+            &["--monitor-ssid=0"],
+            &["--monitor-ssid", "--monitor-ssid=5"],
+            &["--help", "--bogus"],
+            &["fingerprint", "fingerprint"],
+            &["fingerprint", "--monitor-ssid"],
+            &["fingerprint", "export"],
+            &["export", "--format"],
+            &["export", "--format", "xml"],
+            &["--evaluate"],
+            &["fingerprint", "--format=json"],
+            &["import"],
+            &["import", "a.yaml", "b.yaml"],
+            &["import", "a.yaml", "--evaluate"],
+            &["export", "--force"],
+            &["sync", "--monitor-ssid"],
+            &["sync", "--force"],
+            &["-i", "--monitor-ssid"],
+            &["status", "--interactive"],
+            &["install"],
+            &["install", "--monitor-ssid"],
+            &["--nm-dispatcher"],
+            &["install", "--nm-dispatcher", "--nm-dispatcher"],
+            &["install", "--nm-dispatcher", "--task-scheduler"],
+            &["sync", "--task-scheduler"],
+            &["install", "--cron"],
+            &["install", "--cron", "* * *"],
+            &["install", "--cron=@hourly", "--nm-dispatcher"],
+            &["--cron=@daily"],
+            &["match-helper"],
+            &["match-helper", "--network="],
+            &["match-helper", "--network", "a", "--network", "b"],
+            &["--network", "office"],
+            &["--listen=127.0.0.1:7422"],
+            &["--monitor-ssid", "--listen", "0.0.0.0:7422"],
+            &["--monitor-ssid", "--listen", "localhost"],
+            &["--dbus"],
+            &["--adopt", "--adopt"],
+            &["--adopt=config"],
+            &["sync", "--adopt"],
+            &["--monitor-ssid", "hosts-json"],
+            &["../hosts-json"],
+            &["fingerprint", "--dbus"],
+            &["--list"],
+            &["status", "--monitor-ssid"],
+            &["restore", "1", "2"],
+            &["restore", "--list", "1"],
+            &["--check"],
+            &["monitor", "--monitor-ssid"],
+            &["--dry-run", "--monitor-ssid"],
+            &["diff", "-i"],
+            &["diff", "--adopt"],
+            &["diff", "--init-dirs"],
+            &["sync", "--dry-run"],
+            &["--stdout", "--monitor-ssid"],
+            &["--stdout", "--adopt"],
+            &["--stdout", "--timings"],
+            &["diff", "--stdout"],
+            &["export", "--stdout"],
+            &["-o"],
+            &["--output="],
+            &["--config-dir"],
+            &["--stdout", "-o", "config"],
+            &["monitor", "--interval=0"],
+            &["monitor", "--interactive"],
+            &["--interval", "5"],
+            &["validate", "--force"],
+            &["status", "--format=json", "--format=yaml"],
+            &["install", "--nm-dispatcher", "--cron", "@daily"],
+        ] {
+            assert!(matches!(parse_args(args), Err(Error::Invalid(_))), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! # Color
+//!
+//! This module colors the reports printed on stdout, e.g. the timings of matched and unmatched
+//! conditions and the lines of diffs. Colors are only used when stdout is a terminal, unless the
+//! `NO_COLOR` environment variable is set, `TERM` is `dumb`, or `--no-color` is given.
+
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+/// A color of the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Color {
+    /// Added lines, matches and local rules
+    Green,
+    /// Removed lines, misses and remote rules
+    Red,
+    /// Warnings and changes made outside of sshconfgen
+    Yellow,
+    /// Headers
+    Bold,
+}
+
+impl Color {
+    /// Get the SGR code of the color
+    fn code(self) -> &'static str {
+        match self {
+            Color::Green => "32",
+            Color::Red => "31",
+            Color::Yellow => "33",
+            Color::Bold => "1",
+        }
+    }
+}
+
+/// Turn colors off, whether or not the terminal supports them
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+/// Get whether text printed on stdout is colored
+pub fn is_enabled() -> bool {
+    static DETECTED: OnceLock<bool> = OnceLock::new();
+    !DISABLED.load(Ordering::SeqCst) && *DETECTED.get_or_init(detect)
+}
+
+/// Check whether stdout is a terminal that should get colors
+fn detect() -> bool {
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let dumb = std::env::var_os("TERM").is_some_and(|term| term == "dumb");
+    !no_color && !dumb && std::io::stdout().is_terminal() && enable_ansi()
+}
+
+/// Let the Windows console interpret escape sequences, which it doesn't by default
+#[cfg(windows)]
+fn enable_ansi() -> bool {
+    use windows_sys::Win32::System::Console::{
+        GetConsoleMode, GetStdHandle, SetConsoleMode, ENABLE_VIRTUAL_TERMINAL_PROCESSING,
+        STD_OUTPUT_HANDLE,
+    };
+
+    // SAFETY: the handle is only passed to console functions, which fail on invalid handles.
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        let mut mode = 0;
+        GetConsoleMode(handle, &mut mode) != 0
+            && (mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING != 0
+                || SetConsoleMode(handle, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING) != 0)
+    }
+}
+
+#[cfg(not(windows))]
+fn enable_ansi() -> bool {
+    true
+}
+
+/// Color `text` if colors are enabled. Pad `text` before coloring it, width and alignment
+/// don't apply to the escape sequences.
+pub fn paint(text: impl std::fmt::Display, color: Color) -> String {
+    if is_enabled() {
+        format!("\x1b[{}m{}\x1b[0m", color.code(), text)
+    } else {
+        text.to_string()
+    }
+}
@@ -0,0 +1,193 @@
+//! # Context
+//!
+//! This module contains the evaluation context of a generation run, which memoizes the probes so
+//! conditions shared by several fragments only reach the network once per run.
+
+use crate::error::Error;
+use crate::fingerprint::Fingerprint;
+use crate::hwaddr::NeighborTable;
+use crate::provider::Providers;
+use crate::ssid::Connection;
+use crate::state::State;
+use crate::vpn::Adapter;
+use crate::{is_verbose, verbose_println};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+/// A probe result whose error can be handed to every condition that asked for it
+pub type Shared<T> = std::result::Result<T, Arc<Error>>;
+
+/// The results of a probe keyed by its argument, a pending probe is shared by concurrent callers
+type Memo<T> = Mutex<HashMap<String, Arc<OnceLock<T>>>>;
+
+/// The providers of a run along with the results of the probes made so far
+///
+/// Clones share the same results, so a context can be handed to concurrently running probes.
+#[derive(Clone)]
+pub struct Context {
+    pub providers: Providers,
+    probes: Arc<Probes>,
+    state: Option<Arc<Mutex<State>>>,
+}
+
+#[derive(Default)]
+struct Probes {
+    fingerprint: OnceLock<Fingerprint>,
+    ping_timeout: OnceLock<Duration>,
+    connections: OnceLock<Shared<Vec<Connection>>>,
+    vpn_adapters: OnceLock<Shared<Vec<Adapter>>>,
+    pings: Memo<bool>,
+    arp_pings: Memo<bool>,
+    hw_addresses: Memo<Shared<String>>,
+    ipv4_neighbors: OnceLock<Shared<NeighborTable>>,
+    ipv6_neighbors: OnceLock<Shared<NeighborTable>>,
+}
+
+impl Context {
+    /// Create an empty context for a run using `providers`
+    pub fn new(providers: &Providers) -> Context {
+        Context { providers: providers.clone(), probes: Arc::default(), state: None }
+    }
+
+    /// Create a context reusing the probe results kept in `state` from previous runs
+    pub fn with_state(providers: &Providers, state: State) -> Context {
+        Context { state: Some(Arc::new(Mutex::new(state))), ..Context::new(providers) }
+    }
+
+    /// Get the state with the probe results of this run added, if the context has one
+    pub fn state(&self) -> Option<State> {
+        Some(self.state.as_ref()?.lock().unwrap().clone())
+    }
+
+    /// Get the fingerprint of the network, detected once per run
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.probes.fingerprint.get_or_init(|| Fingerprint::detect(self)).clone()
+    }
+
+    /// Get the ping timeout for this network, scaled to the round trip time of the gateway
+    /// within the configured bounds. Measured once per run, on the first ping.
+    pub fn ping_timeout(&self) -> Duration {
+        *self.probes.ping_timeout.get_or_init(|| {
+            let settings = &crate::settings::get().ping_timeout;
+            let rtt = self
+                .fingerprint()
+                .gateway_address()
+                .and_then(|gateway| self.providers.ping.rtt(&gateway, settings.max()));
+            let timeout = settings.timeout(rtt);
+            verbose_println!(
+                "Gateway round trip time {:?}, pinging with a {:?} timeout",
+                rtt,
+                timeout
+            );
+            timeout
+        })
+    }
+
+    /// Get every connected wireless interface along with its SSID
+    pub fn connections(&self) -> Shared<Vec<Connection>> {
+        self.probes
+            .connections
+            .get_or_init(|| self.providers.ssid.connections().map_err(Arc::new))
+            .clone()
+    }
+
+    /// Get the VPN adapters that are currently up
+    pub fn vpn_adapters(&self) -> Shared<Vec<Adapter>> {
+        self.probes
+            .vpn_adapters
+            .get_or_init(|| self.providers.vpn.vpn_adapters().map_err(Arc::new))
+            .clone()
+    }
+
+    /// Get whether a host answers an ICMP echo request
+    pub fn pingable(&self, host: &str) -> bool {
+        let ttl = crate::settings::get().state_ttl.ping;
+        memoize(&self.probes.pings, host, || {
+            self.persisted("ping", host, ttl, || {
+                Some(self.providers.ping.pingable_within(host, self.ping_timeout()).to_string())
+            })
+            .is_some_and(|result| result == "true")
+        })
+    }
+
+    /// Get whether a host on the local segment answers an ARP request
+    pub fn arp_pingable(&self, ip_address: &str) -> bool {
+        let ttl = crate::settings::get().state_ttl.arp_ping;
+        memoize(&self.probes.arp_pings, ip_address, || {
+            self.persisted("arp_ping", ip_address, ttl, || {
+                Some(self.providers.ping.arp_pingable(ip_address).to_string())
+            })
+            .is_some_and(|result| result == "true")
+        })
+    }
+
+    /// Get the hardware address of a device given its IP address
+    ///
+    /// Only found addresses are kept in the state file.
+    pub fn hw_address(&self, ip_address: &str) -> Shared<String> {
+        let ttl = crate::settings::get().state_ttl.gateway;
+        memoize(&self.probes.hw_addresses, ip_address, || {
+            let mut error = None;
+            let mac = self.persisted("gateway", ip_address, ttl, || {
+                self.lookup_neighbor(ip_address).map_err(|e| error = Some(e)).ok()
+            });
+
+            mac.ok_or_else(|| error.unwrap_or_else(|| Arc::new(Error::NotFound("MAC address"))))
+        })
+    }
+
+    /// Look an address up in the neighbor table read for this run, or on its own when the
+    /// provider can't read the whole table
+    fn lookup_neighbor(&self, ip_address: &str) -> Shared<String> {
+        let ipv6 = ip_address.split('%').next().unwrap().parse::<Ipv6Addr>().is_ok();
+        let neighbors =
+            if ipv6 { &self.probes.ipv6_neighbors } else { &self.probes.ipv4_neighbors };
+        let table = neighbors
+            .get_or_init(|| self.providers.neighbor.neighbor_table(ipv6).map_err(Arc::new));
+
+        match table {
+            Ok(table) => table.lookup(ip_address).map_err(Arc::new),
+            Err(e) if matches!(**e, Error::Unsupported(_)) => {
+                self.providers.neighbor.hw_address(ip_address).map_err(Arc::new)
+            }
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Get the result of a probe from the state file, or probe and keep the result for `ttl`
+    /// seconds. `None` results are not kept.
+    fn persisted(
+        &self,
+        kind: &str,
+        key: &str,
+        ttl: u64,
+        probe: impl FnOnce() -> Option<String>,
+    ) -> Option<String> {
+        let Some(state) = self.state.as_ref().filter(|_| ttl > 0) else {
+            return probe();
+        };
+
+        let fingerprint = self.fingerprint();
+        let name = format!("{}:{}", kind, key);
+        {
+            let mut state = state.lock().unwrap();
+            state.observe(&fingerprint);
+            if let Some(result) = state.get(&name) {
+                verbose_println!("Reusing the {} result for {} from the state file", kind, key);
+                return Some(result.to_string());
+            }
+        }
+
+        let result = probe()?;
+        state.lock().unwrap().insert(name, result.clone(), ttl);
+        Some(result)
+    }
+}
+
+/// Get the result of the probe for `key`, running `probe` unless it was already made
+fn memoize<T: Clone>(memo: &Memo<T>, key: &str, probe: impl FnOnce() -> T) -> T {
+    let cell = memo.lock().unwrap().entry(key.to_string()).or_default().clone();
+    cell.get_or_init(probe).clone()
+}
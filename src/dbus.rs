@@ -0,0 +1,108 @@
+//! # D-Bus
+//!
+//! This module publishes monitor mode on the session bus as `org.sshconfgen`, so desktop widgets
+//! and other services can show the current profile and request generations. The object
+//! `/org/sshconfgen` has the interface `org.sshconfgen` with:
+//!
+//! * the `ActiveProfile` property, the profile of the last generation, empty before it
+//! * the `Fingerprint` property, the network of the last generation as JSON, empty before it
+//! * the `Regenerate` method, requesting a generation
+//!
+//! Both properties emit `PropertiesChanged` when the profile changes.
+
+use crate::error::{Error, Result};
+use crate::monitor::{Monitor, ProfileChange, Trigger};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use zbus::zvariant::Value;
+
+/// The well-known name and interface of the service
+pub const SERVICE_NAME: &str = "org.sshconfgen";
+
+/// The path of the object of the service
+pub const OBJECT_PATH: &str = "/org/sshconfgen";
+
+/// The profile and fingerprint of the last generation, as published
+#[derive(Default)]
+struct Published {
+    profile: String,
+    fingerprint: String,
+}
+
+impl Published {
+    fn from_change(change: &ProfileChange) -> Published {
+        let fingerprint = change
+            .fingerprint
+            .as_ref()
+            .and_then(|fingerprint| serde_json::to_string(fingerprint).ok());
+        Published {
+            profile: change.profile.clone().unwrap_or_default(),
+            fingerprint: fingerprint.unwrap_or_default(),
+        }
+    }
+}
+
+/// The object published on the bus
+struct Service {
+    monitor: Monitor,
+    published: Arc<Mutex<Published>>,
+}
+
+#[zbus::interface(name = "org.sshconfgen")]
+impl Service {
+    /// Request a generation
+    fn regenerate(&self) {
+        self.monitor.trigger(Trigger::Requested);
+    }
+
+    /// The active named network, or `local` or `remote`
+    #[zbus(property)]
+    fn active_profile(&self) -> String {
+        self.published.lock().unwrap().profile.clone()
+    }
+
+    /// The network the conditions were evaluated on, as JSON
+    #[zbus(property)]
+    fn fingerprint(&self) -> String {
+        self.published.lock().unwrap().fingerprint.clone()
+    }
+}
+
+/// Publish `monitor` on the session bus until the process exits. The connection is served on a
+/// background thread, and the properties follow the profile changes reported by the monitor.
+pub fn publish(monitor: &Monitor) -> Result<()> {
+    let published = Arc::new(Mutex::new(Published::default()));
+    let service = Service { monitor: monitor.clone(), published: published.clone() };
+
+    let connection = zbus::blocking::connection::Builder::session()
+        .and_then(|builder| builder.name(SERVICE_NAME))
+        .and_then(|builder| builder.serve_at(OBJECT_PATH, service))
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::Bus(e.to_string()))?;
+
+    monitor.subscribe(move |change| {
+        let current = Published::from_change(change);
+        let changed = HashMap::from([
+            ("ActiveProfile", Value::from(current.profile.clone())),
+            ("Fingerprint", Value::from(current.fingerprint.clone())),
+        ]);
+        *published.lock().unwrap() = current;
+
+        let body = (SERVICE_NAME, changed, Vec::<&str>::new());
+        let properties = "org.freedesktop.DBus.Properties";
+        if let Err(e) = connection.emit_signal(
+            None::<&str>,
+            OBJECT_PATH,
+            properties,
+            "PropertiesChanged",
+            &body,
+        ) {
+            crate::platform::warn_once(
+                "dbus",
+                &format!("Unable to signal the profile change over D-Bus: {}", e),
+            );
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! # Diff
+//!
+//! This module compares two versions of a file line by line, to show what a generation would
+//! change before it is written. Changes are printed as a unified diff with three lines of context,
+//! colored on terminals.
+
+use crate::color::{paint, Color};
+use std::fmt::Write;
+
+/// The lines of context around each change
+const CONTEXT: usize = 3;
+
+/// A line of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Get the changes turning `old` into `new`, keeping the longest common sequence of lines
+pub fn changes<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+
+    // lengths[i][j] is the length of the longest common sequence of a[i..] and b[j..].
+    let mut lengths = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes: Vec<Change> = old[..prefix].iter().map(|line| Change::Same(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            changes.push(Change::Same(a[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            changes.push(Change::Removed(a[i]));
+            i += 1;
+        } else {
+            changes.push(Change::Added(b[j]));
+            j += 1;
+        }
+    }
+    changes.extend(a[i..].iter().map(|line| Change::Removed(line)));
+    changes.extend(b[j..].iter().map(|line| Change::Added(line)));
+    changes.extend(old[old.len() - suffix..].iter().map(|line| Change::Same(line)));
+    changes
+}
+
+/// Get the unified diff of `old`, named `old_name`, and `new`, named `new_name`. It is empty when
+/// they have the same lines.
+pub fn unified(old_name: &str, new_name: &str, old: &str, new: &str) -> String {
+    let changes = changes(old, new);
+    let changed: Vec<usize> =
+        (0..changes.len()).filter(|&index| !matches!(changes[index], Change::Same(_))).collect();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    // The hunks, as ranges of changes, merged when their context overlaps
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &index in &changed {
+        let (start, end) =
+            (index.saturating_sub(CONTEXT), (index + CONTEXT + 1).min(changes.len()));
+        match hunks.last_mut() {
+            Some(hunk) if start <= hunk.1 => hunk.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut diff = String::new();
+    let _ = writeln!(diff, "{}", paint(format!("--- {}", old_name), Color::Bold));
+    let _ = writeln!(diff, "{}", paint(format!("+++ {}", new_name), Color::Bold));
+    for (start, end) in hunks {
+        let before = &changes[..start];
+        let old_start = before.iter().filter(|change| !matches!(change, Change::Added(_))).count();
+        let new_start =
+            before.iter().filter(|change| !matches!(change, Change::Removed(_))).count();
+        let hunk = &changes[start..end];
+        let old_count = hunk.iter().filter(|change| !matches!(change, Change::Added(_))).count();
+        let new_count = hunk.iter().filter(|change| !matches!(change, Change::Removed(_))).count();
+        // An empty side starts at the line before the hunk.
+        let header = format!(
+            "@@ -{},{} +{},{} @@",
+            old_start + usize::from(old_count > 0),
+            old_count,
+            new_start + usize::from(new_count > 0),
+            new_count
+        );
+        let _ = writeln!(diff, "{}", paint(header, Color::Bold));
+
+        for change in hunk {
+            let _ = match change {
+                Change::Same(line) => writeln!(diff, " {}", line),
+                Change::Removed(line) => {
+                    writeln!(diff, "{}", paint(format!("-{}", line), Color::Red))
+                }
+                Change::Added(line) => {
+                    writeln!(diff, "{}", paint(format!("+{}", line), Color::Green))
+                }
+            };
+        }
+    }
+
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_changed_lines_with_context() {
+        let old = "Host a\n  HostName 10.0.0.1\n\nHost b\n  User me\n";
+        let new = "Host a\n  HostName a.example.com\n\nHost b\n  User me\n\nHost c\n";
+        assert_eq!(
+            changes(old, new)[..3],
+            [
+                Change::Same("Host a"),
+                Change::Removed("  HostName 10.0.0.1"),
+                Change::Added("  HostName a.example.com")
+            ]
+        );
+
+        let diff = unified("config", "config (new)", old, new);
+        let expected =
+            "--- config\n+++ config (new)\n@@ -1,5 +1,7 @@\n Host a\n-  HostName 10.0.0.1\n\
+                        +  HostName a.example.com\n \n Host b\n   User me\n+\n+Host c\n";
+        assert_eq!(diff, expected);
+        assert_eq!(unified("a", "b", old, old), "");
+
+        let diff = unified("config", "config (new)", "", "Host a\n");
+        assert!(diff.contains("@@ -0,0 +1,1 @@\n+Host a\n"), "{}", diff);
+    }
+}
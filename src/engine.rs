@@ -0,0 +1,307 @@
+//! # Engine
+//!
+//! This module contains the async engine probing the conditions of every fragment concurrently,
+//! so a generation finishes within a bounded wall-clock budget however many unreachable hosts
+//! the fragments list.
+
+use crate::context::Context;
+use crate::phases::Recorder;
+use crate::sshconf::{local_condition_match, local_rules_match, Condition, Fragment};
+use crate::{is_verbose, verbose_println};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::{task::JoinSet, time::Instant};
+
+/// The default time budget for probing the conditions of every fragment
+pub const DEFAULT_BUDGET: Duration = Duration::from_secs(10);
+
+/// The number of condition lines probed at once, across all fragments
+const MAX_PROBES: usize = 8;
+
+/// How long probing runs before progress is reported on the terminal
+const PROGRESS_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the progress line is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The longest progress line, so it fits a single row of a default terminal
+const PROGRESS_WIDTH: usize = 79;
+
+/// Get the probe budget from the settings
+pub fn budget() -> Duration {
+    crate::settings::get().probe_budget.map(Duration::from_secs).unwrap_or(DEFAULT_BUDGET)
+}
+
+/// Get whether the conditions of each fragment match, probing all fragments concurrently
+///
+/// The condition lines of a fragment are probed in parallel and the pending probes are cancelled
+/// as soon as one matches. Fragments still being probed once `budget` runs out are reported as
+/// `None`, callers use their remote rules.
+///
+/// When probing takes longer than a second and stderr is a terminal, the number of probes done and
+/// the one waited on the longest are reported on stderr until it completes.
+pub fn evaluate(fragments: &[Fragment], context: &Context, budget: Duration) -> Vec<Option<bool>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .max_blocking_threads(MAX_PROBES)
+        .build();
+    let runtime = match runtime {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            verbose_println!("Unable to start the probe engine ({}), probing sequentially", e);
+            return fragments
+                .iter()
+                .map(|fragment| {
+                    Some(local_rules_match(context, &fragment.path, &fragment.conditions))
+                })
+                .collect();
+        }
+    };
+
+    let mut matches = vec![None; fragments.len()];
+    let mut fragment_timings = vec![None; fragments.len()];
+    // The probes run on other threads, they record into the generation of this one.
+    let recorder = crate::phases::current();
+    let progress = Arc::new(Mutex::new(Progress::new(fragments)));
+    runtime.block_on(async {
+        let started = Instant::now();
+        let mut tasks = JoinSet::new();
+        for (index, fragment) in fragments.iter().enumerate() {
+            let context = context.clone();
+            let path = fragment.path.clone();
+            let conditions = fragment.conditions.clone();
+            let recorder = recorder.clone();
+            let progress = progress.clone();
+            tasks.spawn(async move {
+                let outcome =
+                    evaluate_fragment(context, index, path, conditions, recorder, progress).await;
+                (index, outcome)
+            });
+        }
+
+        let interactive = std::io::stderr().is_terminal() && !is_verbose();
+        let reporter = interactive.then(|| tokio::spawn(report_progress(progress.clone())));
+
+        let deadline = started + budget;
+        loop {
+            match tokio::time::timeout_at(deadline, tasks.join_next()).await {
+                Ok(Some(Ok((index, matched)))) => {
+                    matches[index] = Some(matched);
+                    fragment_timings[index] = Some(started.elapsed());
+                    progress.lock().unwrap().finish_fragment(index);
+                }
+                // A panicking probe doesn't match.
+                Ok(Some(Err(_))) => {}
+                Ok(None) => break,
+                Err(_) => {
+                    verbose_println!(
+                        "Probing took longer than {}s, using remote rules for the remaining \
+                         fragments",
+                        budget.as_secs()
+                    );
+                    break;
+                }
+            }
+        }
+
+        if let Some(reporter) = reporter {
+            reporter.abort();
+            progress.lock().unwrap().clear();
+        }
+
+        // Dropping the set cancels the fragments that are still being probed.
+    });
+
+    // Probes waiting on an external command can't be interrupted, don't wait for them.
+    runtime.shutdown_background();
+
+    if let Some(recorder) = recorder {
+        recorder.fragments(fragments.iter().zip(&matches).zip(fragment_timings).map(
+            |((fragment, matched), elapsed)| FragmentTiming {
+                path: fragment.path.clone(),
+                matched: *matched,
+                elapsed,
+            },
+        ));
+    }
+
+    matches
+}
+
+/// Get whether any condition of a fragment matches, cancelling the pending probes on the first
+/// match
+async fn evaluate_fragment(
+    context: Context,
+    index: usize,
+    path: PathBuf,
+    conditions: Vec<Condition>,
+    recorder: Option<Recorder>,
+    progress: Arc<Mutex<Progress>>,
+) -> bool {
+    let mut probes = JoinSet::new();
+    for (probe, condition) in conditions.into_iter().enumerate() {
+        let context = context.clone();
+        let path = path.clone();
+        let recorder = recorder.clone();
+        let progress = progress.clone();
+        probes.spawn_blocking(move || {
+            let started = Instant::now();
+            progress.lock().unwrap().start((index, probe), &condition, &path);
+            let matched = local_condition_match(&context, &path, &condition.key, &condition.value);
+            progress.lock().unwrap().finish((index, probe));
+            if let Some(recorder) = recorder {
+                recorder.record(&format!("evaluation/{}", condition.key), started.elapsed());
+                recorder.condition(ConditionTiming {
+                    path,
+                    condition: condition.to_string(),
+                    elapsed: started.elapsed(),
+                    matched,
+                });
+            }
+            matched
+        });
+    }
+
+    while let Some(matched) = probes.join_next().await {
+        if matched.unwrap_or(false) {
+            probes.abort_all();
+            return true;
+        }
+    }
+
+    false
+}
+
+/// The probes of a generation, reported while they take long
+struct Progress {
+    /// The number of condition lines of all fragments
+    total: usize,
+    /// The number of condition lines probed, or no longer needed
+    done: usize,
+    /// The number of condition lines of each fragment left to probe
+    left: Vec<usize>,
+    /// The probes running, by fragment and condition line, with what they wait on
+    running: BTreeMap<(usize, usize), (String, Instant)>,
+    /// The width of the progress line on the terminal, to clear it
+    shown: usize,
+}
+
+impl Progress {
+    fn new(fragments: &[Fragment]) -> Progress {
+        let left: Vec<usize> = fragments.iter().map(|fragment| fragment.conditions.len()).collect();
+        Progress { total: left.iter().sum(), done: 0, left, running: BTreeMap::new(), shown: 0 }
+    }
+
+    /// Record that a probe started waiting on `condition`
+    fn start(&mut self, probe: (usize, usize), condition: &Condition, path: &Path) {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.running.insert(probe, (format!("{} ({})", condition, file_name), Instant::now()));
+    }
+
+    /// Record that a probe completed
+    fn finish(&mut self, probe: (usize, usize)) {
+        self.running.remove(&probe);
+        if let Some(left) = self.left.get_mut(probe.0).filter(|left| **left > 0) {
+            *left -= 1;
+            self.done += 1;
+        }
+    }
+
+    /// Record that a fragment was decided, its pending probes are no longer needed
+    fn finish_fragment(&mut self, fragment: usize) {
+        self.running.retain(|probe, _| probe.0 != fragment);
+        if let Some(left) = self.left.get_mut(fragment) {
+            self.done += std::mem::take(left);
+        }
+    }
+
+    /// Get the progress line: the probes done and the one waited on the longest
+    fn line(&self) -> String {
+        let mut line = format!("Probing: {}/{} done", self.done, self.total);
+        if let Some((target, started)) = self.running.values().min_by_key(|(_, started)| *started) {
+            line += &format!(", waiting {:.1}s on {}", started.elapsed().as_secs_f64(), target);
+        }
+        line.chars().take(PROGRESS_WIDTH).collect()
+    }
+
+    /// Redraw the progress line over the previous one
+    fn show(&mut self) {
+        let line = self.line();
+        let width = line.chars().count();
+        eprint!("\r{}{}", line, " ".repeat(self.shown.saturating_sub(width)));
+        let _ = std::io::stderr().flush();
+        self.shown = width;
+    }
+
+    /// Clear the progress line, if it was shown
+    fn clear(&mut self) {
+        if self.shown > 0 {
+            eprint!("\r{}\r", " ".repeat(self.shown));
+            let _ = std::io::stderr().flush();
+            self.shown = 0;
+        }
+    }
+}
+
+/// Report the progress of probing on stderr once it takes longer than [`PROGRESS_DELAY`], until
+/// aborted
+async fn report_progress(progress: Arc<Mutex<Progress>>) {
+    tokio::time::sleep(PROGRESS_DELAY).await;
+    let mut interval = tokio::time::interval(PROGRESS_INTERVAL);
+    loop {
+        interval.tick().await;
+        progress.lock().unwrap().show();
+    }
+}
+
+/// How long a fragment took to probe
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FragmentTiming {
+    pub path: PathBuf,
+    /// Whether its conditions matched, `None` if it was cut off by the budget
+    pub matched: Option<bool>,
+    /// How long after probing started it completed, `None` if it was cut off by the budget
+    pub elapsed: Option<Duration>,
+}
+
+/// How long a condition line took to probe
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConditionTiming {
+    pub path: PathBuf,
+    pub condition: String,
+    pub elapsed: Duration,
+    pub matched: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_probes_no_longer_needed() {
+        let condition =
+            |value: &str| Condition { key: "LocalPing".to_string(), value: value.to_string() };
+        let fragment =
+            |conditions| Fragment { path: "lab.sshconf".into(), conditions, ..Fragment::default() };
+        let fragments = [
+            fragment(vec![condition("10.0.0.1"), condition("10.0.0.2")]),
+            fragment(vec![condition("::1")]),
+        ];
+        let mut progress = Progress::new(&fragments);
+        assert_eq!(progress.line(), "Probing: 0/3 done");
+
+        progress.start((0, 0), &fragments[0].conditions[0], &fragments[0].path);
+        progress.start((1, 0), &fragments[1].conditions[0], &fragments[1].path);
+        progress.finish((1, 0));
+        assert!(progress.line().starts_with("Probing: 1/3 done, waiting "));
+        assert!(progress.line().ends_with("s on LocalPing 10.0.0.1 (lab.sshconf)"));
+
+        progress.finish_fragment(0);
+        progress.finish((0, 0));
+        assert_eq!(progress.line(), "Probing: 3/3 done");
+    }
+}
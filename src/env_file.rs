@@ -0,0 +1,68 @@
+//! # Env file
+//!
+//! This module writes the env file, a shell-sourceable summary of the last generation next to the
+//! state file, so shell prompts, tmux status lines and scripts can show or branch on the current
+//! profile without running the detection themselves:
+//!
+//! ```text
+//! SSHCONFGEN_PROFILE='office'
+//! SSHCONFGEN_SSID='corp'
+//! SSHCONFGEN_CHANGED=1
+//! ```
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::platform::shell_quote;
+use std::path::PathBuf;
+
+/// Get the path of the env file of `paths`, `env` next to the state file
+pub fn env_path(paths: &Paths) -> PathBuf {
+    paths.state_dir.join("env")
+}
+
+/// Get the contents of the env file: the profile, the connected SSIDs separated by commas, and
+/// whether the profile differs from the previous generation
+pub fn render(profile: &str, ssids: &[String], changed: bool) -> String {
+    format!(
+        "SSHCONFGEN_PROFILE={}\nSSHCONFGEN_SSID={}\nSSHCONFGEN_CHANGED={}\n",
+        shell_quote(profile),
+        shell_quote(&ssids.join(",")),
+        u8::from(changed)
+    )
+}
+
+/// Get the profile recorded in an env file
+pub fn profile(contents: &str) -> Option<&str> {
+    let value = contents.lines().find_map(|line| line.strip_prefix("SSHCONFGEN_PROFILE="))?;
+    value.strip_prefix('\'')?.strip_suffix('\'')
+}
+
+/// Write the env file of a generation that selected `profile`, the active named network, or
+/// `local` or `remote` depending on whether any fragment uses its local rules
+pub fn write(paths: &Paths, profile: &str, ssids: &[String]) -> Result<()> {
+    let path = env_path(paths);
+    let previous = crate::file::read_file(&path).unwrap_or_default();
+    let changed = self::profile(&previous) != Some(profile);
+
+    std::fs::create_dir_all(&paths.state_dir).map_err(Error::io("create", &paths.state_dir))?;
+
+    crate::file::write_if_changed(&path, &render(profile, ssids, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_sourceable_variables() {
+        let contents = render("office", &["corp".to_string(), "Bob's net".to_string()], true);
+        assert_eq!(
+            contents,
+            "SSHCONFGEN_PROFILE='office'\nSSHCONFGEN_SSID='corp,Bob'\\''s net'\n\
+             SSHCONFGEN_CHANGED=1\n"
+        );
+        assert_eq!(profile(&contents), Some("office"));
+        assert_eq!(profile(&render("remote", &[], false)), Some("remote"));
+        assert_eq!(profile(""), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! # Error
+//!
+//! This module contains the error type shared by the detection functions and the generation
+//! pipeline.
+
+use std::{io, path::PathBuf};
+
+/// A `Result` whose error defaults to [`Error`]
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error raised while detecting the network or generating the SSH config
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// An external tool is not installed or could not be started, it has already been reported
+    #[error("{0} is unavailable")]
+    ToolUnavailable(String),
+    /// The capability is not available on this platform
+    #[error("{0} is not supported on this platform")]
+    Unsupported(&'static str),
+    /// The looked-up entry does not exist
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    /// The network could not be observed
+    #[error("{0}")]
+    Detection(&'static str),
+    /// The output of an external tool could not be parsed
+    #[error("Unable to parse the {0} output")]
+    Parse(&'static str),
+    /// A value given by the user is not valid
+    #[error("Invalid {0}")]
+    Invalid(String),
+    /// The home directory could not be determined
+    #[error("Unable to determine home directory")]
+    HomeNotFound,
+    /// A directory the configuration is read from does not exist
+    #[error("{} directory does not exist", .0.display())]
+    MissingDirectory(PathBuf),
+    /// Reading or writing a file failed
+    #[error("Unable to {action} {}: {source}", path.display())]
+    Io { action: &'static str, path: PathBuf, source: io::Error },
+    /// The repository of shared fragments could not be synced
+    #[error("Unable to sync: {0}")]
+    Sync(String),
+    /// A secret of a template could not be looked up
+    #[error("Unable to look up {0}")]
+    Secret(String),
+    /// A hook running the binary could not be installed
+    #[error("Unable to install {0}")]
+    Install(String),
+    /// The binary could not be updated
+    #[error("Unable to update: {0}")]
+    Update(String),
+    /// The D-Bus service could not be published
+    #[error("Unable to publish the D-Bus service: {0}")]
+    Bus(String),
+    /// Sessions use hosts the generation changes, and `[sessions] check` refuses to write
+    #[error("{0} would keep using the previous host definitions, close them or run with --force")]
+    ActiveSessions(String),
+    /// Canary hosts could not be connected to with the generated config
+    #[error("Unable to connect to {0}")]
+    SmokeTest(String),
+    /// Checking the fragments printed warnings
+    #[error("Invalid fragments, see the warnings above ({0})")]
+    InvalidFragments(usize),
+    /// The SSH config was not written by sshconfgen, and is kept until adopted
+    #[error(
+        "{} was not generated by sshconfgen, run sshconfgen --adopt to archive it first",
+        .0.display()
+    )]
+    Unmanaged(PathBuf),
+    /// An environment variable overriding a setting is not valid
+    #[error("Invalid environment: {0}")]
+    Environment(String),
+    /// The settings file is not valid
+    #[error("Invalid settings in {}: {message}", path.display())]
+    Settings { path: PathBuf, message: String },
+    /// The binary exits with this status without a message, e.g. a named network that isn't
+    /// active for `Match exec`, or a plugin that failed
+    #[error("Exited with status {0}")]
+    Exit(i32),
+}
+
+impl Error {
+    /// Create a closure wrapping an I/O error on `path`, for use with `map_err`
+    pub fn io(action: &'static str, path: impl Into<PathBuf>) -> impl FnOnce(io::Error) -> Error {
+        let path = path.into();
+        move |source| Error::Io { action, path, source }
+    }
+
+    /// Get the exit code of the binary for this error, following the BSD `sysexits.h` values
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Invalid(_) => 64,
+            Error::InvalidFragments(_) => 65,
+            Error::MissingDirectory(_) => 66,
+            Error::HomeNotFound => 67,
+            Error::ToolUnavailable(_) | Error::Unsupported(_) => 69,
+            Error::Io { .. } => 74,
+            Error::Unmanaged(_) => 73,
+            Error::ActiveSessions(_) => 75,
+            Error::Environment(_) | Error::Settings { .. } => 78,
+            Error::Exit(code) => *code,
+            _ => 1,
+        }
+    }
+}
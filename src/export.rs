@@ -0,0 +1,58 @@
+//! # Export
+//!
+//! This module contains the structured export of the rule base, the parsed fragments along with
+//! the outcome of their conditions on the current network, for external tools and audits.
+
+use crate::context::Context;
+use crate::fingerprint::Fingerprint;
+use crate::provider::Providers;
+use crate::sshconf::Fragment;
+use serde::Serialize;
+use std::path::Path;
+
+/// The fragments of a directory in processing order, and the network they were evaluated on
+#[derive(Debug, Serialize)]
+pub struct Export {
+    /// The network fingerprint, only when the conditions were evaluated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<Fingerprint>,
+    pub fragments: Vec<ExportedFragment>,
+}
+
+/// A fragment and whether its conditions matched
+#[derive(Debug, Serialize)]
+pub struct ExportedFragment {
+    #[serde(flatten)]
+    pub fragment: Fragment,
+    /// Whether the local rules apply, only when the conditions were evaluated. `Some(None)`
+    /// (`null`) when the probe budget ran out first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<Option<bool>>,
+}
+
+/// Export the fragments in a directory, evaluating their conditions on the network seen by
+/// `providers` if given
+pub fn export(ssh_config_dir: &Path, providers: Option<&Providers>) -> Export {
+    let fragments = crate::sshconf::read_fragments(ssh_config_dir);
+
+    let Some(providers) = providers else {
+        return Export {
+            fingerprint: None,
+            fragments: fragments
+                .into_iter()
+                .map(|fragment| ExportedFragment { fragment, matched: None })
+                .collect(),
+        };
+    };
+
+    let context = Context::new(providers);
+    let matches = crate::engine::evaluate(&fragments, &context, crate::engine::budget());
+    Export {
+        fingerprint: Some(context.fingerprint()),
+        fragments: fragments
+            .into_iter()
+            .zip(matches)
+            .map(|(fragment, matched)| ExportedFragment { fragment, matched: Some(matched) })
+            .collect(),
+    }
+}
@@ -0,0 +1,169 @@
+//! # FFI
+//!
+//! This module contains the C ABI of the library, declared in `include/sshconfgen.h`, so editor
+//! plugins and tools written in other languages can run the engine in-process.
+//!
+//! Strings are UTF-8 and NUL-terminated. Returned strings are owned by the caller and must be
+//! released with [`sshconfgen_string_free`]. Failing functions record a message retrieved with
+//! [`sshconfgen_last_error`], and panics are caught at the boundary and reported the same way.
+
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::provider::Providers;
+use crate::sshconf::Fragment;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The exit code reported for a panic, as for an internal software error in `sysexits.h`
+const PANIC_EXIT_CODE: c_int = 70;
+
+/// Get the version of the library, a static string that must not be freed
+#[no_mangle]
+pub extern "C" fn sshconfgen_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// Load the user's settings file, returns 0 or the exit code of the binary for the error
+///
+/// Without it, the functions below use the default settings. Only the first call has an effect.
+#[no_mangle]
+pub extern "C" fn sshconfgen_init(verbose: c_int) -> c_int {
+    status(|| {
+        crate::set_verbose(verbose != 0);
+        crate::load_settings()
+    })
+}
+
+/// Generate the SSH config of the current user and write it, like the `sshconfgen` binary.
+/// Returns 0 or the exit code of the binary for the error.
+#[no_mangle]
+pub extern "C" fn sshconfgen_run() -> c_int {
+    status(|| crate::sshconf::run(&Paths::resolve()?, &Providers::default()))
+}
+
+/// Generate the SSH config from the fragments in `config_dir` without writing it, or from the
+/// user's `config.d` if `config_dir` is NULL. Returns NULL on error.
+///
+/// # Safety
+///
+/// `config_dir` must be NULL or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sshconfgen_generate(config_dir: *const c_char) -> *mut c_char {
+    // SAFETY: guaranteed by the caller.
+    let config_dir = unsafe { optional_str(config_dir) };
+    string(|| {
+        let config_dir = match config_dir? {
+            Some(config_dir) => Path::new(config_dir).to_path_buf(),
+            None => Paths::resolve()?.config_dir,
+        };
+
+        crate::sshconf::generate(&config_dir, &Providers::default())
+    })
+}
+
+/// Get the fingerprint of the current network as JSON, NULL on error
+#[no_mangle]
+pub extern "C" fn sshconfgen_fingerprint() -> *mut c_char {
+    string(|| {
+        let fingerprint = crate::fingerprint::detect(&Providers::default());
+        serde_json::to_string(&fingerprint)
+            .map_err(|e| Error::Invalid(format!("fingerprint: {}", e)))
+    })
+}
+
+/// Evaluate the conditions of a fragment given by its contents against the current network.
+/// Returns 1 if its local rules apply, 0 if its remote rules do (including when probing runs
+/// out of budget), and -1 on error.
+///
+/// # Safety
+///
+/// `contents` must be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sshconfgen_evaluate(contents: *const c_char) -> c_int {
+    // SAFETY: guaranteed by the caller.
+    let contents = unsafe { optional_str(contents) };
+    guard(|| {
+        let contents = contents?.ok_or_else(|| Error::Invalid("fragment: NULL".to_string()))?;
+        let fragment = Fragment::parse(Path::new("<ffi>"), contents);
+        let context = Context::new(&Providers::default());
+        let matched = crate::engine::evaluate(&[fragment], &context, crate::engine::budget());
+        Ok(c_int::from(matched.first().copied().flatten().unwrap_or(false)))
+    })
+    .unwrap_or(-1)
+}
+
+/// Get the message of the last error on the calling thread, NULL if there was none
+///
+/// The string is owned by the library and valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn sshconfgen_last_error() -> *const c_char {
+    LAST_ERROR
+        .with(|error| error.borrow().as_ref().map_or(std::ptr::null(), |error| error.as_ptr()))
+}
+
+/// Release a string returned by the library
+///
+/// # Safety
+///
+/// `string` must be NULL or a string returned by this library, not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn sshconfgen_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        // SAFETY: the string was created by `CString::into_raw`, per the caller.
+        drop(unsafe { CString::from_raw(string) });
+    }
+}
+
+/// Borrow a C string argument, `None` if it is NULL
+///
+/// # Safety
+///
+/// `string` must be NULL or a valid NUL-terminated string outliving the borrow.
+unsafe fn optional_str<'a>(string: *const c_char) -> Result<Option<&'a str>> {
+    if string.is_null() {
+        return Ok(None);
+    }
+
+    // SAFETY: guaranteed by the caller.
+    let string = unsafe { CStr::from_ptr(string) };
+    string.to_str().map(Some).map_err(|_| Error::Invalid("string: not UTF-8".to_string()))
+}
+
+/// Run `f`, recording its error or panic as the last error
+fn guard<T>(f: impl FnOnce() -> Result<T>) -> std::result::Result<T, c_int> {
+    let (message, code) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => {
+            set_last_error(None);
+            return Ok(value);
+        }
+        Ok(Err(e)) => (e.to_string(), e.exit_code()),
+        Err(_) => ("sshconfgen panicked".to_string(), PANIC_EXIT_CODE),
+    };
+
+    set_last_error(Some(message));
+    Err(code)
+}
+
+/// Run `f` for a status code: 0 on success, the exit code of the binary for the error otherwise
+fn status(f: impl FnOnce() -> Result<()>) -> c_int {
+    guard(f).err().unwrap_or(0)
+}
+
+/// Run `f` for a caller-owned string, NULL on error
+fn string(f: impl FnOnce() -> Result<String>) -> *mut c_char {
+    guard(|| CString::new(f()?).map_err(|_| Error::Invalid("output: contains NUL".to_string())))
+        .map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+fn set_last_error(message: Option<String>) {
+    let message =
+        message.map(|message| CString::new(message.replace('\0', " ")).unwrap_or_default());
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+}
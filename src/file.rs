@@ -2,56 +2,258 @@
 //!
 //! This module contains file utilities for reading, writing, and manipulating files.
 
-use regex::Regex;
+use crate::{is_verbose, verbose_println};
 use std::fs::read_dir;
-use std::{fs::File, io, io::Read, io::Write, path::PathBuf};
+use std::io::{BufWriter, Read, Write};
 use std::path::Path;
-use crate::{is_verbose, verbose_println};
+use std::time::Duration;
+use std::{fs, fs::File, io, path::PathBuf};
+
+/// How old a temporary file must be before it is considered left behind by an interrupted run
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(600);
+
+/// Replace a file with what `write` writes, through a buffered temporary file in the same
+/// directory that is renamed over it once complete
+///
+/// The file is left untouched if `write` fails or writes nothing, returns whether it was replaced.
+/// The new file keeps the permissions, owner and extended attributes of the replaced one, or its
+/// ACLs and attributes on Windows, as far as the user is allowed to set them. A file that didn't
+/// exist yet is only accessible to its owner, as it may hold secrets.
+pub fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+) -> io::Result<bool> {
+    let temp_path = temp_path(path);
+    remove_stale_temp_files(path);
+
+    let result = (|| {
+        let mut options = File::options();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = BufWriter::new(options.open(&temp_path)?);
+        write(&mut file)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        if file.metadata()?.len() == 0 {
+            return Ok(false);
+        }
+        drop(file);
+
+        verbose_println!("Replacing {}", path.display());
+        rename_over(&temp_path, path)?;
+        Ok(true)
+    })();
+
+    if !matches!(result, Ok(true)) {
+        let _ = fs::remove_file(&temp_path);
+    }
 
-/// Append a borrowed string slice to a file
-pub fn append_to_file(path: &PathBuf, contents: &str, append_newline: bool) -> io::Result<()> {
-    // Check if the contents are empty, if so, return
-    if contents.is_empty() {
+    result
+}
+
+/// Get the temporary file [`replace_file`] writes `path` to in this process,
+/// `.<name>.<pid>.tmp` next to it
+pub fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()))
+}
+
+/// Replace a file with `contents` like [`replace_file`], unless it already holds them
+pub fn write_if_changed(path: &Path, contents: &str) -> crate::Result<()> {
+    if read_file(path).is_ok_and(|current| current == contents) {
+        verbose_println!("{} is unchanged", path.display());
         return Ok(());
     }
 
-    // If the file doesn't exist, create it
+    replace_file(path, |out| out.write_all(contents.as_bytes()))
+        .map_err(crate::Error::io("write", path))?;
+    Ok(())
+}
+
+/// Create a directory and its missing parents, only accessible to their owner
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+/// Rename `temp_path` over `path`, giving it the permissions, owner and extended attributes of
+/// `path` first. Those the user isn't allowed to set are skipped.
+#[cfg(unix)]
+fn rename_over(temp_path: &Path, path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        if let Err(e) = fs::set_permissions(temp_path, metadata.permissions()) {
+            verbose_println!("Unable to keep the permissions of {}: {}", path.display(), e);
+        }
+        if let Err(e) =
+            std::os::unix::fs::chown(temp_path, Some(metadata.uid()), Some(metadata.gid()))
+        {
+            verbose_println!("Unable to keep the owner of {}: {}", path.display(), e);
+        }
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        if let Err(e) = xattr::copy(path, temp_path) {
+            verbose_println!("Unable to keep the extended attributes of {}: {}", path.display(), e);
+        }
+    }
+
+    fs::rename(temp_path, path)
+}
+
+/// Rename `temp_path` over `path` with `ReplaceFileW`, which keeps the ACLs and attributes of
+/// `path` instead of those `temp_path` inherited from the directory
+#[cfg(windows)]
+fn rename_over(temp_path: &Path, path: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{ReplaceFileW, REPLACEFILE_IGNORE_MERGE_ERRORS};
+
     if !path.exists() {
-        File::create(path)?;
+        return fs::rename(temp_path, path);
     }
 
-    // Check if the contents end with a newline, if not, append one
-    if append_newline {
-        let newline = if cfg!(windows) { "\r\n" } else { "\n" };
-        if !contents.ends_with(newline) {
-            verbose_println!("Appending newline to {}", path.display());
-            contents.to_string().push_str(newline);
-        }
+    let wide = |path: &Path| path.as_os_str().encode_wide().chain([0]).collect::<Vec<u16>>();
+    let (replaced, replacement) = (wide(path), wide(temp_path));
+    // SAFETY: both paths are NUL-terminated and outlive the call, the other pointers may be null.
+    let replaced = unsafe {
+        ReplaceFileW(
+            replaced.as_ptr(),
+            replacement.as_ptr(),
+            std::ptr::null(),
+            REPLACEFILE_IGNORE_MERGE_ERRORS,
+            std::ptr::null(),
+            std::ptr::null(),
+        )
+    };
+    if replaced == 0 {
+        return Err(io::Error::last_os_error());
     }
 
-    let mut file = File::options().append(true).open(path)?;
-    writeln!(file, "{}", contents)?;
     Ok(())
 }
 
-/// Get the contents between two strings
-pub fn get_between(contents: &str, start: &str, end: &str) -> String {
-    let re = Regex::new(&format!(
-        "(?s){}(.*){}",
-        regex::escape(start),
-        regex::escape(end)
-    ))
-    .unwrap();
-    if let Some(caps) = re.captures(contents) {
-        caps.get(1)
-            .map_or(String::new(), |m| m.as_str().trim().to_string())
-    } else {
-        String::new()
+#[cfg(not(any(unix, windows)))]
+fn rename_over(temp_path: &Path, path: &Path) -> io::Result<()> {
+    fs::rename(temp_path, path)
+}
+
+/// Extended attributes, which also hold the SELinux label and macOS metadata of a file
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+mod xattr {
+    use crate::{is_verbose, verbose_println};
+    use std::ffi::{c_char, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// Copy the extended attributes of `from` to `to`, skipping those that can't be set
+    pub fn copy(from: &Path, to: &Path) -> io::Result<()> {
+        let from = CString::new(from.as_os_str().as_bytes())?;
+        let to = CString::new(to.as_os_str().as_bytes())?;
+
+        // SAFETY: the path is NUL-terminated, and the buffer valid for its size.
+        let names = read(|buffer, size| unsafe { list(from.as_ptr(), buffer, size) })?;
+        for name in names.split(|&byte| byte == 0).filter(|name| !name.is_empty()) {
+            let name = CString::new(name)?;
+            // SAFETY: as above, the name is NUL-terminated too.
+            let value =
+                read(|buffer, size| unsafe { get(from.as_ptr(), name.as_ptr(), buffer, size) })?;
+            // SAFETY: as above, the value is valid for its length.
+            if unsafe { set(to.as_ptr(), name.as_ptr(), &value) } != 0 {
+                let error = io::Error::last_os_error();
+                verbose_println!("Unable to copy the {:?} attribute: {}", name, error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Call a function filling a buffer of the given size, first to get the size needed
+    fn read(call: impl Fn(*mut c_char, usize) -> isize) -> io::Result<Vec<u8>> {
+        let size = call(std::ptr::null_mut(), 0);
+        let mut buffer = vec![0; usize::try_from(size).map_err(|_| io::Error::last_os_error())?];
+        let size = call(buffer.as_mut_ptr().cast(), buffer.len());
+        buffer.truncate(usize::try_from(size).map_err(|_| io::Error::last_os_error())?);
+        Ok(buffer)
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    unsafe fn list(path: *const c_char, buffer: *mut c_char, size: usize) -> isize {
+        unsafe { libc::listxattr(path, buffer, size) }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        buffer: *mut c_char,
+        size: usize,
+    ) -> isize {
+        unsafe { libc::getxattr(path, name, buffer.cast(), size) }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    unsafe fn set(path: *const c_char, name: *const c_char, value: &[u8]) -> i32 {
+        unsafe { libc::setxattr(path, name, value.as_ptr().cast(), value.len(), 0) }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn list(path: *const c_char, buffer: *mut c_char, size: usize) -> isize {
+        unsafe { libc::listxattr(path, buffer, size, 0) }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn get(
+        path: *const c_char,
+        name: *const c_char,
+        buffer: *mut c_char,
+        size: usize,
+    ) -> isize {
+        unsafe { libc::getxattr(path, name, buffer.cast(), size, 0, 0) }
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe fn set(path: *const c_char, name: *const c_char, value: &[u8]) -> i32 {
+        unsafe { libc::setxattr(path, name, value.as_ptr().cast(), value.len(), 0, 0) }
+    }
+}
+
+/// Remove the temporary files of `path` left behind by runs that were killed while writing it
+///
+/// Since the file is only ever replaced by a rename, an interrupted run leaves the previous
+/// version in place and at most its temporary file. Those older than [`STALE_TEMP_FILE_AGE`]
+/// can't belong to a run still writing.
+fn remove_stale_temp_files(path: &Path) {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let prefix = format!(".{}.", file_name);
+    let Some(entries) = path.parent().and_then(|dir| read_dir(dir).ok()) else {
+        return;
+    };
+
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_temp_file = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".tmp"))
+            .is_some_and(|pid| pid.parse::<u32>().is_ok());
+        let stale = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age > STALE_TEMP_FILE_AGE));
+
+        if is_temp_file && stale {
+            verbose_println!("Removing {} left by an interrupted run", entry.path().display());
+            let _ = fs::remove_file(entry.path());
+        }
     }
 }
 
 /// Read the contents of a file
-pub fn read_file(path: &PathBuf) -> io::Result<String> {
+pub fn read_file(path: &Path) -> io::Result<String> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
@@ -66,7 +268,7 @@ pub fn get_files_by_extension(directory: &Path, extension: &str) -> Vec<PathBuf>
                 .filter_map(Result::ok)
                 .filter_map(|entry| {
                     let path = entry.path();
-                    if path.is_file() && path.extension().map_or(false, |ext| ext == extension) {
+                    if path.is_file() && path.extension().is_some_and(|ext| ext == extension) {
                         Some(path)
                     } else {
                         None
@@ -76,3 +278,92 @@ pub fn get_files_by_extension(directory: &Path, extension: &str) -> Vec<PathBuf>
         })
         .unwrap_or_else(|_| vec![])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn creates_files_only_their_owner_can_read() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let private = dir.path().join("private");
+        create_private_dir(&private).unwrap();
+        assert_eq!(fs::metadata(&private).unwrap().permissions().mode() & 0o777, 0o700);
+
+        let path = private.join("config");
+        assert!(replace_file(&path, |out| out.write_all(b"Host a\n")).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(replace_file(&path, |out| out.write_all(b"Host b\n")).unwrap());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o644);
+    }
+
+    /// Get the names of the files in `dir`
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn keeps_the_file_when_writing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "Host a\n").unwrap();
+
+        let failed = replace_file(&path, |out| {
+            out.write_all(b"Host b\n")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert!(failed.is_err());
+        assert!(!replace_file(&path, |_| Ok(())).unwrap());
+
+        assert_eq!(read_file(&path).unwrap(), "Host a\n");
+        assert_eq!(names(dir.path()), ["config"]);
+    }
+
+    #[test]
+    fn removes_only_stale_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        let stale = dir.path().join(".config.1.tmp");
+        for name in [".config.1.tmp", ".config.2.tmp", ".config.tmp", ".known_hosts.3.tmp"] {
+            fs::write(dir.path().join(name), "Host partial\n").unwrap();
+        }
+        let old = std::time::SystemTime::now() - STALE_TEMP_FILE_AGE * 2;
+        File::options().write(true).open(&stale).unwrap().set_modified(old).unwrap();
+
+        assert!(replace_file(&path, |out| out.write_all(b"Host a\n")).unwrap());
+
+        // The temp file of a run still writing, and those of other files, are left alone.
+        assert_eq!(
+            names(dir.path()),
+            [".config.2.tmp", ".config.tmp", ".known_hosts.3.tmp", "config"]
+        );
+    }
+
+    #[test]
+    fn swaps_the_file_only_once_it_is_complete() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "Host a\n").unwrap();
+
+        assert!(replace_file(&path, |out| {
+            // The previous file stays in place while the new one is written.
+            assert_eq!(read_file(&path)?, "Host a\n");
+            out.write_all(b"Host b\n")
+        })
+        .unwrap());
+
+        // The temp file is gone once it replaced the file.
+        assert_eq!(read_file(&path).unwrap(), "Host b\n");
+        assert_eq!(names(dir.path()), ["config"]);
+    }
+}
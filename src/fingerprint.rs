@@ -0,0 +1,99 @@
+//! # Fingerprint
+//!
+//! This module contains the fingerprint of the network the system is connected to, used to tell
+//! whether the network changed between two generations and exposed to other scripts by the
+//! `fingerprint` command.
+
+use crate::context::Context;
+use crate::provider::Providers;
+use crate::routes::DefaultRoute;
+use crate::vpn::Adapter;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// The observable state of the network, conditions are expected to match the same way as long as
+/// it doesn't change. Parts that can't be detected are left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The connected Wi-Fi networks
+    pub wifi: Vec<Wifi>,
+    /// The outgoing interface of the preferred default route
+    pub interface: Option<String>,
+    /// The next hop of the preferred default route
+    pub gateway: Option<IpAddr>,
+    pub gateway_mac: Option<String>,
+    /// The IPv4 subnet of `interface`, such as `192.168.1.0/24`
+    pub subnet: Option<String>,
+    /// The DNS search domain of the network
+    pub dns_suffix: Option<String>,
+    pub default_routes: Vec<DefaultRoute>,
+    pub vpn_adapters: Vec<Adapter>,
+}
+
+/// A wireless interface and the network it is associated with
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Wifi {
+    pub interface: String,
+    pub ssid: String,
+    pub bssid: Option<String>,
+}
+
+impl Fingerprint {
+    /// Detect the fingerprint of the current network
+    pub fn detect(context: &Context) -> Fingerprint {
+        let providers = &context.providers;
+        let wifi = context
+            .connections()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|connection| Wifi {
+                bssid: providers.ssid.bssid(&connection.interface),
+                interface: connection.interface,
+                ssid: connection.ssid,
+            })
+            .collect();
+
+        // Routes are sorted IPv4 first, then by ascending metric.
+        let default_routes = providers.routes.default_routes().unwrap_or_default();
+        let preferred = default_routes.first();
+        let interface = preferred.map(|route| route.interface.clone());
+        let gateway = preferred.and_then(|route| route.gateway);
+
+        let gateway_mac = gateway.zip(interface.as_deref()).and_then(|(gateway, interface)| {
+            // Not through the context, whose state file lookups depend on the fingerprint.
+            providers.neighbor.hw_address(&scoped_address(gateway, interface)).ok()
+        });
+
+        Fingerprint {
+            wifi,
+            subnet: interface.as_deref().and_then(|interface| providers.routes.subnet(interface)),
+            dns_suffix: interface
+                .as_deref()
+                .and_then(|interface| providers.routes.dns_suffix(interface)),
+            interface,
+            gateway,
+            gateway_mac,
+            default_routes,
+            vpn_adapters: context.vpn_adapters().unwrap_or_default(),
+        }
+    }
+
+    /// Get the address of the gateway, scoped to the interface when it is link-local
+    pub fn gateway_address(&self) -> Option<String> {
+        Some(scoped_address(self.gateway?, self.interface.as_deref()?))
+    }
+}
+
+/// Format `address` with the zone of `interface` if it is link-local, as it is only unique together
+/// with its zone
+fn scoped_address(address: IpAddr, interface: &str) -> String {
+    match address {
+        IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80 => format!("{}%{}", v6, interface),
+        _ => address.to_string(),
+    }
+}
+
+/// Detect the fingerprint of the current network with `providers`
+pub fn detect(providers: &Providers) -> Fingerprint {
+    Fingerprint::detect(&Context::new(providers))
+}
@@ -0,0 +1,299 @@
+//! # Generator
+//!
+//! This module is the entry point for programs embedding the generation, e.g. a daemon
+//! regenerating the SSH config on its own network events:
+//!
+//! ```no_run
+//! use sshconfgen::generator::{Generator, Outcome};
+//! use sshconfgen::paths::Paths;
+//!
+//! let mut generator = Generator::new(Paths::resolve().unwrap());
+//! let report = generator.generate().unwrap();
+//! if report.outcome == Outcome::Written {
+//!     println!("Generated the {} profile into {:?}", report.profile, report.written);
+//! }
+//! ```
+//!
+//! A generator keeps the fragments and condition outcomes of its previous generations, like
+//! monitor mode does, so it is meant to be kept for the life of the program. Nothing is printed,
+//! the changes and timings of each generation are returned in its [`Report`].
+//!
+//! The other commands of the binary are methods of the generator too, acting on its `.ssh`
+//! directory, e.g. [`Generator::validate`] and [`Generator::restore`], or functions of this
+//! module, e.g. [`install_cron`] and [`self_update`].
+
+pub use crate::adopt::Adoption;
+use crate::cache::Cache;
+pub use crate::engine::{ConditionTiming, FragmentTiming};
+use crate::error::Result;
+pub use crate::export::{Export, ExportedFragment};
+pub use crate::fingerprint::Fingerprint;
+pub use crate::hosts::Host;
+pub use crate::import::Format;
+pub use crate::install::{
+    install_cron, install_nm_dispatcher, install_task_scheduler, parse_cron_schedule,
+};
+pub use crate::migrate::{LayoutMigration, Migration, FORMAT_VERSION};
+pub use crate::monitor::{Monitor, ProfileChange, Status, Trigger};
+use crate::paths::Paths;
+pub use crate::phases::{Phase, Timings};
+pub use crate::plugin::is_valid_name as is_plugin_name;
+use crate::provider::Providers;
+pub use crate::review::Planned;
+pub use crate::smoke::Outcome as SmokeTest;
+pub use crate::source::{fetch_all, install as install_bundle, Source};
+pub use crate::sshconf::Validation;
+pub use crate::status::Generation;
+pub use crate::sync::{sync, Report as SyncReport, SyncMode, SyncSettings};
+pub use crate::update::{check as check_update, update as self_update, Release};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Asked whether to write the files a generation of a profile would change
+pub type Approve = Box<dyn FnMut(&[Planned], &str) -> bool>;
+
+/// Generates the SSH config of a `.ssh` directory
+pub struct Generator {
+    paths: Paths,
+    providers: Providers,
+    cache: Cache,
+    skip_unchanged: bool,
+    options: Options,
+}
+
+/// How the generations of a [`Generator`] are written
+#[derive(Default)]
+pub(crate) struct Options {
+    /// Only work out the changes, nothing is written
+    pub(crate) dry_run: bool,
+    /// Write the config even though sessions use hosts it changes
+    pub(crate) force: bool,
+    /// Asked before the config is written, if set
+    pub(crate) approve: Option<Approve>,
+}
+
+/// What a generation did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The config was generated and written
+    Written,
+    /// Nothing it depends on changed since the last generation, or it came out identical, the
+    /// config was kept
+    Unchanged,
+    /// The config was not written as the changes weren't approved
+    Declined,
+    /// The changes were only worked out, on a dry run, nothing was written
+    Previewed,
+    /// There are no fragments, the config was kept
+    Empty,
+}
+
+/// The result of a generation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub outcome: Outcome,
+    /// The active named network, or `local` or `remote`
+    pub profile: String,
+    /// The fragments, in processing order
+    pub fragments: Vec<PathBuf>,
+    /// The fragments using their local rules
+    pub local: Vec<PathBuf>,
+    /// The files whose contents changed
+    pub written: Vec<PathBuf>,
+    /// The generated config, empty unless it was written or previewed
+    pub config: String,
+    /// The files the generation changes, or would change on a dry run, with their current and
+    /// new contents
+    pub changes: Vec<Planned>,
+    pub timings: Timings,
+    pub elapsed: Duration,
+}
+
+impl Generator {
+    /// Create a generator of the config of `paths`, detecting the network with the built-in
+    /// providers
+    pub fn new(paths: Paths) -> Generator {
+        Generator {
+            paths,
+            providers: Providers::default(),
+            cache: Cache::default(),
+            skip_unchanged: true,
+            options: Options::default(),
+        }
+    }
+
+    /// Detect the network with `providers` instead, e.g. to reuse the events of the program
+    pub fn with_providers(self, providers: Providers) -> Generator {
+        Generator { providers, ..self }
+    }
+
+    /// Set whether to skip writing when nothing the config depends on changed since the last
+    /// generation recorded in the state file, which is the default
+    pub fn with_skip_unchanged(self, skip_unchanged: bool) -> Generator {
+        Generator { skip_unchanged, ..self }
+    }
+
+    /// Set whether to only work out what each generation would change, without writing anything
+    /// or recording the generation
+    pub fn with_dry_run(mut self, dry_run: bool) -> Generator {
+        self.options.dry_run = dry_run;
+        self
+    }
+
+    /// Set whether to write the config even though `[sessions] check = "refuse"` finds sessions
+    /// using hosts it changes
+    pub fn with_force(mut self, force: bool) -> Generator {
+        self.options.force = force;
+        self
+    }
+
+    /// Call `approve` with the files each generation would change and its profile before
+    /// writing them, nothing is written unless it returns true. It isn't called when nothing
+    /// would change.
+    pub fn with_interactive(
+        mut self,
+        approve: impl FnMut(&[Planned], &str) -> bool + 'static,
+    ) -> Generator {
+        self.options.approve = Some(Box::new(approve));
+        self
+    }
+
+    /// Get the paths the generator reads and writes
+    pub fn paths(&self) -> &Paths {
+        &self.paths
+    }
+
+    /// Generate the SSH config and write it, unless nothing changed since the last generation
+    /// recorded in the state file
+    pub fn generate(&mut self) -> Result<Report> {
+        crate::sshconf::write_generation(
+            &self.paths,
+            &self.providers,
+            &mut self.cache,
+            self.skip_unchanged,
+            &mut self.options,
+            Vec::new(),
+        )
+    }
+
+    /// Generate the SSH config and write it, recording `triggers` in the audit log rather than a
+    /// run of sshconfgen. The config is always generated.
+    pub(crate) fn generate_triggered(&mut self, triggers: &BTreeSet<Trigger>) -> Result<Report> {
+        let triggers = triggers.iter().map(|trigger| format!("{:?}", trigger).to_lowercase());
+        crate::sshconf::write_generation(
+            &self.paths,
+            &self.providers,
+            &mut self.cache,
+            false,
+            &mut self.options,
+            triggers.collect(),
+        )
+    }
+
+    /// Get the fragments and condition outcomes of the previous generations
+    pub(crate) fn cache(&self) -> &Cache {
+        &self.cache
+    }
+
+    /// Generate the SSH config without writing anything, the state file included
+    pub fn preview(&mut self) -> Result<String> {
+        let Paths { config_dir, state_dir, .. } = &self.paths;
+        crate::sshconf::generate_from(config_dir, Some(state_dir), &self.providers, &mut self.cache)
+    }
+
+    /// Check the fragments: read them and compose both their local and remote rules, checked
+    /// against the OpenSSH client. Problems are printed as warnings, nothing is probed or written.
+    pub fn validate(&self) -> Validation {
+        crate::sshconf::validate(&self.paths.config_dir)
+    }
+
+    /// Detect the fingerprint of the network
+    pub fn fingerprint(&self) -> Fingerprint {
+        crate::fingerprint::detect(&self.providers)
+    }
+
+    /// Get the parsed fragments, with whether their conditions match if `evaluate` is set
+    pub fn export(&self, evaluate: bool) -> Export {
+        crate::export::export(&self.paths.config_dir, evaluate.then_some(&self.providers))
+    }
+
+    /// Write the fragments defined in `file` to `config.d`, replacing existing ones with `force`.
+    /// Returns the fragments written.
+    pub fn import(&self, file: &Path, format: Format, force: bool) -> Result<Vec<PathBuf>> {
+        crate::import::import(&self.paths.config_dir, file, format, force)
+    }
+
+    /// Pull the repository of shared fragments and fetch the remote bundles set in the settings
+    /// into `config.d`
+    pub fn sync(&self) -> Result<()> {
+        crate::sync::sync_all(&self.paths)
+    }
+
+    /// Archive a hand-written SSH config so generations may replace it, and convert it into a
+    /// fragment of global rules with `fragment`. Returns `None` if the config is already managed.
+    pub fn adopt(&self, fragment: bool) -> Result<Option<Adoption>> {
+        crate::adopt::adopt(&self.paths, fragment)
+    }
+
+    /// Check whether the named network of the settings is the one generations use
+    pub fn is_network_active(&self, network: &str) -> Result<bool> {
+        let networks = &crate::settings::get().networks;
+        crate::network::is_active(network, networks, &self.paths, &self.providers)
+    }
+
+    /// Get the profile of the last generation
+    pub fn profile(&self) -> Result<Option<String>> {
+        crate::profile::read(&self.paths)
+    }
+
+    /// Get the last generation recorded in the state file
+    pub fn status(&self) -> Option<Generation> {
+        crate::status::last(&self.paths)
+    }
+
+    /// Get the hosts of the SSH config in use, sorted by name, with the fragment each comes from
+    pub fn hosts(&self) -> Result<Vec<Host>> {
+        crate::hosts::list(&self.paths)
+    }
+
+    /// Connect to each of `hosts` with the SSH config in use, or to the canary hosts of the
+    /// settings if none is given
+    pub fn smoke_test(&self, hosts: &[String]) -> Vec<SmokeTest> {
+        let hosts =
+            if hosts.is_empty() { &crate::settings::get().smoke_test.hosts[..] } else { hosts };
+        hosts.iter().map(|host| crate::smoke::test(&self.paths.config_file, host)).collect()
+    }
+
+    /// Get the backups of the SSH config, newest first
+    pub fn backups(&self) -> Vec<PathBuf> {
+        crate::backup::list(&self.paths)
+    }
+
+    /// Restore the SSH config from a backup, by its file name or its position in
+    /// [`Generator::backups`] starting at 1, or the newest one. The current config is backed up
+    /// first. Returns the backup restored.
+    pub fn restore(&self, backup: Option<&str>) -> Result<PathBuf> {
+        crate::backup::restore(&self.paths, backup)
+    }
+
+    /// Upgrade the fragments written for an older format than [`FORMAT_VERSION`], keeping the
+    /// originals in the state directory
+    pub fn migrate(&self) -> Result<Vec<Migration>> {
+        crate::migrate::migrate(&self.paths)
+    }
+
+    /// Move the fragments of the legacy `conf.d` directory to `config.d`, leaving a symbolic link
+    /// in its place with `symlink`
+    pub fn migrate_layout(&self, symlink: bool) -> Result<LayoutMigration> {
+        crate::migrate::migrate_layout(&self.paths, symlink)
+    }
+
+    /// Run the `sshconfgen-<name>` executable found in `PATH` with `args`, returning its exit
+    /// status
+    pub fn run_plugin(&self, name: &str, args: &[String]) -> Result<i32> {
+        crate::plugin::run(name, args, &self.paths, &self.providers)
+    }
+}
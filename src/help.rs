@@ -5,19 +5,155 @@
 /// Print the help and usage information
 pub fn print_help() {
     // Get the name of the binary.
-    let binding = std::env::current_exe().unwrap();
-    let binary = binding.file_name().unwrap().to_str().unwrap();
+    let binding = std::env::current_exe().unwrap_or_default();
+    let binary = binding.file_name().and_then(|name| name.to_str()).unwrap_or("sshconfgen");
 
-    println!("{}", format!("Usage: {} [OPTIONS]", binary));
+    println!("Usage: {} [OPTIONS]", binary);
+    let install = "install <--nm-dispatcher|--task-scheduler|--cron <SCHEDULE>>";
+    let match_helper = "match-helper --network <NAME>";
+    let status = "status [--format=<json|yaml>]";
+    let hosts = "hosts [--format=<json|yaml>]";
+    let test = "test [HOST]...";
+    let restore = "restore [--list] [BACKUP]";
+    let self_update = "self-update [--check]";
+    let migrate = "migrate";
+    let migrate_layout = "migrate-layout [--symlink]";
+    let plugin = "<NAME> [ARGS]...";
+    let monitor = "monitor [--interval <SECONDS>]";
+    let commands = [
+        "generate",
+        "diff",
+        monitor,
+        "validate",
+        "fingerprint",
+        "export",
+        "import <FILE>",
+        "sync",
+        install,
+    ];
+    let others = [
+        match_helper,
+        "current",
+        status,
+        hosts,
+        test,
+        restore,
+        migrate,
+        migrate_layout,
+        self_update,
+        plugin,
+    ];
+    for command in commands.into_iter().chain(others) {
+        println!("       {} {}", binary, command);
+    }
     println!(
-"
+        "
 -h, --help\t\tPrints this help information
 -V, --version\t\tPrints version information
+-v, --verbose\t\tPrints progress, the reason each rule section was chosen and how long each phase
+              \t\tof the generation took.
+    --timings		Reports how long each fragment and condition took to evaluate, slowest first,
+              		and the total generation time.
+    --no-color\t\tPrints reports without colors. Colors are only used on a terminal, and not when
+              \t\tNO_COLOR is set or TERM is dumb.
+    --adopt[=fragment]\tArchives an existing SSH config not written by sshconfgen, which is
+              \t\totherwise never overwritten, in the backup directory. With =fragment its
+              \t\trules are also written to config.d/adopted.sshconf as global rules. A config
+              \t\tholding # sshconfgen BEGIN and # sshconfgen END lines is managed between
+              \t\tthem only.
     --monitor-ssid[=#]\tMonitor the SSID and regenerate the SSH config file when the SSID changes.
-              \t\tif # is specified, the SSID will be checked every # seconds, defaults to 20.
+              \t\tif # is specified, the SSID will be checked every # seconds, defaults to the
+              \t\tmonitor_interval setting or 20.
+    --listen <ADDR>\tWith --monitor-ssid, serves POST /trigger (generate now), GET /status and
+              \t\tGET /fingerprint over HTTP on a loopback address, e.g. 127.0.0.1:7422.
+    --init-dirs\t\tCreates the .ssh and config.d directories, only their owner can access, if
+              \t\tthey are missing. On a terminal sshconfgen asks to create them otherwise,
+              \t\texcept with --dry-run, which fails on missing directories.
+-i, --interactive\tShows the hosts added, removed and changed and the diff of each file the
+              \t\tgeneration writes, and asks for confirmation before writing them.
+    --dry-run\t\tPrints the same as --interactive without writing any file, also for an SSH
+              \t\tconfig not written by sshconfgen. sshconfgen diff does the same.
+    --config-dir <PATH>\tReads the fragments from PATH instead of the config_dir setting or
+              \t\t$HOME/.ssh/config.d, for every command.
+-o, --output <PATH>\tGenerates PATH instead of the ssh_config setting or $HOME/.ssh/config. Backups
+              \t\tand the state of the generation follow it, for every command.
+    --stdout\t\tPrints the generated config instead of writing it, progress goes to stderr.
+    --force\t\tWrites the SSH config even though open sessions use hosts it changes, which
+              \t\t[sessions] check = \"refuse\" in the settings otherwise prevents.
+    --dbus\t\tLinux only. With --monitor-ssid, publishes org.sshconfgen on the session bus, with
+              \t\tthe ActiveProfile and Fingerprint properties and the Regenerate method.
+
+monitor\t\t\tGenerates, then monitors the SSIDs like --monitor-ssid, accepting --listen and --dbus.
+    --interval <SECONDS>\tThe SSID polling interval, defaults to the monitor_interval setting or 20.
+validate\t\tChecks the fragments without probing or writing anything: parses each and checks
+              \t\tboth its local and remote rules against the OpenSSH client. Exits with 65 when
+              \t\tthere are warnings.
+fingerprint\t\tPrints the detected network (SSIDs and BSSIDs, default route, gateway IP and MAC,
+              \t\tsubnet, DNS suffix, VPN adapters) as JSON, without generating anything.
+export\t\t\tPrints the parsed fragments, their conditions and rules, without generating anything.
+    --format=<json|yaml>\tOutput format, defaults to json.
+    --evaluate\t\tAlso prints the network fingerprint and whether each fragment matches.
+import <FILE>\t\tWrites the fragments defined in a JSON or YAML file to config.d as <name>.sshconf.
+    --format=<json|yaml>\tInput format, inferred from the file extension by default.
+    --force\t\tReplaces existing fragments, otherwise nothing is written if any exists.
+sync\t\t\tPulls the git repository set as [sync] in the settings file into config.d, then
+              \t\tgenerates. With mode = \"overlay\" it is checked out in config.sync and its
+              \t\tfragments are copied next to the local ones, locally edited ones are kept.
+              \t\tWith read_only = true local changes are discarded instead.
+              \t\tThe .tar.gz bundles set as [[source]] are downloaded over HTTPS, checked
+              \t\tagainst their sha256 and unpacked into config.d/sources/<name>.
+install\t\t\tInstalls a hook running \"generate\" when the network changes.
+    --nm-dispatcher\tA NetworkManager dispatcher script, run as the user who ran sudo. Needs root.
+    --task-scheduler\tA Windows scheduled task of the current user, also run at logon.
+    --cron <SCHEDULE>\tA crontab entry of the current user run on the schedule, e.g.
+              \t\t\"*/5 * * * *\", replacing the one installed before. Only warnings and
+              \t\terrors are mailed.
+match-helper\t\tExits with status 0 if the [[network]] of the settings named by --network, or
+              \t\t\"remote\" if none match, is active, and 1 otherwise. For Match exec lines.
+    --network <NAME>\tThe network to check.
+current\t\t\tPrints the profile of the last generation, the active [[network]] of the settings or
+              \t\tlocal or remote, from ~/.local/state/sshconfgen/profile. For shell prompts.
+status\t\t\tPrints the last generation recorded in ~/.local/state/sshconfgen/state.json: when it
+              \t\tran, the profile, the network and the number of fragments. Generating skips
+              \t\twriting when nothing it depends on changed since.
+    --format=<json|yaml>\tPrints the whole record in this format instead.
+hosts\t\t\tPrints the hosts of the SSH config in use that can be connected to by name, with
+              \t\ttheir HostName, ProxyJump and fragment, separated by tabs.
+    --format=<json|yaml>\tPrints them in this format instead.
+test [HOST]...\t\tConnects to each host with ssh -o BatchMode=yes and the SSH config in use, or
+              \t\tto the [smoke_test] hosts of the settings, also checked after each generation.
+restore [BACKUP]\tRestores the SSH config from a compressed backup, by its number or file name, or
+              \t\tthe newest one. Backups are kept when [backups] keep is set in the settings, in
+              \t\t~/.local/state/sshconfgen/backups or [backups] dir. The current config is
+              \t\tbacked up first.
+    --list\t\tLists the backups of the SSH config, numbered newest first, instead.
+migrate\t\t\tUpgrades the .sshconf fragments of config.d written for an older format to the
+              \t\tcurrent one, recorded as FormatVersion, keeping each original in
+              \t\t~/.local/state/sshconfgen/migrations. Signed fragments and remote bundles are
+              \t\tleft alone.
+migrate-layout\t\tMoves the fragments of $HOME/.ssh/conf.d, read by earlier versions and still
+              \t\tread with a warning, to config.d. Entries whose name is taken are left in place.
+    --symlink\t\tReplaces the emptied conf.d with a link to config.d.
+self-update\t\tReplaces the binary with the latest release of the release endpoint, [update] url
+              \t\tin the settings, once its checksum and the signature of SHA256SUMS by [update]
+              \t\tkey are verified.
+    --check\t\tOnly reports whether a newer release is available.
+<NAME> [ARGS]...\tRuns the sshconfgen-<NAME> executable found in PATH with ARGS. It gets the
+              \t\tSSHCONFGEN_BIN, SSHCONFGEN_VERSION, SSHCONFGEN_SSH_DIR, SSHCONFGEN_CONFIG_DIR,
+              \t\tSSHCONFGEN_CONFIG_FILE and SSHCONFGEN_FINGERPRINT (JSON) environment variables.
 
 This utility generates a new SSH config file by alphabetically parsing
-through .sshconf files found in $HOME/.ssh/conf.d/.
+through .sshconf files found in $HOME/.ssh/config.d/ and its subdirectories, then in
+/etc/sshconfgen/config.d/ unless config.d has a fragment of the same name. On Windows
+%USERPROFILE%\\.ssh is used, or %HOME%\\.ssh if only that exists. Set ssh_dir, config_dir and
+ssh_config in the settings file to override them.
+
+Environment variables override the settings file, and flags override both:
+SSHCONFGEN_SSH_DIR, SSHCONFGEN_CONFIG_DIR, SSHCONFGEN_OUTPUT (ssh_config),
+SSHCONFGEN_SYSTEM_CONFIG_DIR, SSHCONFGEN_PROFILE (local, remote or a [[network]], skipping the
+probes it decides), SSHCONFGEN_TIMEOUT (probe_budget), SSHCONFGEN_MONITOR_INTERVAL,
+SSHCONFGEN_VERBOSE, SSHCONFGEN_TIMINGS and SSHCONFGEN_LOG. SSHCONFGEN_SETTINGS is the path of the
+settings file itself.
 
 The generated file is structured into sections, formatted as follows:
 ------------------------------------------------
@@ -25,6 +161,7 @@ The generated file is structured into sections, formatted as follows:
 LocalSSID foo, bar5ghz
 LocalGateway 192.168.1.1|00:11:22:33:44:55,172.16.1.1|00:55:44:33:22:11
 LocalPing 192.168.1.100,172.16.1.100
+LocalArpPing 192.168.1.50
 # CONDITIONS END
 
 # GLOBAL CONFIG BEGIN
@@ -39,23 +176,65 @@ LocalPing 192.168.1.100,172.16.1.100
 <remote ssh config>
 # REMOTE CONFIG END
 ------------------------------------------------
-Ensure that the .sshconf files within $HOME/.ssh/conf.d/ are properly formatted to be parsed and
+Ensure that the .sshconf files within $HOME/.ssh/config.d/ are properly formatted to be parsed and
 included in the respective sections.
 
-LocalSSID: (Optional) Succeeds if the current SSID matches any of a comma-separated list of SSIDs.
+LocalSSID: (Optional) Succeeds if a connected SSID matches any of a comma-separated list of SSIDs.
+Entries of the form interface:ssid (e.g. wlan1:labnet) only match the SSID of that interface.
+SSIDs containing commas or leading/trailing spaces can be double-quoted (e.g. \" my net \").
 
 LocalGateway: (Optional) Succeeds if any of a comma-separated key-value pair IP/MAC address matches.
 The IP and MAC pairs are separated by a pipe character, and the pairs are separated by commas.
+IPv6 neighbors are supported, link-local addresses need a zone
+(e.g. fe80::1%wlan0|aa:bb:cc:dd:ee:ff).
 
 LocalPing: (Optional) Succeeds if any of a comma-separated list of IP addresses are pingable.
 Warning: This may cause a delay in the generation of the ssh config file if the IP addresses are
 unreachable.
 
-If LocalSSID, LocalGateway, or LocalPing are specified and match or succeed, the contents of the
-local rules section will be included in the generated ssh config file, otherwise the remote rules
-section will be included.
+LocalArpPing: (Optional) Succeeds if any of a comma-separated list of IPv4 addresses answers an ARP
+request. Unlike LocalPing this works when the device drops ICMP, and only on the local segment.
+
+LocalVPN: (Optional) Succeeds if any of a comma-separated list of VPN adapters (by name, or by
+description on Windows) is connected. SSID and gateway conditions always use the physical network.
+
+Priority: (Optional) Orders the fragment, lower priorities are processed first. Fragments without
+a priority have priority 0, fragments with the same priority are processed alphabetically by path.
+Order is an alias of Priority, Priority wins if both are given.
+
+If LocalSSID, LocalGateway, LocalPing, LocalArpPing, or LocalVPN are specified and match or
+succeed, the contents of the local rules section will be included in the generated ssh config
+file, otherwise the remote rules section will be included.
 
 Global rules are always included in the generated ssh config file.
+
+A \"# INCLUDE <path>\" line is replaced with the contents of the file at <path>, relative to
+config.d, before the fragment is parsed. For options shared by several fragments.
+
+Lines of \"name = value\" between \"# VARIABLES BEGIN\" and \"# VARIABLES END\" define variables
+used as {{name}} in the rules of the fragment, or of every fragment with
+\"# VARIABLES BEGIN (global)\".
+
+A rules section marked as \"# LOCAL CONFIG BEGIN (encrypted)\" holds age ciphertext, decrypted with
+the age_identity setting, or ~/.ssh/id_ed25519 or ~/.ssh/id_rsa, when it is used.
+
+A rules section marked as \"# LOCAL CONFIG BEGIN (openssh >= 8.5)\" is dropped when ssh -V reports
+an older client, so a repeated untagged section after it is used instead. Directives the client is
+too old for, e.g. ProxyJump before 7.3, are warned about, or left out with [openssh]
+unsupported = \"strip\" in the settings file.
+
+A \"# sshconfgen: mosh [PORT]\" or \"# sshconfgen: et [PORT]\" comment after a Host line adds a
+mosh-<host> or et-<host> alias to roaming.sh, written next to the SSH config for the shell profile.
+
+Rules can look up secrets with placeholders,
+e.g. \"User {{{{ keychain(\"corp-bastion-user\") }}}}\":
+keychain(\"name\")\tThe macOS Keychain, Windows Credential Manager, or Secret Service password.
+pass(\"path\")\t\tThe first line of the gopass entry, or of the pass entry without gopass.
+op(\"op://v/i/f\")\tThe 1Password secret reference, read with op read.
+bw(\"item#field\")\tThe field of the Bitwarden item, its password without #field. BW_SESSION or the
+              \t\tbw_session_file setting must hold the session key of the unlocked vault.
+vault(\"mount/path:field\")
+              \t\tThe field of the Vault KV secret, read from VAULT_ADDR with VAULT_TOKEN.
 "
     );
-}
\ No newline at end of file
+}
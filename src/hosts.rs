@@ -0,0 +1,97 @@
+//! # Hosts
+//!
+//! This module lists the hosts of the generated SSH config that can be connected to by name, with
+//! where they go on the current network and the fragment defining them, e.g. to feed a picker:
+//!
+//! ```text
+//! sshconfgen hosts | fzf | cut -f1 | xargs -o ssh
+//! ```
+
+use crate::error::Result;
+use crate::paths::Paths;
+use crate::sshconf::Fragment;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// A host of the generated SSH config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Host {
+    /// The name given on `Host` lines
+    pub host: String,
+    pub host_name: Option<String>,
+    pub proxy_jump: Option<String>,
+    /// The first fragment of the last generation whose rules name the host
+    pub fragment: Option<PathBuf>,
+}
+
+impl Host {
+    /// Get the tab-separated line of the host, with `-` for missing values
+    pub fn line(&self) -> String {
+        let fragment = self.fragment.as_ref().map(|path| path.display().to_string());
+        let columns =
+            [Some(self.host.clone()), self.host_name.clone(), self.proxy_jump.clone(), fragment];
+        columns.map(|column| column.unwrap_or_else(|| "-".to_string())).join("\t")
+    }
+}
+
+/// Get the hosts named in the SSH config in use, sorted by name, with the fragment each comes
+/// from. The fragments are those of the last generation, or the current ones if none is recorded.
+pub fn list(paths: &Paths) -> Result<Vec<Host>> {
+    let (_, ssh_config) = crate::network::read_active(&paths.config_file)?;
+    let fragments = match crate::status::last(paths) {
+        Some(generation) => generation
+            .fragments
+            .iter()
+            .filter_map(|path| crate::sshconf::read_fragment(&paths.config_dir, path))
+            .collect(),
+        None => crate::sshconf::read_fragments(&paths.config_dir),
+    };
+
+    Ok(hosts(&ssh_config, &fragments))
+}
+
+/// Get the hosts named in `ssh_config`, attributed to the first of `fragments` naming them
+pub fn hosts(ssh_config: &str, fragments: &[Fragment]) -> Vec<Host> {
+    let names: Vec<_> = fragments
+        .iter()
+        .map(|fragment| {
+            let rules = [&fragment.global_rules, &fragment.local_rules, &fragment.remote_rules];
+            let names =
+                rules.map(|rules| crate::sessions::targets(rules).into_keys().collect::<Vec<_>>());
+            (&fragment.path, names.concat())
+        })
+        .collect();
+
+    crate::sessions::targets(ssh_config)
+        .into_iter()
+        .map(|(host, target)| {
+            let fragment = names
+                .iter()
+                .find(|(_, names)| names.contains(&host))
+                .map(|(path, _)| path.to_path_buf());
+            Host { host, host_name: target.host_name, proxy_jump: target.proxy_jump, fragment }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn attributes_hosts_to_their_fragment() {
+        let contents = "# CONDITIONS BEGIN\nLocalSSID home\n# CONDITIONS END\n\n\
+                        # LOCAL CONFIG BEGIN\nHost nas\n  HostName 192.168.1.10\n\
+                        # LOCAL CONFIG END\n\n# REMOTE CONFIG BEGIN\nHost nas\n\
+                        \x20 HostName nas.example.com\n  ProxyJump bastion\n# REMOTE CONFIG END\n";
+        let fragment = Fragment::parse(Path::new("nas.sshconf"), contents);
+        let ssh_config =
+            "Host nas\n  HostName nas.example.com\n  ProxyJump bastion\n\nHost bastion *.lan\n";
+
+        let hosts = hosts(ssh_config, &[fragment]);
+        assert_eq!(hosts.len(), 2);
+        assert_eq!(hosts[0].line(), "bastion\t-\t-\t-");
+        assert_eq!(hosts[1].line(), "nas\tnas.example.com\tbastion\tnas.sshconf");
+    }
+}
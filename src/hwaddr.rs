@@ -2,50 +2,294 @@
 //!
 //! This module provides a function to get the MAC address of a device given its IP address.
 
-use std::{
-    process::Command,
-    str
-};
+use crate::error::{Error, Result};
+use crate::platform::{netsh_command, run};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+use std::process::Output;
 
 /// Get the hardware address of a device given its IP address
-pub fn get_hw_address(ip_address: &str) -> Result<String, &'static str> {
+pub fn get_hw_address(ip_address: &str) -> Result<String> {
+    let settings = crate::settings::get();
+    if let Some(hwaddr_command) = &settings.hwaddr_command {
+        let mut command = crate::settings::build_command(hwaddr_command, "{ip}", ip_address);
+        let output = run(&mut command, "LocalGateway")?;
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        return match &settings.hwaddr_pattern {
+            Some(pattern) => Regex::new(pattern)
+                .unwrap()
+                .captures(&output)
+                .and_then(|caps| caps.name("mac"))
+                .map(|mac| mac.as_str().to_string())
+                .ok_or(Error::NotFound("MAC address")),
+            None => find_hw_address(&output, ip_address).map(str::to_string),
+        };
+    }
+
+    // Locked-down Windows images may lack arp and netsh, but not the NetTCPIP cmdlets.
+    let arp_tool = if crate::platform::is_wsl() { "arp.exe" } else { "arp" };
+    if (cfg!(target_os = "windows") || crate::platform::is_wsl())
+        && !crate::backend::is_in_path(arp_tool)
+    {
+        return crate::powershell::get_hw_address(ip_address.split('%').next().unwrap());
+    }
+
+    // The native backend only reads the IPv4 ARP table.
+    let address = ip_address.split('%').next().unwrap();
+    let ipv6 = address.parse::<Ipv6Addr>().is_ok();
+    if !ipv6 && use_native_backend() {
+        return crate::native::get_hw_address(ip_address);
+    }
+
+    get_neighbor_table(ipv6)?.lookup(ip_address)
+}
+
+/// A snapshot of the IPv4 or IPv6 neighbor table, read once and queried for any number of
+/// addresses
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NeighborTable {
+    /// Hardware addresses keyed by IP address as listed, with a zone if the listing has one
+    entries: HashMap<String, String>,
+}
+
+impl NeighborTable {
+    /// Parse a neighbor table listing
+    ///
+    /// Column layouts and headers differ per platform and language, so each line is taken as the
+    /// first token shaped like an IP address and the first shaped like a hardware address. Lines
+    /// lacking either (headers, incomplete entries) are skipped, and the first entry of an
+    /// address wins. `ip neigh` lines also register the address scoped to their `dev`.
+    pub fn parse(listing: &str) -> NeighborTable {
+        let mac_re = Regex::new(r"^[0-9A-Fa-f]{1,2}([:-][0-9A-Fa-f]{1,2}){5}$").unwrap();
+        let mut entries = HashMap::new();
+
+        for line in listing.lines() {
+            let tokens: Vec<&str> =
+                line.split_whitespace().map(|token| token.trim_matches(['(', ')'])).collect();
+            let ip = tokens
+                .iter()
+                .find(|token| token.split('%').next().unwrap().parse::<IpAddr>().is_ok());
+            let mac = tokens.iter().find(|token| mac_re.is_match(token));
+            let (Some(ip), Some(mac)) = (ip, mac) else {
+                continue;
+            };
+
+            entries.entry(ip.to_string()).or_insert_with(|| mac.to_string());
+            if let Some(dev) =
+                tokens.iter().position(|token| *token == "dev").and_then(|i| tokens.get(i + 1))
+            {
+                entries.entry(format!("{}%{}", ip, dev)).or_insert_with(|| mac.to_string());
+            }
+        }
+
+        NeighborTable { entries }
+    }
+
+    /// Get the hardware address of `ip_address`
+    ///
+    /// `ip_address` may carry a zone (`fe80::1%wlan0`), which is required to tell link-local
+    /// neighbors on different interfaces apart. Listings that omit the zone of a scoped address
+    /// still match on the address alone.
+    pub fn lookup(&self, ip_address: &str) -> Result<String> {
+        let address = ip_address.split('%').next().unwrap();
+        let mac_address = self
+            .entries
+            .get(ip_address)
+            .or_else(|| self.entries.get(address))
+            .ok_or(Error::NotFound("MAC address"))?;
+
+        // Windows prints dash-separated addresses, match the colon format used everywhere else.
+        // Native Windows keeps its own format, and IPv4 elsewhere is reported as listed.
+        let ipv6 = address.parse::<Ipv6Addr>().is_ok();
+        if cfg!(target_os = "windows") || (!ipv6 && !crate::platform::is_wsl()) {
+            return Ok(mac_address.clone());
+        }
+
+        Ok(mac_address.replace('-', ":").to_lowercase())
+    }
+}
+
+/// Read the whole IPv4 or IPv6 neighbor table with the platform's tools
+///
+/// Unsupported when the neighbors are looked up one address at a time instead: with
+/// `hwaddr_command`, PowerShell, or the native IPv4 backend.
+pub fn get_neighbor_table(ipv6: bool) -> Result<NeighborTable> {
+    let arp_tool = if crate::platform::is_wsl() { "arp.exe" } else { "arp" };
+    if crate::settings::get().hwaddr_command.is_some()
+        || ((cfg!(target_os = "windows") || crate::platform::is_wsl())
+            && !crate::backend::is_in_path(arp_tool))
+        || (!ipv6 && use_native_backend())
+    {
+        return Err(Error::Unsupported("Reading the whole neighbor table"));
+    }
+
+    let output = if ipv6 { read_ipv6_listing()? } else { read_ipv4_listing()? };
+
+    let mut listing = String::from_utf8_lossy(&output.stdout).to_string();
+    if cfg!(target_os = "windows") || crate::platform::is_wsl() {
+        listing = exclude_vpn_entries(&listing);
+    }
+
+    Ok(NeighborTable::parse(&listing))
+}
+
+/// Get whether the IPv4 neighbors are read by the native backend
+fn use_native_backend() -> bool {
+    let tool = if cfg!(target_os = "android") { "ip" } else { "arp" };
+    let native_supported = crate::native::NEIGHBOR_SUPPORTED && !crate::platform::is_wsl();
+    crate::backend::use_native(crate::settings::get().backend.neighbor, tool, native_supported)
+}
+
+/// List the IPv4 neighbor table
+fn read_ipv4_listing() -> Result<Output> {
     let command: &str;
     let args: Vec<&str>;
-    let _formatted_arg: String;
 
-    #[cfg(target_os = "linux")] {
+    #[cfg(target_os = "linux")]
+    {
+        if crate::platform::is_wsl() {
+            // The WSL neighbor table only knows the virtual switch, use the host's table.
+            command = "arp.exe";
+            args = vec!["-a"];
+        } else {
+            command = "arp";
+            args = vec!["-n"];
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
         command = "arp";
-        args = vec!["-n", ip_address];
+        args = vec!["-an"];
+    }
+
+    // arp is not available to unprivileged Termux users, but the neighbor table is.
+    #[cfg(target_os = "android")]
+    {
+        command = "ip";
+        args = vec!["neigh", "show"];
     }
 
-    #[cfg(target_os = "macos")] {
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "illumos",
+        target_os = "solaris"
+    ))]
+    {
         command = "arp";
-        args = vec!["-n", ip_address];
+        args = vec!["-an"];
     }
 
-    #[cfg(target_os = "windows")] {
+    #[cfg(target_os = "windows")]
+    {
         command = "cmd";
-        formatted_arg = format!("arp -a {}", ip_address);
-        args = vec!["/C", &formatted_arg];
+        args = vec!["/C", "arp -a"];
     }
 
     if command.is_empty() {
-        return Err("OS not supported");
+        return Err(Error::Unsupported("Neighbor lookup"));
+    }
+
+    run(crate::platform::command(command).args(args), "LocalGateway")
+}
+
+/// List the IPv6 neighbor cache (NDP)
+fn read_ipv6_listing() -> Result<Output> {
+    if cfg!(target_os = "windows") || crate::platform::is_wsl() {
+        run(&mut netsh_command(&["interface", "ipv6", "show", "neighbors"]), "LocalGateway")
+    } else if cfg!(any(target_os = "linux", target_os = "android")) {
+        run(crate::platform::command("ip").args(["-6", "neigh", "show"]), "LocalGateway")
+    } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+        // illumos has no ndp tool, the neighbor cache is part of the net-to-media table.
+        run(crate::platform::command("netstat").args(["-pn", "-f", "inet6"]), "LocalGateway")
+    } else {
+        // macOS and the BSDs print scoped addresses as "fe80::1%en0".
+        run(crate::platform::command("ndp").args(["-an"]), "LocalGateway")
+    }
+}
+
+/// Remove the `arp -a` entries listed under VPN adapters, so the physical network is matched
+///
+/// Entries are grouped under "Interface: <ip> --- 0x<index>" headers, where only the label is
+/// translated. Looking up the VPN adapters needs PowerShell, so it only happens when an address
+/// is listed under more than one interface.
+fn exclude_vpn_entries(output: &str) -> String {
+    let mut listed = HashSet::new();
+    let duplicated = output
+        .lines()
+        .filter(|line| !line.contains(" --- 0x"))
+        .filter_map(|line| line.split_whitespace().next())
+        .any(|address| !listed.insert(address));
+    if !duplicated {
+        return output.to_string();
     }
 
-    let output = Command::new(command)
-        .args(args)
-        .output()
-        .map_err(|_| "Failed to execute command")?
-        .stdout;
+    let vpn_indexes: Vec<u32> = crate::vpn::get_vpn_adapters()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|adapter| adapter.index)
+        .collect();
 
-    let mac_address = str::from_utf8(&output)
-        .unwrap()
+    let mut in_vpn_block = false;
+    output
         .lines()
-        .filter(|line| line.contains(ip_address))
-        .flat_map(|line| line.split_whitespace().nth(if cfg!(target_os = "windows") { 1 } else { 2 }))
+        .filter(|line| {
+            if let Some((_, index)) = line.split_once(" --- 0x") {
+                in_vpn_block = u32::from_str_radix(index.trim(), 16)
+                    .is_ok_and(|index| vpn_indexes.contains(&index));
+            }
+            !in_vpn_block
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Find the hardware address of `ip_address` in a neighbor table listing
+///
+/// Column layouts and headers differ per platform and language, so look for the entry whose
+/// address is exactly the one asked for and take the token shaped like a hardware address.
+/// Listings that omit the zone of a scoped IPv6 address still match on the address alone.
+fn find_hw_address<'a>(output: &'a str, ip_address: &str) -> Result<&'a str> {
+    let mac_re = Regex::new(r"^[0-9A-Fa-f]{1,2}([:-][0-9A-Fa-f]{1,2}){5}$").unwrap();
+    let address = ip_address.split('%').next().unwrap();
+    output
+        .lines()
+        .filter(|line| {
+            line.split_whitespace().any(|token| {
+                let token = token.trim_start_matches('(').trim_end_matches(')');
+                token == ip_address || (!token.contains('%') && token == address)
+            })
+        })
+        .flat_map(|line| line.split_whitespace().find(|token| mac_re.is_match(token)))
         .next()
-        .ok_or("MAC address not found")?;
+        .ok_or(Error::NotFound("MAC address"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(mac_address.to_string())
+    #[test]
+    fn parses_neighbor_tables() {
+        let listing = "\
+Address                  HWtype  HWaddress           Flags Mask            Iface
+192.168.1.1              ether   aa:bb:cc:dd:ee:01   C                     wlan0
+192.168.1.7                      (incomplete)                              wlan0
+? (10.0.0.1) at aa:bb:cc:dd:ee:02 on en0 ifscope [ethernet]
+  10.0.0.9              aa-bb-cc-dd-ee-03     dynamic
+fe80::1 dev wlan0 lladdr aa:bb:cc:dd:ee:04 router REACHABLE
+fe80::1%en0                     aa:bb:cc:dd:ee:05    en0 23h59m58s S R
+";
+        let table = NeighborTable::parse(listing);
+        assert_eq!(table.lookup("192.168.1.1").unwrap(), "aa:bb:cc:dd:ee:01");
+        assert_eq!(table.lookup("10.0.0.1").unwrap(), "aa:bb:cc:dd:ee:02");
+        assert_eq!(table.lookup("fe80::1%wlan0").unwrap(), "aa:bb:cc:dd:ee:04");
+        assert_eq!(table.lookup("fe80::1%en0").unwrap(), "aa:bb:cc:dd:ee:05");
+        assert!(table.lookup("10.0.0.9").is_ok());
+        assert!(table.lookup("192.168.1.7").is_err());
+    }
 }
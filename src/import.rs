@@ -0,0 +1,218 @@
+//! # Import
+//!
+//! This module converts structured host and condition definitions, e.g. produced by an inventory
+//! system, into `.sshconf` fragments.
+//!
+//! ```yaml
+//! fragments:
+//!   - name: home
+//!     conditions:
+//!       LocalSSID: [homenet]
+//!       Priority: 10
+//!     local:
+//!       - host: nas
+//!         options: { HostName: 192.168.1.10, User: admin }
+//!     remote: |
+//!       Host nas
+//!         HostName nas.example.com
+//! ```
+
+use crate::error::{Error, Result};
+use crate::sshconf::{Condition, Fragment, CONDITION_KEYS};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A structured data format, of the definitions to import or of the reports of the binary
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Json,
+    Yaml,
+}
+
+/// The fragments to create
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Definitions {
+    pub fragments: Vec<Definition>,
+}
+
+/// A fragment to create as `<name>.sshconf`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Definition {
+    pub name: String,
+    /// Conditions and metadata, valued as in `.sshconf.toml` fragments
+    #[serde(default)]
+    pub conditions: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    pub global: Rules,
+    #[serde(default)]
+    pub local: Rules,
+    #[serde(default)]
+    pub remote: Rules,
+}
+
+/// The rules of a section, as SSH config text or as host entries
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Rules {
+    Text(String),
+    Hosts(Vec<Host>),
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules::Text(String::new())
+    }
+}
+
+/// A `Host` block, an option given a list is repeated for each of its values
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Host {
+    pub host: String,
+    #[serde(default)]
+    pub options: BTreeMap<String, toml::Value>,
+}
+
+impl Definitions {
+    /// Parse JSON definitions
+    pub fn from_json(contents: &str) -> std::result::Result<Definitions, String> {
+        serde_json::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    /// Parse YAML definitions
+    pub fn from_yaml(contents: &str) -> std::result::Result<Definitions, String> {
+        serde_yaml_ng::from_str(contents).map_err(|e| e.to_string())
+    }
+
+    /// Convert the definitions into fragments of `ssh_config_dir`
+    pub fn to_fragments(
+        &self,
+        ssh_config_dir: &Path,
+    ) -> std::result::Result<Vec<Fragment>, String> {
+        self.fragments.iter().map(|definition| definition.to_fragment(ssh_config_dir)).collect()
+    }
+}
+
+impl Definition {
+    /// Convert the definition into a fragment of `ssh_config_dir`
+    fn to_fragment(&self, ssh_config_dir: &Path) -> std::result::Result<Fragment, String> {
+        let name = &self.name;
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("{:?} is not a valid fragment name", name));
+        }
+
+        let mut conditions = Vec::new();
+        let mut metadata = BTreeMap::new();
+        for (key, value) in &self.conditions {
+            let value = crate::sshconf::condition_value(key, value)
+                .map_err(|e| format!("{}: {}", name, e))?;
+            if CONDITION_KEYS.contains(&key.as_str()) {
+                conditions.push(Condition { key: key.clone(), value });
+            } else {
+                metadata.insert(key.clone(), value);
+            }
+        }
+
+        let rules = |rules: &Rules| rules.to_config().map_err(|e| format!("{}: {}", name, e));
+        Ok(Fragment {
+            path: ssh_config_dir.join(format!("{}.{}", name, crate::CONFIG_EXTENSION)),
+            conditions,
+            metadata,
+            global_rules: rules(&self.global)?,
+            local_rules: rules(&self.local)?,
+            remote_rules: rules(&self.remote)?,
+            encrypted: Default::default(),
+            includes: Vec::new(),
+            variables: BTreeMap::new(),
+            global_variables: BTreeMap::new(),
+        })
+    }
+}
+
+impl Rules {
+    /// Format the rules as SSH config text
+    fn to_config(&self) -> std::result::Result<String, String> {
+        let hosts = match self {
+            Rules::Text(text) => return Ok(text.trim().to_string()),
+            Rules::Hosts(hosts) => hosts,
+        };
+
+        let mut lines = Vec::new();
+        for host in hosts {
+            lines.push(format!("Host {}", host.host));
+            for (option, value) in &host.options {
+                let values = match value {
+                    toml::Value::Array(values) => values.iter().collect(),
+                    value => vec![value],
+                };
+                for value in values {
+                    let value = match value {
+                        toml::Value::String(value) => value.clone(),
+                        toml::Value::Integer(value) => value.to_string(),
+                        toml::Value::Boolean(value) => {
+                            if *value { "yes" } else { "no" }.to_string()
+                        }
+                        _ => {
+                            return Err(format!(
+                                "{} of host {} must be a string, number or boolean",
+                                option, host.host
+                            ))
+                        }
+                    };
+                    lines.push(format!("  {} {}", option, value));
+                }
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+/// Write the fragments defined in `file`, in `format`, to `ssh_config_dir`, replacing existing
+/// ones with `force`. Returns the fragments written.
+pub fn import(
+    ssh_config_dir: &Path,
+    file: &Path,
+    format: Format,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    let contents = std::fs::read_to_string(file).map_err(Error::io("read", file))?;
+    let definitions = match format {
+        Format::Json => Definitions::from_json(&contents),
+        Format::Yaml => Definitions::from_yaml(&contents),
+    };
+    let fragments = definitions
+        .and_then(|definitions| definitions.to_fragments(ssh_config_dir))
+        .map_err(|e| Error::Invalid(format!("import: {}: {}", file.display(), e)))?;
+    write_fragments(&fragments, force)
+}
+
+/// Write fragments to their paths, returns the written paths
+///
+/// Existing files are only replaced with `force`, nothing is written if any would be replaced
+/// without it.
+pub fn write_fragments(fragments: &[Fragment], force: bool) -> Result<Vec<PathBuf>> {
+    if let Some(existing) = fragments.iter().find(|fragment| !force && fragment.path.exists()) {
+        let message = format!(
+            "import: {} already exists, use --force to replace it",
+            existing.path.display()
+        );
+        return Err(Error::Invalid(message));
+    }
+
+    let mut written = Vec::new();
+    for fragment in fragments {
+        let contents = fragment.to_sshconf();
+        crate::file::replace_file(&fragment.path, |out| {
+            std::io::Write::write_all(out, contents.as_bytes())
+        })
+        .map_err(Error::io("write", &fragment.path))?;
+        written.push(fragment.path.clone());
+    }
+
+    Ok(written)
+}
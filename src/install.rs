@@ -0,0 +1,348 @@
+//! # Install
+//!
+//! This module installs hooks that run `sshconfgen generate` when the network changes, so the SSH
+//! config follows the network without `--monitor-ssid` running in the background.
+//!
+//! On Linux the hook is a NetworkManager dispatcher script, on Windows a scheduled task triggered
+//! by the `NetworkProfile` events logged when a network is connected or disconnected. Servers and
+//! headless boxes without either can generate on a schedule with a crontab entry instead.
+
+use crate::error::{Error, Result};
+use crate::platform::shell_quote;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+/// The directory NetworkManager runs dispatcher scripts from, as root
+pub const NM_DISPATCHER_DIR: &str = "/etc/NetworkManager/dispatcher.d";
+
+/// The dispatcher actions the config is generated on
+const NM_ACTIONS: &[&str] = &["up", "down", "vpn-up", "vpn-down", "connectivity-change"];
+
+/// The name of the scheduled task
+pub const TASK_NAME: &str = "sshconfgen";
+
+/// The events of the `Microsoft-Windows-NetworkProfile/Operational` log the task is triggered by:
+/// a network connected, or disconnected
+const NETWORK_PROFILE_EVENTS: &[u32] = &[10000, 10001];
+
+/// The comment ending the crontab entry, which finds it again when it is updated
+const CRON_MARKER: &str = "# sshconfgen install --cron";
+
+/// The `@` schedules of cron, in place of the five time fields
+const CRON_KEYWORDS: &[&str] =
+    &["@reboot", "@yearly", "@annually", "@monthly", "@weekly", "@daily", "@midnight", "@hourly"];
+
+/// Get the dispatcher script generating the SSH config of `user` with `binary`
+///
+/// NetworkManager waits for dispatcher scripts, so the generation runs in the background, and as
+/// the user since the scripts run as root.
+pub fn nm_dispatcher_script(binary: &Path, user: &str) -> String {
+    format!(
+        "#!/bin/sh
+# Installed by sshconfgen install --nm-dispatcher, regenerates the SSH config of {user} when
+# the network changes.
+case \"$2\" in
+    {actions}) ;;
+    *) exit 0 ;;
+esac
+
+runuser -u {quoted_user} -- {binary} generate >/dev/null 2>&1 &
+",
+        user = user,
+        actions = NM_ACTIONS.join("|"),
+        quoted_user = shell_quote(user),
+        binary = shell_quote(&binary.to_string_lossy()),
+    )
+}
+
+/// Install the NetworkManager dispatcher script of the user, the one running `sudo` if any.
+/// Returns the path of the script.
+pub fn install_nm_dispatcher() -> Result<PathBuf> {
+    if !cfg!(target_os = "linux") {
+        return Err(Error::Unsupported("NetworkManager"));
+    }
+
+    let user = ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|user| !user.is_empty())
+        .or_else(current_user)
+        .ok_or(Error::NotFound("user name"))?;
+    let binary = std::env::current_exe().map_err(Error::io("locate", "sshconfgen"))?;
+
+    let dir = Path::new(NM_DISPATCHER_DIR);
+    if !dir.is_dir() {
+        return Err(Error::MissingDirectory(dir.to_path_buf()));
+    }
+
+    let path = dir.join(format!("90-sshconfgen-{}", user));
+    write_script(&path, &nm_dispatcher_script(&binary, &user))?;
+    Ok(path)
+}
+
+/// Get the name of the user running the binary with `id`, for environments without `$USER`
+fn current_user() -> Option<String> {
+    let output = crate::platform::command("id").arg("-un").output().ok()?;
+    let user = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !user.is_empty()).then_some(user)
+}
+
+/// Get the definition of the scheduled task generating the SSH config of `user`, a
+/// `DOMAIN\user` name, with `binary` at logon and whenever a network is connected or disconnected
+///
+/// Networks often connect and disconnect in bursts, so the events are waited out for a few seconds
+/// and a run is skipped while another is in progress.
+pub fn task_definition(binary: &Path, user: &str) -> String {
+    let events: Vec<String> =
+        NETWORK_PROFILE_EVENTS.iter().map(|id| format!("EventID={}", id)).collect();
+    let query = format!(
+        "<QueryList><Query Id=\"0\" Path=\"{log}\">\
+         <Select Path=\"{log}\">*[System[({events})]]</Select></Query></QueryList>",
+        log = "Microsoft-Windows-NetworkProfile/Operational",
+        events = events.join(" or "),
+    );
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-16"?>
+<Task version="1.2" xmlns="http://schemas.microsoft.com/windows/2004/02/mit/task">
+  <RegistrationInfo>
+    <Description>Generates the SSH config when the network changes</Description>
+  </RegistrationInfo>
+  <Triggers>
+    <LogonTrigger>
+      <UserId>{user}</UserId>
+    </LogonTrigger>
+    <EventTrigger>
+      <Subscription>{query}</Subscription>
+      <Delay>PT5S</Delay>
+    </EventTrigger>
+  </Triggers>
+  <Principals>
+    <Principal id="Author">
+      <UserId>{user}</UserId>
+      <LogonType>InteractiveToken</LogonType>
+      <RunLevel>LeastPrivilege</RunLevel>
+    </Principal>
+  </Principals>
+  <Settings>
+    <MultipleInstancesPolicy>IgnoreNew</MultipleInstancesPolicy>
+    <DisallowStartIfOnBatteries>false</DisallowStartIfOnBatteries>
+    <StopIfGoingOnBatteries>false</StopIfGoingOnBatteries>
+    <StartWhenAvailable>true</StartWhenAvailable>
+    <ExecutionTimeLimit>PT5M</ExecutionTimeLimit>
+  </Settings>
+  <Actions Context="Author">
+    <Exec>
+      <Command>{binary}</Command>
+      <Arguments>generate</Arguments>
+    </Exec>
+  </Actions>
+</Task>
+"#,
+        user = xml_escape(user),
+        query = xml_escape(&query),
+        binary = xml_escape(&binary.to_string_lossy()),
+    )
+}
+
+/// Register the scheduled task of the current user with `schtasks`, replacing any previous version.
+/// Returns the name of the task.
+pub fn install_task_scheduler() -> Result<String> {
+    if !cfg!(target_os = "windows") {
+        return Err(Error::Unsupported("Task Scheduler"));
+    }
+
+    if !crate::backend::is_in_path("schtasks") {
+        return Err(Error::ToolUnavailable("schtasks".to_string()));
+    }
+
+    let user = match (std::env::var("USERDOMAIN"), std::env::var("USERNAME")) {
+        (Ok(domain), Ok(user)) if !domain.is_empty() && !user.is_empty() => {
+            format!("{}\\{}", domain, user)
+        }
+        _ => return Err(Error::NotFound("user name")),
+    };
+    let binary = std::env::current_exe().map_err(Error::io("locate", "sshconfgen"))?;
+
+    // schtasks reads the definition from a file, in UTF-16 as its declaration says.
+    let path = std::env::temp_dir().join(format!("sshconfgen-task.{}.xml", std::process::id()));
+    let definition: Vec<u8> = [0xff, 0xfe]
+        .into_iter()
+        .chain(task_definition(&binary, &user).encode_utf16().flat_map(u16::to_le_bytes))
+        .collect();
+    fs::write(&path, definition).map_err(Error::io("write", &path))?;
+
+    let output = crate::platform::command("schtasks")
+        .args(["/Create", "/F", "/TN", TASK_NAME, "/XML"])
+        .arg(&path)
+        .output();
+    let _ = fs::remove_file(&path);
+
+    match output {
+        Ok(output) if output.status.success() => Ok(TASK_NAME.to_string()),
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(Error::Install(format!("the {} task: {}", TASK_NAME, stderr.trim())))
+        }
+        Err(_) => Err(Error::ToolUnavailable("schtasks".to_string())),
+    }
+}
+
+/// Check that `schedule` is five cron time fields or an `@` keyword, and normalize its spacing
+pub fn parse_cron_schedule(schedule: &str) -> Result<String> {
+    let schedule = schedule.split_whitespace().collect::<Vec<_>>().join(" ");
+    let fields: Vec<&str> = schedule.split(' ').collect();
+    let valid = match fields[..] {
+        [keyword] => CRON_KEYWORDS.contains(&keyword),
+        [_, _, _, _, _] => fields
+            .iter()
+            .all(|field| field.chars().all(|c| c.is_ascii_alphanumeric() || "*/,-".contains(c))),
+        _ => false,
+    };
+    if !valid {
+        return Err(Error::Invalid(format!(
+            "cron schedule: {}, expected five fields or an @ keyword",
+            schedule
+        )));
+    }
+
+    Ok(schedule)
+}
+
+/// Get the crontab entry generating the SSH config with `binary` on `schedule`
+///
+/// Cron mails the output of its jobs, so only warnings and errors are kept.
+pub fn cron_entry(schedule: &str, binary: &Path) -> Result<String> {
+    let schedule = parse_cron_schedule(schedule)?;
+
+    // A % starts the standard input of the job in crontabs, so it can't be left unescaped.
+    let binary = shell_quote(&binary.to_string_lossy()).replace('%', "\\%");
+    Ok(format!("{} {} generate >/dev/null {}", schedule, binary, CRON_MARKER))
+}
+
+/// Replace the entries installed before in `crontab` with `entry`, keeping every other line as is
+pub fn update_crontab(crontab: &str, entry: &str) -> String {
+    let mut lines: Vec<&str> =
+        crontab.lines().filter(|line| !line.trim_end().ends_with(CRON_MARKER)).collect();
+    lines.push(entry);
+    format!("{}\n", lines.join("\n"))
+}
+
+/// Add the crontab entry of the current user, or update it if it was installed before. Returns the
+/// entry.
+pub fn install_cron(schedule: &str) -> Result<String> {
+    if !cfg!(unix) {
+        return Err(Error::Unsupported("cron"));
+    }
+
+    if !crate::backend::is_in_path("crontab") {
+        return Err(Error::ToolUnavailable("crontab".to_string()));
+    }
+
+    let binary = std::env::current_exe().map_err(Error::io("locate", "sshconfgen"))?;
+    let entry = cron_entry(schedule, &binary)?;
+
+    // `crontab -l` fails when the user has no crontab yet, which is only an error if it has output.
+    let output = crate::platform::command("crontab")
+        .arg("-l")
+        .output()
+        .map_err(|_| Error::ToolUnavailable("crontab".to_string()))?;
+    let crontab = String::from_utf8(output.stdout).map_err(|_| Error::Parse("crontab"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && !stderr.to_lowercase().contains("no crontab") {
+        return Err(Error::Install(format!("the crontab entry: {}", stderr.trim())));
+    }
+
+    let mut child = crate::platform::command("crontab")
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| Error::ToolUnavailable("crontab".to_string()))?;
+    let Some(mut stdin) = child.stdin.take() else {
+        return Err(Error::ToolUnavailable("crontab".to_string()));
+    };
+    let written = stdin.write_all(update_crontab(&crontab, &entry).as_bytes());
+    drop(stdin);
+
+    let output =
+        child.wait_with_output().map_err(|_| Error::ToolUnavailable("crontab".to_string()))?;
+    if let Err(e) = written {
+        return Err(Error::Install(format!("the crontab entry: {}", e)));
+    } else if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Install(format!("the crontab entry: {}", stderr.trim())));
+    }
+
+    Ok(entry)
+}
+
+/// Write an executable script only its owner can modify, replacing any previous version
+fn write_script(path: &Path, script: &str) -> Result<()> {
+    if let Err(e) = fs::write(path, script) {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            return Err(Error::Install(format!(
+                "{}: it is not writable, run it with sudo",
+                path.display()
+            )));
+        }
+        return Err(Error::io("write", path)(e));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+            .map_err(Error::io("set permissions of", path))?;
+    }
+
+    Ok(())
+}
+
+/// Escape the XML special characters of `value`
+fn xml_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_dispatcher_scripts() {
+        let script = nm_dispatcher_script(Path::new("/opt/it's/sshconfgen"), "alice");
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("    up|down|vpn-up|vpn-down|connectivity-change) ;;\n"));
+        assert!(script.contains("runuser -u 'alice' -- '/opt/it'\\''s/sshconfgen' generate"));
+    }
+
+    #[test]
+    fn writes_task_definitions() {
+        let definition = task_definition(Path::new(r"C:\Tools & Co\sshconfgen.exe"), r"CORP\alice");
+        assert!(definition.contains(r"<UserId>CORP\alice</UserId>"));
+        assert!(definition.contains(r"<Command>C:\Tools &amp; Co\sshconfgen.exe</Command>"));
+        assert!(definition.contains("*[System[(EventID=10000 or EventID=10001)]]&lt;/Select&gt;"));
+    }
+
+    #[test]
+    fn updates_crontab_entries() {
+        let binary = Path::new("/opt/100%/sshconfgen");
+        let entry = cron_entry(" */5  * * * 1-5", binary).unwrap();
+        let expected = "*/5 * * * 1-5 '/opt/100\\%/sshconfgen' generate >/dev/null";
+        assert_eq!(entry, format!("{} # sshconfgen install --cron", expected));
+        assert!(cron_entry("@hourly", binary).is_ok());
+        for schedule in ["", "* * * *", "@often", "* * * * * echo", "* * * * ;"] {
+            assert!(cron_entry(schedule, binary).is_err(), "{}", schedule);
+        }
+
+        let crontab = format!("MAILTO=me\n0 * * * * backup\n@daily old {}\n", CRON_MARKER);
+        assert_eq!(
+            update_crontab(&crontab, &entry),
+            format!("MAILTO=me\n0 * * * * backup\n{}\n", entry)
+        );
+        assert_eq!(update_crontab("", &entry), format!("{}\n", entry));
+    }
+}
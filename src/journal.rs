@@ -0,0 +1,230 @@
+//! # Journal
+//!
+//! This module records what is about to be written before the SSH config is changed: the intent,
+//! the backup of the config and, for each file, its temporary file, previous contents and the
+//! checksum of its new contents. The entry is removed once every file is written.
+//!
+//! A run killed in between, e.g. by a crash or a power loss, leaves the entry behind, and the
+//! next generation or restore repairs what it left: the files are kept if they were all written,
+//! otherwise their previous contents are put back. Either way the temporary files are removed, so
+//! the config is never left half written, e.g. a named network's include file replaced while the
+//! SSH config still points at the previous one.
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::review::Planned;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// What the journaled run was doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Intent {
+    /// Writing a generated config
+    Generate,
+    /// Restoring the config from a backup
+    Restore,
+}
+
+impl Intent {
+    /// Get the name of the run, as reported by [`repair`]
+    fn name(self) -> &'static str {
+        match self {
+            Intent::Generate => "generation",
+            Intent::Restore => "restore",
+        }
+    }
+}
+
+/// The files a run is about to write
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    pub intent: Intent,
+    /// The backup of the SSH config made before writing, if backups are enabled
+    pub backup: Option<PathBuf>,
+    pub files: Vec<Entry>,
+}
+
+/// A file a journaled run is about to write
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// The temporary file the new contents are written to before it is renamed over `path`
+    pub temp_file: PathBuf,
+    /// The contents of the file, empty if it didn't exist
+    pub previous: String,
+    /// The SHA-256 checksum of the new contents
+    pub sha256: String,
+}
+
+/// How [`repair`] left the files of an interrupted run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    /// Every file had been written, they are kept
+    Completed,
+    /// Some files hadn't been written, the previous contents of all of them were put back
+    RolledBack,
+}
+
+/// Get the path of the journal of the SSH config of `paths`, in the state directory
+pub fn journal_path(paths: &Paths) -> PathBuf {
+    let hash = crate::source::sha256_hex(paths.config_file.to_string_lossy().as_bytes());
+    paths.state_dir.join(format!("journal-{}.json", &hash[..8]))
+}
+
+/// Record that `files` are about to be written, before touching any of them
+pub fn begin(
+    paths: &Paths,
+    intent: Intent,
+    backup: Option<&Path>,
+    files: &[Planned],
+) -> Result<()> {
+    let files = files
+        .iter()
+        .map(|file| Entry {
+            path: file.path.clone(),
+            temp_file: crate::file::temp_path(&file.path),
+            previous: file.current.clone(),
+            sha256: crate::source::sha256_hex(file.new.as_bytes()),
+        })
+        .collect();
+    let journal = Journal { intent, backup: backup.map(Path::to_path_buf), files };
+
+    crate::file::create_private_dir(&paths.state_dir)
+        .map_err(Error::io("create", &paths.state_dir))?;
+    let path = journal_path(paths);
+    let contents = serde_json::to_string_pretty(&journal).unwrap_or_default();
+    crate::file::replace_file(&path, |out| out.write_all(contents.as_bytes()))
+        .map_err(Error::io("write", &path))?;
+    Ok(())
+}
+
+/// Remove the journal once every file is written, or put back
+pub fn finish(paths: &Paths) -> Result<()> {
+    let path = journal_path(paths);
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::io("remove", &path)(e)),
+        _ => Ok(()),
+    }
+}
+
+/// Repair the files of a run interrupted after [`begin`], if any, and remove its journal. Must be
+/// called holding the lock of `paths`, so the run isn't still writing.
+pub fn repair(paths: &Paths) -> Result<Option<Repair>> {
+    let path = journal_path(paths);
+    let contents = match crate::file::read_file(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::io("read", &path)(e)),
+    };
+    let journal: Journal = serde_json::from_str(&contents)
+        .map_err(|e| Error::Invalid(format!("journal {}: {}", path.display(), e)))?;
+
+    for entry in &journal.files {
+        let _ = std::fs::remove_file(&entry.temp_file);
+    }
+
+    let written = journal.files.iter().all(|entry| {
+        crate::file::read_file(&entry.path)
+            .is_ok_and(|contents| crate::source::sha256_hex(contents.as_bytes()) == entry.sha256)
+    });
+    let repair = if written {
+        Repair::Completed
+    } else {
+        let previous: Vec<Planned> = journal
+            .files
+            .iter()
+            .map(|entry| Planned {
+                path: entry.path.clone(),
+                current: entry.previous.clone(),
+                new: String::new(),
+            })
+            .collect();
+        crate::review::restore(&previous)?;
+        Repair::RolledBack
+    };
+
+    let (config, intent) = (paths.config_file.display(), journal.intent.name());
+    let message = match (repair, &journal.backup) {
+        (Repair::Completed, _) => format!("Completed the interrupted {} of {}.", intent, config),
+        (Repair::RolledBack, Some(backup)) => format!(
+            "Put back {} as it was before an interrupted {}, also kept as {}.",
+            config,
+            intent,
+            backup.display()
+        ),
+        (Repair::RolledBack, None) => {
+            format!("Put back {} as it was before an interrupted {}.", config, intent)
+        }
+    };
+    crate::platform::warn_once(&path.to_string_lossy(), &message);
+
+    finish(paths)?;
+    Ok(Some(repair))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Get the files of a generation for a named network, an include file it creates and the SSH
+    /// config it changes
+    fn generation(paths: &Paths) -> Vec<Planned> {
+        let include_file = paths.ssh_dir.join("config.office");
+        vec![
+            Planned { path: include_file, current: String::new(), new: "Host b\n".to_string() },
+            Planned {
+                path: paths.config_file.clone(),
+                current: "Host a\n".to_string(),
+                new: "Include config.office\n".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn rolls_back_interrupted_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(dir.path(), &dir.path().join("state"));
+        std::fs::write(&paths.config_file, "Host a\n").unwrap();
+        let planned = generation(&paths);
+        let backup = dir.path().join("config.gz");
+        begin(&paths, Intent::Generate, Some(&backup), &planned).unwrap();
+
+        // Killed once the include file was written, while writing the SSH config.
+        std::fs::write(&planned[0].path, &planned[0].new).unwrap();
+        let temp_file = crate::file::temp_path(&paths.config_file);
+        std::fs::write(&temp_file, "Incl").unwrap();
+
+        assert_eq!(repair(&paths).unwrap(), Some(Repair::RolledBack));
+        assert_eq!(std::fs::read_to_string(&paths.config_file).unwrap(), "Host a\n");
+        assert!(!planned[0].path.exists());
+        assert!(!temp_file.exists());
+        assert!(!journal_path(&paths).exists());
+        assert_eq!(repair(&paths).unwrap(), None);
+    }
+
+    #[test]
+    fn keeps_runs_interrupted_once_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(dir.path(), &dir.path().join("state"));
+        std::fs::write(&paths.config_file, "Host a\n").unwrap();
+        let planned = generation(&paths);
+        begin(&paths, Intent::Generate, None, &planned).unwrap();
+        let journal: Journal =
+            serde_json::from_str(&std::fs::read_to_string(journal_path(&paths)).unwrap()).unwrap();
+        assert_eq!(journal.intent, Intent::Generate);
+        assert_eq!(journal.files[1].previous, "Host a\n");
+
+        for file in &planned {
+            std::fs::write(&file.path, &file.new).unwrap();
+        }
+        assert_eq!(repair(&paths).unwrap(), Some(Repair::Completed));
+        assert_eq!(std::fs::read_to_string(&paths.config_file).unwrap(), "Include config.office\n");
+        assert!(!journal_path(&paths).exists());
+
+        begin(&paths, Intent::Restore, None, &planned).unwrap();
+        finish(&paths).unwrap();
+        assert_eq!(repair(&paths).unwrap(), None);
+    }
+}
@@ -0,0 +1,194 @@
+//! # SSH Config Generator
+//!
+//! This library generates SSH client config based on user-defined rules. The pipeline is run by
+//! a [`generator::Generator`], which reports what each generation did:
+//!
+//! ```no_run
+//! use sshconfgen::generator::Generator;
+//! use sshconfgen::paths::Paths;
+//! use sshconfgen::provider::Providers;
+//!
+//! let mut generator = Generator::new(Paths::resolve().unwrap())
+//!     .with_providers(Providers::default())
+//!     .with_dry_run(true);
+//! let report = generator.generate().unwrap();
+//! println!("{}", report.config);
+//! ```
+//!
+//! [`generator::Generator::preview`] only evaluates the conditions, nothing is written. The
+//! network is detected through the [`provider`] traits, which tests and embedders can replace.
+//!
+//! Settings default to an empty `config.toml`, call [`load_settings`] to use the user's settings
+//! file.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub use error::{Error, Result};
+pub use sshconf::{parse_sections, Condition, Fragment, SECTIONS};
+
+mod adopt;
+mod age;
+mod arping;
+mod audit;
+mod backend;
+mod backup;
+mod cache;
+mod context;
+#[cfg(target_os = "linux")]
+mod dbus;
+mod engine;
+mod env_file;
+pub mod error;
+mod export;
+pub mod ffi;
+mod file;
+mod fingerprint;
+pub mod generator;
+mod hosts;
+mod hwaddr;
+mod import;
+mod install;
+mod journal;
+mod listen;
+mod migrate;
+mod monitor;
+mod native;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod netlink;
+mod network;
+mod openssh;
+pub mod paths;
+mod phases;
+mod ping;
+mod platform;
+mod plugin;
+mod powershell;
+mod profile;
+pub mod provider;
+mod region;
+mod review;
+mod roaming;
+mod routes;
+mod secret;
+mod sessions;
+mod settings;
+mod signature;
+mod smoke;
+mod source;
+mod sshconf;
+mod ssid;
+mod state;
+mod status;
+mod sync;
+mod template;
+mod terminal;
+mod update;
+mod vpn;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const CONFIG_EXTENSION: &str = "sshconf";
+
+pub const TOML_CONFIG_EXTENSION: &str = "sshconf.toml";
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// `println!` to the log target (stdout or stderr) if the verbose flag is set
+#[doc(hidden)]
+#[macro_export]
+macro_rules! verbose_println {
+    ($($arg:tt)*) => {
+        if is_verbose() {
+            $crate::print_verbose(format_args!($($arg)*));
+        }
+    };
+}
+
+/// Get whether progress is printed
+pub fn is_verbose() -> bool {
+    VERBOSE.load(Ordering::SeqCst)
+}
+
+/// Set whether progress is printed
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::SeqCst);
+}
+
+/// Set where progress is printed
+fn set_log_target(target: settings::LogTarget) {
+    LOG_TO_STDERR.store(target == settings::LogTarget::Stderr, Ordering::SeqCst);
+}
+
+/// The settings given on the command line, taking precedence over the settings file
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    /// The SSH config generated, relative to the current directory or starting with `~/`
+    pub ssh_config: Option<PathBuf>,
+    /// The directory of the fragments, likewise
+    pub config_dir: Option<PathBuf>,
+    /// Whether to print progress, also set by the `verbose` setting
+    pub verbose: bool,
+    /// Whether to print progress on stderr whatever the `log` setting, e.g. as the generated
+    /// config goes to stdout
+    pub log_to_stderr: bool,
+}
+
+/// The settings the program embedding the generation acts on itself, see [`load_settings_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preferences {
+    /// Whether to print how long each fragment and condition took
+    pub timings: bool,
+    /// The SSID polling interval of monitor mode, in seconds
+    pub monitor_interval: Option<u64>,
+    /// The line ending of the generated config
+    pub newline: &'static str,
+}
+
+/// Load the user's settings file, until then the default settings are used. Only the first call
+/// has an effect.
+pub fn load_settings() -> Result<()> {
+    settings::init(settings::load()?);
+    Ok(())
+}
+
+/// Load the user's settings file like [`load_settings`], with `overrides` applied, and set where
+/// progress is printed
+pub fn load_settings_with(overrides: &Overrides) -> Result<Preferences> {
+    let mut settings = settings::load()?;
+    if let Some(ssh_config) = &overrides.ssh_config {
+        settings.ssh_config = Some(absolute(ssh_config)?);
+    }
+    if let Some(config_dir) = &overrides.config_dir {
+        settings.config_dir = Some(absolute(config_dir)?);
+    }
+    set_verbose(overrides.verbose || settings.verbose);
+    let log = if overrides.log_to_stderr { settings::LogTarget::Stderr } else { settings.log };
+    set_log_target(log);
+
+    let preferences = Preferences {
+        timings: settings.timings,
+        monitor_interval: settings.monitor_interval,
+        newline: settings.newline.as_str(),
+    };
+    settings::init(settings);
+    Ok(preferences)
+}
+
+/// Get the absolute path of a path given on the command line, expanding a leading `~`
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let path = platform::expand_home(path);
+    std::path::absolute(&path).map_err(Error::io("resolve", &path))
+}
+
+/// Print a line of progress, used by [`verbose_println!`]
+#[doc(hidden)]
+pub fn print_verbose(args: std::fmt::Arguments) {
+    if LOG_TO_STDERR.load(Ordering::SeqCst) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
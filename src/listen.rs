@@ -0,0 +1,231 @@
+//! # Listen
+//!
+//! This module contains the HTTP listener of monitor mode, so local automation, or a browser
+//! extension, can trigger generations and query the state of a running sshconfgen:
+//!
+//! * `POST /trigger` requests a generation
+//! * `GET /status` returns what the worker is doing and the outcome of the last generation
+//! * `GET /fingerprint` detects the network and returns its fingerprint
+//!
+//! Only loopback addresses are served. Requests naming another host, as DNS rebinding does, or
+//! sent by web pages rather than extensions are refused.
+
+use crate::error::{Error, Result};
+use crate::monitor::{Monitor, ProfileChange, Trigger};
+use crate::provider::Providers;
+use crate::{is_verbose, verbose_println};
+use serde_json::json;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The largest request head read, requests have no body
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The origins of browser extensions, the only web origins served
+const EXTENSION_ORIGINS: &[&str] =
+    &["chrome-extension://", "moz-extension://", "safari-web-extension://"];
+
+/// The state of the monitor the listener reports
+#[derive(Clone)]
+pub struct Listener {
+    monitor: Monitor,
+    providers: Providers,
+    last_change: Arc<Mutex<Option<ProfileChange>>>,
+}
+
+/// An HTTP response
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Listener {
+    /// Create a listener for `monitor`, keeping the last profile change it reports
+    pub fn new(monitor: &Monitor, providers: &Providers) -> Listener {
+        let listener = Listener {
+            monitor: monitor.clone(),
+            providers: providers.clone(),
+            last_change: Arc::default(),
+        };
+
+        let last_change = listener.last_change.clone();
+        monitor.subscribe(move |change| *last_change.lock().unwrap() = Some(change.clone()));
+        listener
+    }
+
+    /// Serve `address` on a background thread, one request at a time. Returns the address
+    /// listened on, which has the port picked by the system if `address` had port 0.
+    pub fn spawn(self, address: SocketAddr) -> Result<SocketAddr> {
+        if !address.ip().is_loopback() {
+            return Err(Error::Invalid(format!(
+                "listen address: {}, it must be a loopback address",
+                address
+            )));
+        }
+
+        let server =
+            TcpListener::bind(address).map_err(Error::io("listen on", address.to_string()))?;
+        let address = server.local_addr().map_err(Error::io("listen on", address.to_string()))?;
+        verbose_println!("Listening on http://{}", address);
+
+        std::thread::spawn(move || {
+            for stream in server.incoming().filter_map(|stream| stream.ok()) {
+                if let Err(e) = self.serve(stream) {
+                    verbose_println!("Unable to answer an HTTP request: {}", e);
+                }
+            }
+        });
+
+        Ok(address)
+    }
+
+    /// Read a request from `stream` and answer it
+    fn serve(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n")
+            && request.len() < MAX_REQUEST_SIZE
+        {
+            let read = stream.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        let response = self.respond(&String::from_utf8_lossy(&request));
+        let reason = match response.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            _ => "Method Not Allowed",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            response.status,
+            reason,
+            response.body.len(),
+            response.body
+        )?;
+        stream.flush()
+    }
+
+    /// Answer the head of a request
+    pub fn respond(&self, request: &str) -> Response {
+        let error = |status, message: &str| Response {
+            status,
+            body: json!({ "error": message }).to_string(),
+        };
+
+        let mut lines = request.lines();
+        let request_line: Vec<&str> = lines.next().unwrap_or_default().split(' ').collect();
+        let [method, target, _version] = request_line[..] else {
+            return error(400, "malformed request");
+        };
+        let path = target.split('?').next().unwrap_or_default();
+
+        let header = |name: &str| {
+            lines.clone().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+            })
+        };
+        if !header("Host").is_some_and(is_local_host) {
+            return error(403, "the Host must be a loopback address or localhost");
+        }
+        if header("Origin").is_some_and(|origin| {
+            !EXTENSION_ORIGINS.iter().any(|prefix| origin.starts_with(prefix))
+        }) {
+            return error(403, "web pages are not allowed");
+        }
+
+        match (method, path) {
+            ("POST", "/trigger") => {
+                self.monitor.trigger(Trigger::Requested);
+                Response { status: 202, body: json!({ "triggered": true }).to_string() }
+            }
+            ("GET", "/status") => {
+                let last_change = self.last_change.lock().unwrap();
+                let last_change = last_change.as_ref();
+                let body = json!({
+                    "status": format!("{:?}", self.monitor.status()).to_lowercase(),
+                    "profile": last_change.and_then(|change| change.profile.as_ref()),
+                    "fingerprint": last_change.and_then(|change| change.fingerprint.as_ref()),
+                    "local": last_change.map(|change| &change.local),
+                });
+                Response { status: 200, body: body.to_string() }
+            }
+            ("GET", "/fingerprint") => {
+                let fingerprint = crate::fingerprint::detect(&self.providers);
+                Response {
+                    status: 200,
+                    body: serde_json::to_string(&fingerprint).unwrap_or_default(),
+                }
+            }
+            (_, "/trigger" | "/status" | "/fingerprint") => error(405, "method not allowed"),
+            _ => error(404, "not found"),
+        }
+    }
+}
+
+/// Check whether the `Host` header of a request names this machine
+fn is_local_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_local_requests() {
+        let monitor = Monitor::new();
+        let listener = Listener::new(&monitor, &Providers::default());
+        let request = |head: &str| listener.respond(&format!("{}\r\n\r\n", head));
+
+        assert_eq!(request("POST /trigger HTTP/1.1\r\nHost: 127.0.0.1:7422").status, 202);
+        let status = request(
+            "GET /status?pretty HTTP/1.1\r\nhost: localhost\r\nOrigin: moz-extension://abc",
+        );
+        assert_eq!(status.status, 200);
+        assert!(status.body.contains("\"status\":\"idle\""));
+
+        assert_eq!(request("GET /trigger HTTP/1.1\r\nHost: [::1]:7422").status, 405);
+        assert_eq!(request("GET /config HTTP/1.1\r\nHost: localhost").status, 404);
+        assert_eq!(request("GET /status HTTP/1.1\r\nHost: evil.example").status, 403);
+        assert_eq!(request("POST /trigger HTTP/1.1").status, 403);
+        assert_eq!(
+            request("POST /trigger HTTP/1.1\r\nHost: localhost\r\nOrigin: https://evil.example")
+                .status,
+            403
+        );
+        assert_eq!(request("garbage").status, 400);
+
+        let mut generations = Vec::new();
+        monitor
+            .run(|triggers| {
+                generations.push(triggers.clone());
+                monitor.stop();
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(generations, vec![std::collections::BTreeSet::from([Trigger::Requested])]);
+    }
+}
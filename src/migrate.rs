@@ -0,0 +1,456 @@
+//! # Migrate
+//!
+//! This module upgrades `.sshconf` fragments written for an older format, so changes to the
+//! syntax don't strand existing fragments. The format of a fragment is its `FormatVersion`
+//! metadata, and fragments without one are in format 1, the current format.
+//!
+//! A new format gets a step in [`STEPS`] rewriting the fragments of the previous one. Older
+//! formats are still read, `sshconfgen migrate` rewrites the fragments in place and keeps each
+//! original in the `migrations` directory of the state directory.
+//!
+//! Older versions also read the fragments from `~/.ssh/conf.d` rather than `~/.ssh/config.d`.
+//! Both are read, and `sshconfgen migrate-layout` moves the fragments of `conf.d` over.
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::sshconf::{get_key_value, section_marker, Fragment, SECTIONS};
+use crate::{is_verbose, verbose_println};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The format version of the fragments written by this version
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The metadata key holding the format version of a fragment
+pub const FORMAT_VERSION_KEY: &str = "FormatVersion";
+
+/// The steps upgrading the contents of a fragment, the first from format 1 to format 2
+const STEPS: [fn(&str) -> String; FORMAT_VERSION as usize - 1] = [];
+
+/// The directory of the state directory keeping the fragments as they were before a migration
+const BACKUP_DIR: &str = "migrations";
+
+/// A fragment rewritten by `sshconfgen migrate`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub path: PathBuf,
+    /// The format the fragment was in
+    pub from: u32,
+    /// The copy of the original fragment
+    pub backup: PathBuf,
+}
+
+/// The fragments moved by `sshconfgen migrate-layout`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayoutMigration {
+    /// The files and directories moved, from `conf.d` to `config.d`
+    pub moved: Vec<(PathBuf, PathBuf)>,
+    /// The files and directories left in `conf.d`, as `config.d` has one of the same name
+    pub conflicts: Vec<PathBuf>,
+    /// The symbolic link left in place of `conf.d`
+    pub link: Option<PathBuf>,
+}
+
+/// Get the format version of a fragment, 1 for fragments without one
+pub fn format_version(fragment: &Fragment) -> std::result::Result<u32, String> {
+    match fragment.metadata.get(FORMAT_VERSION_KEY) {
+        Some(version) => match version.parse() {
+            Ok(version) if version > 0 => Ok(version),
+            _ => Err(format!("{} {} is not a positive number", FORMAT_VERSION_KEY, version)),
+        },
+        None => Ok(1),
+    }
+}
+
+/// Report a fragment written for a newer sshconfgen, whose syntax may not be understood, or with
+/// an invalid format version. Older formats are only reported in verbose mode.
+pub fn check(fragment: &Fragment) {
+    let path = fragment.path.display();
+    match format_version(fragment) {
+        Ok(version) if version > FORMAT_VERSION => {
+            let message = format!(
+                "{} is in format {}, this sshconfgen reads up to {}.",
+                path, version, FORMAT_VERSION
+            );
+            crate::platform::warn_once(&fragment.path.to_string_lossy(), &message);
+        }
+        Ok(version) if version < FORMAT_VERSION => {
+            verbose_println!(
+                "{} is in format {}, run sshconfgen migrate to upgrade it",
+                path,
+                version
+            );
+        }
+        Ok(_) => {}
+        Err(e) => crate::platform::warn_once(
+            &fragment.path.to_string_lossy(),
+            &format!("{}: {}.", path, e),
+        ),
+    }
+}
+
+/// Upgrade the contents of a fragment in format `from` with `steps`, to the format following
+/// the last step
+fn upgrade(contents: &str, from: u32, steps: &[fn(&str) -> String]) -> String {
+    let remaining = steps.iter().skip(from.saturating_sub(1) as usize);
+    let contents = remaining.fold(contents.to_string(), |contents, step| step(&contents));
+    set_format_version(&contents, steps.len() as u32 + 1)
+}
+
+/// Rewrite the `.sshconf` fragments of `config.d` in an older format, keeping a copy of each in
+/// the state directory. The bundles of remote sources, replaced by the next sync, and signed
+/// fragments, whose signature would no longer match, are left alone.
+pub fn migrate(paths: &Paths) -> Result<Vec<Migration>> {
+    migrate_with(paths, &STEPS)
+}
+
+/// Rewrite the fragments of `config.d` older than the format following the last of `steps`
+fn migrate_with(paths: &Paths, steps: &[fn(&str) -> String]) -> Result<Vec<Migration>> {
+    let current = steps.len() as u32 + 1;
+    let _lock = paths.lock()?;
+    let sources_dir = paths.config_dir.join(crate::source::SOURCES_DIR);
+
+    let mut migrations = Vec::new();
+    for path in crate::sshconf::discover_layered(&paths.config_dir, &[]) {
+        if path.starts_with(&sources_dir)
+            || path.to_string_lossy().ends_with(crate::TOML_CONFIG_EXTENSION)
+        {
+            continue;
+        }
+
+        let contents = crate::file::read_file(&path).map_err(Error::io("read", &path))?;
+        let from = match format_version(&Fragment::parse(&path, &contents)) {
+            Ok(version) if version < current => version,
+            Ok(_) => continue,
+            Err(e) => {
+                crate::platform::warn_once(
+                    &path.to_string_lossy(),
+                    &format!("Skipping {}: {}.", path.display(), e),
+                );
+                continue;
+            }
+        };
+        if crate::signature::signature_path(&path).exists() {
+            let message = format!(
+                "Skipping {}, migrating it would invalidate its signature.",
+                path.display()
+            );
+            crate::platform::warn_once(&path.to_string_lossy(), &message);
+            continue;
+        }
+
+        let backup = backup_path(paths, &path, from);
+        if let Some(parent) = backup.parent() {
+            std::fs::create_dir_all(parent).map_err(Error::io("create", parent))?;
+        }
+        std::fs::write(&backup, &contents).map_err(Error::io("write", &backup))?;
+        let migrated = upgrade(&contents, from, steps);
+        crate::file::replace_file(&path, |out| out.write_all(migrated.as_bytes()))
+            .map_err(Error::io("write", &path))?;
+        verbose_println!("Migrated {} from format {}", path.display(), from);
+        migrations.push(Migration { path, from, backup });
+    }
+
+    Ok(migrations)
+}
+
+/// Move the contents of the legacy `conf.d` directory to `config.d`, renaming the directory
+/// itself when `config.d` doesn't exist. Entries whose name is taken in `config.d` are left in
+/// place. With `symlink`, the emptied `conf.d` is replaced by a link to `config.d`, for the tools
+/// still looking there.
+pub fn migrate_layout(paths: &Paths, symlink: bool) -> Result<LayoutMigration> {
+    let _lock = paths.lock()?;
+    let mut migration = LayoutMigration::default();
+    let Some(legacy_dir) = crate::paths::legacy_config_dir(&paths.config_dir) else {
+        return Ok(migration);
+    };
+    // A link left by an earlier migration is already in place.
+    if !std::fs::symlink_metadata(&legacy_dir).is_ok_and(|metadata| metadata.is_dir()) {
+        return Ok(migration);
+    }
+
+    if std::fs::symlink_metadata(&paths.config_dir).is_err() {
+        std::fs::rename(&legacy_dir, &paths.config_dir)
+            .map_err(Error::io("rename", &legacy_dir))?;
+        migration.moved.push((legacy_dir.clone(), paths.config_dir.clone()));
+    } else {
+        let entries = std::fs::read_dir(&legacy_dir).map_err(Error::io("read", &legacy_dir))?;
+        let mut entries: Vec<PathBuf> =
+            entries.filter_map(|entry| Some(entry.ok()?.path())).collect();
+        entries.sort();
+        for path in entries {
+            let target = paths.config_dir.join(path.file_name().unwrap_or_default());
+            if std::fs::symlink_metadata(&target).is_ok() {
+                migration.conflicts.push(path);
+                continue;
+            }
+            std::fs::rename(&path, &target).map_err(Error::io("rename", &path))?;
+            verbose_println!("Moved {} to {}", path.display(), target.display());
+            migration.moved.push((path, target));
+        }
+
+        if !migration.conflicts.is_empty() {
+            return Ok(migration);
+        }
+        std::fs::remove_dir(&legacy_dir).map_err(Error::io("remove", &legacy_dir))?;
+    }
+
+    if symlink {
+        // A relative link keeps working when the .ssh directory is moved or mounted elsewhere.
+        symlink_dir(Path::new(crate::paths::CONFIG_DIR), &legacy_dir)
+            .map_err(Error::io("link", &legacy_dir))?;
+        migration.link = Some(legacy_dir);
+    }
+
+    Ok(migration)
+}
+
+/// Create a symbolic link to the directory `target` at `link`
+#[cfg(unix)]
+fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink_dir(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn symlink_dir(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Get the copy of a fragment of `config.d` in format `version`,
+/// `<state dir>/migrations/<fragment>.v<version>.bak`
+fn backup_path(paths: &Paths, path: &Path, version: u32) -> PathBuf {
+    let relative = path.strip_prefix(&paths.config_dir).unwrap_or(path);
+    let mut name = paths.state_dir.join(BACKUP_DIR).join(relative).into_os_string();
+    name.push(format!(".v{}.bak", version));
+    PathBuf::from(name)
+}
+
+/// What to do with a line of the conditions section
+enum Rewrite {
+    Keep,
+    Remove,
+    Replace(String),
+}
+
+/// Rewrite the `Key value` lines of the conditions section with `rewrite`, given the key and
+/// value of the line and the keys of the section. Line endings are kept.
+fn rewrite_conditions(
+    contents: &str,
+    mut rewrite: impl FnMut(&str, &str, &[String]) -> Rewrite,
+) -> String {
+    let mut keys = Vec::new();
+    let mut in_conditions = false;
+    for line in contents.lines() {
+        match section_marker(line) {
+            Some((name, begin)) => in_conditions = begin && name == SECTIONS[0],
+            None if in_conditions => keys.push(get_key_value(line.trim()).0),
+            None => {}
+        }
+    }
+
+    let mut rewritten = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\r', '\n']);
+        match section_marker(body) {
+            Some((name, begin)) => in_conditions = begin && name == SECTIONS[0],
+            None if in_conditions => {
+                let (key, value) = get_key_value(body.trim());
+                match rewrite(&key, &value, &keys) {
+                    Rewrite::Keep => {}
+                    Rewrite::Remove => continue,
+                    Rewrite::Replace(replacement) => {
+                        rewritten.push_str(&replacement);
+                        rewritten.push_str(&line[line.trim_end_matches(['\r', '\n']).len()..]);
+                        continue;
+                    }
+                }
+            }
+            None => {}
+        }
+        rewritten.push_str(line);
+    }
+
+    rewritten
+}
+
+/// Set the `FormatVersion` of a fragment to `version`, adding a conditions section at the top of
+/// a fragment without one
+fn set_format_version(contents: &str, version: u32) -> String {
+    let line = format!("{} {}", FORMAT_VERSION_KEY, version);
+    let has_conditions =
+        contents.lines().any(|line| section_marker(line) == Some((SECTIONS[0], true)));
+    if !has_conditions {
+        let newline = if contents.contains("\r\n") { "\r\n" } else { "\n" };
+        let section = format!("# {0} BEGIN{1}{2}{1}# {0} END{1}{1}", SECTIONS[0], newline, line);
+        // Keep a byte order mark and the YAML front matter at the top.
+        let bom = if contents.starts_with('\u{feff}') { "\u{feff}" } else { "" };
+        let body = &contents[bom.len()..];
+        let (front_matter, body) = body.split_at(front_matter_len(body));
+        return format!("{}{}{}{}", bom, front_matter, section, body);
+    }
+
+    let mut seen = false;
+    let stamped = rewrite_conditions(contents, |key, _, _| match key {
+        FORMAT_VERSION_KEY if std::mem::replace(&mut seen, true) => Rewrite::Remove,
+        FORMAT_VERSION_KEY => Rewrite::Replace(line.clone()),
+        _ => Rewrite::Keep,
+    });
+    if seen {
+        return stamped;
+    }
+
+    // No FormatVersion line to replace, add one after the BEGIN marker.
+    let mut added = String::with_capacity(stamped.len() + line.len() + 2);
+    let mut done = false;
+    for segment in stamped.split_inclusive('\n') {
+        added.push_str(segment);
+        if !done
+            && section_marker(segment.trim_end_matches(['\r', '\n'])) == Some((SECTIONS[0], true))
+        {
+            let newline = if segment.ends_with("\r\n") { "\r\n" } else { "\n" };
+            added.push_str(&line);
+            added.push_str(newline);
+            done = true;
+        }
+    }
+    added
+}
+
+/// Get the length of the YAML front matter at the start of a fragment, with its end line
+fn front_matter_len(contents: &str) -> usize {
+    let mut lines = contents.split_inclusive('\n');
+    if lines.next().map(str::trim_end) != Some("---") {
+        return 0;
+    }
+
+    let mut offset = contents.find('\n').map_or(contents.len(), |end| end + 1);
+    for line in lines {
+        offset += line.len();
+        if matches!(line.trim_end(), "---" | "...") {
+            return offset;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A format 2 step for the tests, rewriting `Order` to `Priority` unless overridden by one
+    fn rename_order(contents: &str) -> String {
+        rewrite_conditions(contents, |key, value, keys| match key {
+            "Order" if keys.iter().any(|key| key == "Priority") => Rewrite::Remove,
+            "Order" => Rewrite::Replace(format!("Priority {}", value)),
+            _ => Rewrite::Keep,
+        })
+    }
+
+    #[test]
+    fn upgrades_fragments_to_the_current_format() {
+        let contents = "# CONDITIONS BEGIN\nLocalSSID home\nOrder -10\n# CONDITIONS END\n";
+        let fragment = Fragment::parse(Path::new("a.sshconf"), contents);
+        assert_eq!(format_version(&fragment), Ok(FORMAT_VERSION));
+        assert_eq!(fragment.priority(), -10);
+
+        let contents =
+            "# CONDITIONS BEGIN\r\nLocalSSID home\r\nOrder -10\r\n# CONDITIONS END\r\n\r\n\
+                        # GLOBAL CONFIG BEGIN\r\nHost a\r\n# GLOBAL CONFIG END\r\n";
+        let migrated = upgrade(contents, 1, &[rename_order]);
+        assert_eq!(
+            migrated,
+            "# CONDITIONS BEGIN\r\nFormatVersion 2\r\nLocalSSID home\r\nPriority -10\r\n\
+             # CONDITIONS END\r\n\r\n# GLOBAL CONFIG BEGIN\r\nHost a\r\n# GLOBAL CONFIG END\r\n"
+        );
+        let fragment = Fragment::parse(Path::new("a.sshconf"), &migrated);
+        assert_eq!(format_version(&fragment), Ok(2));
+        assert_eq!(fragment.priority(), -10);
+        assert_eq!(upgrade(&migrated, 2, &[rename_order]), migrated);
+
+        let overridden =
+            "# CONDITIONS BEGIN\nPriority 5\nOrder -10\nFormatVersion 1\n# CONDITIONS END\n";
+        let expected = "# CONDITIONS BEGIN\nPriority 5\nFormatVersion 2\n# CONDITIONS END\n";
+        assert_eq!(upgrade(overridden, 1, &[rename_order]), expected);
+
+        let global = "---\nOwner: ops\n---\n# GLOBAL CONFIG BEGIN\nHost a\n# GLOBAL CONFIG END\n";
+        let migrated = upgrade(global, 1, &[rename_order]);
+        let expected = "---\nOwner: ops\n---\n# CONDITIONS BEGIN\nFormatVersion 2\n\
+                        # CONDITIONS END\n\n# GLOBAL";
+        assert!(migrated.starts_with(expected), "{}", migrated);
+        let fragment = Fragment::parse(Path::new("b.sshconf"), &migrated);
+        assert!(fragment.conditions.is_empty());
+        assert_eq!(fragment.metadata.get("Owner").map(String::as_str), Some("ops"));
+    }
+
+    #[test]
+    fn rewrites_fragments_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(dir.path(), &dir.path().join("state"));
+        std::fs::create_dir_all(paths.config_dir.join(crate::source::SOURCES_DIR)).unwrap();
+        std::fs::create_dir_all(paths.config_dir.join("work")).unwrap();
+        let old = "# CONDITIONS BEGIN\nOrder 3\n# CONDITIONS END\n";
+        for name in ["a.sshconf", "work/b.sshconf", "sources/c.sshconf"] {
+            std::fs::write(paths.config_dir.join(name), old).unwrap();
+        }
+        assert!(migrate(&paths).unwrap().is_empty());
+
+        let current = upgrade(old, 1, &[rename_order]);
+        std::fs::write(paths.config_dir.join("d.sshconf"), &current).unwrap();
+        let migrations = migrate_with(&paths, &[rename_order]).unwrap();
+        let (a, b) = (paths.config_dir.join("a.sshconf"), paths.config_dir.join("work/b.sshconf"));
+        let backups = paths.state_dir.join(BACKUP_DIR);
+        assert_eq!(
+            migrations,
+            vec![
+                Migration { path: a.clone(), from: 1, backup: backups.join("a.sshconf.v1.bak") },
+                Migration { path: b, from: 1, backup: backups.join("work/b.sshconf.v1.bak") },
+            ]
+        );
+        assert_eq!(std::fs::read_to_string(&migrations[1].backup).unwrap(), old);
+        assert_eq!(std::fs::read_to_string(&a).unwrap(), current);
+        assert!(migrate_with(&paths, &[rename_order]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn moves_fragments_out_of_conf_d() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(dir.path(), &dir.path().join("state"));
+        let legacy_dir = dir.path().join("conf.d");
+        std::fs::create_dir_all(legacy_dir.join("work")).unwrap();
+        std::fs::write(legacy_dir.join("a.sshconf"), "Host a\n").unwrap();
+        std::fs::write(legacy_dir.join("work/b.sshconf"), "Host b\n").unwrap();
+        assert!(paths.check().is_ok());
+        assert_eq!(crate::sshconf::discover_fragments(&paths.config_dir).len(), 2);
+
+        let migration = migrate_layout(&paths, false).unwrap();
+        assert_eq!(migration.moved, vec![(legacy_dir.clone(), paths.config_dir.clone())]);
+        assert!(paths.config_dir.join("work/b.sshconf").is_file());
+        assert!(!legacy_dir.exists());
+        assert_eq!(migrate_layout(&paths, true).unwrap(), LayoutMigration::default());
+
+        std::fs::create_dir_all(&legacy_dir).unwrap();
+        std::fs::write(legacy_dir.join("a.sshconf"), "Host old\n").unwrap();
+        std::fs::write(legacy_dir.join("c.sshconf"), "Host c\n").unwrap();
+        let migration = migrate_layout(&paths, true).unwrap();
+        assert_eq!(
+            migration.moved,
+            vec![(legacy_dir.join("c.sshconf"), paths.config_dir.join("c.sshconf"))]
+        );
+        assert_eq!(migration.conflicts, vec![legacy_dir.join("a.sshconf")]);
+        assert_eq!(migration.link, None);
+
+        // Windows only lets privileged users create symbolic links.
+        if cfg!(unix) {
+            std::fs::remove_file(legacy_dir.join("a.sshconf")).unwrap();
+            let migration = migrate_layout(&paths, true).unwrap();
+            assert_eq!(migration.link, Some(legacy_dir.clone()));
+            assert_eq!(std::fs::read_link(&legacy_dir).unwrap(), Path::new("config.d"));
+            assert!(legacy_dir.join("c.sshconf").is_file());
+        }
+    }
+}
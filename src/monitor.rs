@@ -0,0 +1,272 @@
+//! # Monitor
+//!
+//! This module contains the generation loop of a long-running sshconfgen. Event sources trigger
+//! generations from any thread, a single worker runs them one at a time, and the triggers
+//! arriving during a generation are coalesced into a single follow-up generation. Subscribers
+//! are told when a generation changes the selected rules or sees a new network.
+
+use crate::error::{Error, Result};
+use crate::fingerprint::Fingerprint;
+use crate::generator::{Generator, Report};
+use crate::provider::Providers;
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A callback told about profile changes
+type Subscriber = Arc<dyn Fn(&ProfileChange) + Send + Sync>;
+
+/// Why a generation was requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Trigger {
+    /// The network changed, e.g. the connected SSIDs
+    Network,
+    /// A fragment was added, changed or removed
+    Fragments,
+    /// A generation was asked for explicitly
+    Requested,
+}
+
+/// What the worker of a monitor is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Waiting for a trigger
+    #[default]
+    Idle,
+    /// Running a generation, triggers are queued for the next one
+    Generating,
+    /// The last generation failed, e.g. on open sessions or a rolled back smoke test, waiting for
+    /// a trigger to try again
+    Failed,
+    /// Stopped, triggers are ignored
+    Stopped,
+}
+
+/// The outcome of a generation that selected other rules, or ran on another network, than the
+/// previous one. The first generation is always reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileChange {
+    /// Why the generation ran
+    pub triggers: BTreeSet<Trigger>,
+    /// The network the conditions were evaluated on, `None` without fragments
+    pub fingerprint: Option<Fingerprint>,
+    /// The active named network, or `local` or `remote` without named networks, `None` without
+    /// fragments
+    pub profile: Option<String>,
+    /// The fragments whose local rules are in use
+    pub local: BTreeSet<PathBuf>,
+    /// The fragments that switched to their local rules
+    pub activated: BTreeSet<PathBuf>,
+    /// The fragments that switched to their remote rules
+    pub deactivated: BTreeSet<PathBuf>,
+}
+
+/// The trigger queue of a generation worker
+///
+/// Clones share the same queue, so each event source can own one.
+#[derive(Clone, Default)]
+pub struct Monitor {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    queue: Mutex<Queue>,
+    wake: Condvar,
+    /// Held by the worker for as long as it runs, so generations never overlap
+    worker: Mutex<()>,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+#[derive(Default)]
+struct Queue {
+    pending: BTreeSet<Trigger>,
+    status: Status,
+}
+
+impl Monitor {
+    /// Create an idle monitor with no pending triggers
+    pub fn new() -> Monitor {
+        Monitor::default()
+    }
+
+    /// Request a generation, merged with the requests still pending. Ignored once stopped.
+    pub fn trigger(&self, trigger: Trigger) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.status != Status::Stopped {
+            queue.pending.insert(trigger);
+            self.shared.wake.notify_all();
+        }
+    }
+
+    /// Stop the worker once its current generation, if any, completes
+    pub fn stop(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.status = Status::Stopped;
+        queue.pending.clear();
+        self.shared.wake.notify_all();
+    }
+
+    /// Get what the worker is doing
+    pub fn status(&self) -> Status {
+        self.shared.queue.lock().unwrap().status
+    }
+
+    /// Serve the HTTP listener of the monitor on the loopback `address`, on a background thread,
+    /// to trigger generations and report the profile. Returns the address listened on, which has
+    /// the port picked by the system if `address` had port 0.
+    pub fn listen(&self, address: SocketAddr, providers: &Providers) -> Result<SocketAddr> {
+        crate::listen::Listener::new(self, providers).spawn(address)
+    }
+
+    /// Publish the monitor on the session bus until the process exits, on a background thread.
+    /// Only available on Linux.
+    pub fn publish(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        return crate::dbus::publish(self);
+        #[cfg(not(target_os = "linux"))]
+        return Err(Error::Unsupported("D-Bus"));
+    }
+
+    /// Call `callback` with each profile change reported by [`Monitor::run_generations`]
+    ///
+    /// Callbacks run on the worker thread once the config is written, the next generation waits
+    /// for them to return.
+    pub fn subscribe(&self, callback: impl Fn(&ProfileChange) + Send + Sync + 'static) {
+        self.shared.subscribers.lock().unwrap().push(Arc::new(callback));
+    }
+
+    /// Generate the SSH config with `generator` on the calling thread each time generations are
+    /// triggered, until the monitor is stopped, handing the report of each to `report` and
+    /// telling the subscribers about profile changes
+    ///
+    /// A generation refused because of open sessions, or rolled back by the smoke test, is
+    /// reported as a warning and leaves the monitor [`Status::Failed`] until the next trigger,
+    /// other errors stop it.
+    pub fn run_generations(
+        &self,
+        generator: &mut Generator,
+        mut report: impl FnMut(&Report),
+    ) -> Result<()> {
+        let mut previous: Option<(Option<Fingerprint>, Option<String>, BTreeSet<PathBuf>)> = None;
+
+        self.run(|triggers| {
+            match generator.generate_triggered(triggers) {
+                Ok(generated) => report(&generated),
+                // Open sessions and unreachable canary hosts are routine while roaming, the next
+                // trigger tries again.
+                Err(e @ (Error::ActiveSessions(_) | Error::SmokeTest(_))) => {
+                    eprintln!(
+                        "Warning: {}, the config will be generated again on the next change",
+                        e
+                    );
+                    self.shared.queue.lock().unwrap().status = Status::Failed;
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+
+            let cache = generator.cache();
+
+            let profile = cache.profile().map(str::to_string);
+            let current = (cache.fingerprint().cloned(), profile, cache.local_fragments().clone());
+            if previous.as_ref() == Some(&current) {
+                return Ok(());
+            }
+
+            let previous_local =
+                previous.replace(current.clone()).map(|(_, _, local)| local).unwrap_or_default();
+            let (fingerprint, profile, local) = current;
+            let change = ProfileChange {
+                triggers: triggers.clone(),
+                fingerprint,
+                profile,
+                activated: local.difference(&previous_local).cloned().collect(),
+                deactivated: previous_local.difference(&local).cloned().collect(),
+                local,
+            };
+
+            // Not called under the lock, so callbacks can subscribe or trigger.
+            let subscribers = self.shared.subscribers.lock().unwrap().clone();
+            for subscriber in subscribers {
+                subscriber(&change);
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Run `generate` on the calling thread each time generations are triggered, with the
+    /// triggers since the previous generation, until the monitor is stopped
+    ///
+    /// A failed generation stops the monitor and its error is returned.
+    pub fn run(&self, mut generate: impl FnMut(&BTreeSet<Trigger>) -> Result<()>) -> Result<()> {
+        let _worker = self.shared.worker.lock().unwrap();
+
+        loop {
+            let triggers = {
+                let mut queue = self.shared.queue.lock().unwrap();
+                while queue.pending.is_empty() && queue.status != Status::Stopped {
+                    queue = self.shared.wake.wait(queue).unwrap();
+                }
+
+                if queue.status == Status::Stopped {
+                    return Ok(());
+                }
+
+                queue.status = Status::Generating;
+                std::mem::take(&mut queue.pending)
+            };
+
+            let result = generate(&triggers);
+
+            let mut queue = self.shared.queue.lock().unwrap();
+            if let Err(e) = result {
+                queue.status = Status::Stopped;
+                return Err(e);
+            }
+
+            if queue.status == Status::Generating {
+                queue.status = Status::Idle;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coalesces_triggers_arriving_during_a_generation() {
+        let monitor = Monitor::new();
+        monitor.trigger(Trigger::Requested);
+
+        let mut generations = Vec::new();
+        monitor
+            .run(|triggers| {
+                generations.push(triggers.clone());
+                if generations.len() == 1 {
+                    let sources: Vec<_> = [Trigger::Network, Trigger::Fragments, Trigger::Network]
+                        .into_iter()
+                        .map(|trigger| {
+                            let monitor = monitor.clone();
+                            std::thread::spawn(move || monitor.trigger(trigger))
+                        })
+                        .collect();
+                    sources.into_iter().for_each(|source| source.join().unwrap());
+                    assert_eq!(monitor.status(), Status::Generating);
+                } else {
+                    monitor.stop();
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(generations.len(), 2);
+        assert_eq!(generations[0], BTreeSet::from([Trigger::Requested]));
+        assert_eq!(generations[1], BTreeSet::from([Trigger::Network, Trigger::Fragments]));
+        assert_eq!(monitor.status(), Status::Stopped);
+    }
+}
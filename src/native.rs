@@ -0,0 +1,202 @@
+//! # Native
+//!
+//! This module contains pure Rust detection backends that don't rely on external commands, for
+//! minimal or fully static installations.
+
+use crate::error::{Error, Result};
+use crate::ssid::Connection;
+use std::time::Duration;
+
+/// Whether the native SSID backend is available on this platform
+pub const SSID_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Whether the native neighbor table backend is available on this platform
+pub const NEIGHBOR_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+/// Whether the native ping backend is available on this platform
+pub const PING_SUPPORTED: bool =
+    cfg!(any(target_os = "linux", target_os = "android", target_os = "macos"));
+
+/// Get every connected wireless interface along with its SSID using nl80211
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_connections() -> Result<Vec<Connection>> {
+    nl80211::get_interfaces()
+}
+
+/// Get every connected wireless interface along with its SSID using nl80211
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_connections() -> Result<Vec<Connection>> {
+    Err(Error::Unsupported("The native SSID backend"))
+}
+
+/// Get the hardware address of a device given its IP address from `/proc/net/arp`
+pub fn get_hw_address(ip_address: &str) -> Result<String> {
+    if !NEIGHBOR_SUPPORTED {
+        return Err(Error::Unsupported("The native neighbor backend"));
+    }
+
+    // "IP address  HW type  Flags  HW address  Mask  Device", incomplete entries have flags 0x0.
+    let table =
+        std::fs::read_to_string("/proc/net/arp").map_err(Error::io("read", "/proc/net/arp"))?;
+    table
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 4 && fields[0] == ip_address && fields[2] != "0x0")
+        .map(|fields| fields[3].to_string())
+        .ok_or(Error::NotFound("MAC address"))
+}
+
+/// Get whether a host answers an ICMP echo request within `timeout`
+///
+/// Uses unprivileged ICMP datagram sockets, which Linux only allows for groups listed in the
+/// `net.ipv4.ping_group_range` sysctl.
+#[cfg(unix)]
+pub fn get_pingable(host: &str, timeout: Duration) -> Result<bool> {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::net::{ToSocketAddrs, UdpSocket};
+    use std::time::Instant;
+
+    if !PING_SUPPORTED {
+        return Err(Error::Unsupported("The native ping backend"));
+    }
+
+    let address = (host, 0)
+        .to_socket_addrs()
+        .map_err(|_| Error::Detection("Unable to resolve host"))?
+        .next()
+        .ok_or(Error::Detection("Unable to resolve host"))?;
+
+    let (domain, protocol, request, reply) = if address.is_ipv4() {
+        (Domain::IPV4, Protocol::ICMPV4, 8, 0)
+    } else {
+        (Domain::IPV6, Protocol::ICMPV6, 128, 129)
+    };
+
+    let socket = Socket::new(domain, Type::DGRAM, Some(protocol))
+        .map_err(|_| Error::Detection("Unprivileged ICMP sockets are not permitted"))?;
+    let socket: UdpSocket = socket.into();
+
+    // Type, code, checksum, identifier, sequence number, payload. The kernel replaces the
+    // identifier with the socket's port, so replies are recognized by the sequence number.
+    let sequence = std::process::id() as u16;
+    let mut packet = vec![request, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(b"sshconfgen");
+    let checksum = icmp_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    socket
+        .send_to(&packet, address)
+        .map_err(|_| Error::Detection("Unable to send ICMP echo request"))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0u8; 1500];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+            break;
+        }
+
+        let Ok(len) = socket.recv(&mut buffer) else {
+            break;
+        };
+
+        // macOS includes the IPv4 header in datagram replies, Linux does not.
+        let mut icmp = &buffer[..len];
+        if address.is_ipv4() && icmp.first().is_some_and(|byte| byte >> 4 == 4) {
+            icmp = icmp.get(usize::from(icmp[0] & 0x0f) * 4..).unwrap_or_default();
+        }
+
+        if icmp.len() >= 8 && icmp[0] == reply && icmp[6..8] == sequence.to_be_bytes() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Get whether a host answers an ICMP echo request within `timeout`
+#[cfg(not(unix))]
+pub fn get_pingable(_host: &str, _timeout: Duration) -> Result<bool> {
+    Err(Error::Unsupported("The native ping backend"))
+}
+
+/// Compute the internet checksum of an ICMP message
+#[cfg(unix)]
+fn icmp_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum();
+
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+/// A minimal nl80211 client for the interface list
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod nl80211 {
+    use crate::error::{Error, Result};
+    use crate::netlink::{self, NETLINK_GENERIC, NLM_F_DUMP, NLM_F_REQUEST};
+    use crate::ssid::Connection;
+    use std::os::fd::OwnedFd;
+
+    const GENL_ID_CTRL: u16 = 0x10;
+    const CTRL_CMD_GETFAMILY: u8 = 3;
+    const CTRL_ATTR_FAMILY_ID: u16 = 1;
+    const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+    const NL80211_CMD_GET_INTERFACE: u8 = 5;
+    const NL80211_ATTR_IFNAME: u16 = 4;
+    const NL80211_ATTR_SSID: u16 = 52;
+    const GENL_HEADER_LEN: usize = 4;
+
+    /// Get the connected wireless interfaces from a nl80211 interface dump
+    pub fn get_interfaces() -> Result<Vec<Connection>> {
+        let socket = netlink::open(NETLINK_GENERIC)?;
+        let family = get_family_id(&socket)?;
+
+        netlink::send(
+            &socket,
+            family,
+            NLM_F_REQUEST | NLM_F_DUMP,
+            &[NL80211_CMD_GET_INTERFACE, 0, 0, 0],
+            &[],
+        )?;
+
+        let mut connections = Vec::new();
+        for message in netlink::receive(&socket, GENL_HEADER_LEN)? {
+            let attributes = &message.attributes;
+            let interface = attributes.iter().find(|(kind, _)| *kind == NL80211_ATTR_IFNAME);
+            let ssid = attributes.iter().find(|(kind, _)| *kind == NL80211_ATTR_SSID);
+            if let (Some((_, interface)), Some((_, ssid))) = (interface, ssid) {
+                connections.push(Connection {
+                    interface: String::from_utf8_lossy(interface)
+                        .trim_end_matches('\0')
+                        .to_string(),
+                    ssid: String::from_utf8_lossy(ssid).to_string(),
+                });
+            }
+        }
+
+        connections.sort_by(|a, b| a.interface.cmp(&b.interface));
+        Ok(connections)
+    }
+
+    /// Resolve the dynamic generic netlink family id of nl80211
+    fn get_family_id(socket: &OwnedFd) -> Result<u16> {
+        let name = netlink::attribute(CTRL_ATTR_FAMILY_NAME, b"nl80211\0");
+        netlink::send(socket, GENL_ID_CTRL, NLM_F_REQUEST, &[CTRL_CMD_GETFAMILY, 1, 0, 0], &name)?;
+
+        // The controller answers with an error when no wireless driver registered the family.
+        netlink::receive(socket, GENL_HEADER_LEN)
+            .map_err(|_| Error::Unsupported("nl80211"))?
+            .iter()
+            .flat_map(|message| &message.attributes)
+            .find(|(kind, value)| *kind == CTRL_ATTR_FAMILY_ID && value.len() >= 2)
+            .map(|(_, value)| u16::from_ne_bytes([value[0], value[1]]))
+            .ok_or(Error::Unsupported("nl80211"))
+    }
+}
@@ -0,0 +1,166 @@
+//! # Netlink
+//!
+//! This module contains a minimal netlink client shared by the nl80211 (generic netlink) and
+//! rtnetlink readers, so neither needs external tools.
+
+use crate::error::{Error, Result};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+pub const NETLINK_ROUTE: i32 = 0;
+pub const NETLINK_GENERIC: i32 = 16;
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_MULTI: u16 = 0x2;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NLMSG_HEADER_LEN: usize = 16;
+
+/// The `(type, value)` attributes of a netlink message
+pub type Attributes = Vec<(u16, Vec<u8>)>;
+
+/// A reply message, split into its family-specific header and its attributes
+pub struct Message {
+    pub header: Vec<u8>,
+    pub attributes: Attributes,
+}
+
+/// Open and bind a netlink socket of the given protocol
+pub fn open(protocol: i32) -> Result<OwnedFd> {
+    // SAFETY: plain socket syscalls, the descriptor is owned by the returned OwnedFd.
+    unsafe {
+        let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, protocol);
+        if fd < 0 {
+            return Err(Error::Detection("Unable to open a netlink socket"));
+        }
+
+        let socket = OwnedFd::from_raw_fd(fd);
+        let mut address: libc::sockaddr_nl = std::mem::zeroed();
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let bound = libc::bind(
+            fd,
+            &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        );
+
+        let timeout = libc::timeval { tv_sec: 2, tv_usec: 0 };
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+        );
+
+        if bound < 0 {
+            return Err(Error::Detection("Unable to bind the netlink socket"));
+        }
+
+        Ok(socket)
+    }
+}
+
+/// Encode a netlink attribute, padded to four bytes
+pub fn attribute(kind: u16, value: &[u8]) -> Vec<u8> {
+    let mut attribute = Vec::new();
+    attribute.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+    attribute.extend_from_slice(&kind.to_ne_bytes());
+    attribute.extend_from_slice(value);
+    attribute.resize(align(attribute.len()), 0);
+    attribute
+}
+
+/// Send a netlink request made of a family-specific header followed by attributes
+pub fn send(socket: &OwnedFd, kind: u16, flags: u16, header: &[u8], payload: &[u8]) -> Result<()> {
+    let len = NLMSG_HEADER_LEN + header.len() + payload.len();
+    let mut message = Vec::with_capacity(len);
+    message.extend_from_slice(&(len as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&1u32.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(header);
+    message.extend_from_slice(payload);
+
+    // SAFETY: the buffer is valid for its length for the duration of the call.
+    let sent = unsafe { libc::send(socket.as_raw_fd(), message.as_ptr().cast(), message.len(), 0) };
+    if sent < 0 {
+        return Err(Error::Detection("Unable to send the netlink request"));
+    }
+
+    Ok(())
+}
+
+/// Receive the replies to a request, whose family-specific headers are `header_len` bytes long
+pub fn receive(socket: &OwnedFd, header_len: usize) -> Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    let mut buffer = vec![0u8; 32768];
+
+    loop {
+        // SAFETY: the buffer is valid for writes of its length for the duration of the call.
+        let len =
+            unsafe { libc::recv(socket.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), 0) };
+        if len < 0 {
+            return Err(Error::Detection("Unable to receive the netlink reply"));
+        }
+
+        let mut data = &buffer[..len as usize];
+        while data.len() >= NLMSG_HEADER_LEN {
+            let message_len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let kind = u16::from_ne_bytes([data[4], data[5]]);
+            let flags = u16::from_ne_bytes([data[6], data[7]]);
+            if message_len < NLMSG_HEADER_LEN || message_len > data.len() {
+                return Err(Error::Parse("netlink"));
+            }
+
+            match kind {
+                NLMSG_DONE => return Ok(messages),
+                NLMSG_ERROR => {
+                    let error = data
+                        .get(16..20)
+                        .map_or(0, |e| i32::from_ne_bytes([e[0], e[1], e[2], e[3]]));
+                    if error != 0 {
+                        return Err(Error::Detection("The kernel rejected the netlink request"));
+                    }
+                    return Ok(messages);
+                }
+                _ => {
+                    let body = &data[NLMSG_HEADER_LEN..message_len];
+                    let header_len = header_len.min(body.len());
+                    messages.push(Message {
+                        header: body[..header_len].to_vec(),
+                        attributes: parse_attributes(&body[header_len..]),
+                    });
+                }
+            }
+
+            // Replies that aren't part of a dump consist of a single message.
+            if flags & NLM_F_MULTI == 0 {
+                return Ok(messages);
+            }
+
+            data = &data[align(message_len).min(data.len())..];
+        }
+    }
+}
+
+/// Parse the netlink attributes of a message payload
+fn parse_attributes(mut data: &[u8]) -> Attributes {
+    let mut attributes = Vec::new();
+    while data.len() >= 4 {
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & 0x3fff;
+        if len < 4 || len > data.len() {
+            break;
+        }
+
+        attributes.push((kind, data[4..len].to_vec()));
+        data = &data[align(len).min(data.len())..];
+    }
+
+    attributes
+}
+
+/// Round a length up to the netlink alignment of four bytes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
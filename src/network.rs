@@ -0,0 +1,240 @@
+//! # Network
+//!
+//! This module contains the named networks of the settings, e.g. `office` and `home`. When some
+//! are set, the config generated on each network is kept in its own include file next to the SSH
+//! config, e.g. `config.office`, and the SSH config itself only holds an `Include` of the file of
+//! the active network. Switching networks then rewrites that one line, and going back to the
+//! config of another network is a matter of editing it.
+
+use crate::context::Context;
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::provider::Providers;
+use crate::sshconf::{Condition, CONDITION_KEYS};
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The network active when none of the named networks match
+pub const FALLBACK_NETWORK: &str = "remote";
+
+/// A named network, given as a `[[network]]` table
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    /// The name of the network, which is the extension of its include file
+    pub name: String,
+    /// The conditions recognizing the network, valued as in `.sshconf.toml` fragments. The
+    /// network is active if any of them matches.
+    pub conditions: BTreeMap<String, toml::Value>,
+}
+
+impl Network {
+    /// Get the conditions of the network in the marker format
+    pub fn conditions(&self) -> std::result::Result<Vec<Condition>, String> {
+        self.conditions
+            .iter()
+            .map(|(key, value)| {
+                let value = crate::sshconf::condition_value(key, value)?;
+                Ok(Condition { key: key.clone(), value })
+            })
+            .collect()
+    }
+
+    /// Check that the network has a usable name and conditions
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let valid_name =
+            self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if self.name.is_empty() || !valid_name || self.name == FALLBACK_NETWORK {
+            return Err(format!(
+                "network {:?} needs a name of letters, digits, - and _ other than remote",
+                self.name
+            ));
+        }
+
+        if self.conditions.is_empty() {
+            return Err(format!("network {} needs conditions", self.name));
+        }
+
+        if let Some(key) =
+            self.conditions.keys().find(|key| !CONDITION_KEYS.contains(&key.as_str()))
+        {
+            return Err(format!("network {}: {} is not a condition", self.name, key));
+        }
+
+        self.conditions().map(|_| ()).map_err(|e| format!("network {}: {}", self.name, e))
+    }
+}
+
+/// Get the name of the first network whose conditions match, or [`FALLBACK_NETWORK`]
+pub fn active(networks: &[Network], context: &Context) -> String {
+    let source = crate::settings::settings_path().unwrap_or_default();
+    let network = networks.iter().find(|network| {
+        // The conditions were validated along with the settings.
+        let conditions = network.conditions().unwrap_or_default();
+        crate::sshconf::local_rules_match(context, &source, &conditions)
+    });
+
+    network.map_or(FALLBACK_NETWORK, |network| &network.name).to_string()
+}
+
+/// Check whether `name` is the active network, for `Match exec` lines. Only the networks up to it
+/// are evaluated, reusing the probe results kept in the state file, and nothing is written.
+pub fn is_active(
+    name: &str,
+    networks: &[Network],
+    paths: &Paths,
+    providers: &Providers,
+) -> Result<bool> {
+    let networks = match networks.iter().position(|network| network.name == name) {
+        Some(position) => &networks[..=position],
+        None if name == FALLBACK_NETWORK => networks,
+        None => {
+            return Err(Error::Invalid(format!("network: {}, it is not in the settings", name)))
+        }
+    };
+
+    let context = match crate::state::load(&paths.state_dir) {
+        Some(state) => Context::with_state(providers, state),
+        None => Context::new(providers),
+    };
+    Ok(active(networks, &context) == name)
+}
+
+/// Get the include file of a network, named after the SSH config
+pub fn include_file(ssh_config_file: &Path, network: &str) -> PathBuf {
+    let file_name = ssh_config_file.file_name().unwrap_or_default().to_string_lossy();
+    ssh_config_file.with_file_name(format!("{}.{}", file_name, network))
+}
+
+/// Get the SSH config including the config of a network
+pub fn switcher(include_file: &Path, network: &str) -> String {
+    // OpenSSH reads forward slashes on Windows too, and doesn't take backslashes literally.
+    let path = include_file.to_string_lossy().replace('\\', "/");
+    let newline = crate::settings::get().newline.as_str();
+    format!(
+        "# Generated by sshconfgen for the {network} network{newline}Include \"{path}\"{newline}"
+    )
+}
+
+/// Get the include file of an SSH config written by [`switcher`]
+pub fn included_file(ssh_config: &str) -> Option<PathBuf> {
+    let ssh_config = crate::region::body(ssh_config).unwrap_or(ssh_config);
+    let mut lines = ssh_config.lines();
+    lines.next()?.strip_prefix("# Generated by sshconfgen for the ")?;
+    let path = lines.next()?.strip_prefix("Include \"")?.strip_suffix('"')?;
+    Some(PathBuf::from(path))
+}
+
+/// Read the SSH config in use: the include file of the active network when the SSH config is a
+/// switcher, or the SSH config itself. Returns the file read and its contents.
+pub fn read_active(ssh_config_file: &Path) -> Result<(PathBuf, String)> {
+    let contents =
+        crate::file::read_file(ssh_config_file).map_err(Error::io("read", ssh_config_file))?;
+    match included_file(&contents) {
+        Some(include_file) => {
+            let contents =
+                crate::file::read_file(&include_file).map_err(Error::io("read", &include_file))?;
+            Ok((include_file, contents))
+        }
+        None => Ok((ssh_config_file.to_path_buf(), contents)),
+    }
+}
+
+/// Write `ssh_config`, generated on `network`, to the include file of the network, and point the
+/// SSH config, or its managed region, at it. Files whose contents are unchanged are not rewritten.
+/// An empty config leaves both files untouched.
+pub fn write_configs(ssh_config_file: &Path, network: &str, ssh_config: &str) -> Result<()> {
+    if ssh_config.is_empty() {
+        verbose_println!("The new config is empty, keeping {}", ssh_config_file.display());
+        return Ok(());
+    }
+
+    verbose_println!("Active network: {}", network);
+    let include_file = include_file(ssh_config_file, network);
+    let newline = crate::settings::get().newline.as_str();
+    crate::file::write_if_changed(&include_file, &format!("{}{}", ssh_config, newline))?;
+    let current = crate::file::read_file(ssh_config_file).unwrap_or_default();
+    let switcher =
+        crate::region::splice(ssh_config_file, &current, &switcher(&include_file, network))?;
+    crate::file::write_if_changed(ssh_config_file, &switcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{Providers, SsidProvider};
+    use crate::ssid::Connection;
+    use std::sync::Arc;
+
+    struct Office;
+
+    impl SsidProvider for Office {
+        fn connections(&self) -> Result<Vec<Connection>> {
+            Ok(vec![Connection { interface: "wlan0".to_string(), ssid: "corp".to_string() }])
+        }
+    }
+
+    fn network(name: &str, conditions: &str) -> Network {
+        Network { name: name.to_string(), conditions: toml::from_str(conditions).unwrap() }
+    }
+
+    #[test]
+    fn selects_the_first_matching_network() {
+        let home = network("home", "LocalSSID = \"homenet\"");
+        let office = network("office", "LocalSSID = [\"guest\", \"corp\"]");
+        let context = Context::new(&Providers { ssid: Arc::new(Office), ..Providers::default() });
+
+        assert_eq!(active(&[home.clone(), office.clone()], &context), "office");
+        assert_eq!(active(&[home], &context), FALLBACK_NETWORK);
+
+        assert!(office.validate().is_ok());
+        for invalid in [
+            network("remote", "LocalSSID = \"a\""),
+            network("a/b", "LocalSSID = \"a\""),
+            network("a", ""),
+        ] {
+            assert!(invalid.validate().is_err(), "{}", invalid.name);
+        }
+        assert!(network("a", "Priority = \"1\"").validate().is_err());
+
+        let providers = Providers { ssid: Arc::new(Office), ..Providers::default() };
+        let networks =
+            [network("office", "LocalSSID = \"corp\""), network("home", "LocalSSID = \"homenet\"")];
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(&dir.path().join(".ssh"), &dir.path().join("state"));
+        assert!(is_active("office", &networks, &paths, &providers).unwrap());
+        assert!(!is_active("home", &networks, &paths, &providers).unwrap());
+        assert!(!is_active(FALLBACK_NETWORK, &networks, &paths, &providers).unwrap());
+        let lab = is_active("lab", &networks, &paths, &providers);
+        assert!(matches!(lab, Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn switches_between_include_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        let newline = crate::settings::get().newline.as_str();
+
+        write_configs(&config, "office", "Host a\n").unwrap();
+        write_configs(&config, "home", "Host b\n").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(include_file(&config, "office")).unwrap(),
+            format!("Host a\n{}", newline)
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("config.home")).unwrap(),
+            format!("Host b\n{}", newline)
+        );
+        assert!(std::fs::read_to_string(&config).unwrap().contains("config.home\""));
+        let switcher = std::fs::read_to_string(&config).unwrap();
+        assert!(included_file(&switcher).is_some_and(|path| path.ends_with("config.home")));
+        assert_eq!(included_file("Host a\n"), None);
+
+        write_configs(&config, "office", "").unwrap();
+        assert!(std::fs::read_to_string(&config).unwrap().contains("config.home\""));
+        write_configs(&config, "office", "Host a\n").unwrap();
+        assert!(std::fs::read_to_string(&config).unwrap().contains("config.office\""));
+    }
+}
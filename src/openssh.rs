@@ -0,0 +1,224 @@
+//! # OpenSSH
+//!
+//! This module checks the generated config against the OpenSSH client it is written for. The
+//! client version is detected with `ssh -V`, or set in the settings when the config is used on
+//! another machine. Directives the client is older than are warned about, or left out with
+//! `unsupported = "strip"`, since a single unknown directive makes `ssh` refuse the whole config.
+//!
+//! Fragments can also give variants of a section for newer clients, tagged with the version they
+//! need, e.g. `# LOCAL CONFIG BEGIN (openssh >= 8.5)`. Variants the client is too old for are
+//! dropped when the fragment is read, so the first remaining one is used, as for any repeated
+//! section, and an untagged variant placed last is the fallback.
+
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+/// The directives that are not understood by every OpenSSH client still in use, with the
+/// version that introduced them
+const DIRECTIVES: &[(&str, Version)] = &[
+    ("IgnoreUnknown", Version::new(6, 3)),
+    ("CanonicalDomains", Version::new(6, 5)),
+    ("CanonicalizeFallbackLocal", Version::new(6, 5)),
+    ("CanonicalizeHostname", Version::new(6, 5)),
+    ("CanonicalizeMaxDots", Version::new(6, 5)),
+    ("CanonicalizePermittedCNAMEs", Version::new(6, 5)),
+    ("AddKeysToAgent", Version::new(7, 2)),
+    ("Include", Version::new(7, 3)),
+    ("ProxyJump", Version::new(7, 3)),
+    ("RemoteCommand", Version::new(7, 6)),
+    ("SetEnv", Version::new(7, 8)),
+    ("SecurityKeyProvider", Version::new(8, 2)),
+    ("KnownHostsCommand", Version::new(8, 5)),
+    ("PubkeyAcceptedAlgorithms", Version::new(8, 5)),
+    ("ForkAfterAuthentication", Version::new(8, 7)),
+    ("SessionType", Version::new(8, 7)),
+    ("StdinNull", Version::new(8, 7)),
+    ("RequiredRSASize", Version::new(9, 1)),
+    ("ChannelTimeout", Version::new(9, 2)),
+    ("EnableEscapeCommandline", Version::new(9, 2)),
+    ("ObscureKeystrokeTiming", Version::new(9, 5)),
+];
+
+/// The detected client version, `None` if `ssh -V` failed
+static CLIENT_VERSION: OnceLock<Option<Version>> = OnceLock::new();
+
+/// An OpenSSH version, e.g. `8.5`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32) -> Version {
+        Version { major, minor }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    /// Parse a `major.minor` version, ignoring a portable release suffix such as `p1`
+    fn from_str(version: &str) -> Result<Version, String> {
+        let invalid = || format!("{} is not an OpenSSH version such as 8.5", version);
+        let (major, minor) = version.split_once('.').ok_or_else(invalid)?;
+        let minor = minor.split(|c: char| !c.is_ascii_digit()).next().unwrap_or_default();
+        Ok(Version::new(
+            major.parse().map_err(|_| invalid())?,
+            minor.parse().map_err(|_| invalid())?,
+        ))
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = String;
+
+    fn try_from(version: String) -> Result<Version, String> {
+        version.parse()
+    }
+}
+
+/// What happens to the directives the client is too old for
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unsupported {
+    /// Keep them, warning once about each
+    #[default]
+    Warn,
+    /// Leave them out of the generated config
+    Strip,
+    /// Keep them without checking, `ssh -V` is not run
+    Keep,
+}
+
+/// How the generated config is checked against the OpenSSH client
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpensshSettings {
+    /// What happens to the directives the client is too old for
+    pub unsupported: Unsupported,
+    /// The version of the client, detected with `ssh -V` by default
+    pub version: Option<Version>,
+}
+
+/// Parse the output of `ssh -V`, e.g. `OpenSSH_9.6p1 Ubuntu-3ubuntu13, OpenSSL 3.0.13` or
+/// `OpenSSH_for_Windows_8.1p1, LibreSSL 3.0.2`
+pub fn parse_version(output: &str) -> Option<Version> {
+    let release = output.split_once("OpenSSH_")?.1;
+    let release = release.strip_prefix("for_Windows_").unwrap_or(release);
+    release.split([',', ' ']).next()?.parse().ok()
+}
+
+/// Get the version of the OpenSSH client, as set in the settings or detected with `ssh -V`
+pub fn client_version() -> Option<Version> {
+    if let Some(version) = crate::settings::get().openssh.version {
+        return Some(version);
+    }
+
+    *CLIENT_VERSION.get_or_init(|| {
+        // ssh -V prints to stderr.
+        let output = crate::platform::command("ssh").arg("-V").output().ok()?;
+        let version = parse_version(&String::from_utf8_lossy(&output.stderr))
+            .or_else(|| parse_version(&String::from_utf8_lossy(&output.stdout)));
+        match version {
+            Some(version) => verbose_println!("OpenSSH client version: {}", version),
+            None => verbose_println!("Unable to detect the OpenSSH client version"),
+        }
+        version
+    })
+}
+
+/// Check whether the client is at least `version`. An undetected client is not.
+pub fn supports(version: Version) -> bool {
+    client_version().is_some_and(|client| client >= version)
+}
+
+/// Get the version a line of SSH config needs, if it is newer than the oldest clients
+fn required_version(line: &str) -> Option<(&str, Version)> {
+    let keyword = line.trim_start().split(|c: char| c.is_whitespace() || c == '=').next()?;
+    DIRECTIVES
+        .iter()
+        .find(|(directive, _)| directive.eq_ignore_ascii_case(keyword))
+        .map(|(_, version)| (keyword, *version))
+}
+
+/// Check the rules of a section read from `source` against the client, as the settings say
+pub fn filter(rules: &str, source: &Path) -> String {
+    let unsupported = crate::settings::get().openssh.unsupported;
+    match client_version() {
+        Some(client) if unsupported != Unsupported::Keep => {
+            filter_for(rules, source, client, unsupported)
+        }
+        _ => rules.to_string(),
+    }
+}
+
+/// Check the rules of a section read from `source` against the `client` version, warning about
+/// or leaving out the lines it is too old for
+pub fn filter_for(rules: &str, source: &Path, client: Version, unsupported: Unsupported) -> String {
+    let lines = rules.lines().filter(|line| {
+        let Some((keyword, required)) =
+            required_version(line).filter(|(_, required)| client < *required)
+        else {
+            return true;
+        };
+
+        let source = source.display();
+        let message = format!(
+            "{} in {} needs OpenSSH {}, the client is {}",
+            keyword, source, required, client
+        );
+        if unsupported == Unsupported::Strip {
+            verbose_println!("Leaving out {}", message);
+            false
+        } else {
+            crate::platform::warn_once(&format!("{} {}", source, keyword), &message);
+            true
+        }
+    });
+
+    lines.collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_directives_of_newer_clients() {
+        assert_eq!(
+            parse_version("OpenSSH_9.6p1 Ubuntu-3ubuntu13, OpenSSL 3.0.13"),
+            Some(Version::new(9, 6))
+        );
+        assert_eq!(
+            parse_version("OpenSSH_for_Windows_8.1p1, LibreSSL 3.0.2"),
+            Some(Version::new(8, 1))
+        );
+        assert_eq!(parse_version("ssh: command not found"), None);
+        assert!("8".parse::<Version>().is_err());
+
+        let rules =
+            "Include ~/.ssh/extra\nHost bastion\n  proxyjump=gw\n  SessionType none\n  User ops";
+        let source = Path::new("a.sshconf");
+        assert_eq!(
+            filter_for(rules, source, Version::new(7, 2), Unsupported::Strip),
+            "Host bastion\n  User ops"
+        );
+        assert_eq!(
+            filter_for(rules, source, Version::new(8, 2), Unsupported::Strip),
+            "Include ~/.ssh/extra\nHost bastion\n  proxyjump=gw\n  User ops"
+        );
+        assert_eq!(filter_for(rules, source, Version::new(7, 2), Unsupported::Warn), rules);
+    }
+}
@@ -0,0 +1,151 @@
+//! # Paths
+//!
+//! This module contains the locations the generation pipeline reads fragments from and writes
+//! the SSH config to, so they can be pointed elsewhere (e.g. temporary directories in tests).
+
+use crate::error::{Error, Result};
+use crate::{is_verbose, verbose_println};
+use std::fs::{File, TryLockError};
+use std::path::{Path, PathBuf};
+
+/// The directory of the fragments in `.ssh`
+pub const CONFIG_DIR: &str = "config.d";
+
+/// The directory of the fragments of older versions, read along with [`CONFIG_DIR`] until it is
+/// migrated by `sshconfgen migrate-layout`
+pub const LEGACY_CONFIG_DIR: &str = "conf.d";
+
+/// The directories and files used by a generation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paths {
+    /// The `.ssh` directory
+    pub ssh_dir: PathBuf,
+    /// The directory holding the `.sshconf` fragments
+    pub config_dir: PathBuf,
+    /// The generated SSH client config file
+    pub config_file: PathBuf,
+    /// The directory of the state file, and of the backups, profile, env file and audit log kept
+    /// next to it
+    pub state_dir: PathBuf,
+}
+
+impl Paths {
+    /// Get the paths inside an `.ssh` directory, `config.d` and `config`, keeping the state in
+    /// `state_dir`
+    pub fn new(ssh_dir: &Path, state_dir: &Path) -> Paths {
+        Paths {
+            ssh_dir: ssh_dir.to_path_buf(),
+            config_dir: ssh_dir.join(CONFIG_DIR),
+            config_file: ssh_dir.join("config"),
+            state_dir: state_dir.to_path_buf(),
+        }
+    }
+
+    /// Get the paths of the current user, honoring the `ssh_dir`, `config_dir` and `ssh_config`
+    /// settings. The state is kept in `$XDG_STATE_HOME/sshconfgen`, or `~/.local/state/sshconfgen`
+    pub fn resolve() -> Result<Paths> {
+        let ssh_dir = crate::platform::ssh_dir().ok_or(Error::HomeNotFound)?;
+        let state_dir = crate::state::state_dir().ok_or(Error::HomeNotFound)?;
+        let config_dir =
+            crate::settings::get().config_dir.as_deref().map(crate::platform::expand_home);
+
+        Ok(Paths {
+            config_file: crate::platform::ssh_config_file(&ssh_dir),
+            config_dir: config_dir.unwrap_or_else(|| ssh_dir.join(CONFIG_DIR)),
+            ..Paths::new(&ssh_dir, &state_dir)
+        })
+    }
+
+    /// Get the lock file guarding the generations writing to the `.ssh` directory
+    pub fn lock_file(&self) -> PathBuf {
+        self.ssh_dir.join(".sshconfgen.lock")
+    }
+
+    /// Lock the `.ssh` directory for a generation, waiting for the generation of another process,
+    /// e.g. a cron run while generating by hand, to finish first. The lock is released when the
+    /// returned guard is dropped, or when the process exits.
+    pub fn lock(&self) -> Result<Lock> {
+        let path = self.lock_file();
+        let file = File::options().create(true).truncate(false).write(true).open(&path);
+        let file = file.map_err(Error::io("open", &path))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                verbose_println!("Waiting for another generation to release {}", path.display());
+                file.lock().map_err(Error::io("lock", &path))?;
+            }
+            Err(TryLockError::Error(e)) => return Err(Error::io("lock", &path)(e)),
+        }
+
+        Ok(Lock { _file: file })
+    }
+
+    /// Check that the `.ssh` and fragment directories exist. The legacy `conf.d` directory
+    /// stands in for a missing `config.d`.
+    pub fn check(&self) -> Result<()> {
+        if !self.ssh_dir.is_dir() {
+            return Err(Error::MissingDirectory(self.ssh_dir.clone()));
+        }
+
+        let legacy = legacy_config_dir(&self.config_dir).is_some_and(|dir| dir.is_dir());
+        if !self.config_dir.is_dir() && !legacy {
+            return Err(Error::MissingDirectory(self.config_dir.clone()));
+        }
+
+        Ok(())
+    }
+
+    /// Create the missing `.ssh` and fragment directories, which only their owner can access like
+    /// OpenSSH expects. Returns the directories created.
+    pub fn init_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut created = Vec::new();
+        for dir in [&self.ssh_dir, &self.config_dir] {
+            if dir.is_dir() {
+                continue;
+            }
+
+            crate::file::create_private_dir(dir).map_err(Error::io("create", dir))?;
+            verbose_println!("Created {}", dir.display());
+            created.push(dir.clone());
+        }
+
+        Ok(created)
+    }
+}
+
+/// Get the legacy `conf.d` directory next to a `config.d` fragment directory
+pub fn legacy_config_dir(config_dir: &Path) -> Option<PathBuf> {
+    (config_dir.file_name()? == CONFIG_DIR).then(|| config_dir.with_file_name(LEGACY_CONFIG_DIR))
+}
+
+/// The lock of the `.ssh` directory held by a generation, see [`Paths::lock`]
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_missing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Paths::new(&dir.path().join(".ssh"), &dir.path().join("state"));
+        assert!(matches!(paths.check(), Err(Error::MissingDirectory(_))));
+
+        assert_eq!(
+            paths.init_dirs().unwrap(),
+            vec![paths.ssh_dir.clone(), paths.config_dir.clone()]
+        );
+        assert!(paths.check().is_ok());
+        assert!(paths.init_dirs().unwrap().is_empty());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&paths.ssh_dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! # Phases
+//!
+//! This module times the phases of each generation: discovering and parsing the fragments,
+//! evaluating their conditions, by condition type, composing the config, validating it against
+//! the OpenSSH client, and writing it. The timings are returned in the report of the generation,
+//! and the binary prints the breakdown in verbose mode, in monitor mode too, so a phase growing
+//! slower can be told apart from the others:
+//!
+//! ```text
+//! Phases:
+//!    0.001s  discovery
+//!    0.004s  parsing over 12 runs
+//!    1.203s  evaluation
+//!    1.200s    LocalPing over 3 runs
+//!    0.010s    LocalSSID
+//!    0.002s  composition
+//!    0.001s    validation over 12 runs
+//!    0.003s  writing
+//!    1.214s  total
+//! ```
+//!
+//! Condition types are summed over their probes, which run concurrently, so they can add up to
+//! more than the evaluation.
+
+use crate::engine::{ConditionTiming, FragmentTiming};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The separator of a phase and the part of it a sub-phase times
+const SEPARATOR: char = '/';
+
+thread_local! {
+    /// The recorder of the generation running on this thread, if any
+    static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+}
+
+/// The time spent in a phase
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Phase {
+    /// The name of the phase, `<phase>/<part>` for a part of a phase
+    pub name: String,
+    pub elapsed: Duration,
+    /// How many times the phase ran
+    pub runs: usize,
+}
+
+/// How long the parts of a generation took
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Timings {
+    /// The phases, in the order they first ran
+    pub phases: Vec<Phase>,
+    /// How long each probed fragment took, in processing order
+    pub fragments: Vec<FragmentTiming>,
+    /// How long each condition line took, in the order they completed. Conditions cancelled by
+    /// a match or still running once the budget ran out are not listed.
+    pub conditions: Vec<ConditionTiming>,
+}
+
+/// Collects the timings of a generation, shared with the threads probing its conditions
+#[derive(Clone, Default)]
+pub(crate) struct Recorder(Arc<Mutex<Timings>>);
+
+impl Recorder {
+    /// Add `elapsed` to the time spent in `phase`
+    pub(crate) fn record(&self, phase: &str, elapsed: Duration) {
+        let mut timings = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match timings.phases.iter_mut().find(|recorded| recorded.name == phase) {
+            Some(recorded) => {
+                recorded.elapsed += elapsed;
+                recorded.runs += 1;
+            }
+            None => timings.phases.push(Phase { name: phase.to_string(), elapsed, runs: 1 }),
+        }
+    }
+
+    /// Add how long a condition line took
+    pub(crate) fn condition(&self, timing: ConditionTiming) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).conditions.push(timing);
+    }
+
+    /// Add how long the probed fragments took
+    pub(crate) fn fragments(&self, timings: impl IntoIterator<Item = FragmentTiming>) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).fragments.extend(timings);
+    }
+}
+
+/// Run `f`, recording the phases timed on this thread and the probes it starts, and get its
+/// result with their timings
+pub(crate) fn collect<T>(f: impl FnOnce() -> T) -> (T, Timings) {
+    let recorder = Recorder::default();
+    let previous = RECORDER.with(|current| current.replace(Some(recorder.clone())));
+    let result = f();
+    RECORDER.with(|current| current.replace(previous));
+    let timings = std::mem::take(&mut *recorder.0.lock().unwrap_or_else(|e| e.into_inner()));
+    (result, timings)
+}
+
+/// Get the recorder of the generation running on this thread, to hand to the threads it starts
+pub(crate) fn current() -> Option<Recorder> {
+    RECORDER.with(|current| current.borrow().clone())
+}
+
+/// Add `elapsed` to the time spent in `phase` by the generation running on this thread, if any
+pub fn record(phase: &str, elapsed: Duration) {
+    if let Some(recorder) = current() {
+        recorder.record(phase, elapsed);
+    }
+}
+
+/// Run `f` and add the time it took to `phase`
+pub fn time<T>(phase: &str, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = f();
+    record(phase, started.elapsed());
+    result
+}
+
+impl Timings {
+    /// Get the lines of the breakdown of the phases and the `total` time of the generation, the
+    /// parts of each phase following it
+    pub fn lines(&self, total: Duration) -> Vec<String> {
+        let phases = &self.phases;
+        let line = |phase: &Phase, name: &str| {
+            let runs =
+                if phase.runs > 1 { format!(" over {} runs", phase.runs) } else { String::new() };
+            format!("{:>9.3}s  {}{}", phase.elapsed.as_secs_f64(), name, runs)
+        };
+
+        let mut lines = Vec::new();
+        for phase in phases.iter().filter(|phase| !phase.name.contains(SEPARATOR)) {
+            lines.push(line(phase, &phase.name));
+            let prefix = format!("{}{}", phase.name, SEPARATOR);
+            for part in phases.iter().filter(|part| part.name.starts_with(&prefix)) {
+                lines.push(line(part, &format!("  {}", &part.name[prefix.len()..])));
+            }
+        }
+        lines.push(line(&Phase { name: String::new(), elapsed: total, runs: 1 }, "total"));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_parts_of_each_phase_under_it() {
+        let phase = |name: &str, millis: u64, runs: usize| Phase {
+            name: name.to_string(),
+            elapsed: Duration::from_millis(millis),
+            runs,
+        };
+        let phases = vec![
+            phase("evaluation/LocalPing", 1200, 3),
+            phase("discovery", 1, 1),
+            phase("evaluation", 1000, 1),
+            phase("evaluation/LocalSSID", 10, 1),
+        ];
+
+        assert_eq!(
+            Timings { phases, ..Timings::default() }.lines(Duration::from_millis(1100)),
+            [
+                "    0.001s  discovery",
+                "    1.000s  evaluation",
+                "    1.200s    LocalPing over 3 runs",
+                "    0.010s    LocalSSID",
+                "    1.100s  total",
+            ]
+        );
+    }
+
+    #[test]
+    fn records_only_the_phases_of_the_generation() {
+        record("discovery", Duration::from_millis(1));
+        let ((), timings) = collect(|| time("parsing", || ()));
+        let names: Vec<&str> = timings.phases.iter().map(|phase| phase.name.as_str()).collect();
+        assert_eq!(names, ["parsing"]);
+        assert!(current().is_none());
+    }
+}
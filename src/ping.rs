@@ -2,31 +2,143 @@
 //!
 //! This module contains the function to ping a host.
 
-use std::process::Command;
+use crate::platform::{command, run};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
 
-/// Get whether a host is pingable
+/// The ping timeout when the link latency is not taken into account
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many round trips to the gateway a ping may take before the host counts as unreachable
+const RTT_FACTOR: u32 = 8;
+
+/// Bounds of the ping timeout derived from the gateway round trip time, in milliseconds
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutSettings {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        TimeoutSettings { min: 200, max: 2000 }
+    }
+}
+
+impl TimeoutSettings {
+    /// Get the upper bound, also used when the gateway doesn't answer
+    pub fn max(&self) -> Duration {
+        Duration::from_millis(self.max)
+    }
+
+    /// Get the timeout for a link whose gateway answered within `rtt`, if it did
+    pub fn timeout(&self, rtt: Option<Duration>) -> Duration {
+        rtt.map_or(self.max(), |rtt| {
+            (rtt * RTT_FACTOR).clamp(Duration::from_millis(self.min), self.max())
+        })
+    }
+}
+
+/// Get whether a host is pingable, waiting for [`DEFAULT_TIMEOUT`]
 pub fn get_pingable(host: &str) -> bool {
+    get_pingable_within(host, DEFAULT_TIMEOUT)
+}
+
+/// Get the round trip time to a host, if it answers within `timeout`
+///
+/// Measured around a whole ping, so the external command startup is included.
+pub fn get_rtt(host: &str, timeout: Duration) -> Option<Duration> {
+    let start = Instant::now();
+    get_pingable_within(host, timeout).then(|| start.elapsed())
+}
+
+/// Get whether a host is pingable, waiting up to `timeout` for each of the two attempts
+///
+/// The `ping` commands of most platforms only take whole seconds, so the timeout is rounded up
+/// for them. `ping_command` and the PowerShell fallback use their own timeouts.
+pub fn get_pingable_within(host: &str, timeout: Duration) -> bool {
+    let millis = timeout.as_millis().max(1).to_string();
+    let secs = timeout.as_millis().div_ceil(1000).max(1).to_string();
+
+    if let Some(ping_command) = &crate::settings::get().ping_command {
+        return (0..2).any(|_| {
+            let mut command = crate::settings::build_command(ping_command, "{host}", host);
+            run(&mut command, "LocalPing").is_ok_and(|output| output.status.success())
+        });
+    }
+
+    if crate::backend::use_native(
+        crate::settings::get().backend.ping,
+        "ping",
+        crate::native::PING_SUPPORTED,
+    ) {
+        for _i in 0..2 {
+            match crate::native::get_pingable(host, timeout) {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => {
+                    crate::platform::warn_once(
+                        "native ping",
+                        &format!("{}, skipping LocalPing conditions.", e),
+                    );
+                    return false;
+                }
+            }
+        }
+
+        return false;
+    }
+
     let mut ping_output;
 
     // Loop up to 4 times until we get a successful ping.
     for _i in 0..2 {
         //println!("Pinging {} (attempt {})", host, _i + 1);
-        if cfg!(target_os = "windows") {
-            ping_output = Command::new("ping")
-                .args([host, "-n", "1", "-w", "1000"])
-                .output()
-                .expect("Failed to execute command");
+        if cfg!(target_os = "windows") && !crate::backend::is_in_path("ping") {
+            return crate::powershell::get_pingable(host);
+        } else if cfg!(target_os = "windows") {
+            ping_output = run(command("ping").args([host, "-n", "1", "-w", &millis]), "LocalPing");
+        } else if cfg!(target_os = "freebsd") {
+            // FreeBSD's -W is in milliseconds, -t is the overall timeout in seconds.
+            ping_output = run(command("ping").args(["-c", "1", "-t", &secs, host]), "LocalPing");
+        } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+            // Without -s, illumos' ping takes the timeout in seconds after the host and prints
+            // "<host> is alive" once it answers.
+            ping_output = run(command("ping").args([host, &secs]), "LocalPing");
+        } else if cfg!(any(target_os = "openbsd", target_os = "netbsd")) {
+            ping_output = run(command("ping").args(["-c", "1", "-w", &secs, host]), "LocalPing");
         } else {
-            ping_output = Command::new("ping")
-                .args([host, "-c", "1", "-W", "1"])
-                .output()
-                .expect("Failed to execute command");
+            ping_output = run(command("ping").args([host, "-c", "1", "-W", &secs]), "LocalPing");
         }
 
-        if ping_output.status.success() {
+        // A missing ping binary has already been reported, there is no point in retrying.
+        let Ok(ping_output) = ping_output else {
+            return false;
+        };
+
+        // Windows exits successfully on "destination host unreachable" replies from a router,
+        // only an actual echo reply carries a TTL, which is printed untranslated.
+        let replied = !cfg!(target_os = "windows")
+            || String::from_utf8_lossy(&ping_output.stdout).contains("TTL=");
+
+        if ping_output.status.success() && replied {
             return true;
         }
     }
 
     false
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_timeouts_with_the_gateway_rtt() {
+        let settings = TimeoutSettings::default();
+        assert_eq!(settings.timeout(Some(Duration::from_millis(2))), Duration::from_millis(200));
+        assert_eq!(settings.timeout(Some(Duration::from_millis(60))), Duration::from_millis(480));
+        assert_eq!(settings.timeout(Some(Duration::from_millis(900))), Duration::from_secs(2));
+        assert_eq!(settings.timeout(None), Duration::from_secs(2));
+    }
+}
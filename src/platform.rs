@@ -0,0 +1,199 @@
+//! # Platform
+//!
+//! This module contains helpers for detecting the environment the binary is running in.
+
+use crate::error::{Error, Result};
+use std::{
+    collections::HashSet,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::Mutex,
+};
+
+/// Default Termux home directory, used when `$HOME` is not set.
+const TERMUX_HOME: &str = "/data/data/com.termux/files/home";
+
+/// Keys of the warnings that have already been printed during this run.
+static REPORTED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Get whether the binary is running inside the Termux app on Android
+pub fn is_termux() -> bool {
+    std::env::var_os("TERMUX_VERSION").is_some()
+        || std::env::var("PREFIX").is_ok_and(|prefix| prefix.contains("com.termux"))
+}
+
+/// Get whether the binary is running inside the Windows Subsystem for Linux
+///
+/// WSL exposes the Windows executables (`netsh.exe`, `arp.exe`, ...) through interop, which is
+/// the only way to see the host's real network since the Linux side sits behind a virtual NIC.
+pub fn is_wsl() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+
+    if std::env::var_os("WSL_DISTRO_NAME").is_some() {
+        return true;
+    }
+
+    std::fs::read_to_string("/proc/sys/kernel/osrelease")
+        .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
+
+/// Get the home directory of the current user
+///
+/// Termux runs as an app user without a passwd entry, so `$HOME` is the only reliable source.
+pub fn home_dir() -> Option<PathBuf> {
+    if is_termux() {
+        return Some(
+            std::env::var_os("HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(TERMUX_HOME)),
+        );
+    }
+
+    dirs::home_dir()
+}
+
+/// Get the `.ssh` directory holding `config` and `config.d`
+///
+/// The `ssh_dir` setting takes precedence. On Windows, OpenSSH reads `%USERPROFILE%\.ssh` while
+/// the MSYS2/Cygwin builds follow `%HOME%`, and both may be redirected away from the profile
+/// folder `dirs` reports, so the first of those that has a `.ssh` directory is used.
+pub fn ssh_dir() -> Option<PathBuf> {
+    if let Some(ssh_dir) = &crate::settings::get().ssh_dir {
+        return Some(expand_home(ssh_dir));
+    }
+
+    if cfg!(target_os = "windows") {
+        let candidates: Vec<PathBuf> = ["USERPROFILE", "HOME"]
+            .iter()
+            .filter_map(|var| std::env::var_os(var).filter(|dir| !dir.is_empty()))
+            .map(|dir| PathBuf::from(dir).join(".ssh"))
+            .collect();
+
+        if let Some(ssh_dir) = candidates.iter().find(|dir| dir.is_dir()) {
+            return Some(ssh_dir.clone());
+        }
+
+        if let Some(ssh_dir) = candidates.into_iter().next() {
+            return Some(ssh_dir);
+        }
+    }
+
+    home_dir().map(|home| home.join(".ssh"))
+}
+
+/// Get the SSH client config file that is generated, `<ssh_dir>/config` unless overridden by the
+/// `ssh_config` setting
+pub fn ssh_config_file(ssh_dir: &Path) -> PathBuf {
+    match &crate::settings::get().ssh_config {
+        Some(ssh_config) => expand_home(ssh_config),
+        None => ssh_dir.join("config"),
+    }
+}
+
+/// Get the directory of the fragments provided by the administrator, see
+/// [`crate::sshconf::discover_fragments`]. `None` if disabled by an empty `system_config_dir`.
+pub fn system_config_dir() -> Option<PathBuf> {
+    if let Some(dir) = &crate::settings::get().system_config_dir {
+        return (!dir.as_os_str().is_empty()).then(|| expand_home(dir));
+    }
+
+    if cfg!(target_os = "windows") {
+        let program_data = std::env::var_os("ProgramData").filter(|dir| !dir.is_empty())?;
+        return Some(PathBuf::from(program_data).join("sshconfgen").join("config.d"));
+    }
+
+    Some(PathBuf::from("/etc/sshconfgen/config.d"))
+}
+
+/// Expand a leading `~` to the home directory of the current user
+pub fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Create a command for an external tool whose output will be parsed
+///
+/// Messages are forced to the untranslated C locale so the output does not depend on the user's
+/// language, while the character set (`LC_CTYPE`) is left alone so non-ASCII names survive.
+pub fn command(program: &str) -> Command {
+    let mut command = Command::new(program);
+    command.env_remove("LC_ALL").env_remove("LANGUAGE").env("LC_MESSAGES", "C");
+    command
+}
+
+/// Create a command running `netsh` with its output encoded as UTF-8
+///
+/// netsh writes in the console's OEM code page, which mangles non-ASCII names unless the code
+/// page is switched first.
+pub fn netsh_command(args: &[&str]) -> Command {
+    let mut command = Command::new(if is_wsl() { "cmd.exe" } else { "cmd" });
+    command.args(["/C", "chcp", "65001", ">NUL", "&&", "netsh"]).args(args);
+    command
+}
+
+/// Run an external tool needed by a condition type (e.g. `LocalSSID`)
+///
+/// A tool that is missing or cannot be started is reported once per run, naming the package that
+/// provides it, and an error is returned so only the conditions depending on it are skipped.
+pub fn run(command: &mut Command, condition: &str) -> Result<Output> {
+    command.output().map_err(|e| {
+        let program = command.get_program().to_string_lossy().to_string();
+        let reason = match e.kind() {
+            io::ErrorKind::NotFound => match providing_package(&program) {
+                Some(package) => format!("is not installed (provided by the {} package)", package),
+                None => "was not found in PATH".to_string(),
+            },
+            _ => format!("could not be run ({})", e),
+        };
+
+        warn_once(&program, &format!("{} {}, skipping {} conditions.", program, reason, condition));
+        Error::ToolUnavailable(program)
+    })
+}
+
+/// Print a warning, unless one was already printed for `key` during this run
+pub fn warn_once(key: &str, message: &str) {
+    let mut reported = REPORTED.lock().unwrap_or_else(|e| e.into_inner());
+    if reported.get_or_insert_with(HashSet::new).insert(key.to_string()) {
+        eprintln!("Warning: {}", message);
+    }
+}
+
+/// Get how many warnings were printed during this run
+pub fn warning_count() -> usize {
+    REPORTED.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map_or(0, HashSet::len)
+}
+
+/// Quote `value` as a single POSIX shell word
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Get the package providing an external tool that may be missing on the current platform
+fn providing_package(program: &str) -> Option<&'static str> {
+    if cfg!(target_os = "android") {
+        return match program {
+            "termux-wifi-connectioninfo" => Some("termux-api (and the Termux:API app)"),
+            "ip" => Some("iproute2"),
+            "ping" => Some("inetutils"),
+            _ => None,
+        };
+    }
+
+    if cfg!(target_os = "linux") {
+        return match program {
+            "iwgetid" => Some("wireless-tools"),
+            "arp" => Some("net-tools"),
+            "ip" => Some("iproute2"),
+            "ping" => Some("iputils-ping"),
+            _ => None,
+        };
+    }
+
+    None
+}
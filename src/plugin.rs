@@ -0,0 +1,51 @@
+//! # Plugin
+//!
+//! This module runs external subcommands: `sshconfgen foo` runs the `sshconfgen-foo` executable
+//! found in `PATH` with the remaining arguments, so exporters and importers can be added without
+//! changing sshconfgen. The plugin gets the context of the generation through the environment:
+//!
+//! * `SSHCONFGEN_BIN`: the sshconfgen binary
+//! * `SSHCONFGEN_VERSION`: its version
+//! * `SSHCONFGEN_SSH_DIR`: the `.ssh` directory
+//! * `SSHCONFGEN_CONFIG_DIR`: the directory of the fragments
+//! * `SSHCONFGEN_CONFIG_FILE`: the generated SSH config
+//! * `SSHCONFGEN_FINGERPRINT`: the detected network, as printed by `sshconfgen fingerprint`
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::provider::Providers;
+
+/// The prefix of the executables of external subcommands
+pub const PREFIX: &str = "sshconfgen-";
+
+/// Check whether `name` can be the name of an external subcommand: a letter, then letters,
+/// digits, `-` and `_`
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Run the external subcommand `name` with `args`, returning its exit code
+pub fn run(name: &str, args: &[String], paths: &Paths, providers: &Providers) -> Result<i32> {
+    let program = format!("{}{}", PREFIX, name);
+    if !is_valid_name(name) || !crate::backend::is_in_path(&program) {
+        return Err(Error::Invalid(format!("command: {}, {} is not in PATH", name, program)));
+    }
+
+    let fingerprint = crate::fingerprint::detect(providers);
+    let binary = std::env::current_exe().unwrap_or_default();
+    let status = std::process::Command::new(&program)
+        .args(args)
+        .env("SSHCONFGEN_BIN", binary)
+        .env("SSHCONFGEN_VERSION", crate::VERSION)
+        .env("SSHCONFGEN_SSH_DIR", &paths.ssh_dir)
+        .env("SSHCONFGEN_CONFIG_DIR", &paths.config_dir)
+        .env("SSHCONFGEN_CONFIG_FILE", &paths.config_file)
+        .env("SSHCONFGEN_FINGERPRINT", serde_json::to_string(&fingerprint).unwrap_or_default())
+        .status()
+        .map_err(|_| Error::ToolUnavailable(program))?;
+
+    // A plugin killed by a signal has no exit code.
+    Ok(status.code().unwrap_or(1))
+}
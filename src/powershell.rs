@@ -0,0 +1,169 @@
+//! # PowerShell
+//!
+//! This module contains the PowerShell-based detection used on Windows when the classic tools
+//! (`netsh`, `arp`, `ping`) are not available, as on some locked-down images.
+
+use crate::error::{Error, Result};
+use crate::platform::run;
+use crate::routes::DefaultRoute;
+use crate::ssid::Connection;
+use std::process::Command;
+
+/// Create a command running a PowerShell script
+fn powershell_command(script: &str) -> Command {
+    let program = if crate::platform::is_wsl() { "powershell.exe" } else { "powershell" };
+    let mut command = crate::platform::command(program);
+    command.args(["-NoProfile", "-NonInteractive", "-Command", script]);
+    command
+}
+
+/// Run a PowerShell script whose output is piped through `ConvertTo-Json`
+///
+/// A single result is serialized as an object and several as an array, both yield a list.
+pub fn run_json(script: &str, condition: &str) -> Result<Vec<serde_json::Value>> {
+    let output = run(&mut powershell_command(script), condition)?;
+    match serde_json::from_slice(&output.stdout) {
+        Ok(serde_json::Value::Array(values)) => Ok(values),
+        Ok(serde_json::Value::Null) | Err(_)
+            if output.stdout.iter().all(u8::is_ascii_whitespace) =>
+        {
+            Ok(vec![])
+        }
+        Ok(value) => Ok(vec![value]),
+        Err(_) => Err(Error::Parse("PowerShell")),
+    }
+}
+
+/// Get the connected Wi-Fi adapters and the network profile names they are connected to
+///
+/// Wi-Fi profiles are named after the SSID, unless they were renamed by the user.
+pub fn get_connections() -> Result<Vec<Connection>> {
+    // NdisPhysicalMedium 9 is Native 802.11.
+    let script = "$wifi = Get-NetAdapter | Where-Object { $_.NdisPhysicalMedium -eq 9 } | \
+        Select-Object -ExpandProperty Name; \
+        Get-NetConnectionProfile | Where-Object { $wifi -contains $_.InterfaceAlias } | \
+        Select-Object InterfaceAlias, Name | ConvertTo-Json -Compress";
+
+    Ok(run_json(script, "LocalSSID")?
+        .iter()
+        .filter_map(|profile| {
+            Some(Connection {
+                interface: profile["InterfaceAlias"].as_str()?.to_string(),
+                ssid: profile["Name"].as_str()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Get the hardware address of an IPv4 or IPv6 neighbor with `Get-NetNeighbor`
+pub fn get_hw_address(ip_address: &str) -> Result<String> {
+    if ip_address.contains(['\'', '"', ';', '`', '$']) {
+        return Err(Error::Invalid(format!("IP address {}", ip_address)));
+    }
+
+    let script = format!(
+        "Get-NetNeighbor -IPAddress '{}' -ErrorAction SilentlyContinue | \
+        Where-Object {{ $_.State -ne 'Unreachable' -and $_.State -ne 'Incomplete' }} | \
+        Select-Object LinkLayerAddress | ConvertTo-Json -Compress",
+        ip_address
+    );
+
+    run_json(&script, "LocalGateway")?
+        .iter()
+        .filter_map(|neighbor| neighbor["LinkLayerAddress"].as_str())
+        .find(|mac| !mac.is_empty() && *mac != "00-00-00-00-00-00")
+        .map(|mac| {
+            // Match the colon format used by the other platforms under WSL, like arp.exe.
+            if crate::platform::is_wsl() {
+                mac.replace('-', ":").to_lowercase()
+            } else {
+                mac.to_string()
+            }
+        })
+        .ok_or(Error::NotFound("MAC address"))
+}
+
+/// Get whether a host answers a single echo request with `Test-Connection`
+pub fn get_pingable(host: &str) -> bool {
+    if host.contains(['\'', '"', ';', '`', '$']) {
+        return false;
+    }
+
+    let script = format!("Test-Connection -ComputerName '{}' -Count 1 -Quiet", host);
+    run(&mut powershell_command(&script), "LocalPing")
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).trim() == "True")
+}
+
+/// Get the default routes of the Windows host with `Get-NetRoute`
+pub fn get_default_routes() -> Result<Vec<DefaultRoute>> {
+    let script = "Get-NetRoute -DestinationPrefix 0.0.0.0/0, ::/0 -ErrorAction SilentlyContinue | \
+        Select-Object InterfaceAlias, NextHop, RouteMetric | ConvertTo-Json -Compress";
+
+    Ok(run_json(script, "routes")?
+        .iter()
+        .filter_map(|route| {
+            Some(DefaultRoute {
+                interface: route["InterfaceAlias"].as_str()?.to_string(),
+                // On-link routes have the unspecified address as next hop.
+                gateway: route["NextHop"]
+                    .as_str()
+                    .and_then(|gateway| gateway.parse::<std::net::IpAddr>().ok())
+                    .filter(|gateway| !gateway.is_unspecified()),
+                metric: route["RouteMetric"].as_u64().and_then(|metric| u32::try_from(metric).ok()),
+            })
+        })
+        .collect())
+}
+
+/// Get the IPv4 subnet of an interface with `Get-NetIPAddress`
+pub fn get_subnet(interface: &str) -> Option<String> {
+    if interface.contains(['\'', '"', ';', '`', '$']) {
+        return None;
+    }
+
+    let script = format!(
+        "Get-NetIPAddress -InterfaceAlias '{}' -AddressFamily IPv4 -ErrorAction SilentlyContinue | \
+        Select-Object IPAddress, PrefixLength | ConvertTo-Json -Compress",
+        interface
+    );
+
+    let address = run_json(&script, "fingerprint").ok()?.into_iter().next()?;
+    let ip = address["IPAddress"].as_str()?.parse().ok()?;
+    let prefix_len = u32::try_from(address["PrefixLength"].as_u64()?).ok()?;
+    Some(crate::routes::format_subnet(ip, prefix_len))
+}
+
+/// Get the connection-specific DNS suffix of an interface with `Get-DnsClient`
+pub fn get_dns_suffix(interface: &str) -> Option<String> {
+    if interface.contains(['\'', '"', ';', '`', '$']) {
+        return None;
+    }
+
+    let script = format!(
+        "Get-DnsClient -InterfaceAlias '{}' -ErrorAction SilentlyContinue | \
+        Select-Object ConnectionSpecificSuffix | ConvertTo-Json -Compress",
+        interface
+    );
+
+    run_json(&script, "fingerprint")
+        .ok()?
+        .iter()
+        .filter_map(|client| client["ConnectionSpecificSuffix"].as_str())
+        .find(|suffix| !suffix.is_empty())
+        .map(str::to_string)
+}
+
+/// Get the process IDs and command lines of the running `ssh` clients with `Get-CimInstance`
+#[cfg(windows)]
+pub fn get_ssh_processes() -> Result<Vec<(u32, String)>> {
+    let script = "Get-CimInstance Win32_Process -Filter \"Name = 'ssh.exe'\" | \
+        Select-Object ProcessId, CommandLine | ConvertTo-Json -Compress";
+
+    Ok(run_json(script, "session")?
+        .iter()
+        .filter_map(|process| {
+            let pid = u32::try_from(process["ProcessId"].as_u64()?).ok()?;
+            Some((pid, process["CommandLine"].as_str()?.to_string()))
+        })
+        .collect())
+}
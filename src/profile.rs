@@ -0,0 +1,31 @@
+//! # Profile
+//!
+//! This module keeps the name of the active profile in the `profile` file next to the state file,
+//! rewritten whenever it changes, so shell prompts and status bars can show which rules are live
+//! by reading a single line. The profile is the active named network, or `local` or `remote`
+//! depending on whether any fragment uses its local rules.
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use std::path::PathBuf;
+
+/// Get the path of the profile file of `paths`, `profile` next to the state file
+pub fn profile_path(paths: &Paths) -> PathBuf {
+    paths.state_dir.join("profile")
+}
+
+/// Write the profile of a generation, unless the file already holds it
+pub fn write(paths: &Paths, profile: &str) -> Result<()> {
+    std::fs::create_dir_all(&paths.state_dir).map_err(Error::io("create", &paths.state_dir))?;
+    crate::file::write_if_changed(&profile_path(paths), &format!("{}\n", profile))
+}
+
+/// Read the profile of the last generation, `None` if nothing was generated yet
+pub fn read(paths: &Paths) -> Result<Option<String>> {
+    let path = profile_path(paths);
+    match crate::file::read_file(&path) {
+        Ok(contents) => Ok(Some(contents.trim().to_string()).filter(|profile| !profile.is_empty())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::io("read", path)(e)),
+    }
+}
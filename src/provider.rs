@@ -0,0 +1,159 @@
+//! # Providers
+//!
+//! This module contains the traits the conditions use to observe the network, so the condition
+//! logic can run against mock providers and alternative backends can be plugged in.
+
+use crate::error::{Error, Result};
+pub use crate::hwaddr::NeighborTable;
+pub use crate::routes::DefaultRoute;
+pub use crate::ssid::Connection;
+pub use crate::vpn::Adapter;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reports the SSIDs of the connected Wi-Fi adapters
+pub trait SsidProvider: Send + Sync {
+    /// Get every connected wireless interface along with its SSID
+    fn connections(&self) -> Result<Vec<Connection>>;
+
+    /// Get the BSSID of the access point a wireless interface is associated with
+    fn bssid(&self, _interface: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Checks whether hosts are reachable
+pub trait PingProvider: Send + Sync {
+    /// Get whether a host answers an ICMP echo request
+    fn pingable(&self, host: &str) -> bool;
+
+    /// Get whether a host answers an ICMP echo request within `timeout`
+    fn pingable_within(&self, host: &str, _timeout: Duration) -> bool {
+        self.pingable(host)
+    }
+
+    /// Get the round trip time to a host, if it answers within `timeout`
+    fn rtt(&self, _host: &str, _timeout: Duration) -> Option<Duration> {
+        None
+    }
+
+    /// Get whether a host on the local segment answers an ARP request
+    fn arp_pingable(&self, ip_address: &str) -> bool;
+}
+
+/// Looks up hosts in the neighbor (ARP/NDP) table
+pub trait NeighborProvider: Send + Sync {
+    /// Get the hardware address of a device given its IP address
+    fn hw_address(&self, ip_address: &str) -> Result<String>;
+
+    /// Read the whole IPv4 or IPv6 neighbor table, so many addresses cost a single lookup
+    fn neighbor_table(&self, _ipv6: bool) -> Result<NeighborTable> {
+        Err(Error::Unsupported("Reading the whole neighbor table"))
+    }
+}
+
+/// Reports the default routes
+pub trait RouteProvider: Send + Sync {
+    /// Get the default routes, IPv4 routes first and then by ascending metric
+    fn default_routes(&self) -> Result<Vec<DefaultRoute>>;
+
+    /// Get the IPv4 subnet of an interface in CIDR notation
+    fn subnet(&self, _interface: &str) -> Option<String> {
+        None
+    }
+
+    /// Get the DNS search domain of the network an interface is connected to
+    fn dns_suffix(&self, _interface: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Reports the connected VPN adapters
+pub trait VpnProvider: Send + Sync {
+    /// Get the VPN adapters that are currently up
+    fn vpn_adapters(&self) -> Result<Vec<Adapter>>;
+}
+
+/// The providers used to evaluate conditions, shared by the concurrently running probes
+#[derive(Clone)]
+pub struct Providers {
+    pub ssid: Arc<dyn SsidProvider>,
+    pub ping: Arc<dyn PingProvider>,
+    pub neighbor: Arc<dyn NeighborProvider>,
+    pub routes: Arc<dyn RouteProvider>,
+    pub vpn: Arc<dyn VpnProvider>,
+}
+
+impl Default for Providers {
+    /// The providers observing this system, with the backends chosen in the settings
+    fn default() -> Self {
+        Providers {
+            ssid: Arc::new(SystemProvider),
+            ping: Arc::new(SystemProvider),
+            neighbor: Arc::new(SystemProvider),
+            routes: Arc::new(SystemProvider),
+            vpn: Arc::new(SystemProvider),
+        }
+    }
+}
+
+/// The built-in detection, using external commands or the native backends per the settings
+pub struct SystemProvider;
+
+impl SsidProvider for SystemProvider {
+    fn connections(&self) -> Result<Vec<Connection>> {
+        crate::ssid::get_connections()
+    }
+
+    fn bssid(&self, interface: &str) -> Option<String> {
+        crate::ssid::get_bssid(interface)
+    }
+}
+
+impl PingProvider for SystemProvider {
+    fn pingable(&self, host: &str) -> bool {
+        crate::ping::get_pingable(host)
+    }
+
+    fn pingable_within(&self, host: &str, timeout: Duration) -> bool {
+        crate::ping::get_pingable_within(host, timeout)
+    }
+
+    fn rtt(&self, host: &str, timeout: Duration) -> Option<Duration> {
+        crate::ping::get_rtt(host, timeout)
+    }
+
+    fn arp_pingable(&self, ip_address: &str) -> bool {
+        crate::arping::get_arp_pingable(ip_address)
+    }
+}
+
+impl NeighborProvider for SystemProvider {
+    fn hw_address(&self, ip_address: &str) -> Result<String> {
+        crate::hwaddr::get_hw_address(ip_address)
+    }
+
+    fn neighbor_table(&self, ipv6: bool) -> Result<NeighborTable> {
+        crate::hwaddr::get_neighbor_table(ipv6)
+    }
+}
+
+impl RouteProvider for SystemProvider {
+    fn default_routes(&self) -> Result<Vec<DefaultRoute>> {
+        crate::routes::get_default_routes()
+    }
+
+    fn subnet(&self, interface: &str) -> Option<String> {
+        crate::routes::get_subnet(interface)
+    }
+
+    fn dns_suffix(&self, interface: &str) -> Option<String> {
+        crate::routes::get_dns_suffix(interface)
+    }
+}
+
+impl VpnProvider for SystemProvider {
+    fn vpn_adapters(&self) -> Result<Vec<Adapter>> {
+        crate::vpn::get_vpn_adapters()
+    }
+}
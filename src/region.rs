@@ -0,0 +1,145 @@
+//! # Region
+//!
+//! This module keeps the hand-written parts of an SSH config across generations. When the config
+//! holds a managed region, only the lines between its markers are generated:
+//!
+//! ```text
+//! Host *
+//!   ServerAliveInterval 30
+//!
+//! # sshconfgen BEGIN
+//! ...
+//! # sshconfgen END
+//!
+//! Host legacy
+//!   HostKeyAlgorithms +ssh-rsa
+//! ```
+//!
+//! The region starts with `Match all`, ending the block of the hand-written lines above it, which
+//! would otherwise apply to the generated global rules, and the `Include` of a named network. A
+//! config with a managed region is treated as managed by sshconfgen, it doesn't need adopting.
+
+use crate::error::{Error, Result};
+use std::ops::Range;
+use std::path::Path;
+
+/// The line starting the managed region
+pub const BEGIN: &str = "# sshconfgen BEGIN";
+
+/// The line ending the managed region
+pub const END: &str = "# sshconfgen END";
+
+/// The first line of the managed region, applying what follows to every host
+const MATCH_ALL: &str = "Match all";
+
+/// Get the byte range of the lines between the markers of `contents`, `None` if it has none
+fn find(contents: &str) -> std::result::Result<Option<Range<usize>>, String> {
+    let (mut begin, mut end) = (None, None);
+    let mut offset = 0;
+    for line in contents.split_inclusive('\n') {
+        match line.trim() {
+            BEGIN if begin.is_some() => return Err(format!("{} is repeated", BEGIN)),
+            BEGIN => begin = Some(offset + line.len()),
+            END if begin.is_none() => return Err(format!("{} comes before {}", END, BEGIN)),
+            END if end.is_some() => return Err(format!("{} is repeated", END)),
+            END => end = Some(offset),
+            _ => {}
+        }
+        offset += line.len();
+    }
+
+    match (begin, end) {
+        (Some(begin), Some(end)) => Ok(Some(begin..end)),
+        (None, None) => Ok(None),
+        _ => Err(format!("{} has no matching {}", BEGIN, END)),
+    }
+}
+
+/// Check whether `contents` has a well-formed managed region
+pub fn has_region(contents: &str) -> bool {
+    find(contents).is_ok_and(|range| range.is_some())
+}
+
+/// Get the lines of the managed region of `contents`, if it has a well-formed one
+pub fn body(contents: &str) -> Option<&str> {
+    find(contents).ok().flatten().map(|range| &contents[range])
+}
+
+/// Get the contents `config_file`, holding `current`, is replaced with to write `new`: `new` itself
+/// unless `current` has a managed region, in which case only the region is replaced. Malformed
+/// markers fail rather than losing what surrounds them.
+pub fn splice(config_file: &Path, current: &str, new: &str) -> Result<String> {
+    let range = find(current).map_err(|e| {
+        Error::Invalid(format!("managed region of {}: {}", config_file.display(), e))
+    })?;
+    let Some(range) = range else {
+        return Ok(new.to_string());
+    };
+
+    let newline = crate::settings::get().newline.as_str();
+    let new = new.trim_end_matches(['\r', '\n']);
+    let body = if new.is_empty() {
+        String::new()
+    } else {
+        format!("{}{}{}{}", MATCH_ALL, newline, new, newline)
+    };
+    // The begin marker may be the last line, without a line ending of its own.
+    let separator = if current[..range.start].ends_with('\n') { "" } else { newline };
+    Ok(format!("{}{}{}{}", &current[..range.start], separator, body, &current[range.end..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_only_the_managed_region() {
+        let newline = crate::settings::get().newline.as_str();
+        let path = Path::new("config");
+        let current =
+            "Host *\n  User me\n\n# sshconfgen BEGIN\nHost old\n# sshconfgen END\n\nHost legacy\n";
+        let spliced = splice(path, current, &format!("Host a{0}{0}Host b{0}{0}", newline)).unwrap();
+        let expected = format!(
+            "Host *\n  User me\n\n# sshconfgen BEGIN\nMatch all{0}Host a{0}{0}Host b{0}\
+             # sshconfgen END\n\nHost legacy\n",
+            newline
+        );
+        assert_eq!(spliced, expected);
+        let generated = format!("Match all{0}Host a{0}{0}Host b{0}", newline);
+        assert_eq!(body(&spliced), Some(generated.as_str()));
+        assert_eq!(splice(path, "Host old\n", "Host a\n").unwrap(), "Host a\n");
+
+        for malformed in [
+            "# sshconfgen BEGIN\n",
+            "# sshconfgen END\n# sshconfgen BEGIN\n",
+            "# sshconfgen BEGIN\n# sshconfgen BEGIN\n",
+        ] {
+            assert!(splice(path, malformed, "Host a\n").is_err(), "{:?}", malformed);
+            assert!(!has_region(malformed));
+        }
+    }
+
+    #[test]
+    fn ends_the_host_block_above_the_region() {
+        let newline = crate::settings::get().newline.as_str();
+        let current = "Host jump\n  User admin\n# sshconfgen BEGIN\n# sshconfgen END\n";
+        let new = format!("ServerAliveInterval 30{0}Include ~/.ssh/config.office{0}", newline);
+        let spliced = splice(Path::new("config"), current, &new).unwrap();
+
+        // The generated global rules come after a Match all, not inside the Host jump block.
+        let lines: Vec<&str> = spliced.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            [
+                "Host jump",
+                "  User admin",
+                "# sshconfgen BEGIN",
+                "Match all",
+                "ServerAliveInterval 30",
+                "Include ~/.ssh/config.office",
+                "# sshconfgen END",
+            ]
+        );
+        assert_eq!(splice(Path::new("config"), current, "").unwrap(), current);
+    }
+}
@@ -0,0 +1,148 @@
+//! # Review
+//!
+//! This module works out the files a generation would change, so they can be confirmed before
+//! they are written, with `--interactive`, or only previewed, with `--dry-run`. The changes of
+//! each file are summarized by the hosts added, removed and changed. Nothing is backed up or
+//! written unless they are approved.
+
+use crate::error::{Error, Result};
+use crate::sessions::targets;
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A file a generation would change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Planned {
+    pub path: PathBuf,
+    /// The contents of the file, empty if it doesn't exist
+    pub current: String,
+    pub new: String,
+}
+
+/// Get the files that writing `ssh_config`, generated on `network` if any, would change, see
+/// [`crate::network::write_configs`]
+pub fn planned(
+    ssh_config_file: &Path,
+    network: Option<&str>,
+    ssh_config: &str,
+) -> Result<Vec<Planned>> {
+    if ssh_config.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let newline = crate::settings::get().newline.as_str();
+    let contents = format!("{}{}", ssh_config, newline);
+    let files = match network {
+        Some(network) => {
+            let include_file = crate::network::include_file(ssh_config_file, network);
+            let switcher = crate::network::switcher(&include_file, network);
+            vec![(include_file, contents), (ssh_config_file.to_path_buf(), switcher)]
+        }
+        None => vec![(ssh_config_file.to_path_buf(), contents)],
+    };
+
+    let mut planned = Vec::new();
+    for (path, new) in files {
+        let current = crate::file::read_file(&path).unwrap_or_default();
+        // Only the managed region of the SSH config is replaced when it holds one.
+        let new = if path == ssh_config_file {
+            crate::region::splice(&path, &current, &new)?
+        } else {
+            new
+        };
+        if current != new {
+            planned.push(Planned { path, current, new });
+        }
+    }
+    Ok(planned)
+}
+
+impl Planned {
+    /// Summarize the hosts the file gains, loses and changes, by `HostName` or `ProxyJump`
+    pub fn summary(&self) -> String {
+        summary(&self.current, &self.new)
+    }
+}
+
+/// Put back the previous contents of the files a generation wrote, removing the ones it created.
+/// Files still holding their previous contents are left alone.
+pub fn restore(written: &[Planned]) -> Result<()> {
+    for file in written {
+        match crate::file::read_file(&file.path) {
+            Ok(contents) if contents == file.current => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && file.current.is_empty() => {
+                continue
+            }
+            _ => {}
+        }
+
+        let restored = if file.current.is_empty() {
+            std::fs::remove_file(&file.path).or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        } else {
+            crate::file::replace_file(&file.path, |out| out.write_all(file.current.as_bytes()))
+                .map(|_| ())
+        };
+        restored.map_err(Error::io("restore", &file.path))?;
+    }
+
+    Ok(())
+}
+
+/// Summarize the hosts `new` adds, removes and changes, as `HostName` or `ProxyJump`, over
+/// `current`
+pub fn summary(current: &str, new: &str) -> String {
+    let (current, new) = (targets(current), targets(new));
+    let added: Vec<&str> =
+        new.keys().filter(|host| !current.contains_key(*host)).map(String::as_str).collect();
+    let removed: Vec<&str> =
+        current.keys().filter(|host| !new.contains_key(*host)).map(String::as_str).collect();
+    let changed: Vec<&str> = current
+        .iter()
+        .filter(|(host, target)| new.get(*host).is_some_and(|new| new != *target))
+        .map(|(host, _)| host.as_str())
+        .collect();
+
+    let parts: Vec<String> = [("added", added), ("removed", removed), ("changed", changed)]
+        .into_iter()
+        .filter(|(_, hosts)| !hosts.is_empty())
+        .map(|(what, hosts)| format!("{} {} ({})", hosts.len(), what, hosts.join(", ")))
+        .collect();
+    if parts.is_empty() {
+        "no hosts added, removed or changed".to_string()
+    } else {
+        format!("hosts {}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarizes_the_hosts_that_change() {
+        let current = "Host a\n  HostName 10.0.0.1\n\nHost b\n  User me\n";
+        let new = "Host a\n  HostName a.example.com\n\nHost c d\n  User me\n";
+        assert_eq!(summary(current, new), "hosts 2 added (c, d), 1 removed (b), 1 changed (a)");
+        assert_eq!(summary(current, current), "no hosts added, removed or changed");
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        let newline = crate::settings::get().newline.as_str();
+        std::fs::write(&config, format!("Host a{}", newline)).unwrap();
+        assert!(planned(&config, None, "Host a").unwrap().is_empty());
+        assert!(planned(&config, None, "").unwrap().is_empty());
+        let planned = planned(&config, Some("office"), "Host a").unwrap();
+        assert_eq!(planned.len(), 2);
+        assert_eq!(planned[0].current, "");
+
+        // Only the include file was written before the switcher failed.
+        std::fs::write(&planned[0].path, &planned[0].new).unwrap();
+        restore(&planned).unwrap();
+        assert!(!planned[0].path.exists());
+        assert_eq!(std::fs::read_to_string(&config).unwrap(), planned[1].current);
+    }
+}
@@ -0,0 +1,155 @@
+//! # Roaming
+//!
+//! This module writes the companion file of the roaming shells, mosh and Eternal Terminal, next
+//! to the SSH config. Hosts are tagged in the rules of fragments with comments, e.g.
+//!
+//! ```text
+//! Host nas
+//!   HostName nas.example.com
+//!   # sshconfgen: mosh 60001
+//!   # sshconfgen: et 8022
+//! ```
+//!
+//! for `mosh` on UDP port 60001 and `et` on TCP port 8022, the ports being optional. The file
+//! defines an alias for each, e.g. `mosh-nas` and `et-nas`, to source from the shell profile.
+//! Since the tags are read from the generated config, they follow the same local or remote
+//! decision as the rest of the rules, and both tools bootstrap through `ssh` with the generated
+//! config, so they reach the same address.
+
+use crate::error::Result;
+use crate::platform::shell_quote;
+use std::path::{Path, PathBuf};
+
+/// The prefix of the comments tagging a host
+const TAG: &str = "sshconfgen:";
+
+/// A roaming shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tool {
+    Mosh,
+    Et,
+}
+
+/// A host tagged for a roaming shell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Companion {
+    pub host: String,
+    pub tool: Tool,
+    /// The UDP port of the mosh server or the TCP port of the et server, the default if `None`
+    pub port: Option<u16>,
+}
+
+impl Companion {
+    /// Get the alias line of the companion
+    pub fn alias(&self) -> String {
+        let command = match (self.tool, self.port) {
+            // mosh resolves the host itself unless told to use the address ssh connected to.
+            (Tool::Mosh, Some(port)) => {
+                format!("mosh --experimental-remote-ip=remote --port={} {}", port, self.host)
+            }
+            (Tool::Mosh, None) => format!("mosh --experimental-remote-ip=remote {}", self.host),
+            (Tool::Et, Some(port)) => format!("et {}:{}", self.host, port),
+            (Tool::Et, None) => format!("et {}", self.host),
+        };
+        let name = match self.tool {
+            Tool::Mosh => "mosh",
+            Tool::Et => "et",
+        };
+        format!("alias {}-{}={}", name, self.host, shell_quote(&command))
+    }
+}
+
+/// Get the path of the companion file, `roaming.sh` next to the SSH config
+pub fn roaming_path(ssh_config_file: &Path) -> PathBuf {
+    ssh_config_file.with_file_name("roaming.sh")
+}
+
+/// Get the hosts of an SSH config tagged for a roaming shell. A tag applies to the first name of
+/// the `Host` line it follows, invalid tags are ignored with a warning.
+pub fn companions(ssh_config: &str) -> Vec<Companion> {
+    let mut host: Option<&str> = None;
+    let mut companions = Vec::new();
+    for line in ssh_config.lines().map(str::trim) {
+        let mut words = line.split_whitespace();
+        if words.next().is_some_and(|keyword| keyword.eq_ignore_ascii_case("host")) {
+            host = words.find(|name| !name.contains(['*', '?', '!']));
+            continue;
+        }
+
+        let Some(tag) =
+            line.strip_prefix('#').and_then(|comment| comment.trim_start().strip_prefix(TAG))
+        else {
+            continue;
+        };
+        match (host, parse_tag(tag)) {
+            (Some(host), Some((tool, port))) => {
+                companions.push(Companion { host: host.to_string(), tool, port })
+            }
+            _ => {
+                let message =
+                    format!("Ignoring the tag {:?}, it needs a Host line and mosh or et", line);
+                crate::platform::warn_once(line, &message);
+            }
+        }
+    }
+    companions
+}
+
+/// Parse the tool and optional port of a tag
+fn parse_tag(tag: &str) -> Option<(Tool, Option<u16>)> {
+    let mut words = tag.split_whitespace();
+    let tool = match words.next()? {
+        "mosh" => Tool::Mosh,
+        "et" => Tool::Et,
+        _ => return None,
+    };
+    let port = match words.next() {
+        Some(port) => Some(port.parse().ok().filter(|port| *port > 0)?),
+        None => None,
+    };
+    words.next().is_none().then_some((tool, port))
+}
+
+/// Write the companion file of the hosts tagged in `ssh_config`. Without tags, a file left by a
+/// previous generation is emptied, so stale aliases don't point at the wrong network.
+pub fn write(ssh_config_file: &Path, ssh_config: &str) -> Result<()> {
+    let companions = companions(ssh_config);
+    let path = roaming_path(ssh_config_file);
+    if companions.is_empty() && !path.exists() {
+        return Ok(());
+    }
+
+    let mut contents = "# Generated by sshconfgen, source it from the shell profile\n".to_string();
+    for companion in &companions {
+        contents.push_str(&companion.alias());
+        contents.push('\n');
+    }
+
+    crate::file::write_if_changed(&path, &contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_tagged_hosts() {
+        let config = "Host nas nas.lan\n  HostName 10.0.0.2\n  # sshconfgen: mosh 60001\n\
+                      \x20 #sshconfgen: et\nHost *\n  # sshconfgen: mosh\n\
+                      Host web\n  # sshconfgen: telnet\n  # sshconfgen: et 8022\n";
+        let companions = companions(config);
+        assert_eq!(
+            companions,
+            [
+                Companion { host: "nas".to_string(), tool: Tool::Mosh, port: Some(60001) },
+                Companion { host: "nas".to_string(), tool: Tool::Et, port: None },
+                Companion { host: "web".to_string(), tool: Tool::Et, port: Some(8022) },
+            ]
+        );
+        assert_eq!(
+            companions[0].alias(),
+            "alias mosh-nas='mosh --experimental-remote-ip=remote --port=60001 nas'"
+        );
+        assert_eq!(companions[2].alias(), "alias et-web='et web:8022'");
+    }
+}
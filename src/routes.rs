@@ -0,0 +1,429 @@
+//! # Routes
+//!
+//! This module contains the functions to look up the default routes of the system, used by the
+//! conditions that depend on the upstream network (gateway, default interface, wired uplink).
+
+use crate::error::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr},
+};
+
+/// A default route, IPv4 or IPv6
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DefaultRoute {
+    /// The name of the outgoing interface
+    pub interface: String,
+    /// The next hop, `None` for on-link routes (point-to-point links, some VPNs)
+    pub gateway: Option<IpAddr>,
+    /// The route metric, lower is preferred. Not every platform reports it.
+    pub metric: Option<u32>,
+}
+
+impl fmt::Display for DefaultRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gateway {
+            Some(gateway) => write!(f, "via {} dev {}", gateway, self.interface)?,
+            None => write!(f, "dev {}", self.interface)?,
+        }
+
+        match self.metric {
+            Some(metric) => write!(f, " metric {}", metric),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Get the default routes, IPv4 routes first and then by ascending metric
+pub fn get_default_routes() -> Result<Vec<DefaultRoute>> {
+    // The Linux side of WSL only routes through the virtual switch, ask the Windows host instead.
+    let mut routes = if crate::platform::is_wsl() {
+        crate::powershell::get_default_routes()?
+    } else {
+        get_system_default_routes()?
+    };
+
+    routes.sort_by_key(|route| {
+        (route.gateway.is_some_and(|gateway| gateway.is_ipv6()), route.metric)
+    });
+    Ok(routes)
+}
+
+/// Get the IPv4 subnet of an interface in CIDR notation, such as `192.168.1.0/24`
+pub fn get_subnet(interface: &str) -> Option<String> {
+    if cfg!(windows) || crate::platform::is_wsl() {
+        return crate::powershell::get_subnet(interface);
+    }
+
+    get_system_subnet(interface)
+}
+
+/// Get the DNS search domain of the network an interface is connected to
+pub fn get_dns_suffix(interface: &str) -> Option<String> {
+    if cfg!(windows) {
+        return crate::powershell::get_dns_suffix(interface);
+    }
+
+    // resolv.conf only has the search domains of every network at once, WSL generates it from
+    // the host's settings.
+    parse_resolv_conf_suffix(&fs::read_to_string("/etc/resolv.conf").ok()?)
+}
+
+/// Get the first search domain of a `resolv.conf`, the last `search` or `domain` line wins
+fn parse_resolv_conf_suffix(contents: &str) -> Option<String> {
+    let mut suffix = None;
+    for line in contents.lines() {
+        let mut tokens = line.split_whitespace();
+        if let Some("search" | "domain") = tokens.next() {
+            suffix = tokens.next().map(|domain| domain.trim_end_matches('.').to_string());
+        }
+    }
+
+    suffix.filter(|suffix| !suffix.is_empty())
+}
+
+/// Format the subnet an address belongs to in CIDR notation
+pub fn format_subnet(address: Ipv4Addr, prefix_len: u32) -> String {
+    let mask = u32::MAX.checked_shl(32 - prefix_len.min(32)).unwrap_or(0);
+    format!("{}/{}", Ipv4Addr::from(u32::from(address) & mask), prefix_len.min(32))
+}
+
+/// Get the IPv4 subnet of an interface with `getifaddrs`
+#[cfg(unix)]
+fn get_system_subnet(interface: &str) -> Option<String> {
+    let mut addresses: *mut libc::ifaddrs = std::ptr::null_mut();
+
+    // SAFETY: getifaddrs allocates the list, which is walked while valid and freed once.
+    unsafe {
+        if libc::getifaddrs(&mut addresses) != 0 {
+            return None;
+        }
+
+        let mut subnet = None;
+        let mut entry = addresses;
+        while !entry.is_null() {
+            let current = &*entry;
+            entry = current.ifa_next;
+
+            let name = std::ffi::CStr::from_ptr(current.ifa_name).to_string_lossy();
+            if name != interface || current.ifa_addr.is_null() || current.ifa_netmask.is_null() {
+                continue;
+            }
+
+            if i32::from((*current.ifa_addr).sa_family) != libc::AF_INET {
+                continue;
+            }
+
+            let address = &*(current.ifa_addr as *const libc::sockaddr_in);
+            let netmask = &*(current.ifa_netmask as *const libc::sockaddr_in);
+            let address = Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr));
+            let prefix_len = u32::from_be(netmask.sin_addr.s_addr).count_ones();
+            subnet = Some(format_subnet(address, prefix_len));
+            break;
+        }
+
+        libc::freeifaddrs(addresses);
+        subnet
+    }
+}
+
+/// Windows subnets are read with PowerShell
+#[cfg(not(unix))]
+fn get_system_subnet(_interface: &str) -> Option<String> {
+    None
+}
+
+/// Get the default routes from the main routing table, with rtnetlink or `ip route`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_system_default_routes() -> Result<Vec<DefaultRoute>> {
+    use crate::platform::{command, run};
+
+    if crate::backend::use_native(crate::settings::get().backend.routes, "ip", true) {
+        return rtnetlink::get_default_routes();
+    }
+
+    let mut routes = Vec::new();
+    for family in ["-4", "-6"] {
+        let output = run(command("ip").args([family, "route", "show", "default"]), "routes")?;
+        routes.extend(parse_ip_route(&String::from_utf8_lossy(&output.stdout)));
+    }
+
+    Ok(routes)
+}
+
+/// Get the default routes from the lines of `ip route show default`, such as
+/// `default via 192.168.1.1 dev wlan0 proto dhcp metric 600`
+///
+/// Multipath routes, whose next hops are listed on the lines below, are skipped as rtnetlink
+/// skips them. A route without a metric has metric 0.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_ip_route(output: &str) -> Vec<DefaultRoute> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if tokens.first() != Some(&"default") {
+                return None;
+            }
+
+            let value =
+                |key: &str| tokens.windows(2).find(|pair| pair[0] == key).map(|pair| pair[1]);
+            Some(DefaultRoute {
+                interface: value("dev")?.to_string(),
+                gateway: value("via").and_then(|gateway| gateway.parse().ok()),
+                metric: Some(value("metric").and_then(|metric| metric.parse().ok()).unwrap_or(0)),
+            })
+        })
+        .collect()
+}
+
+/// Get the best IPv4 and IPv6 routes to any destination with `GetBestRoute2`, or the default
+/// routes with PowerShell. PowerShell is always installed but slow to start, so it is only used
+/// when the command backend is selected.
+#[cfg(windows)]
+fn get_system_default_routes() -> Result<Vec<DefaultRoute>> {
+    if crate::settings::get().backend.routes == crate::backend::Backend::Command {
+        return crate::powershell::get_default_routes();
+    }
+
+    Ok(iphlpapi::get_default_routes())
+}
+
+/// Get the default routes from `route -n get`, which macOS, the BSDs and illumos share
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn get_system_default_routes() -> Result<Vec<DefaultRoute>> {
+    use crate::platform::{command, run};
+
+    if crate::backend::use_native(crate::settings::get().backend.routes, "route", false) {
+        return Err(crate::error::Error::Unsupported("The native routes backend"));
+    }
+
+    let mut routes = Vec::new();
+    for args in [&["-n", "get", "default"][..], &["-n", "get", "-inet6", "default"]] {
+        // A missing default route is reported with a non-zero exit status.
+        let output = run(command("route").args(args), "routes")?;
+        if !output.status.success() {
+            continue;
+        }
+
+        routes.extend(parse_route_get(&String::from_utf8_lossy(&output.stdout)));
+    }
+
+    Ok(routes)
+}
+
+/// Get the default route from the "key: value" lines of `route -n get default`
+///
+/// On-link gateways are printed as `link#<index>` and link-local ones carry a zone, neither is
+/// needed to identify the next hop.
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn parse_route_get(output: &str) -> Option<DefaultRoute> {
+    let value = |key: &str| {
+        output.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            (name.trim() == key).then(|| value.trim())
+        })
+    };
+
+    Some(DefaultRoute {
+        interface: value("interface")?.to_string(),
+        gateway: value("gateway").and_then(|gateway| gateway.split('%').next()?.parse().ok()),
+        metric: None,
+    })
+}
+
+/// A minimal rtnetlink reader for the default routes
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod rtnetlink {
+    use super::DefaultRoute;
+    use crate::error::Result;
+    use crate::netlink::{self, NETLINK_ROUTE, NLM_F_DUMP, NLM_F_REQUEST};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    const RTM_GETROUTE: u16 = 26;
+    const RTM_HEADER_LEN: usize = 12;
+    const RT_TABLE_MAIN: u32 = 254;
+    const RTN_UNICAST: u8 = 1;
+    const RTA_OIF: u16 = 4;
+    const RTA_GATEWAY: u16 = 5;
+    const RTA_PRIORITY: u16 = 6;
+    const RTA_TABLE: u16 = 15;
+
+    /// Get the unicast routes with a zero-length destination prefix from the main table
+    pub fn get_default_routes() -> Result<Vec<DefaultRoute>> {
+        let socket = netlink::open(NETLINK_ROUTE)?;
+
+        // struct rtmsg with AF_UNSPEC, dumping both address families.
+        netlink::send(
+            &socket,
+            RTM_GETROUTE,
+            NLM_F_REQUEST | NLM_F_DUMP,
+            &[0; RTM_HEADER_LEN],
+            &[],
+        )?;
+
+        let mut routes = Vec::new();
+        for message in netlink::receive(&socket, RTM_HEADER_LEN)? {
+            let [family, dst_len, _, _, table, _, _, kind, ..] = message.header[..] else {
+                continue;
+            };
+
+            let attribute = |wanted: u16| {
+                message
+                    .attributes
+                    .iter()
+                    .find(|(kind, _)| *kind == wanted)
+                    .map(|(_, value)| &value[..])
+            };
+            let as_u32 = |value: &[u8]| value.try_into().ok().map(u32::from_ne_bytes);
+
+            // Tables above 255 are only reported in the RTA_TABLE attribute.
+            let table = attribute(RTA_TABLE).and_then(as_u32).unwrap_or(u32::from(table));
+            if dst_len != 0 || kind != RTN_UNICAST || table != RT_TABLE_MAIN {
+                continue;
+            }
+
+            let Some(index) = attribute(RTA_OIF).and_then(as_u32) else {
+                continue;
+            };
+
+            let gateway = attribute(RTA_GATEWAY).and_then(|value| match i32::from(family) {
+                libc::AF_INET => <[u8; 4]>::try_from(value)
+                    .ok()
+                    .map(|octets| IpAddr::from(Ipv4Addr::from(octets))),
+                libc::AF_INET6 => <[u8; 16]>::try_from(value)
+                    .ok()
+                    .map(|octets| IpAddr::from(Ipv6Addr::from(octets))),
+                _ => None,
+            });
+
+            routes.push(DefaultRoute {
+                interface: interface_name(index),
+                gateway,
+                metric: Some(attribute(RTA_PRIORITY).and_then(as_u32).unwrap_or(0)),
+            });
+        }
+
+        Ok(routes)
+    }
+
+    /// Get the name of an interface given its index, falling back to the index itself
+    fn interface_name(index: u32) -> String {
+        let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+
+        // SAFETY: the buffer is IF_NAMESIZE long, as required by if_indextoname.
+        let result = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+        if result.is_null() {
+            return index.to_string();
+        }
+
+        // SAFETY: on success the buffer holds a nul-terminated name.
+        unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }.to_string_lossy().to_string()
+    }
+}
+
+/// A minimal IP Helper client for the default routes
+#[cfg(windows)]
+mod iphlpapi {
+    use super::DefaultRoute;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        ConvertInterfaceLuidToAlias, GetBestRoute2, MIB_IPFORWARD_ROW2,
+    };
+    use windows_sys::Win32::Networking::WinSock::{AF_INET, AF_INET6, SOCKADDR_INET};
+
+    /// Get the best route to the unspecified IPv4 and IPv6 addresses, which is the default route
+    pub fn get_default_routes() -> Vec<DefaultRoute> {
+        [AF_INET, AF_INET6].into_iter().filter_map(get_best_route).collect()
+    }
+
+    /// Get the best route to the unspecified address of a family
+    fn get_best_route(family: u16) -> Option<DefaultRoute> {
+        // SAFETY: zeroed SOCKADDR_INET and MIB_IPFORWARD_ROW2 are valid, and every pointer
+        // passed to GetBestRoute2 outlives the call.
+        unsafe {
+            let mut destination: SOCKADDR_INET = std::mem::zeroed();
+            destination.si_family = family;
+            let mut route: MIB_IPFORWARD_ROW2 = std::mem::zeroed();
+            let mut source: SOCKADDR_INET = std::mem::zeroed();
+            let result = GetBestRoute2(
+                std::ptr::null(),
+                0,
+                std::ptr::null(),
+                &destination,
+                0,
+                &mut route,
+                &mut source,
+            );
+            if result != 0 {
+                return None;
+            }
+
+            // The unspecified next hop marks an on-link route.
+            let gateway = match route.NextHop.si_family {
+                AF_INET => IpAddr::from(Ipv4Addr::from(u32::from_be(
+                    route.NextHop.Ipv4.sin_addr.S_un.S_addr,
+                ))),
+                AF_INET6 => IpAddr::from(Ipv6Addr::from(route.NextHop.Ipv6.sin6_addr.u.Byte)),
+                _ => return None,
+            };
+
+            let mut alias = [0u16; 257];
+            let interface = if ConvertInterfaceLuidToAlias(
+                &route.InterfaceLuid,
+                alias.as_mut_ptr(),
+                alias.len(),
+            ) == 0
+            {
+                let len = alias.iter().position(|&c| c == 0).unwrap_or(alias.len());
+                String::from_utf16_lossy(&alias[..len])
+            } else {
+                route.InterfaceIndex.to_string()
+            };
+
+            Some(DefaultRoute {
+                interface,
+                gateway: Some(gateway).filter(|gateway| !gateway.is_unspecified()),
+                metric: Some(route.Metric),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_subnets() {
+        assert_eq!(format_subnet(Ipv4Addr::new(192, 168, 1, 57), 24), "192.168.1.0/24");
+        assert_eq!(format_subnet(Ipv4Addr::new(10, 1, 2, 3), 0), "0.0.0.0/0");
+        assert_eq!(format_subnet(Ipv4Addr::new(10, 1, 2, 3), 32), "10.1.2.3/32");
+    }
+
+    #[test]
+    fn parses_resolv_conf_search_domains() {
+        let contents = "# generated\nnameserver 192.168.1.1\ndomain old.example\n\
+                        search lan.example. corp.example\n";
+        assert_eq!(parse_resolv_conf_suffix(contents).as_deref(), Some("lan.example"));
+        assert_eq!(parse_resolv_conf_suffix("nameserver 1.1.1.1\n"), None);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn parses_ip_route_default_routes() {
+        let output = "default via 192.168.1.1 dev wlan0 proto dhcp src 192.168.1.57 metric 600\n\
+                      default dev wg0 scope link\n\
+                      default proto static metric 100\n\
+                      \tnexthop via 10.0.0.1 dev eth0 weight 1\n\
+                      unreachable default metric 4278198272\n";
+        let routes = parse_ip_route(output);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].to_string(), "via 192.168.1.1 dev wlan0 metric 600");
+        assert_eq!(routes[1].to_string(), "dev wg0 metric 0");
+
+        let output = "default via fe80::1 dev wlan0 proto ra metric 600 pref medium\n";
+        assert_eq!(parse_ip_route(output)[0].gateway, "fe80::1".parse().ok());
+    }
+}
@@ -0,0 +1,385 @@
+//! # Secret
+//!
+//! This module resolves the template functions looking up secrets, see [`crate::template`].
+//!
+//! * `keychain("name")`: the generic password of that name in the macOS Keychain or the Windows
+//!   Credential Manager, or the Secret Service item whose `service` attribute is that name.
+//! * `pass("path")`: the first line of that entry of gopass, or of pass if gopass is not
+//!   installed.
+//! * `op("op://vault/item/field")`: a secret reference of 1Password, read with `op read`.
+//! * `bw("item#field")`: a field of a Bitwarden item, its password without `#field`.
+//! * `vault("mount/path:field")`: a field of a HashiCorp Vault KV secret, read from `VAULT_ADDR`
+//!   with `VAULT_TOKEN` or the token of `vault login`.
+//!
+//! Looked-up values are kept in memory for `cache_ttl` seconds, so a secret used by several
+//! fragments, or by the generations of a long-running process, is only looked up once. They are
+//! never written to disk.
+
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Looked-up values by function and argument, with the time they were looked up
+type Lookups = HashMap<(String, String), (String, Instant)>;
+
+static CACHE: Mutex<Option<Lookups>> = Mutex::new(None);
+
+/// The fields of Bitwarden items `bw get` can read
+const BW_FIELDS: &[&str] = &["password", "username", "notes", "totp", "uri"];
+
+/// How secrets are looked up
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretSettings {
+    /// Seconds looked-up values are kept in memory, 0 disables keeping them. Defaults to 300.
+    pub cache_ttl: u64,
+    /// The 1Password account of `op`, e.g. `my.1password.com`, defaults to the last one used
+    pub op_account: Option<String>,
+    /// A file holding the Bitwarden session key, as printed by `bw unlock --raw`, used when
+    /// `BW_SESSION` is not set
+    pub bw_session_file: Option<PathBuf>,
+}
+
+impl Default for SecretSettings {
+    fn default() -> Self {
+        SecretSettings { cache_ttl: 300, op_account: None, bw_session_file: None }
+    }
+}
+
+/// Resolve a template function, reusing the value of a recent lookup
+pub fn lookup(function: &str, argument: &str) -> Result<String> {
+    let ttl = Duration::from_secs(crate::settings::get().secrets.cache_ttl);
+    cached(function, argument, ttl, || match function {
+        "keychain" => keychain(argument),
+        "pass" => pass(argument),
+        "op" => op(argument),
+        "bw" => bw(argument),
+        "vault" => vault(argument),
+        _ => Err(Error::Invalid(format!("template function: {}", function))),
+    })
+}
+
+/// Get the value of a lookup from the cache if it is younger than `ttl`, or from `resolve`.
+/// Failed lookups are not kept, so they are retried, e.g. once the vault is unlocked.
+fn cached(
+    function: &str,
+    argument: &str,
+    ttl: Duration,
+    resolve: impl FnOnce() -> Result<String>,
+) -> Result<String> {
+    let key = (function.to_string(), argument.to_string());
+    if let Some((value, _)) = CACHE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get_or_insert_with(HashMap::new)
+        .get(&key)
+        .filter(|(_, resolved)| resolved.elapsed() < ttl)
+    {
+        return Ok(value.clone());
+    }
+
+    let value = resolve()?;
+    if !ttl.is_zero() {
+        let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        cache.get_or_insert_with(HashMap::new).insert(key, (value.clone(), Instant::now()));
+    }
+    Ok(value)
+}
+
+/// Look up a password in the keychain of the platform
+pub fn keychain(name: &str) -> Result<String> {
+    #[cfg(windows)]
+    {
+        credential_manager::read(name)
+    }
+
+    #[cfg(not(windows))]
+    {
+        let mut command = if cfg!(target_os = "macos") {
+            let mut command = crate::platform::command("security");
+            command.args(["find-generic-password", "-w", "-s", name]);
+            command
+        } else {
+            let mut command = crate::platform::command("secret-tool");
+            command.args(["lookup", "service", name]);
+            command
+        };
+
+        run(&mut command, &format!("keychain({:?})", name))
+    }
+}
+
+/// Look up the password, the first line, of a pass or gopass entry
+pub fn pass(path: &str) -> Result<String> {
+    let mut command = if crate::backend::is_in_path("gopass") {
+        let mut command = crate::platform::command("gopass");
+        command.args(["show", "--password", "--", path]);
+        command
+    } else {
+        let mut command = crate::platform::command("pass");
+        command.args(["show", "--", path]);
+        command
+    };
+
+    let entry = run(&mut command, &format!("pass({:?})", path))?;
+    Ok(entry.lines().next().unwrap_or_default().to_string())
+}
+
+/// Read a 1Password secret reference, `op://vault/item/field`
+pub fn op(reference: &str) -> Result<String> {
+    if !reference.starts_with("op://") {
+        let message =
+            format!("1Password secret reference: {}, expected op://vault/item/field", reference);
+        return Err(Error::Invalid(message));
+    }
+
+    let mut command = crate::platform::command("op");
+    command.arg("read").arg("--no-newline");
+    if let Some(account) = &crate::settings::get().secrets.op_account {
+        command.args(["--account", account]);
+    }
+    command.arg(reference);
+
+    run(&mut command, &format!("op({:?})", reference))
+}
+
+/// Read a field of a Bitwarden item, `item#field`, or its password without a field
+///
+/// The vault must be unlocked, with the session key in `BW_SESSION` or the `bw_session_file`
+/// setting: bw is never allowed to prompt.
+pub fn bw(item: &str) -> Result<String> {
+    let (item, field) = match item.rsplit_once('#') {
+        Some((item, field)) if BW_FIELDS.contains(&field) => (item, field),
+        _ => (item, "password"),
+    };
+
+    let mut command = crate::platform::command("bw");
+    command.args(["get", field, item, "--nointeraction"]);
+    if std::env::var_os("BW_SESSION").is_none() {
+        if let Some(file) = &crate::settings::get().secrets.bw_session_file {
+            let file = crate::platform::expand_home(file);
+            let session = crate::file::read_file(&file).map_err(Error::io("read", &file))?;
+            command.env("BW_SESSION", session.trim());
+        }
+    }
+
+    run(&mut command, &format!("bw({:?})", item)).map_err(|e| match e {
+        Error::Secret(message) if message.contains("locked") => Error::Secret(format!(
+            "{}, unlock it with `bw unlock` and set BW_SESSION or bw_session_file",
+            message
+        )),
+        e => e,
+    })
+}
+
+/// Read a field of a Vault KV secret, `mount/path:field`, from either version of the engine
+pub fn vault(reference: &str) -> Result<String> {
+    let lookup = format!("vault({:?})", reference);
+    let invalid =
+        || Error::Invalid(format!("Vault secret: {}, expected mount/path:field", reference));
+    let (path, field) = reference.rsplit_once(':').ok_or_else(invalid)?;
+    let (mount, secret) = path.trim_matches('/').split_once('/').ok_or_else(invalid)?;
+
+    let address = std::env::var("VAULT_ADDR");
+    let address =
+        address.map_err(|_| Error::Secret(format!("{}, VAULT_ADDR is not set", lookup)))?;
+    let token = std::env::var("VAULT_TOKEN").ok().or_else(|| {
+        let home = crate::platform::home_dir()?;
+        crate::file::read_file(&home.join(".vault-token")).ok()
+    });
+    let token =
+        token.ok_or_else(|| Error::Secret(format!("{}, VAULT_TOKEN is not set", lookup)))?;
+
+    // The token is given on stdin, so it doesn't show in the process list.
+    let mut headers = format!("X-Vault-Token: {}\n", token.trim());
+    if let Ok(namespace) = std::env::var("VAULT_NAMESPACE") {
+        headers += &format!("X-Vault-Namespace: {}\n", namespace);
+    }
+
+    let address = address.trim_end_matches('/');
+    let urls = [
+        (format!("{}/v1/{}/data/{}", address, mount, secret), 2),
+        (format!("{}/v1/{}", address, path), 1),
+    ];
+    for (url, version) in urls {
+        let (status, body) = http_get(&url, &headers, &lookup)?;
+        match status {
+            200 => {
+                let value = vault_field(&body, version, field);
+                return value
+                    .ok_or_else(|| Error::Secret(format!("{}, no field {}", lookup, field)));
+            }
+            404 => continue,
+            status => return Err(Error::Secret(format!("{}, Vault answered {}", lookup, status))),
+        }
+    }
+
+    Err(Error::Secret(format!("{}, not found", lookup)))
+}
+
+/// Get a field of the response of a Vault KV read, as text
+fn vault_field(body: &str, version: u8, field: &str) -> Option<String> {
+    let response: serde_json::Value = serde_json::from_str(body).ok()?;
+    let data = match version {
+        2 => &response["data"]["data"],
+        _ => &response["data"],
+    };
+
+    match &data[field] {
+        serde_json::Value::String(value) => Some(value.clone()),
+        value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => {
+            Some(value.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Get `url` with curl, with the headers given on stdin, returning the status and body
+fn http_get(url: &str, headers: &str, lookup: &str) -> Result<(u16, String)> {
+    let mut child = crate::platform::command("curl")
+        .args([
+            "--silent",
+            "--show-error",
+            "--max-time",
+            "30",
+            "--header",
+            "@-",
+            "--write-out",
+            "\n%{http_code}",
+        ])
+        .arg(url)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| Error::ToolUnavailable("curl".to_string()))?;
+
+    let Some(mut stdin) = child.stdin.take() else {
+        return Err(Error::ToolUnavailable("curl".to_string()));
+    };
+    let headers = headers.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(headers.as_bytes()));
+    let output =
+        child.wait_with_output().map_err(|_| Error::ToolUnavailable("curl".to_string()))?;
+    let _ = writer.join();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status) = stdout.rsplit_once('\n').unwrap_or_default();
+    match status.trim().parse() {
+        Ok(status) if output.status.success() => Ok((status, body.to_string())),
+        _ => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            Err(Error::Secret(format!("{} with curl: {}", lookup, stderr.trim())))
+        }
+    }
+}
+
+/// Run a secret store command, returning its output
+///
+/// The output is only returned when the command succeeds with some output, an error otherwise.
+/// `lookup` names the looked-up secret in errors, which never include the output.
+fn run(command: &mut Command, lookup: &str) -> Result<String> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command.output().map_err(|_| Error::ToolUnavailable(program.clone()))?;
+
+    let value = String::from_utf8(output.stdout)
+        .map_err(|_| Error::Secret(format!("{}, not UTF-8", lookup)))?;
+    if !output.status.success() || value.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = stderr.lines().next().unwrap_or("not found").trim().to_string();
+        return Err(Error::Secret(format!("{} with {}: {}", lookup, program, reason)));
+    }
+
+    Ok(value)
+}
+
+#[cfg(windows)]
+mod credential_manager {
+    use crate::error::{Error, Result};
+    use windows_sys::Win32::Security::Credentials::{
+        CredFree, CredReadW, CREDENTIALW, CRED_TYPE_GENERIC,
+    };
+
+    /// Read the password of a generic credential
+    pub fn read(name: &str) -> Result<String> {
+        let target: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        let mut credential: *mut CREDENTIALW = std::ptr::null_mut();
+
+        // SAFETY: the target is nul-terminated, and on success the credential is freed once
+        // its blob has been copied.
+        let blob = unsafe {
+            if CredReadW(target.as_ptr(), CRED_TYPE_GENERIC, 0, &mut credential) == 0 {
+                return Err(Error::Secret(format!(
+                    "keychain({:?}) in the Credential Manager",
+                    name
+                )));
+            }
+
+            let size = (*credential).CredentialBlobSize as usize;
+            let blob = if size == 0 {
+                Vec::new()
+            } else {
+                std::slice::from_raw_parts((*credential).CredentialBlob, size).to_vec()
+            };
+            CredFree(credential.cast());
+            blob
+        };
+
+        // cmdkey and PowerShell store UTF-16 passwords, other tools UTF-8.
+        let utf16: Option<Vec<u16>> = (blob.len() % 2 == 0).then(|| {
+            blob.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect()
+        });
+        utf16
+            .and_then(|utf16| String::from_utf16(&utf16).ok())
+            .filter(|password| !password.contains('\0'))
+            .or_else(|| String::from_utf8(blob).ok())
+            .ok_or_else(|| Error::Secret(format!("keychain({:?}), not text", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caches_successful_lookups() {
+        let mut lookups = 0;
+        let mut resolve = |value: Result<String>| {
+            lookups += 1;
+            value
+        };
+
+        let ttl = Duration::from_secs(60);
+        assert!(cached("test", "fails", ttl, || resolve(Err(Error::Secret("test".to_string()))))
+            .is_err());
+        assert_eq!(cached("test", "fails", ttl, || resolve(Ok("a".to_string()))).unwrap(), "a");
+        assert_eq!(cached("test", "fails", ttl, || resolve(Ok("b".to_string()))).unwrap(), "a");
+        assert_eq!(
+            cached("test", "uncached", Duration::ZERO, || resolve(Ok("c".to_string()))).unwrap(),
+            "c"
+        );
+        assert_eq!(
+            cached("test", "uncached", Duration::ZERO, || resolve(Ok("d".to_string()))).unwrap(),
+            "d"
+        );
+        assert_eq!(lookups, 4);
+    }
+
+    #[test]
+    fn reads_vault_fields() {
+        let v2 = r#"{"data": {"data": {"hostname": "bastion.example.com", "port": 2222},
+                     "metadata": {}}}"#;
+        assert_eq!(vault_field(v2, 2, "hostname").as_deref(), Some("bastion.example.com"));
+        assert_eq!(vault_field(v2, 2, "port").as_deref(), Some("2222"));
+        assert_eq!(vault_field(v2, 2, "user"), None);
+
+        let v1 = r#"{"data": {"hostname": "bastion.example.com"}}"#;
+        assert_eq!(vault_field(v1, 1, "hostname").as_deref(), Some("bastion.example.com"));
+        assert_eq!(vault_field(v1, 2, "hostname"), None);
+    }
+}
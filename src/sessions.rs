@@ -0,0 +1,322 @@
+//! # Sessions
+//!
+//! This module looks for the SSH connections a generation would leave pointing at stale host
+//! definitions. The hosts whose `HostName` or `ProxyJump` differ between the SSH config in use
+//! and the new one are checked for a running ControlMaster, with `ssh -O check`, and for `ssh`
+//! processes naming them. What happens then is set by `[sessions] check`:
+//!
+//! * `off`, the default: nothing is checked
+//! * `warn`: the sessions are reported and the config is written
+//! * `delay`: the config is written once the sessions are closed, or after `delay` seconds
+//! * `refuse`: the config is only written with `--force`
+
+use crate::error::{Error, Result};
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// How long `delay` waits for the sessions to close when not set
+const DEFAULT_DELAY: u64 = 300;
+
+/// How often `delay` looks for the sessions again
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What a generation does when sessions use hosts it changes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionCheck {
+    /// Don't look for sessions
+    #[default]
+    Off,
+    /// Report them and write the config
+    Warn,
+    /// Wait for them to close before writing the config
+    Delay,
+    /// Only write the config with `--force`
+    Refuse,
+}
+
+/// How the sessions to changed hosts are handled
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    pub check: SessionCheck,
+    /// How many seconds `delay` waits for the sessions to close, 300 by default
+    pub delay: Option<u64>,
+}
+
+impl SessionSettings {
+    /// Get how long `delay` waits for the sessions to close
+    pub fn delay(&self) -> Duration {
+        Duration::from_secs(self.delay.unwrap_or(DEFAULT_DELAY))
+    }
+
+    /// Check that the delay is set with `delay`
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.delay.is_some() && self.check != SessionCheck::Delay {
+            return Err("sessions: delay needs check = \"delay\"".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the connections to a host go
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host_name: Option<String>,
+    pub proxy_jump: Option<String>,
+}
+
+/// A connection to a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub host: String,
+    /// The process ID of the `ssh` client, `None` for a ControlMaster
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "ssh {} (pid {})", self.host, pid),
+            None => write!(f, "the ControlMaster of {}", self.host),
+        }
+    }
+}
+
+/// Get the targets of the hosts named in the `Host` lines of an SSH config. As with `ssh`, the
+/// first value given for a host is used. Patterns and `Match` blocks are skipped.
+pub fn targets(ssh_config: &str) -> BTreeMap<String, Target> {
+    let mut targets: BTreeMap<String, Target> = BTreeMap::new();
+    let mut hosts: Vec<String> = Vec::new();
+    for line in ssh_config.lines().map(str::trim) {
+        let (keyword, value) =
+            line.split_once(|c: char| c.is_whitespace() || c == '=').unwrap_or((line, ""));
+        let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim_end();
+        if keyword.eq_ignore_ascii_case("host") {
+            let names = value.split_whitespace().filter(|name| !name.contains(['*', '?', '!']));
+            hosts = names.map(str::to_string).collect();
+            for host in &hosts {
+                targets.entry(host.clone()).or_default();
+            }
+        } else if keyword.eq_ignore_ascii_case("match") {
+            hosts.clear();
+        } else if keyword.eq_ignore_ascii_case("hostname")
+            || keyword.eq_ignore_ascii_case("proxyjump")
+        {
+            for host in &hosts {
+                let target = targets.entry(host.clone()).or_default();
+                let field = if keyword.eq_ignore_ascii_case("hostname") {
+                    &mut target.host_name
+                } else {
+                    &mut target.proxy_jump
+                };
+                field.get_or_insert_with(|| value.to_string());
+            }
+        }
+    }
+
+    targets
+}
+
+/// Get the hosts of `current` whose target differs in `new`, or that `new` no longer has
+pub fn changed_hosts(current: &str, new: &str) -> Vec<String> {
+    let new = targets(new);
+    targets(current)
+        .into_iter()
+        .filter(|(host, target)| new.get(host) != Some(target))
+        .map(|(host, _)| host)
+        .collect()
+}
+
+/// Get the sessions to `hosts` through `ssh_config_file`, whose contents are `ssh_config`
+pub fn active(ssh_config_file: &Path, ssh_config: &str, hosts: &[String]) -> Vec<Session> {
+    let mut sessions = Vec::new();
+    // Without ControlMaster there is no master to ask, and asking costs an ssh run per host.
+    if ssh_config.to_ascii_lowercase().contains("controlmaster") {
+        for host in hosts {
+            let status = crate::platform::command("ssh")
+                .arg("-F")
+                .arg(ssh_config_file)
+                .args(["-O", "check", host.as_str()])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+            if status.is_ok_and(|status| status.success()) {
+                sessions.push(Session { host: host.clone(), pid: None });
+            }
+        }
+    }
+
+    for (pid, args) in ssh_processes() {
+        let Some(host) =
+            hosts.iter().find(|host| args.iter().skip(1).any(|arg| names_host(arg, host)))
+        else {
+            continue;
+        };
+        sessions.push(Session { host: host.clone(), pid: Some(pid) });
+    }
+
+    sessions
+}
+
+/// Check whether an argument of `ssh` is `host`, `user@host` or `ssh://[user@]host[:port]`
+fn names_host(arg: &str, host: &str) -> bool {
+    let destination =
+        arg.strip_prefix("ssh://").map_or(arg, |url| url.split(['/', ':']).next().unwrap_or(url));
+    let destination = destination.rsplit_once('@').map_or(destination, |(_, host)| host);
+    destination == host
+}
+
+/// Get the process IDs and arguments of the running `ssh` clients
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn ssh_processes() -> Vec<(u32, Vec<String>)> {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let pid = entry.file_name().to_str()?.parse().ok()?;
+            let cmdline = std::fs::read(entry.path().join("cmdline")).ok()?;
+            let args = cmdline
+                .split(|byte| *byte == 0)
+                .map(|arg| String::from_utf8_lossy(arg).to_string());
+            Some((pid, args.filter(|arg| !arg.is_empty()).collect()))
+        })
+        .filter(|(_, args): &(u32, Vec<String>)| {
+            args.first().is_some_and(|program| is_ssh(program))
+        })
+        .collect()
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn ssh_processes() -> Vec<(u32, Vec<String>)> {
+    let Ok(output) =
+        crate::platform::command("ps").args(["-A", "-o", "pid=", "-o", "args="]).output()
+    else {
+        return Vec::new();
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (pid, args) = line.trim_start().split_once(char::is_whitespace)?;
+            Some((pid.parse().ok()?, args.split_whitespace().map(str::to_string).collect()))
+        })
+        .filter(|(_, args): &(u32, Vec<String>)| {
+            args.first().is_some_and(|program| is_ssh(program))
+        })
+        .collect()
+}
+
+#[cfg(windows)]
+fn ssh_processes() -> Vec<(u32, Vec<String>)> {
+    let processes = crate::powershell::get_ssh_processes().unwrap_or_default();
+    let args = |command_line: String| command_line.split_whitespace().map(str::to_string).collect();
+    processes.into_iter().map(|(pid, command_line)| (pid, args(command_line))).collect()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn ssh_processes() -> Vec<(u32, Vec<String>)> {
+    Vec::new()
+}
+
+/// Check whether a program is the `ssh` client
+#[cfg(unix)]
+fn is_ssh(program: &str) -> bool {
+    Path::new(program).file_name().is_some_and(|name| name == "ssh")
+}
+
+/// Look for the sessions to the hosts `new_config` changes in the SSH config in use, and warn,
+/// wait or refuse to go on as `[sessions] check` says. With `force` it is never refused.
+pub fn check(ssh_config_file: &Path, new_config: &str, force: bool) -> Result<()> {
+    let settings = &crate::settings::get().sessions;
+    if settings.check == SessionCheck::Off {
+        return Ok(());
+    }
+
+    let (current_file, current) = crate::network::read_active(ssh_config_file).unwrap_or_default();
+    // The hosts written outside the managed region are left alone.
+    let hosts = changed_hosts(crate::region::body(&current).unwrap_or(&current), new_config);
+    if hosts.is_empty() {
+        return Ok(());
+    }
+    verbose_println!("Changed hosts: {}", hosts.join(", "));
+
+    let mut sessions = active(ssh_config_file, &current, &hosts);
+    if sessions.is_empty() {
+        return Ok(());
+    }
+
+    if settings.check == SessionCheck::Delay {
+        let deadline = Instant::now() + settings.delay();
+        let message = format!(
+            "Waiting up to {}s for {} to close.",
+            settings.delay().as_secs(),
+            list(&sessions)
+        );
+        crate::platform::warn_once("sessions", &message);
+        while !sessions.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL);
+            sessions = active(ssh_config_file, &current, &hosts);
+        }
+        if sessions.is_empty() {
+            return Ok(());
+        }
+    } else if settings.check == SessionCheck::Refuse && !force {
+        return Err(Error::ActiveSessions(list(&sessions)));
+    }
+
+    let current_file = current_file.display();
+    let message = format!(
+        "The previous definitions of {} are still used by {}.",
+        current_file,
+        list(&sessions)
+    );
+    crate::platform::warn_once(&format!("sessions {}", list(&sessions)), &message);
+    Ok(())
+}
+
+/// List sessions for a message
+fn list(sessions: &[Session]) -> String {
+    sessions.iter().map(Session::to_string).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_hosts_whose_target_changed() {
+        let current = "Host nas backup\n  HostName 192.168.1.10\n\n\
+                       Host web\n  HostName=web.example.com\n  ProxyJump bastion\n\
+                       \x20 HostName ignored\n\n\
+                       Host *\n  HostName other\n\nHost old\n  User me\n";
+        let targets = targets(current);
+        let web = Target {
+            host_name: Some("web.example.com".into()),
+            proxy_jump: Some("bastion".into()),
+        };
+        assert_eq!(targets["web"], web);
+        assert_eq!(targets["backup"].host_name.as_deref(), Some("192.168.1.10"));
+        assert_eq!(targets["old"], Target::default());
+        assert!(!targets.contains_key("*"));
+
+        let new = "Host nas backup\n  HostName nas.example.com\n\n\
+                   Host web\n  HostName web.example.com\n  ProxyJump bastion\n";
+        assert_eq!(changed_hosts(current, new), ["backup", "nas", "old"]);
+        assert!(changed_hosts(current, current).is_empty());
+
+        assert!(names_host("me@nas", "nas"));
+        assert!(names_host("ssh://me@nas:2222", "nas"));
+        assert!(!names_host("nas2", "nas"));
+    }
+}
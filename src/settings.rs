@@ -0,0 +1,405 @@
+//! # Settings
+//!
+//! This module contains the application settings loaded from `~/.config/sshconfgen/config.toml`.
+
+use crate::backend::BackendSettings;
+use crate::backup::BackupSettings;
+use crate::error::{Error, Result};
+use crate::network::Network;
+use crate::openssh::OpensshSettings;
+use crate::ping::TimeoutSettings;
+use crate::secret::SecretSettings;
+use crate::sessions::SessionSettings;
+use crate::signature::SignatureSettings;
+use crate::smoke::SmokeTestSettings;
+use crate::source::Source;
+use crate::state::TtlSettings;
+use crate::sync::SyncSettings;
+use crate::terminal::TerminalSettings;
+use crate::update::UpdateSettings;
+use serde::Deserialize;
+use std::{path::PathBuf, process::Command, sync::OnceLock};
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Application settings, every entry is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Command printing the connected SSIDs, replacing the built-in detection
+    pub ssid_command: Option<Vec<String>>,
+    /// Regex applied to each line of `ssid_command` output, capturing `ssid` and optionally
+    /// `interface`. Defaults to the whole line being the SSID.
+    pub ssid_pattern: Option<String>,
+    /// Command printing the neighbor entry of `{ip}`, replacing the built-in ARP lookup
+    pub hwaddr_command: Option<Vec<String>>,
+    /// Regex applied to the `hwaddr_command` output, capturing `mac`
+    pub hwaddr_pattern: Option<String>,
+    /// Command pinging `{host}` once, succeeding when it exits with status 0
+    pub ping_command: Option<Vec<String>>,
+    /// Directory holding `config` and `config.d`, e.g. `C:\\ProgramData\\ssh` for the system-wide
+    /// OpenSSH for Windows config. Defaults to `~/.ssh`.
+    pub ssh_dir: Option<PathBuf>,
+    /// The generated SSH client config file, e.g. `C:\\ProgramData\\ssh\\ssh_config`. Defaults to
+    /// `config` in `ssh_dir`.
+    pub ssh_config: Option<PathBuf>,
+    /// Directory of the fragments. Defaults to `config.d` in `ssh_dir`.
+    pub config_dir: Option<PathBuf>,
+    /// Directory of the fragments provided by the administrator, layered under `config.d`.
+    /// Defaults to `/etc/sshconfgen/config.d`, or `%ProgramData%\\sshconfgen\\config.d` on Windows.
+    /// An empty path disables it.
+    pub system_config_dir: Option<PathBuf>,
+    /// The age identity `(encrypted)` sections are decrypted with. Defaults to `id_ed25519` or
+    /// `id_rsa` in the `.ssh` directory.
+    pub age_identity: Option<PathBuf>,
+    /// Seconds the conditions of all fragments may take to probe, fragments still being probed
+    /// afterwards use their remote rules. Defaults to 10.
+    pub probe_budget: Option<u64>,
+    /// Generate this profile instead of evaluating conditions: `local` or `remote` uses the rules
+    /// of that kind of every fragment, the name of a `[[network]]` generates that network
+    pub profile: Option<String>,
+    /// Bounds of the ping timeout, which is scaled to the round trip time of the gateway
+    pub ping_timeout: TimeoutSettings,
+    /// Seconds the results of slow probes are kept in the state file between runs, while the
+    /// network fingerprint is unchanged
+    pub state_ttl: TtlSettings,
+    /// Whether each capability uses external commands or the native implementation
+    pub backend: BackendSettings,
+    /// Seconds between SSID polls of `--monitor-ssid` without an interval. Defaults to 20.
+    pub monitor_interval: Option<u64>,
+    /// Print progress as with `--verbose`
+    pub verbose: bool,
+    /// Report evaluation durations as with `--timings`
+    pub timings: bool,
+    /// Where progress is printed
+    pub log: LogTarget,
+    /// Write the profile and SSIDs of each generation to the env file, see [`crate::env_file`]
+    pub env_file: bool,
+    /// Append every generation and restored backup to the audit log, see [`crate::audit`]
+    pub audit_log: bool,
+    /// How the generated config is checked against the OpenSSH client, see [`crate::openssh`]
+    pub openssh: OpensshSettings,
+    /// The terminal profiles written with the hosts of each generation, see [`crate::terminal`]
+    pub terminal: TerminalSettings,
+    /// The compressed backups of the replaced SSH configs, see [`crate::backup`]
+    pub backups: BackupSettings,
+    /// What a generation changing the hosts of open sessions does, see [`crate::sessions`]
+    pub sessions: SessionSettings,
+    /// The canary hosts connected to after each generation, see [`crate::smoke`]
+    pub smoke_test: SmokeTestSettings,
+    /// Line endings of the generated config
+    pub newline: Newline,
+    /// The git repository of shared fragments pulled by `sshconfgen sync`
+    pub sync: Option<SyncSettings>,
+    /// Remote fragment bundles fetched by `sshconfgen sync`, given as `[[source]]` tables
+    #[serde(rename = "source")]
+    pub sources: Vec<Source>,
+    /// The keys fragments and bundles must be signed with
+    pub signatures: SignatureSettings,
+    /// How the secrets of templates are looked up
+    pub secrets: SecretSettings,
+    /// Where `sshconfgen self-update` downloads releases from, see [`crate::update`]
+    pub update: UpdateSettings,
+    /// Named networks given as `[[network]]` tables, each generated into its own include file
+    #[serde(rename = "network")]
+    pub networks: Vec<Network>,
+}
+
+/// Where progress is printed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    #[default]
+    Stdout,
+    /// Keeps stdout for output, e.g. the JSON of `sshconfgen fingerprint`
+    Stderr,
+}
+
+/// Line endings of the generated config
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Newline {
+    /// CRLF on Windows, LF elsewhere
+    #[default]
+    Native,
+    Lf,
+    Crlf,
+}
+
+impl Newline {
+    /// Get the line ending
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Newline::Native if cfg!(windows) => "\r\n",
+            Newline::Native | Newline::Lf => "\n",
+            Newline::Crlf => "\r\n",
+        }
+    }
+}
+
+/// The environment variables overriding settings, each named after the setting in capitals
+pub const ENV_VARS: [&str; 10] = [
+    "SSHCONFGEN_SSH_DIR",
+    "SSHCONFGEN_CONFIG_DIR",
+    "SSHCONFGEN_OUTPUT",
+    "SSHCONFGEN_SYSTEM_CONFIG_DIR",
+    "SSHCONFGEN_PROFILE",
+    "SSHCONFGEN_TIMEOUT",
+    "SSHCONFGEN_MONITOR_INTERVAL",
+    "SSHCONFGEN_VERBOSE",
+    "SSHCONFGEN_TIMINGS",
+    "SSHCONFGEN_LOG",
+];
+
+/// Get the path of the settings file
+///
+/// `$SSHCONFGEN_SETTINGS` takes precedence. `$XDG_CONFIG_HOME` is honored on every platform,
+/// otherwise `~/.config` is used.
+pub fn settings_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("SSHCONFGEN_SETTINGS").filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| crate::platform::home_dir().map(|home| home.join(".config")))?;
+
+    Some(config_dir.join("sshconfgen").join("config.toml"))
+}
+
+/// Load the settings file, a missing file yields the default settings, then apply the overrides
+/// of the environment variables, see [`ENV_VARS`]
+pub fn load() -> Result<Settings> {
+    let path = settings_path().filter(|path| path.exists());
+    let mut settings = match &path {
+        Some(path) => {
+            let contents = crate::file::read_file(path).map_err(Error::io("read", path))?;
+            let invalid = |message: String| Error::Settings { path: path.clone(), message };
+            toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?
+        }
+        None => Settings::default(),
+    };
+
+    settings.apply_env(|name| std::env::var(name).ok()).map_err(Error::Environment)?;
+
+    let source = path.unwrap_or_else(|| PathBuf::from("the environment"));
+    settings.validate().map_err(|message| Error::Settings { path: source, message })?;
+    Ok(settings)
+}
+
+/// Get the environment variables overriding settings that are set, by name
+pub fn env_overrides() -> Vec<(&'static str, String)> {
+    ENV_VARS
+        .into_iter()
+        .filter_map(|name| {
+            Some((name, std::env::var(name).ok().filter(|value| !value.is_empty())?))
+        })
+        .collect()
+}
+
+/// Parse a boolean environment variable
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Set the settings used for the rest of the run
+pub fn init(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Get the settings of the current run
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+impl Settings {
+    /// Override the settings with the environment variables `var` gets, an empty variable being
+    /// unset
+    fn apply_env(
+        &mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> std::result::Result<(), String> {
+        for name in ENV_VARS {
+            let Some(value) = var(name).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            let invalid =
+                |expected: &str| format!("{} must be {}, not {:?}", name, expected, value);
+            let seconds = || value.parse::<u64>().map_err(|_| invalid("a number of seconds"));
+            let boolean = || parse_bool(&value).ok_or_else(|| invalid("true or false"));
+
+            match name {
+                "SSHCONFGEN_SSH_DIR" => self.ssh_dir = Some(PathBuf::from(&value)),
+                "SSHCONFGEN_CONFIG_DIR" => self.config_dir = Some(PathBuf::from(&value)),
+                "SSHCONFGEN_OUTPUT" => self.ssh_config = Some(PathBuf::from(&value)),
+                "SSHCONFGEN_SYSTEM_CONFIG_DIR" => {
+                    self.system_config_dir = Some(PathBuf::from(&value))
+                }
+                "SSHCONFGEN_PROFILE" => self.profile = Some(value.clone()),
+                "SSHCONFGEN_TIMEOUT" => self.probe_budget = Some(seconds()?),
+                "SSHCONFGEN_MONITOR_INTERVAL" => self.monitor_interval = Some(seconds()?),
+                "SSHCONFGEN_VERBOSE" => self.verbose = boolean()?,
+                "SSHCONFGEN_TIMINGS" => self.timings = boolean()?,
+                "SSHCONFGEN_LOG" => {
+                    self.log = match value.as_str() {
+                        "stdout" => LogTarget::Stdout,
+                        "stderr" => LogTarget::Stderr,
+                        _ => return Err(invalid("stdout or stderr")),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the command overrides are usable
+    fn validate(&self) -> std::result::Result<(), String> {
+        for (name, command) in [
+            ("ssid_command", &self.ssid_command),
+            ("hwaddr_command", &self.hwaddr_command),
+            ("ping_command", &self.ping_command),
+        ] {
+            if command.as_ref().is_some_and(|command| command.is_empty()) {
+                return Err(format!("{} must not be empty", name));
+            }
+        }
+
+        for (name, pattern, group) in [
+            ("ssid_pattern", &self.ssid_pattern, "ssid"),
+            ("hwaddr_pattern", &self.hwaddr_pattern, "mac"),
+        ] {
+            let Some(pattern) = pattern else {
+                continue;
+            };
+
+            let regex = regex::Regex::new(pattern).map_err(|e| format!("{}: {}", name, e))?;
+            if !regex.capture_names().any(|capture| capture == Some(group)) {
+                return Err(format!("{} must capture (?P<{}>...)", name, group));
+            }
+        }
+
+        if self.monitor_interval == Some(0) {
+            return Err("monitor_interval must be positive".to_string());
+        }
+
+        if self.ping_timeout.max == 0 || self.ping_timeout.min > self.ping_timeout.max {
+            return Err("ping_timeout needs 0 < min <= max".to_string());
+        }
+
+        if self.sync.as_ref().is_some_and(|sync| sync.repository.is_empty()) {
+            return Err("sync.repository must not be empty".to_string());
+        }
+
+        self.signatures.validate()?;
+        self.terminal.validate()?;
+        self.backups.validate()?;
+        self.update.validate()?;
+        self.sessions.validate()?;
+        self.smoke_test.validate()?;
+
+        let mut names = std::collections::HashSet::new();
+        for source in &self.sources {
+            source.validate()?;
+            if source.signed && !self.signatures.enabled() {
+                return Err(format!(
+                    "source {} is signed, but there are no signature keys",
+                    source.url
+                ));
+            }
+            if !names.insert(source.name()) {
+                return Err(format!("source {} has the name of another source", source.url));
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+        for network in &self.networks {
+            network.validate()?;
+            if !names.insert(&network.name) {
+                return Err(format!("network {} is defined more than once", network.name));
+            }
+        }
+
+        if let Some(profile) = &self.profile {
+            if !matches!(profile.as_str(), "local" | "remote") && !names.contains(profile) {
+                return Err(format!("profile {} is neither local, remote nor a network", profile));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Build a user-configured command, replacing `placeholder` in its arguments with `value`
+pub fn build_command(command: &[String], placeholder: &str, value: &str) -> Command {
+    let args: Vec<String> = command[1..]
+        .iter()
+        .map(
+            |arg| {
+                if placeholder.is_empty() {
+                    arg.clone()
+                } else {
+                    arg.replace(placeholder, value)
+                }
+            },
+        )
+        .collect();
+
+    let mut command = crate::platform::command(&command[0]);
+    command.args(args);
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_the_settings_file() {
+        let mut settings: Settings = toml::from_str("verbose = true\nprobe_budget = 10\n").unwrap();
+        let env = |name: &str| match name {
+            "SSHCONFGEN_VERBOSE" => Some("no".to_string()),
+            "SSHCONFGEN_TIMEOUT" => Some("3".to_string()),
+            "SSHCONFGEN_OUTPUT" => Some("/tmp/ssh_config".to_string()),
+            "SSHCONFGEN_PROFILE" => Some(String::new()),
+            _ => None,
+        };
+        settings.apply_env(env).unwrap();
+        assert!(!settings.verbose);
+        assert_eq!(settings.probe_budget, Some(3));
+        assert_eq!(settings.ssh_config, Some(PathBuf::from("/tmp/ssh_config")));
+        assert_eq!(settings.profile, None);
+
+        let error =
+            settings.apply_env(|name| (name == "SSHCONFGEN_TIMEOUT").then(|| "soon".to_string()));
+        assert_eq!(
+            error.unwrap_err(),
+            "SSHCONFGEN_TIMEOUT must be a number of seconds, not \"soon\""
+        );
+
+        settings.profile = Some("office".to_string());
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn patterns_must_capture_the_value() {
+        let validate = |toml: &str| toml::from_str::<Settings>(toml).unwrap().validate();
+        assert!(validate("ssid_pattern = 'SSID: (?P<ssid>.+)'\n").is_ok());
+        assert!(validate("hwaddr_pattern = 'at (?P<mac>[0-9a-f:]+)'\n").is_ok());
+        assert_eq!(
+            validate("ssid_pattern = 'SSID: (.+)'\n").unwrap_err(),
+            "ssid_pattern must capture (?P<ssid>...)"
+        );
+        assert_eq!(
+            validate("hwaddr_pattern = 'at (?P<ssid>.+)'\n").unwrap_err(),
+            "hwaddr_pattern must capture (?P<mac>...)"
+        );
+        assert!(validate("ssid_pattern = '('\n").is_err());
+    }
+}
@@ -0,0 +1,142 @@
+//! # Signature
+//!
+//! This module verifies the minisign signatures of fragments and fragment bundles, so rules
+//! provided by a company can't be modified without it being noticed. A fragment is signed by the
+//! `.minisig` file next to it, as written by `minisign -S -m <fragment>`.
+
+use minisign_verify::{PublicKey, Signature};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// The extension of signature files, appended to the name of the signed file
+pub const SIGNATURE_EXTENSION: &str = "minisig";
+
+/// The keys fragments and bundles are signed with
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignatureSettings {
+    /// minisign public keys in base64, e.g. the second line of `minisign.pub`
+    pub keys: Vec<String>,
+    /// Whether unsigned fragments and bundles are refused, not only those with an invalid
+    /// signature
+    pub strict: bool,
+}
+
+/// The outcome of checking a signature
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verification {
+    /// Signed by one of the keys
+    Valid,
+    /// Not signed
+    Unsigned,
+    /// Not signed by any of the keys, or modified since
+    Invalid(String),
+}
+
+impl SignatureSettings {
+    /// Get whether signatures are checked
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Decode the public keys
+    pub fn public_keys(&self) -> std::result::Result<Vec<PublicKey>, String> {
+        self.keys
+            .iter()
+            .map(|key| {
+                PublicKey::from_base64(key.trim())
+                    .map_err(|e| format!("signature key {}: {}", key, e))
+            })
+            .collect()
+    }
+
+    /// Check that the keys can be decoded, and that strict mode has keys
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.strict && !self.enabled() {
+            return Err("signatures.strict needs signatures.keys".to_string());
+        }
+
+        self.public_keys().map(|_| ())
+    }
+}
+
+/// Check that `signature`, the contents of a `.minisig` file, signs `contents` with one of `keys`
+pub fn verify(contents: &[u8], signature: Option<&str>, keys: &[PublicKey]) -> Verification {
+    let Some(signature) = signature else {
+        return Verification::Unsigned;
+    };
+
+    let signature = match Signature::decode(signature.trim()) {
+        Ok(signature) => signature,
+        Err(e) => return Verification::Invalid(e.to_string()),
+    };
+
+    let mut error = "no signature key".to_string();
+    for key in keys {
+        match key.verify(contents, &signature, true) {
+            Ok(()) => return Verification::Valid,
+            Err(e) => error = e.to_string(),
+        }
+    }
+
+    Verification::Invalid(error)
+}
+
+/// Get the signature file of a file
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", SIGNATURE_EXTENSION));
+    PathBuf::from(name)
+}
+
+/// Get whether a fragment may be included, given the signature settings: fragments with an
+/// invalid signature never are, and unsigned fragments are not in strict mode. Refused fragments
+/// are reported once per run.
+pub fn is_trusted(path: &Path, contents: &[u8]) -> bool {
+    let settings = &crate::settings::get().signatures;
+    if !settings.enabled() {
+        return true;
+    }
+
+    // The keys were validated along with the settings.
+    let keys = settings.public_keys().unwrap_or_default();
+    let signature = crate::file::read_file(&signature_path(path)).ok();
+    let reason = match verify(contents, signature.as_deref(), &keys) {
+        Verification::Valid => return true,
+        Verification::Unsigned if !settings.strict => return true,
+        Verification::Unsigned => "it is not signed".to_string(),
+        Verification::Invalid(e) => format!("its signature is invalid ({})", e),
+    };
+
+    let message = format!("Skipping {}, {}.", path.display(), reason);
+    crate::platform::warn_once(&path.to_string_lossy(), &message);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The test vector of the minisign-verify crate, signing "test".
+    const KEY: &str = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQf6LRCGA9i559r3g7V1qNyJDApGip8MfqcadIgT9CuhV3EMhHoN1mGTkUidF/z7SrlQgXdy8ofjb7bNJJylDOocrCo8KLzZwo=
+trusted comment: timestamp:1556193335\tfile:test
+y/rUw2y8/hOUYjZU71eHp/Wo1KZ40fGy2VJEDl34XMJM+TX48Ss/17u3IvIfbVR1FkZZSNCisQbuQY+bHwhEBg==";
+
+    #[test]
+    fn verifies_signatures() {
+        let keys =
+            SignatureSettings { keys: vec![KEY.to_string()], strict: true }.public_keys().unwrap();
+
+        assert_eq!(verify(b"test", Some(SIGNATURE), &keys), Verification::Valid);
+        assert_eq!(verify(b"test", None, &keys), Verification::Unsigned);
+        assert!(matches!(verify(b"tampered", Some(SIGNATURE), &keys), Verification::Invalid(_)));
+        assert!(matches!(verify(b"test", Some("garbage"), &keys), Verification::Invalid(_)));
+        assert!(matches!(verify(b"test", Some(SIGNATURE), &[]), Verification::Invalid(_)));
+
+        let other = SignatureSettings { keys: vec!["RWQf6LRCGA9i5".to_string()], strict: false };
+        assert!(other.validate().is_err());
+        assert!(SignatureSettings { keys: vec![], strict: true }.validate().is_err());
+    }
+}
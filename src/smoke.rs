@@ -0,0 +1,142 @@
+//! # Smoke test
+//!
+//! This module checks that the generated config actually connects, by running
+//! `ssh -F <config> -o BatchMode=yes -o ConnectTimeout=3 <host> true` against canary hosts. It runs
+//! with `sshconfgen test`, and after each generation changing the config when `[smoke_test] hosts`
+//! are set, in which case a failing generation can be rolled back to the previous config.
+
+use crate::error::{Error, Result};
+use crate::review::Planned;
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::path::Path;
+use std::process::Stdio;
+
+/// How many seconds `ssh` waits for a connection when not set
+const DEFAULT_TIMEOUT: u64 = 3;
+
+/// The canary hosts checked after each generation
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmokeTestSettings {
+    /// The hosts connected to after each generation, none by default
+    pub hosts: Vec<String>,
+    /// Whether to restore the previous config when a host can't be connected to
+    pub rollback: bool,
+    /// The `ConnectTimeout` of `ssh`, in seconds, 3 by default
+    pub timeout: Option<u64>,
+}
+
+impl SmokeTestSettings {
+    /// Get the `ConnectTimeout` of `ssh`
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Check that the hosts can be given to `ssh` and the timeout is positive
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if let Some(host) = self.hosts.iter().find(|host| host.is_empty() || host.starts_with('-'))
+        {
+            return Err(format!("smoke_test: {:?} is not a host", host));
+        } else if self.timeout == Some(0) {
+            return Err("smoke_test: timeout must be positive".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// The result of connecting to a host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub host: String,
+    pub connected: bool,
+    /// The last line `ssh` printed on stderr, or why it couldn't be run
+    pub message: String,
+}
+
+/// Connect to `host` with `ssh_config_file` and run `true`, without prompting for anything
+pub fn test(ssh_config_file: &Path, host: &str) -> Outcome {
+    let timeout = crate::settings::get().smoke_test.timeout();
+    verbose_println!("Connecting to {} with {}", host, ssh_config_file.display());
+    let output = crate::platform::command("ssh")
+        .arg("-F")
+        .arg(ssh_config_file)
+        .args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            &format!("ConnectTimeout={}", timeout),
+            "--",
+            host,
+            "true",
+        ])
+        .stdin(Stdio::null())
+        .output();
+
+    let (connected, message) = match output {
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = stderr.lines().rfind(|line| !line.trim().is_empty()).unwrap_or_default();
+            (output.status.success(), message.trim().to_string())
+        }
+        Err(e) => (false, format!("ssh could not be run ({})", e)),
+    };
+    Outcome { host: host.to_string(), connected, message }
+}
+
+/// Connect to the canary hosts after a generation wrote `written`, warning about the ones that
+/// fail, or restoring the previous contents of the files and failing with `rollback`
+pub fn after_generation(ssh_config_file: &Path, written: &[Planned]) -> Result<()> {
+    let settings = &crate::settings::get().smoke_test;
+    if settings.hosts.is_empty() || written.is_empty() {
+        return Ok(());
+    }
+
+    let outcomes = settings.hosts.iter().map(|host| test(ssh_config_file, host));
+    let failed: Vec<Outcome> = outcomes.filter(|outcome| !outcome.connected).collect();
+    if failed.is_empty() {
+        verbose_println!("Connected to {}", settings.hosts.join(", "));
+        return Ok(());
+    }
+
+    let hosts = failed
+        .iter()
+        .map(|outcome| format!("{} ({})", outcome.host, outcome.message))
+        .collect::<Vec<_>>();
+    if !settings.rollback {
+        let message = format!("Unable to connect to {} with the new config.", hosts.join(", "));
+        crate::platform::warn_once("smoke_test", &message);
+        return Ok(());
+    }
+
+    crate::review::restore(written)?;
+    Err(Error::SmokeTest(format!("{}, the previous config was restored", hosts.join(", "))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_the_previous_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = dir.path().join("config");
+        let include_file = dir.path().join("config.office");
+        std::fs::write(&config, "Include config.office\n").unwrap();
+        std::fs::write(&include_file, "Host a\n").unwrap();
+
+        let written = [
+            Planned { path: config.clone(), current: "Host b\n".to_string(), new: String::new() },
+            Planned { path: include_file.clone(), current: String::new(), new: String::new() },
+        ];
+        crate::review::restore(&written).unwrap();
+        assert_eq!(std::fs::read_to_string(&config).unwrap(), "Host b\n");
+        assert!(!include_file.exists());
+
+        let settings =
+            SmokeTestSettings { hosts: vec!["-oProxyCommand=x".to_string()], ..Default::default() };
+        assert!(settings.validate().is_err());
+        assert_eq!(SmokeTestSettings::default().timeout(), DEFAULT_TIMEOUT);
+    }
+}
@@ -0,0 +1,226 @@
+//! # Source
+//!
+//! This module fetches remote fragment bundles, `.tar.gz` archives served over HTTPS, e.g. by an
+//! intranet. Each bundle is pinned by its SHA-256 checksum and unpacked into its own directory
+//! under `config.d/sources`, whose fragments are generated along with the local ones.
+
+use crate::error::{Error, Result};
+use crate::signature::{Verification, SIGNATURE_EXTENSION};
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The directory of `config.d` holding the unpacked bundles
+pub const SOURCES_DIR: &str = "sources";
+
+/// The file recording the checksum of the unpacked bundle, in its directory
+const CHECKSUM_FILE: &str = ".sha256";
+
+/// A remote fragment bundle
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    /// The HTTPS URL of the `.tar.gz` bundle
+    pub url: String,
+    /// The SHA-256 checksum of the bundle, in hexadecimal
+    pub sha256: String,
+    /// The directory the bundle is unpacked into, defaults to the file name of the URL without
+    /// its extension
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Whether the bundle is signed by `<url>.minisig`, always checked in strict mode
+    #[serde(default)]
+    pub signed: bool,
+}
+
+impl Source {
+    /// Get the directory name of the bundle
+    pub fn name(&self) -> String {
+        if let Some(name) = &self.name {
+            return name.clone();
+        }
+
+        let file_name = self.url.rsplit('/').next().unwrap_or_default();
+        let file_name = file_name.split(['?', '#']).next().unwrap_or_default();
+        [".tar.gz", ".tgz"]
+            .iter()
+            .find_map(|extension| file_name.strip_suffix(extension))
+            .unwrap_or(file_name)
+            .to_string()
+    }
+
+    /// Get whether the signature of the bundle is checked
+    pub fn is_verified(&self) -> bool {
+        let settings = &crate::settings::get().signatures;
+        settings.enabled() && (self.signed || settings.strict)
+    }
+
+    /// Check that the source can be fetched
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !self.url.starts_with("https://") {
+            return Err(format!("source {} must be an https:// URL", self.url));
+        }
+
+        if self.sha256.len() != 64 || !self.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("sha256 of source {} must be 64 hexadecimal digits", self.url));
+        }
+
+        let name = self.name();
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(format!("source {} needs a name, {:?} is not valid", self.url, name));
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the directory a bundle is unpacked into
+pub fn source_dir(ssh_config_dir: &Path, source: &Source) -> PathBuf {
+    ssh_config_dir.join(SOURCES_DIR).join(source.name())
+}
+
+/// Fetch and unpack the bundles whose checksum changed, and remove the directories of bundles no
+/// longer listed. Returns the directories of the unpacked bundles.
+pub fn fetch_all(ssh_config_dir: &Path, sources: &[Source]) -> Result<Vec<PathBuf>> {
+    let mut fetched = Vec::new();
+    for source in sources {
+        let dir = source_dir(ssh_config_dir, source);
+        let checksum = crate::file::read_file(&dir.join(CHECKSUM_FILE)).unwrap_or_default();
+        if checksum.trim().eq_ignore_ascii_case(&source.sha256) {
+            verbose_println!("{} is up to date", dir.display());
+            continue;
+        }
+
+        verbose_println!("Downloading {}", source.url);
+        let download_dir = ssh_config_dir.join(SOURCES_DIR);
+        let bundle = download(&source.url, &download_dir)?;
+        let signature = if source.is_verified() {
+            let signature =
+                download(&format!("{}.{}", source.url, SIGNATURE_EXTENSION), &download_dir)?;
+            Some(String::from_utf8_lossy(&signature).to_string())
+        } else {
+            None
+        };
+        fetched.push(install(ssh_config_dir, source, &bundle, signature.as_deref())?);
+    }
+
+    let names: Vec<String> = sources.iter().map(Source::name).collect();
+    let sources_dir = ssh_config_dir.join(SOURCES_DIR);
+    for entry in fs::read_dir(&sources_dir).into_iter().flatten().filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.path().is_dir() && !name.starts_with('.') && !names.contains(&name) {
+            verbose_println!("Removing {}", entry.path().display());
+            fs::remove_dir_all(entry.path()).map_err(Error::io("remove", entry.path()))?;
+        }
+    }
+
+    Ok(fetched)
+}
+
+/// Verify a downloaded bundle against the checksum of its source, and its signature if the source
+/// is verified, then unpack it, replacing the previous version. Returns the directory it was
+/// unpacked into.
+pub fn install(
+    ssh_config_dir: &Path,
+    source: &Source,
+    bundle: &[u8],
+    signature: Option<&str>,
+) -> Result<PathBuf> {
+    let checksum = sha256_hex(bundle);
+    if !checksum.eq_ignore_ascii_case(&source.sha256) {
+        return Err(Error::Sync(format!(
+            "{} has checksum {}, expected {}",
+            source.url, checksum, source.sha256
+        )));
+    }
+
+    if source.is_verified() {
+        let keys = crate::settings::get().signatures.public_keys().unwrap_or_default();
+        let reason = match crate::signature::verify(bundle, signature, &keys) {
+            Verification::Valid => None,
+            Verification::Unsigned => Some("is not signed".to_string()),
+            Verification::Invalid(e) => Some(format!("has an invalid signature ({})", e)),
+        };
+        if let Some(reason) = reason {
+            return Err(Error::Sync(format!("{} {}", source.url, reason)));
+        }
+    }
+
+    if !crate::backend::is_in_path("tar") {
+        return Err(Error::ToolUnavailable("tar".to_string()));
+    }
+
+    // Unpacked next to the previous version first, so a broken bundle leaves it in place.
+    let dir = source_dir(ssh_config_dir, source);
+    let staging = dir.with_file_name(format!(".{}.{}.tmp", source.name(), std::process::id()));
+    let archive = staging.with_extension("tar.gz");
+    let _ = fs::remove_dir_all(&staging);
+    fs::create_dir_all(&staging).map_err(Error::io("create", &staging))?;
+    fs::write(&archive, bundle).map_err(Error::io("write", &archive))?;
+
+    let output =
+        crate::platform::command("tar").arg("-xzf").arg(&archive).arg("-C").arg(&staging).output();
+    let _ = fs::remove_file(&archive);
+    match output {
+        Ok(output) if output.status.success() => {}
+        result => {
+            let _ = fs::remove_dir_all(&staging);
+            let reason = match result {
+                Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                Err(e) => e.to_string(),
+            };
+            return Err(Error::Sync(format!("unable to unpack {}: {}", source.url, reason)));
+        }
+    }
+
+    let checksum_file = staging.join(CHECKSUM_FILE);
+    fs::write(&checksum_file, &checksum).map_err(Error::io("write", &checksum_file))?;
+    if dir.exists() {
+        fs::remove_dir_all(&dir).map_err(Error::io("remove", &dir))?;
+    }
+    fs::rename(&staging, &dir).map_err(Error::io("rename", &staging))?;
+
+    Ok(dir)
+}
+
+/// Download `url` with curl, only over HTTPS, keeping the file in `download_dir` until it is read
+pub(crate) fn download(url: &str, download_dir: &Path) -> Result<Vec<u8>> {
+    if !crate::backend::is_in_path("curl") {
+        return Err(Error::ToolUnavailable("curl".to_string()));
+    }
+
+    fs::create_dir_all(download_dir).map_err(Error::io("create", download_dir))?;
+    let path = download_dir.join(format!(".download.{}", std::process::id()));
+    let output = crate::platform::command("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--location",
+            "--proto",
+            "=https",
+            "--max-time",
+            "60",
+        ])
+        .arg("--output")
+        .arg(&path)
+        .arg(url)
+        .output()
+        .map_err(|e| Error::Sync(format!("curl could not be run ({})", e)))?;
+
+    let bundle = fs::read(&path);
+    let _ = fs::remove_file(&path);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(Error::Sync(format!("unable to download {}: {}", url, stderr.trim())));
+    }
+
+    bundle.map_err(Error::io("read", path))
+}
+
+/// Get the SHA-256 checksum of `bytes` in lowercase hexadecimal
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -2,207 +2,1322 @@
 //!
 //! This module is responsible for processing config files and generating the new SSH config file.
 
+use crate::cache::Cache;
+use crate::context::Context;
+use crate::error::Result;
 use crate::file::get_files_by_extension;
-use crate::{hwaddr, is_verbose, ping, ssid, verbose_println};
-use std::{fs, io, path::PathBuf};
+use crate::generator::{Options, Outcome, Report};
+use crate::journal::Intent;
+use crate::network::Network;
+use crate::openssh::Version;
+use crate::paths::Paths;
+use crate::phases::Timings;
+use crate::provider::Providers;
+use crate::{is_verbose, ssid, verbose_println};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
 
-/// Generate a new SSH client config file.
-pub fn ssh_config_gen() -> io::Result<()> {
-    let home_dir = match dirs::home_dir() {
-        Some(path) => path,
-        None => {
-            eprintln!("Error: Unable to determine home directory");
-            std::process::exit(1);
+/// The markers of a block of variables, see [`parse_variables`]
+const VARIABLES_BEGIN: &str = "# VARIABLES BEGIN";
+const VARIABLES_END: &str = "# VARIABLES END";
+
+/// The line splicing a shared snippet into a `.sshconf` fragment, followed by its path
+const INCLUDE_DIRECTIVE: &str = "# INCLUDE ";
+
+/// How deep `# INCLUDE` lines may nest
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// The number of hosts of a LocalPing or LocalArpPing condition probed at once
+const PROBE_THREADS: usize = 4;
+
+/// The keys of the conditions section that are evaluated against the network
+pub const CONDITION_KEYS: &[&str] =
+    &["LocalSSID", "LocalGateway", "LocalPing", "LocalArpPing", "LocalVPN"];
+
+/// The names of the sections of a fragment, as written in their markers
+pub const SECTIONS: [&str; 4] = ["CONDITIONS", "GLOBAL CONFIG", "LOCAL CONFIG", "REMOTE CONFIG"];
+
+/// A parsed `.sshconf` fragment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fragment {
+    pub path: PathBuf,
+    /// The conditions deciding between the local and remote rules, in file order
+    pub conditions: Vec<Condition>,
+    /// The other `Key value` lines of the conditions section
+    pub metadata: BTreeMap<String, String>,
+    pub global_rules: String,
+    pub local_rules: String,
+    pub remote_rules: String,
+    /// The sections whose rules are age ciphertext, decrypted when they are used
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub encrypted: BTreeSet<String>,
+    /// The snippets spliced in by `# INCLUDE` lines
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<PathBuf>,
+    /// The variables of the `VARIABLES` block
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+    /// The variables of the `VARIABLES (global)` block, shared with every fragment
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub global_variables: BTreeMap<String, String>,
+}
+
+/// A `Key value` line of the conditions section, such as `LocalPing 192.168.1.100`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Condition {
+    pub key: String,
+    pub value: String,
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.key, self.value)
+    }
+}
+
+impl Condition {
+    /// Parse the `Key value` lines of a conditions section, lines without a value are skipped
+    pub fn parse_all(section: &str) -> Vec<Condition> {
+        section
+            .lines()
+            .map(get_key_value)
+            .filter(|(key, _)| !key.is_empty())
+            .map(|(key, value)| Condition { key, value })
+            .collect()
+    }
+}
+
+impl Fragment {
+    /// Get the `Priority` of the fragment, or its alias `Order`, fragments with a lower priority
+    /// come first. Defaults to 0, as does an invalid value. `Priority` wins if both are given.
+    pub fn priority(&self) -> i64 {
+        let Some((key, priority)) =
+            ["Priority", "Order"].into_iter().find_map(|key| Some((key, self.metadata.get(key)?)))
+        else {
+            return 0;
+        };
+
+        priority.parse().unwrap_or_else(|_| {
+            verbose_println!("Ignoring invalid {} {} in {}", key, priority, self.path.display());
+            0
+        })
+    }
+
+    /// Parse the sections of a fragment, missing sections are left empty
+    ///
+    /// The fragment may start with a YAML front matter between `---` lines, mapping condition and
+    /// metadata keys to values as in `.sshconf.toml` fragments. Its conditions are added to those
+    /// of the conditions section, whose metadata takes precedence. An invalid front matter is
+    /// ignored with a warning. See [`parse_sections`] for how malformed sections are handled.
+    pub fn parse(path: &Path, contents: &str) -> Fragment {
+        let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+        let (front_matter, contents) = split_front_matter(contents);
+        let ([section, global_rules, local_rules, remote_rules], encrypted) =
+            split_sections(path, contents);
+        let (variables, global_variables) = parse_variables(path, contents);
+
+        let mut entries = front_matter
+            .map(|front_matter| {
+                parse_front_matter(front_matter).unwrap_or_else(|e| {
+                    let message = format!("Ignoring the front matter of {}: {}", path.display(), e);
+                    crate::platform::warn_once(&path.to_string_lossy(), message.trim_end());
+                    Vec::new()
+                })
+            })
+            .unwrap_or_default();
+        entries.extend(Condition::parse_all(&section));
+        let (conditions, metadata): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|condition| CONDITION_KEYS.contains(&condition.key.as_str()));
+        let encrypted = SECTIONS.iter().zip(encrypted).filter(|(_, encrypted)| *encrypted);
+
+        Fragment {
+            path: path.to_path_buf(),
+            conditions,
+            metadata: metadata
+                .into_iter()
+                .map(|condition| (condition.key, condition.value))
+                .collect(),
+            global_rules,
+            local_rules,
+            remote_rules,
+            encrypted: encrypted.map(|(name, _)| name.to_string()).collect(),
+            includes: Vec::new(),
+            variables,
+            global_variables,
         }
-    };
+    }
+}
+
+/// A `.sshconf.toml` fragment
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlFragment {
+    #[serde(default)]
+    conditions: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    global: String,
+    #[serde(default)]
+    local: String,
+    #[serde(default)]
+    remote: String,
+}
+
+impl Fragment {
+    /// Parse a `.sshconf.toml` fragment, whose `conditions` table holds the conditions and
+    /// metadata, and whose `global`, `local` and `remote` strings hold the rules
+    ///
+    /// A condition is a string, or an array of strings joined as the comma-separated list of the
+    /// marker format. `LocalGateway` entries can also be `{ ip = "...", mac = "..." }` tables.
+    pub fn parse_toml(path: &Path, contents: &str) -> std::result::Result<Fragment, String> {
+        let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+        let fragment: TomlFragment = toml::from_str(contents).map_err(|e| e.to_string())?;
+
+        let mut conditions = Vec::new();
+        let mut metadata = BTreeMap::new();
+        for (key, value) in fragment.conditions {
+            let value = condition_value(&key, &value)?;
+            if CONDITION_KEYS.contains(&key.as_str()) {
+                conditions.push(Condition { key, value });
+            } else {
+                metadata.insert(key, value);
+            }
+        }
+
+        Ok(Fragment {
+            path: path.to_path_buf(),
+            conditions,
+            metadata,
+            global_rules: fragment.global.trim().to_string(),
+            local_rules: fragment.local.trim().to_string(),
+            remote_rules: fragment.remote.trim().to_string(),
+            encrypted: BTreeSet::new(),
+            includes: Vec::new(),
+            variables: BTreeMap::new(),
+            global_variables: BTreeMap::new(),
+        })
+    }
 
-    let ssh_dir = home_dir.join(".ssh");
-    let ssh_config_file = ssh_dir.join("config");
-    let ssh_config_dir = ssh_dir.join("config.d/");
-    let timestamp = chrono::Local::now().format("%Y%m%d%H%M%S").to_string();
-    let sshd_config_backup_file = ssh_dir.join(&format!("config.{}.orig", timestamp));
+    /// Format the fragment in the marker format, omitting empty sections
+    pub fn to_sshconf(&self) -> String {
+        let conditions = self
+            .conditions
+            .iter()
+            .map(Condition::to_string)
+            .chain(self.metadata.iter().map(|(key, value)| format!("{} {}", key, value)))
+            .collect::<Vec<_>>()
+            .join("\n");
 
-    parse_and_process(&ssh_config_dir, &ssh_config_file, &sshd_config_backup_file);
-    cleanup(&ssh_config_file, &sshd_config_backup_file);
+        let variables = |variables: &BTreeMap<String, String>, tag: &str| {
+            let lines: Vec<String> =
+                variables.iter().map(|(key, value)| format!("{} = {}", key, value)).collect();
+            (!lines.is_empty())
+                .then(|| format!("{VARIABLES_BEGIN}{tag}\n{}\n{VARIABLES_END}\n", lines.join("\n")))
+        };
+        let blocks =
+            [variables(&self.global_variables, " (global)"), variables(&self.variables, "")];
 
-    Ok(())
+        let sections = [&conditions, &self.global_rules, &self.local_rules, &self.remote_rules]
+            .into_iter()
+            .zip(SECTIONS)
+            .filter(|(section, _)| !section.is_empty())
+            .map(|(section, name)| {
+                let encrypted = if self.encrypted.contains(name) { " (encrypted)" } else { "" };
+                format!("# {name} BEGIN{encrypted}\n{section}\n# {name} END\n")
+            });
+        blocks.into_iter().flatten().chain(sections).collect::<Vec<_>>().join("\n")
+    }
 }
 
-/// Parse and process the config files.
-fn parse_and_process(ssh_config_dir: &PathBuf, ssh_config_file: &PathBuf, sshd_config_backup_file: &PathBuf) {
-    let mut config_files = get_files_by_extension(&ssh_config_dir, crate::CONFIG_EXTENSION);
+/// Parse the `# VARIABLES BEGIN` blocks of a fragment into the variables of the fragment and,
+/// from the blocks tagged `(global)`, those shared with every fragment. Each line of a block is a
+/// `name = value` pair, optionally quoted; blank lines and `#` comments are skipped. A block ends
+/// with `# VARIABLES END` or at the start of a section.
+pub fn parse_variables(
+    path: &Path,
+    contents: &str,
+) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+    let (mut variables, mut global_variables) = (BTreeMap::new(), BTreeMap::new());
+    let mut block: Option<&mut BTreeMap<String, String>> = None;
 
-    // If there are no config files, return early.
-    if config_files.is_empty() {
-        verbose_println!("No config files found in {}", ssh_config_dir.display());
-        return;
+    for line in contents.lines() {
+        let line = line.trim();
+        if let Some(tag) = line.strip_prefix(VARIABLES_BEGIN) {
+            block = match tag.trim() {
+                "" => Some(&mut variables),
+                "(global)" => Some(&mut global_variables),
+                _ => {
+                    verbose_println!("Ignoring the {} block of {}", line, path.display());
+                    None
+                }
+            };
+            continue;
+        }
+
+        let Some(variables) = block.as_deref_mut() else {
+            continue;
+        };
+        if line == VARIABLES_END || parse_marker(line).is_some() {
+            block = None;
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let pair = line.split_once('=').map(|(name, value)| (name.trim(), value.trim()));
+            match pair.filter(|(name, _)| crate::template::is_variable_name(name)) {
+                Some((name, value)) => {
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|value| value.strip_suffix('"'))
+                        .unwrap_or(value);
+                    variables.insert(name.to_string(), value.to_string());
+                }
+                None => verbose_println!(
+                    "Ignoring the invalid variable {:?} in {}",
+                    line,
+                    path.display()
+                ),
+            }
+        }
     }
 
-    config_files.sort();
+    (variables, global_variables)
+}
+
+/// Split the YAML front matter between `---` lines off the start of a fragment, if it has one
+///
+/// The front matter may also end with a `...` line. Without an end line there is no front matter.
+fn split_front_matter(contents: &str) -> (Option<&str>, &str) {
+    let mut lines = contents.split_inclusive('\n');
+    if lines.next().map(str::trim_end) != Some("---") {
+        return (None, contents);
+    }
+
+    let start = contents.find('\n').map_or(contents.len(), |end| end + 1);
+    let mut offset = start;
+    for line in lines {
+        if matches!(line.trim_end(), "---" | "...") {
+            return (Some(&contents[start..offset]), &contents[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+
+    (None, contents)
+}
+
+/// Parse the mapping of a YAML front matter into conditions and metadata entries
+fn parse_front_matter(front_matter: &str) -> std::result::Result<Vec<Condition>, String> {
+    if front_matter.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let entries: BTreeMap<String, toml::Value> =
+        serde_yaml_ng::from_str(front_matter).map_err(|e| e.to_string())?;
+    entries
+        .into_iter()
+        .map(|(key, value)| Ok(Condition { value: condition_value(&key, &value)?, key }))
+        .collect()
+}
+
+/// Convert the TOML or YAML value of a condition or metadata entry to its marker format value
+pub(crate) fn condition_value(
+    key: &str,
+    value: &toml::Value,
+) -> std::result::Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value.clone()),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                toml::Value::Table(gateway) if key == "LocalGateway" => {
+                    let field = |name: &str| gateway.get(name).and_then(|field| field.as_str());
+                    match (field("ip"), field("mac")) {
+                        (Some(ip), Some(mac)) if gateway.len() == 2 => {
+                            Ok(format!("{}|{}", ip, mac))
+                        }
+                        _ => Err(format!("{} entries need exactly an ip and a mac", key)),
+                    }
+                }
+                toml::Value::Array(_) | toml::Value::Table(_) => {
+                    Err(format!("{} entries must be strings", key))
+                }
+                value => condition_value(key, value),
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map(|values| values.join(",")),
+        _ => Err(format!("{} must be a string or an array of strings", key)),
+    }
+}
+
+/// Split a fragment into its `# <SECTION> BEGIN` ... `# <SECTION> END` sections, in the order of
+/// [`SECTIONS`], with their lines joined by `\n` and trimmed
+///
+/// Markers only count on a line of their own, surrounding whitespace aside. A leading BOM and CRLF
+/// line endings are accepted. Malformed fragments degrade one section at a time, never swallowing
+/// a neighboring section:
+/// * a section still open when another one begins, or when the file ends, is dropped
+/// * an END marker not closing the open section is ignored
+/// * only the first occurrence of a repeated section is used
+///
+/// Sections tagged with a newer OpenSSH version than the client are dropped.
+pub fn parse_sections(path: &Path, contents: &str) -> [String; 4] {
+    split_sections(path, contents).0
+}
+
+/// Split a fragment into its sections like [`parse_sections`], along with whether each section
+/// was marked `(encrypted)`. Sections tagged with a newer OpenSSH version than the client are
+/// dropped, see [`crate::openssh`].
+fn split_sections(path: &Path, contents: &str) -> ([String; 4], [bool; 4]) {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let mut sections: [Option<(Vec<&str>, bool)>; 4] = Default::default();
+    let mut open: Option<(Marker, Vec<&str>)> = None;
+
+    for line in contents.lines() {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match parse_marker(line) {
+            Some(marker) if marker.begin => {
+                if let Some((unterminated, _)) = open.replace((marker, Vec::new())) {
+                    let name = SECTIONS[unterminated.index];
+                    verbose_println!(
+                        "Ignoring the unterminated {} section of {}",
+                        name,
+                        path.display()
+                    );
+                }
+            }
+            Some(marker) if open.as_ref().is_some_and(|(open, _)| open.index == marker.index) => {
+                let (Marker { index, encrypted, openssh, .. }, lines) = open.take().unwrap();
+                if let Some(version) = openssh.filter(|version| !crate::openssh::supports(*version))
+                {
+                    let name = SECTIONS[index];
+                    verbose_println!(
+                        "Ignoring the {} section of {} for OpenSSH {}",
+                        name,
+                        path.display(),
+                        version
+                    );
+                } else if sections[index].is_some() {
+                    verbose_println!(
+                        "Ignoring the repeated {} section of {}",
+                        SECTIONS[index],
+                        path.display()
+                    );
+                } else {
+                    sections[index] = Some((lines, encrypted));
+                }
+            }
+            Some(marker) => {
+                verbose_println!(
+                    "Ignoring a stray {} END marker in {}",
+                    SECTIONS[marker.index],
+                    path.display()
+                );
+            }
+            None => {
+                if let Some((_, lines)) = &mut open {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+
+    if let Some((unterminated, _)) = open {
+        let name = SECTIONS[unterminated.index];
+        verbose_println!("Ignoring the unterminated {} section of {}", name, path.display());
+    }
+
+    let encrypted = sections
+        .each_ref()
+        .map(|section| section.as_ref().is_some_and(|(_, encrypted)| *encrypted));
+    let sections = sections.map(|section| {
+        section.map_or_else(String::new, |(lines, _)| lines.join("\n").trim().to_string())
+    });
+    (sections, encrypted)
+}
+
+/// A section marker line
+struct Marker {
+    /// The index of the section in [`SECTIONS`]
+    index: usize,
+    begin: bool,
+    /// Whether the section is marked `(encrypted)`
+    encrypted: bool,
+    /// The OpenSSH version the section is tagged for, as in `(openssh >= 8.5)`
+    openssh: Option<Version>,
+}
+
+/// Get the name of the section whose marker is `line`, and whether it is the BEGIN marker
+pub(crate) fn section_marker(line: &str) -> Option<(&'static str, bool)> {
+    parse_marker(line).map(|marker| (SECTIONS[marker.index], marker.begin))
+}
+
+/// Parse a section marker line. Only the BEGIN markers of rules sections can be tagged
+/// `(encrypted)` or `(openssh >= <version>)`, in either order.
+fn parse_marker(line: &str) -> Option<Marker> {
+    let mut marker = line.trim().strip_prefix('#')?.trim_start();
+    let (mut encrypted, mut openssh) = (false, None);
+    while let Some(rest) = marker.strip_suffix(')') {
+        let (rest, tag) = rest.rsplit_once('(')?;
+        match tag.trim() {
+            "encrypted" if !encrypted => encrypted = true,
+            tag => {
+                let version = tag.strip_prefix("openssh")?.trim_start().strip_prefix(">=")?;
+                if openssh.replace(version.trim().parse::<Version>().ok()?).is_some() {
+                    return None;
+                }
+            }
+        }
+        marker = rest.trim_end();
+    }
+
+    let (name, begin) = match marker.strip_suffix(" BEGIN") {
+        Some(name) => (name, true),
+        None => (marker.strip_suffix(" END")?, false),
+    };
+
+    let index = SECTIONS.iter().position(|section| *section == name.trim_end())?;
+    if (encrypted || openssh.is_some()) && (!begin || index == 0) {
+        return None;
+    }
+
+    Some(Marker { index, begin, encrypted, openssh })
+}
 
-    let mut new_ssh_config = String::new();
+/// Generate the SSH config from the fragments in `paths` and write it, detecting the network
+/// with `providers`. Generations writing to the same `.ssh` directory run one at a time, across
+/// processes too. Nothing is written if nothing changed since the last generation recorded in
+/// the state file, see [`crate::status`].
+pub fn run(paths: &Paths, providers: &Providers) -> Result<()> {
+    let options = &mut Options::default();
+    write_generation(paths, providers, &mut Cache::default(), true, options, Vec::new()).map(drop)
+}
 
-    for config_file in config_files {
-        let config_file_path = ssh_config_dir.join(config_file);
-        let config_file_contents = crate::file::read_file(&config_file_path).unwrap();
+/// Generate the SSH config of `paths` and write it, unless `skip_unchanged` and the last
+/// generation had the same inputs on the same network. `triggers` are recorded in the audit log,
+/// `command` if there are none. The report holds how long each phase took.
+pub(crate) fn write_generation(
+    paths: &Paths,
+    providers: &Providers,
+    cache: &mut Cache,
+    skip_unchanged: bool,
+    options: &mut Options,
+    triggers: Vec<String>,
+) -> Result<Report> {
+    let started = std::time::Instant::now();
+    let (report, timings) = crate::phases::collect(|| {
+        generation(paths, providers, cache, skip_unchanged, options, triggers)
+    });
+    let mut report = report?;
+    report.timings = timings;
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
 
-        if config_file_contents.is_empty() {
+/// Run a generation for [`write_generation`]
+fn generation(
+    paths: &Paths,
+    providers: &Providers,
+    cache: &mut Cache,
+    skip_unchanged: bool,
+    options: &mut Options,
+    mut triggers: Vec<String>,
+) -> Result<Report> {
+    // A dry run writes nothing, not even the lock file.
+    let _lock = if options.dry_run { None } else { Some(paths.lock()?) };
+    if !options.dry_run {
+        crate::journal::repair(paths)?;
+    }
+    if is_verbose() {
+        match providers.routes.default_routes() {
+            Ok(routes) => {
+                for route in routes {
+                    verbose_println!("Default route: {}", route);
+                }
+            }
+            Err(e) => verbose_println!("Unable to get the default routes: {}", e),
+        }
+    }
+
+    let settings = crate::settings::get();
+    let fragments = read_fragments_cached(&paths.config_dir, cache);
+    let context = context(Some(&paths.state_dir), providers);
+    let inputs = crate::status::inputs(&fragments);
+    let skip_unchanged = skip_unchanged && !options.dry_run;
+    if let Some(inputs) = inputs.as_deref().filter(|_| skip_unchanged && !fragments.is_empty()) {
+        if let Some(generation) = crate::status::unchanged(paths, inputs, &context.fingerprint()) {
             verbose_println!(
-                "Skipping empty or unreadable config file: {}",
-                config_file_path.display()
+                "Nothing changed since the last generation, keeping {}",
+                paths.config_file.display()
             );
-            continue;
+            cache.set_profile(generation.profile.clone());
+            let fragments = fragments.iter().map(|fragment| fragment.path.clone()).collect();
+            return Ok(Report {
+                outcome: Outcome::Unchanged,
+                profile: generation.profile,
+                fragments,
+                local: Vec::new(),
+                written: Vec::new(),
+                config: String::new(),
+                changes: Vec::new(),
+                timings: Timings::default(),
+                elapsed: Duration::ZERO,
+            });
         }
+    }
 
-        let config_settings = crate::file::get_between(
-            &config_file_contents,
-            "# CONDITIONS BEGIN",
-            "# CONDITIONS END",
-        );
-
-        let local_rules = crate::file::get_between(
-            &config_file_contents,
-            "# LOCAL CONFIG BEGIN",
-            "# LOCAL CONFIG END",
-        );
+    let Selection { fragments, matches, network, connections } =
+        select_from(fragments, &context, cache, &settings.networks);
+    // Dry runs write nothing.
+    if !options.dry_run {
+        save_state(&context, &paths.state_dir);
+    }
+    let config = crate::phases::time("composition", || compose_to_string(&fragments, &matches));
+    let profile = network.clone().unwrap_or_else(|| {
+        let profile = if matches.contains(&true) { "local" } else { "remote" };
+        profile.to_string()
+    });
+    let mut report = Report {
+        outcome: if fragments.is_empty() { Outcome::Empty } else { Outcome::Written },
+        profile: profile.clone(),
+        fragments: fragments.iter().map(|fragment| fragment.path.clone()).collect(),
+        local: fragments
+            .iter()
+            .zip(&matches)
+            .filter(|(_, local)| **local)
+            .map(|(f, _)| f.path.clone())
+            .collect(),
+        written: Vec::new(),
+        config: String::new(),
+        changes: Vec::new(),
+        timings: Timings::default(),
+        elapsed: Duration::ZERO,
+    };
+    if !fragments.is_empty() {
+        let planned = crate::review::planned(&paths.config_file, network.as_deref(), &config)?;
+        // A dry run previews configs sshconfgen doesn't manage yet too, as it leaves them alone.
+        if options.dry_run {
+            report.outcome = Outcome::Previewed;
+            report.config = config;
+            report.changes = planned;
+            return Ok(report);
+        }
+        let recorded = crate::adopt::check(paths)?;
+        crate::sessions::check(&paths.config_file, &config, options.force)?;
+        let approved = match &mut options.approve {
+            Some(approve) if !planned.is_empty() => approve(&planned, &profile),
+            _ => true,
+        };
+        if !approved {
+            report.outcome = Outcome::Declined;
+            report.changes = planned;
+            return Ok(report);
+        }
+        // An identical config isn't rewritten, to leave its backups and modification time alone.
+        if planned.is_empty() {
+            verbose_println!(
+                "The new config is identical, keeping {}",
+                paths.config_file.display()
+            );
+            report.outcome = Outcome::Unchanged;
+        } else {
+            let writing = std::time::Instant::now();
+            let backup = crate::backup::backup(paths)?;
+            crate::journal::begin(paths, Intent::Generate, backup.as_deref(), &planned)?;
+            let written = match &network {
+                Some(network) => {
+                    crate::network::write_configs(&paths.config_file, network, &config)
+                }
+                None => write_config(&paths.config_file, &config),
+            };
+            // A named network writes two files, the first must not be left changed alone.
+            if let Err(e) = written {
+                if let Err(restore) = crate::review::restore(&planned) {
+                    let message = format!("Unable to restore the previous config: {}", restore);
+                    crate::platform::warn_once("restore", &message);
+                } else {
+                    crate::journal::finish(paths)?;
+                }
+                return Err(e);
+            }
+            crate::journal::finish(paths)?;
+            crate::backup::prune(paths);
+            crate::phases::record("writing", writing.elapsed());
+            crate::smoke::after_generation(&paths.config_file, &planned)?;
+        }
+        report.written = planned.iter().map(|file| file.path.clone()).collect();
+        report.changes = planned;
+        if !recorded && paths.config_file.exists() {
+            if let Err(e) = crate::adopt::record(paths) {
+                verbose_println!(
+                    "Unable to record {} in the state file: {}",
+                    paths.config_file.display(),
+                    e
+                );
+            }
+        }
+    }
 
-        let remote_rules = crate::file::get_between(
-            &config_file_contents,
-            "# REMOTE CONFIG BEGIN",
-            "# REMOTE CONFIG END",
+    if !fragments.is_empty() {
+        let mut written = vec![paths.config_file.clone()];
+        written.extend(
+            network
+                .as_ref()
+                .map(|network| crate::network::include_file(&paths.config_file, network)),
         );
 
-        let global_rules = crate::file::get_between(
-            &config_file_contents,
-            "# GLOBAL CONFIG BEGIN",
-            "# GLOBAL CONFIG END",
-        );
+        let generation = crate::status::Generation {
+            time: crate::state::now(),
+            profile: profile.clone(),
+            fingerprint: context.fingerprint(),
+            fragments: fragments.iter().map(|fragment| fragment.path.clone()).collect(),
+            inputs,
+            written: BTreeMap::new(),
+        };
+        if let Err(e) = crate::status::record(paths, generation, &written) {
+            verbose_println!("Unable to record the generation in the state file: {}", e);
+        }
 
-        let use_local_config = local_rules_match(&config_file_path, config_settings);
+        if triggers.is_empty() {
+            triggers.push("command".to_string());
+        }
+        let decisions =
+            fragments.iter().zip(&matches).map(|(fragment, local)| crate::audit::Decision {
+                path: fragment.path.clone(),
+                conditions: fragment.conditions.iter().map(Condition::to_string).collect(),
+                local: *local,
+            });
+        let event = crate::audit::Event::Generation {
+            triggers,
+            profile: profile.clone(),
+            fingerprint: Box::new(context.fingerprint()),
+            fragments: decisions.collect(),
+            config: paths.config_file.clone(),
+            sha256: crate::source::sha256_hex(config.as_bytes()),
+        };
+        if let Err(e) = crate::audit::append(paths, event) {
+            crate::platform::warn_once("audit", &format!("Unable to write the audit log: {}", e));
+        }
 
-        // New line delimiter for Windows or Unix
-        let newline = if cfg!(windows) { "\r\n" } else { "\n" };
+        if let Err(e) = crate::profile::write(paths, &profile) {
+            crate::platform::warn_once(
+                "profile",
+                &format!("Unable to write the profile file: {}", e),
+            );
+        }
+        if settings.env_file {
+            let ssids: Vec<String> =
+                connections.into_iter().map(|connection| connection.ssid).collect();
+            if let Err(e) = crate::env_file::write(paths, &profile, &ssids) {
+                crate::platform::warn_once(
+                    "env_file",
+                    &format!("Unable to write the env file: {}", e),
+                );
+            }
+        }
 
-        if !global_rules.is_empty() {
-            verbose_println!("Using global ssh rules from {}", config_file_path.display());
-            new_ssh_config.push_str(&global_rules);
-            new_ssh_config.push_str(newline);
+        if let Err(e) = crate::roaming::write(&paths.config_file, &config) {
+            crate::platform::warn_once(
+                "roaming",
+                &format!("Unable to write the mosh and et aliases: {}", e),
+            );
+        }
+        if settings.terminal.enabled() {
+            if let Err(e) = crate::terminal::write(&settings.terminal, &config, &profile) {
+                crate::platform::warn_once(
+                    "terminal",
+                    &format!("Unable to write the terminal profiles: {}", e),
+                );
+            }
         }
+        cache.set_profile(profile);
+        report.config = config;
+    }
+
+    Ok(report)
+}
+
+/// Generate the SSH config from the fragments in a directory, without writing anything.
+/// Without fragments the generated config is empty.
+pub fn generate(ssh_config_dir: &Path, providers: &Providers) -> Result<String> {
+    generate_cached(ssh_config_dir, providers, &mut Cache::default())
+}
+
+/// Generate the SSH config like [`generate`], reusing the fragments and condition outcomes of
+/// the previous generations sharing `cache`.
+pub fn generate_cached(
+    ssh_config_dir: &Path,
+    providers: &Providers,
+    cache: &mut Cache,
+) -> Result<String> {
+    generate_from(ssh_config_dir, crate::state::state_dir().as_deref(), providers, cache)
+}
+
+/// Generate the SSH config like [`generate_cached`], reusing the probe results of the state file
+/// of `state_dir`. The networks are evaluated as by [`run`], which writes the same config, but
+/// nothing is written, the state file included.
+pub(crate) fn generate_from(
+    ssh_config_dir: &Path,
+    state_dir: Option<&Path>,
+    providers: &Providers,
+    cache: &mut Cache,
+) -> Result<String> {
+    let fragments = read_fragments_cached(ssh_config_dir, cache);
+    let context = context(state_dir, providers);
+    let networks = &crate::settings::get().networks;
+    let selection = select_from(fragments, &context, cache, networks);
+    Ok(compose_to_string(&selection.fragments, &selection.matches))
+}
 
-        if use_local_config {
-            if !local_rules.is_empty() {
-                // No need to verbose print here, the local matching functions already do that.
-                new_ssh_config.push_str(&local_rules);
-                new_ssh_config.push_str(newline);
+/// The fragments of a directory and the network their conditions were evaluated on
+#[derive(Default)]
+struct Selection {
+    fragments: Vec<Fragment>,
+    /// Whether each fragment uses its local rules
+    matches: Vec<bool>,
+    /// The active named network, if any are set
+    network: Option<String>,
+    /// The connected SSIDs, if they were detected
+    connections: Vec<ssid::Connection>,
+}
+
+/// Save the probe results of a generation in the state file of `state_dir`. The state only saves
+/// time, failing to keep it doesn't fail the run.
+fn save_state(context: &Context, state_dir: &Path) {
+    if let Some(Err(e)) = context.state().map(|mut state| state.save(state_dir)) {
+        verbose_println!("Unable to save the state file: {}", e);
+    }
+}
+
+/// Read the fragments in a directory through `cache`
+fn read_fragments_cached(ssh_config_dir: &Path, cache: &mut Cache) -> Vec<Fragment> {
+    let fragments = cache.read_fragments(ssh_config_dir);
+    if fragments.is_empty() {
+        verbose_println!("No config files found in {}", ssh_config_dir.display());
+    }
+    fragments
+}
+
+/// Get the detection context of a generation, reusing the probe results of the state file of
+/// `state_dir`, if any
+fn context(state_dir: Option<&Path>, providers: &Providers) -> Context {
+    match state_dir.and_then(crate::state::load) {
+        Some(state) => Context::with_state(providers, state),
+        None => Context::new(providers),
+    }
+}
+
+/// Evaluate whether the conditions of `fragments` match, along with which of `networks` is
+/// active if any are given
+fn select_from(
+    fragments: Vec<Fragment>,
+    context: &Context,
+    cache: &mut Cache,
+    networks: &[Network],
+) -> Selection {
+    // If there are no config files, return early.
+    if fragments.is_empty() {
+        return Selection::default();
+    }
+
+    // The profile setting replaces the evaluation of the conditions it decides.
+    let profile = crate::settings::get().profile.as_deref();
+    let (matches, network) = crate::phases::time("evaluation", || {
+        let matches = match profile {
+            Some(profile @ ("local" | "remote")) => {
+                verbose_println!(
+                    "Using the {} rules of every fragment, as set by the profile",
+                    profile
+                );
+                vec![profile == "local"; fragments.len()]
             }
-        } else if !remote_rules.is_empty() {
-            verbose_println!("Using remote ssh rules from {}", config_file_path.display());
-            new_ssh_config.push_str(&remote_rules);
-            new_ssh_config.push_str(newline);
+            _ => cache.evaluate(&fragments, context),
+        };
+        let named = |name: &&str| {
+            *name == crate::network::FALLBACK_NETWORK
+                || networks.iter().any(|network| network.name == *name)
+        };
+        let network = (!networks.is_empty()).then(|| match profile.filter(named) {
+            Some(name) => name.to_string(),
+            None => crate::network::active(networks, context),
+        });
+        (matches, network)
+    });
+
+    // Only the env file needs the SSIDs when no condition asked for them.
+    let connections = if crate::settings::get().env_file {
+        context.connections().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    Selection { fragments, matches, network, connections }
+}
+
+/// Get the paths of the `.sshconf` and `.sshconf.toml` fragments in a directory, in the legacy
+/// `conf.d` next to it, and in the directory of the fragments provided by the administrator, see
+/// [`discover_layered`]. Reading `conf.d` is reported as deprecated.
+pub fn discover_fragments(ssh_config_dir: &Path) -> Vec<PathBuf> {
+    let legacy_dir = crate::paths::legacy_config_dir(ssh_config_dir).filter(|dir| dir.is_dir());
+    let system_dir = crate::platform::system_config_dir();
+    let lower_dirs: Vec<&Path> =
+        legacy_dir.iter().chain(&system_dir).map(PathBuf::as_path).collect();
+    let config_files = discover_layered(ssh_config_dir, &lower_dirs);
+
+    if let Some(legacy_dir) =
+        legacy_dir.filter(|dir| config_files.iter().any(|path| path.starts_with(dir)))
+    {
+        let message = format!(
+            "Reading fragments from {}, which is deprecated, run sshconfgen migrate-layout to move \
+             them to {}.",
+            legacy_dir.display(),
+            ssh_config_dir.display()
+        );
+        crate::platform::warn_once("conf.d", &message);
+    }
+
+    config_files
+}
+
+/// Get the paths of the fragments in `ssh_config_dir`, layered over those of `lower_dirs`, each in
+/// path order: the user's first, then those of each lower directory in turn.
+///
+/// Fragments are named by their path in their directory: a fragment replaces the ones with the
+/// same name in the directories after it, and an empty one masks them. Lower directories that are
+/// one of the directories before them, e.g. through a symbolic link, are skipped.
+pub fn discover_layered(ssh_config_dir: &Path, lower_dirs: &[&Path]) -> Vec<PathBuf> {
+    let mut config_files = discover_in(ssh_config_dir);
+    let mut upper_dirs = vec![ssh_config_dir];
+    for &lower_dir in lower_dirs {
+        if upper_dirs.iter().any(|dir| is_same_dir(dir, lower_dir)) {
+            continue;
         }
-        
-        // We want each config file to be separated by an empty line.
-        if !new_ssh_config.is_empty() {
-            new_ssh_config.push_str(newline);
+
+        for path in discover_in(lower_dir) {
+            let name = path.strip_prefix(lower_dir).unwrap_or(&path);
+            match upper_dirs.iter().map(|dir| dir.join(name)).find(|upper| upper.is_file()) {
+                Some(upper) => {
+                    verbose_println!("Skipping {}, replaced by {}", path.display(), upper.display())
+                }
+                None => config_files.push(path),
+            }
         }
+        upper_dirs.push(lower_dir);
     }
 
-    if !new_ssh_config.is_empty() {
-        backup_config(&ssh_config_file, &sshd_config_backup_file);
-        verbose_println!("Populating {}", ssh_config_file.display());
-        crate::file::append_to_file(&ssh_config_file, &new_ssh_config, true)
-            .expect("Error, unable to append newline to .ssh/config");
+    config_files
+}
+
+/// Check whether two paths name the same directory
+fn is_same_dir(a: &Path, b: &Path) -> bool {
+    a == b
+        || matches!((std::fs::canonicalize(a), std::fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Get the paths of the `.sshconf` and `.sshconf.toml` fragments in a directory and its
+/// subdirectories, e.g. `config.d/work/` or the unpacked bundles of remote sources, in path order.
+///
+/// Hidden directories, such as `.git` or bundles still being unpacked, are skipped, and so are
+/// symbolic links to directories, which could form loops.
+fn discover_in(ssh_config_dir: &Path) -> Vec<PathBuf> {
+    let mut config_files = Vec::new();
+    let mut dirs = vec![ssh_config_dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        config_files.extend(fragments_in(&dir));
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().filter_map(|entry| entry.ok()) {
+            let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if is_dir && !entry.file_name().to_string_lossy().starts_with('.') {
+                dirs.push(entry.path());
+            }
+        }
     }
+
+    config_files.sort();
+    config_files
 }
 
-/// Cleanup the SSH config file and restore the original if necessary.
-fn cleanup(ssh_config_file: &PathBuf, sshd_config_backup_file: &PathBuf) {
-    // Check if the config file was created, if not, restore the original.
-    if !ssh_config_file.exists() {
-        verbose_println!("Warning! New config doesn't exist. Restoring original SSH config file");
-        fs::rename(&sshd_config_backup_file, &ssh_config_file)
-            .expect("Error, unable to restore original SSH config file.");
-    } else if ssh_config_file.exists() {
-        let metadata = fs::metadata(&ssh_config_file)
-            .expect("Error, unable to get metadata for new SSH config file.");
+/// Get the paths of the fragments directly in a directory
+pub(crate) fn fragments_in(dir: &Path) -> Vec<PathBuf> {
+    let toml_suffix = format!(".{}", crate::TOML_CONFIG_EXTENSION);
+    let mut config_files = get_files_by_extension(dir, crate::CONFIG_EXTENSION);
+    config_files.extend(get_files_by_extension(dir, "toml").into_iter().filter(|path| {
+        path.file_name().is_some_and(|name| name.to_string_lossy().ends_with(&toml_suffix))
+    }));
+    config_files
+}
 
-        // if new config is empty (file size), restore the original.
-        if metadata.len() == 0 {
-            verbose_println!("Warning! New config is empty. Restoring original SSH config file");
-            fs::rename(&sshd_config_backup_file, &ssh_config_file)
-                .expect("Error, unable to restore original SSH config file.");
-        } else {
-            // Assume the new config file is good, remove the backup.
-            verbose_println!("New SSH config file created, removing backup.");
-            fs::remove_file(&sshd_config_backup_file)
-                .expect("Error, unable to remove backup file.");
+/// Read and parse the fragments in a directory, skipping empty or unreadable files. The fragments
+/// are in processing order, see [`sort_fragments`].
+pub fn read_fragments(ssh_config_dir: &Path) -> Vec<Fragment> {
+    let mut fragments = Vec::new();
+
+    for config_file_path in crate::phases::time("discovery", || discover_fragments(ssh_config_dir))
+    {
+        if let Some(fragment) =
+            crate::phases::time("parsing", || read_fragment(ssh_config_dir, &config_file_path))
+        {
+            fragments.push(fragment);
         }
     }
+
+    sort_fragments(&mut fragments);
+    fragments
+}
+
+/// Sort fragments in processing order: by ascending `Priority`, then those of the user before
+/// those provided by the administrator, then by path.
+///
+/// Paths are compared byte-wise rather than by locale, so the same fragments are always composed
+/// in the same order.
+pub fn sort_fragments(fragments: &mut [Fragment]) {
+    let system_dir = crate::platform::system_config_dir();
+    fragments.sort_by_cached_key(|fragment| {
+        let system = system_dir.as_ref().is_some_and(|dir| fragment.path.starts_with(dir));
+        (fragment.priority(), system, fragment.path.clone())
+    });
 }
 
-/// Backup the SSH config file.
-fn backup_config(ssh_config_file: &PathBuf, sshd_config_backup_file: &PathBuf) {
-    if ssh_config_file.exists() {
+/// Read and parse a fragment of `ssh_config_dir`, `None` if the file is empty, unreadable, refused
+/// by its signature, or includes a snippet that can't be read.
+///
+/// The `# INCLUDE` lines of `.sshconf` fragments are resolved in `ssh_config_dir`, or in the
+/// directory of the fragments provided by the administrator for theirs.
+pub fn read_fragment(ssh_config_dir: &Path, config_file_path: &Path) -> Option<Fragment> {
+    let config_file_contents = crate::file::read_file(config_file_path).unwrap_or_default();
+
+    if config_file_contents.is_empty() {
         verbose_println!(
-            "SSH config backup created: {}",
-            sshd_config_backup_file.display()
+            "Skipping empty or unreadable config file: {}",
+            config_file_path.display()
         );
+        return None;
+    }
+
+    if !crate::signature::is_trusted(config_file_path, config_file_contents.as_bytes()) {
+        return None;
+    }
 
-        // Rename the file to a backup, it is not a directory
-        fs::rename(&ssh_config_file, &sshd_config_backup_file)
-            .expect("Error, unable to backup SSH config file.");
+    if !config_file_path.to_string_lossy().ends_with(crate::TOML_CONFIG_EXTENSION) {
+        let include_dir =
+            [crate::platform::system_config_dir(), crate::paths::legacy_config_dir(ssh_config_dir)]
+                .into_iter()
+                .flatten()
+                .find(|dir| config_file_path.starts_with(dir))
+                .unwrap_or_else(|| ssh_config_dir.to_path_buf());
+        let (contents, includes) = match splice_includes(&include_dir, &config_file_contents) {
+            Ok(spliced) => spliced,
+            Err(e) => {
+                let message = format!("Skipping {}, {}", config_file_path.display(), e);
+                crate::platform::warn_once(&config_file_path.to_string_lossy(), &message);
+                return None;
+            }
+        };
+        let fragment = Fragment { includes, ..Fragment::parse(config_file_path, &contents) };
+        crate::migrate::check(&fragment);
+        return Some(fragment);
     }
+
+    let fragment = Fragment::parse_toml(config_file_path, &config_file_contents).map_err(|e| {
+        let message =
+            format!("Skipping {}, it is not a valid fragment: {}", config_file_path.display(), e);
+        crate::platform::warn_once(&config_file_path.to_string_lossy(), message.trim_end());
+    });
+    fragment.inspect(crate::migrate::check).ok()
 }
 
-/// Check if the LocalSSID, LocalGateway, or LocalPing keys are present and if any match.
-fn local_rules_match(config_file_path: &PathBuf, config_settings: String) -> bool {
-    let mut use_local_config: bool;
+/// Replace each `# INCLUDE <path>` line of a fragment with the contents of the snippet at `path`
+/// relative to `include_dir`, so options shared by several fragments live in one place. Snippets
+/// may include others. Returns the spliced contents and the snippets included.
+pub fn splice_includes(
+    include_dir: &Path,
+    contents: &str,
+) -> std::result::Result<(String, Vec<PathBuf>), String> {
+    let mut includes = Vec::new();
+    let spliced = splice(include_dir, contents, &mut includes, &mut Vec::new())?;
+    Ok((spliced, includes))
+}
 
-    for line in config_settings.lines() {
-        let (key, value) = get_key_value(line);
+/// Splice the snippets of `contents`, `stack` holding the snippets being spliced
+fn splice(
+    include_dir: &Path,
+    contents: &str,
+    includes: &mut Vec<PathBuf>,
+    stack: &mut Vec<PathBuf>,
+) -> std::result::Result<String, String> {
+    let mut spliced = String::with_capacity(contents.len());
+    for line in contents.split_inclusive('\n') {
+        let name = line.trim().strip_prefix(INCLUDE_DIRECTIVE).map(str::trim);
+        let Some(name) = name.filter(|name| !name.is_empty()) else {
+            spliced.push_str(line);
+            continue;
+        };
 
-        use_local_config = local_ssid_match(&config_file_path, &key, &value);
+        let path = include_dir.join(name);
+        if stack.contains(&path) {
+            return Err(format!("{} includes itself", path.display()));
+        } else if stack.len() == MAX_INCLUDE_DEPTH {
+            return Err(format!("includes nest deeper than {} levels", MAX_INCLUDE_DEPTH));
+        }
 
-        if !use_local_config {
-            use_local_config = local_gateway_match(&config_file_path, &key, &value);
+        let snippet = crate::file::read_file(&path)
+            .map_err(|e| format!("unable to include {}: {}", path.display(), e))?;
+        if !crate::signature::is_trusted(&path, snippet.as_bytes()) {
+            return Err(format!("{} is not trusted", path.display()));
         }
 
-        if !use_local_config {
-            use_local_config = local_ping_made(&config_file_path, &key, &value);
+        stack.push(path.clone());
+        let snippet = splice(
+            include_dir,
+            snippet.strip_prefix('\u{feff}').unwrap_or(&snippet),
+            includes,
+            stack,
+        )?;
+        stack.pop();
+
+        spliced.push_str(&snippet);
+        if !snippet.is_empty() && !snippet.ends_with('\n') {
+            spliced.push('\n');
         }
+        if !includes.contains(&path) {
+            includes.push(path);
+        }
+    }
 
-        if use_local_config {
-            return true;
+    Ok(spliced)
+}
+
+/// The outcome of checking the fragments without generating anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validation {
+    /// The fragments read
+    pub fragments: usize,
+    /// The warnings printed about them
+    pub warnings: usize,
+}
+
+/// Check the fragments in a directory: read them, then compose both their local and their remote
+/// rules, so every section is decrypted, rendered and checked against the OpenSSH client. The
+/// conditions aren't probed and nothing is written.
+pub fn validate(ssh_config_dir: &Path) -> Validation {
+    let warnings = crate::platform::warning_count();
+    let fragments = read_fragments(ssh_config_dir);
+    for local in [true, false] {
+        compose_to_string(&fragments, &vec![local; fragments.len()]);
+    }
+
+    Validation { fragments: fragments.len(), warnings: crate::platform::warning_count() - warnings }
+}
+
+/// Compose the SSH config in memory
+fn compose_to_string(fragments: &[Fragment], matches: &[bool]) -> String {
+    let mut new_ssh_config = Vec::new();
+    // Writing to a Vec can't fail.
+    let _ = compose_config(fragments, matches, &mut new_ssh_config);
+    String::from_utf8_lossy(&new_ssh_config).into_owned()
+}
+
+/// Compose the SSH config from the global rules of each fragment followed by its local or remote
+/// rules, depending on `matches`. Returns whether anything was written.
+fn compose_config(
+    fragments: &[Fragment],
+    matches: &[bool],
+    out: &mut dyn Write,
+) -> io::Result<bool> {
+    let mut written = false;
+
+    let newline = crate::settings::get().newline.as_str();
+    let rules_of = |rules: &str| rules.replace('\n', newline);
+
+    // The first fragment defining a global variable wins, as OpenSSH does with options.
+    let mut global_variables = BTreeMap::new();
+    for fragment in fragments {
+        for (name, value) in &fragment.global_variables {
+            global_variables.entry(name.clone()).or_insert_with(|| value.clone());
         }
     }
 
-    false
+    for (fragment, &use_local_config) in fragments.iter().zip(matches) {
+        let mut variables = global_variables.clone();
+        variables.extend(fragment.variables.clone());
+        let section_rules =
+            |name: &str, rules: &str| section_rules(fragment, name, rules, &variables);
+
+        if !fragment.global_rules.is_empty() {
+            verbose_println!("Using global ssh rules from {}", fragment.path.display());
+            let rules = section_rules(SECTIONS[1], &fragment.global_rules);
+            if !rules.is_empty() {
+                write!(out, "{}{}", rules_of(&rules), newline)?;
+                written = true;
+            }
+        }
+
+        // No need to verbose print the local rules, the local matching functions already do that.
+        let rules = if use_local_config {
+            section_rules(SECTIONS[2], &fragment.local_rules)
+        } else {
+            if !fragment.remote_rules.is_empty() {
+                verbose_println!("Using remote ssh rules from {}", fragment.path.display());
+            }
+            section_rules(SECTIONS[3], &fragment.remote_rules)
+        };
+
+        if !rules.is_empty() {
+            write!(out, "{}{}", rules_of(&rules), newline)?;
+            written = true;
+        }
+
+        // We want each config file to be separated by an empty line.
+        if written {
+            write!(out, "{}", newline)?;
+        }
+    }
+
+    Ok(written)
 }
 
-/// Check if the LocalSSID key is present and if the current SSID matches any of the SSIDs.
-/// If the current SSID matches any of the SSIDs, return true.
-fn local_ssid_match(config_file_path: &PathBuf, key: &String, value: &String) -> bool {
+/// Get the rules of a section of a fragment, decrypting them if it is encrypted and rendering
+/// their templates with `variables`. A section that can't be decrypted or rendered is left out
+/// with a warning.
+fn section_rules(
+    fragment: &Fragment,
+    name: &str,
+    rules: &str,
+    variables: &BTreeMap<String, String>,
+) -> String {
+    if rules.is_empty() {
+        return String::new();
+    }
+
+    let rules = if fragment.encrypted.contains(name) {
+        crate::age::decrypt(rules).map(|rules| rules.trim().to_string())
+    } else {
+        Ok(rules.to_string())
+    };
+
+    let rules =
+        rules.and_then(|rules| crate::template::render(&rules, variables)).unwrap_or_else(|e| {
+            let path = fragment.path.display();
+            let message = format!("Leaving out the {} section of {}: {}", name, path, e);
+            crate::platform::warn_once(&format!("{} {}", path, name), &message);
+            String::new()
+        });
+    crate::phases::time("composition/validation", || crate::openssh::filter(&rules, &fragment.path))
+}
+
+/// Write the new SSH config file, replacing the previous one only once the new one is complete.
+/// Only the managed region of a config holding one is replaced, see [`crate::region`]. An empty
+/// config, or one identical to the existing file, leaves it untouched.
+pub fn write_config(ssh_config_file: &Path, new_ssh_config: &str) -> Result<()> {
+    if new_ssh_config.is_empty() {
+        verbose_println!("The new config is empty, keeping {}", ssh_config_file.display());
+        return Ok(());
+    }
+
+    // The config has always ended with an extra newline.
+    let new_ssh_config = format!("{}{}", new_ssh_config, crate::settings::get().newline.as_str());
+    let current = crate::file::read_file(ssh_config_file).unwrap_or_default();
+    let contents = crate::region::splice(ssh_config_file, &current, &new_ssh_config)?;
+
+    verbose_println!("Populating {}", ssh_config_file.display());
+    crate::file::write_if_changed(ssh_config_file, &contents)
+}
+
+/// Check if any of the LocalSSID, LocalGateway, LocalPing, LocalArpPing, or LocalVPN conditions
+/// match.
+pub fn local_rules_match(
+    context: &Context,
+    config_file_path: &Path,
+    conditions: &[Condition],
+) -> bool {
+    conditions.iter().any(|condition| {
+        local_condition_match(context, config_file_path, &condition.key, &condition.value)
+    })
+}
+
+/// Check if a single condition line matches.
+pub fn local_condition_match(
+    context: &Context,
+    config_file_path: &Path,
+    key: &str,
+    value: &str,
+) -> bool {
+    let mut use_local_config = local_ssid_match(context, config_file_path, key, value);
+
+    if !use_local_config {
+        use_local_config = local_gateway_match(context, config_file_path, key, value);
+    }
+
+    if !use_local_config {
+        use_local_config = local_ping_made(context, config_file_path, key, value);
+    }
+
+    if !use_local_config {
+        use_local_config = local_arp_ping_made(context, config_file_path, key, value);
+    }
+
+    if !use_local_config {
+        use_local_config = local_vpn_match(context, config_file_path, key, value);
+    }
+
+    use_local_config
+}
+
+/// Check if the LocalSSID key is present and if a connected SSID matches any of the SSIDs.
+/// Entries of the form `interface:ssid` only match the SSID of that wireless interface, and
+/// double-quoted entries are taken literally.
+/// If a connected SSID matches any of the SSIDs, return true.
+fn local_ssid_match(context: &Context, config_file_path: &Path, key: &str, value: &str) -> bool {
     if key != "LocalSSID" {
         return false;
     }
 
-    let current_ssid = match ssid::get_current_ssid() {
-        Ok(ssid) => ssid,
+    // Missing tools have already been reported, skip the SSID condition and keep evaluating.
+    let connections = match context.connections() {
+        Ok(connections) => connections,
         Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1);
+            verbose_println!("Skipping LocalSSID for {}: {}", config_file_path.display(), e);
+            return false;
         }
     };
 
-    // Create a value_array of SSIDs delimited by a comma, filter out any empty strings.
-    let value_array: Vec<&str> = value.split(',').filter(|&x| !x.is_empty()).collect();
+    // Check if a connected SSID matches any of the comma-separated (optionally quoted) SSIDs.
+    for entry in ssid::parse_ssid_list(value) {
+        if let Some(connection) = ssid::find_match(&entry, &connections) {
+            verbose_println!(
+                "Using local ssh rules for {} reason: ssid match {}",
+                config_file_path.display(),
+                connection
+            );
 
-    // Check if the current SSID matches any of the SSIDs in the value_array.
-    if value_array.iter().any(|&ssid| ssid == current_ssid) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Check if the LocalPing key is present and if any of the IP addresses are pingable.
+/// If any of the IP addresses are pingable, return true.
+fn local_ping_made(context: &Context, config_file_path: &Path, key: &str, value: &str) -> bool {
+    if key != "LocalPing" {
+        return false;
+    }
+
+    // A list of IP address to ping to determine if we are on a local network
+    let hosts = value.split(',').map(str::trim).filter(|host| !host.is_empty());
+    let hosts = hosts.map(str::to_string).collect();
+    let context = context.clone();
+    if let Some(ip) = first_reachable(hosts, move |ip| context.pingable(ip)) {
         verbose_println!(
-            "Using local ssh rules for {} reason: ssid match {}",
+            "Using local ssh rules for {} reason: ping success {}",
             config_file_path.display(),
-            current_ssid
+            ip
         );
 
         return true;
@@ -211,21 +1326,94 @@ fn local_ssid_match(config_file_path: &PathBuf, key: &String, value: &String) ->
     false
 }
 
-/// Check if the LocalPing key is present and if any of the IP addresses are pingable.
-/// If any of the IP addresses are pingable, return true.
-fn local_ping_made(config_file_path: &PathBuf, key: &String, value: &String) -> bool {
-    if key != "LocalPing" {
+/// Check if the LocalArpPing key is present and if any of the IP addresses answer ARP requests.
+/// If any of the IP addresses answer, return true.
+fn local_arp_ping_made(context: &Context, config_file_path: &Path, key: &str, value: &str) -> bool {
+    if key != "LocalArpPing" {
         return false;
     }
 
-    // A list of IP address to ping to determine if we are on a local network
-    let value_array: Vec<&str> = value.split(',').collect();
-    for ip in value_array {
-        if ping::get_pingable(ip) {
+    // A list of IPv4 addresses on the local segment, unlike ICMP, ARP can't be firewalled off.
+    let hosts =
+        value.split(',').map(str::trim).filter(|ip| !ip.is_empty()).map(str::to_string).collect();
+    let context = context.clone();
+    if let Some(ip) = first_reachable(hosts, move |ip| context.arp_pingable(ip)) {
+        verbose_println!(
+            "Using local ssh rules for {} reason: arp ping success {}",
+            config_file_path.display(),
+            ip
+        );
+
+        return true;
+    }
+
+    false
+}
+
+/// Get the first host `probe` succeeds for, probing up to `PROBE_THREADS` hosts at once
+///
+/// Returns as soon as a host answers, the probes still running are left to finish in the
+/// background and no further hosts are probed.
+fn first_reachable(
+    hosts: Vec<String>,
+    probe: impl Fn(&str) -> bool + Send + Sync + 'static,
+) -> Option<String> {
+    let hosts = Arc::new(hosts);
+    let probe = Arc::new(probe);
+    let next = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::channel();
+
+    for _ in 0..PROBE_THREADS.min(hosts.len()) {
+        let (hosts, probe, next, done, sender) =
+            (hosts.clone(), probe.clone(), next.clone(), done.clone(), sender.clone());
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                let Some(host) = hosts.get(next.fetch_add(1, Ordering::Relaxed)) else {
+                    break;
+                };
+
+                if probe(host) {
+                    let _ = sender.send(host.clone());
+                }
+            }
+        });
+    }
+
+    // Once every worker ran out of hosts without success, all senders are gone and recv fails.
+    drop(sender);
+    let host = receiver.recv().ok();
+    done.store(true, Ordering::Relaxed);
+    host
+}
+
+/// Check if the LocalVPN key is present and if any of the named VPN adapters is connected.
+/// Adapters are matched case-insensitively by name, or by description on Windows.
+/// If any of the VPN adapters is connected, return true.
+fn local_vpn_match(context: &Context, config_file_path: &Path, key: &str, value: &str) -> bool {
+    if key != "LocalVPN" {
+        return false;
+    }
+
+    let adapters = match context.vpn_adapters() {
+        Ok(adapters) => adapters,
+        Err(e) => {
+            verbose_println!("Skipping LocalVPN for {}: {}", config_file_path.display(), e);
+            return false;
+        }
+    };
+
+    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        let matched = adapters.iter().find(|adapter| {
+            adapter.name.eq_ignore_ascii_case(name)
+                || adapter.description.eq_ignore_ascii_case(name)
+        });
+
+        if let Some(adapter) = matched {
             verbose_println!(
-                "Using local ssh rules for {} reason: ping success {}",
+                "Using local ssh rules for {} reason: vpn connected {}",
                 config_file_path.display(),
-                ip
+                adapter.name
             );
 
             return true;
@@ -237,7 +1425,7 @@ fn local_ping_made(config_file_path: &PathBuf, key: &String, value: &String) ->
 
 /// Check if the LocalGateway key is present and if the gateway matches an ip and hw address.
 /// If the gateway matches an ip and hw address, return true.
-fn local_gateway_match(config_file_path: &PathBuf, key: &String, value: &String) -> bool {
+fn local_gateway_match(context: &Context, config_file_path: &Path, key: &str, value: &str) -> bool {
     if key != "LocalGateway" {
         return false;
     }
@@ -249,7 +1437,7 @@ fn local_gateway_match(config_file_path: &PathBuf, key: &String, value: &String)
         if gateway_array.len() == 2 {
             let ip = gateway_array[0];
             let mac = gateway_array[1];
-            if let Ok(mac_address) = hwaddr::get_hw_address(ip) {
+            if let Ok(mac_address) = context.hw_address(ip) {
                 if mac_address == mac {
                     verbose_println!(
                         "Using local ssh rules for {} reason: gateway match {} ({})",
@@ -276,4 +1464,390 @@ pub fn get_key_value(line: &str) -> (String, String) {
     } else {
         (String::new(), String::new())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::provider::{
+        NeighborProvider, PingProvider, RouteProvider, SsidProvider, VpnProvider,
+    };
+    use crate::routes::DefaultRoute;
+    use crate::ssid::Connection;
+    use crate::vpn::Adapter;
+
+    /// A fixed network: one Wi-Fi connection, one neighbor, and one pingable host
+    struct MockProvider;
+
+    impl SsidProvider for MockProvider {
+        fn connections(&self) -> Result<Vec<Connection>> {
+            Ok(vec![Connection { interface: "wlan1".to_string(), ssid: "labnet".to_string() }])
+        }
+    }
+
+    impl PingProvider for MockProvider {
+        fn pingable(&self, host: &str) -> bool {
+            host == "192.168.1.100"
+        }
+
+        fn arp_pingable(&self, _ip_address: &str) -> bool {
+            false
+        }
+    }
+
+    impl NeighborProvider for MockProvider {
+        fn hw_address(&self, ip_address: &str) -> Result<String> {
+            match ip_address {
+                "192.168.1.1" => Ok("00:11:22:33:44:55".to_string()),
+                _ => Err(Error::NotFound("MAC address")),
+            }
+        }
+    }
+
+    impl RouteProvider for MockProvider {
+        fn default_routes(&self) -> Result<Vec<DefaultRoute>> {
+            Ok(vec![])
+        }
+    }
+
+    impl VpnProvider for MockProvider {
+        fn vpn_adapters(&self) -> Result<Vec<Adapter>> {
+            Err(Error::ToolUnavailable("ip".to_string()))
+        }
+    }
+
+    fn providers() -> Providers {
+        Providers {
+            ssid: Arc::new(MockProvider),
+            ping: Arc::new(MockProvider),
+            neighbor: Arc::new(MockProvider),
+            routes: Arc::new(MockProvider),
+            vpn: Arc::new(MockProvider),
+        }
+    }
+
+    #[test]
+    fn first_reachable_stops_at_an_answering_host() {
+        let hosts: Vec<String> = (1..=20).map(|i| format!("10.0.0.{}", i)).collect();
+        let host = first_reachable(hosts, |host| host == "10.0.0.3");
+
+        assert_eq!(host.as_deref(), Some("10.0.0.3"));
+        assert!(first_reachable(vec!["10.0.0.1".to_string()], |_| false).is_none());
+        assert!(first_reachable(Vec::new(), |_| true).is_none());
+    }
+
+    fn matches(conditions: &str) -> bool {
+        local_rules_match(
+            &Context::new(&providers()),
+            Path::new("test.sshconf"),
+            &Condition::parse_all(conditions),
+        )
+    }
+
+    #[test]
+    fn matches_each_condition() {
+        assert!(matches("LocalSSID foo, wlan1:labnet"));
+        assert!(!matches("LocalSSID wlan0:labnet"));
+        assert!(matches("LocalGateway 10.0.0.1|00:55:44:33:22:11,192.168.1.1|00:11:22:33:44:55"));
+        assert!(!matches("LocalGateway 192.168.1.1|00:55:44:33:22:11"));
+        assert!(matches("LocalPing 10.0.0.100,192.168.1.100"));
+        assert!(!matches("LocalArpPing 192.168.1.100"));
+    }
+
+    #[test]
+    fn skips_failing_conditions() {
+        assert!(matches("LocalVPN wg0\nLocalSSID labnet"));
+        assert!(!matches("LocalVPN wg0"));
+    }
+
+    #[test]
+    fn generates_local_or_remote_rules() {
+        let fragment = |conditions: &str| Fragment {
+            path: PathBuf::from("test.sshconf"),
+            conditions: Condition::parse_all(conditions),
+            metadata: BTreeMap::new(),
+            global_rules: "Host *".to_string(),
+            local_rules: "Host local".to_string(),
+            remote_rules: "Host remote".to_string(),
+            encrypted: BTreeSet::new(),
+            includes: Vec::new(),
+            variables: BTreeMap::new(),
+            global_variables: BTreeMap::new(),
+        };
+
+        let fragments = [fragment("LocalSSID labnet"), fragment("LocalSSID other")];
+        let matches = crate::engine::evaluate(
+            &fragments,
+            &Context::new(&providers()),
+            crate::engine::budget(),
+        );
+        let matches: Vec<bool> = matches.into_iter().map(|matched| matched == Some(true)).collect();
+        let config = compose_to_string(&fragments, &matches);
+        let hosts: Vec<&str> = config.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(hosts, vec!["Host *", "Host local", "Host *", "Host remote"]);
+    }
+
+    /// Counts the hosts it is asked to ping, none of which answer
+    #[derive(Default)]
+    struct CountingPing(AtomicUsize);
+
+    impl PingProvider for CountingPing {
+        fn pingable(&self, _host: &str) -> bool {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+
+        fn arp_pingable(&self, _ip_address: &str) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn probes_shared_hosts_once_per_run() {
+        let ping = Arc::new(CountingPing::default());
+        let context = Context::new(&Providers { ping: ping.clone(), ..providers() });
+
+        let conditions = Condition::parse_all(
+            "LocalPing 10.0.0.5,10.0.0.6\nLocalPing 10.0.0.6\nLocalPing 10.0.0.5",
+        );
+        assert!(!local_rules_match(&context, Path::new("a.sshconf"), &conditions[..1]));
+        assert!(!local_rules_match(&context, Path::new("b.sshconf"), &conditions[1..]));
+        assert_eq!(ping.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn trims_the_hosts_to_ping() {
+        assert!(matches("LocalPing 10.0.0.100, 192.168.1.100"));
+
+        let ping = Arc::new(CountingPing::default());
+        let context = Context::new(&Providers { ping: ping.clone(), ..providers() });
+        let conditions = Condition::parse_all("LocalPing 10.0.0.5, ,10.0.0.6,\nLocalPing 10.0.0.6");
+        assert!(!local_rules_match(&context, Path::new("a.sshconf"), &conditions));
+        assert_eq!(ping.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn sorts_fragments_by_priority_then_path() {
+        let fragment = |name: &str, priority: Option<&str>| Fragment {
+            path: PathBuf::from(name),
+            metadata: priority
+                .map(|priority| ("Priority".to_string(), priority.to_string()))
+                .into_iter()
+                .collect(),
+            ..Fragment::default()
+        };
+
+        let mut fragments = vec![
+            fragment("b.sshconf", None),
+            fragment("z.sshconf", Some("-5")),
+            fragment("a.sshconf", Some("10")),
+            fragment("B.sshconf", Some("bogus")),
+            fragment("c.sshconf", Some("10")),
+        ];
+        let mut ordered = fragment("d.sshconf", None);
+        ordered.metadata.insert("Order".to_string(), "-10".to_string());
+        fragments.push(ordered);
+        sort_fragments(&mut fragments);
+
+        let names: Vec<_> =
+            fragments.iter().map(|fragment| fragment.path.to_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            vec!["d.sshconf", "z.sshconf", "B.sshconf", "b.sshconf", "a.sshconf", "c.sshconf"]
+        );
+
+        let mut both = fragment("e.sshconf", Some("3"));
+        both.metadata.insert("Order".to_string(), "-10".to_string());
+        assert_eq!(both.priority(), 3);
+    }
+
+    #[test]
+    fn discovers_fragments_in_subdirectories() {
+        let dir = tempfile::tempdir().unwrap();
+        for path in [
+            "b.sshconf",
+            "work/a.sshconf",
+            "work/lab/c.sshconf.toml",
+            "a/z.sshconf",
+            ".git/x.sshconf",
+        ] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "Host x\n").unwrap();
+        }
+        std::fs::write(dir.path().join("work/notes.txt"), "").unwrap();
+
+        let found: Vec<PathBuf> = discover_fragments(dir.path())
+            .into_iter()
+            .map(|path| path.strip_prefix(dir.path()).unwrap().to_path_buf())
+            .collect();
+        let expected = ["a/z.sshconf", "b.sshconf", "work/a.sshconf", "work/lab/c.sshconf.toml"];
+        assert_eq!(found, expected.map(PathBuf::from));
+    }
+
+    #[test]
+    fn renders_variables() {
+        let bastion = Fragment::parse(
+            Path::new("bastion.sshconf"),
+            "# VARIABLES BEGIN (global)\nbastion = \"jump.example.com\"\n# VARIABLES END\n\
+             # VARIABLES BEGIN\n# the SSH port\nport=2222\nnot a variable\n\
+             # GLOBAL CONFIG BEGIN\nHost {{ bastion }}\n  Port {{port}}\n# GLOBAL CONFIG END\n",
+        );
+        assert_eq!(bastion.variables, BTreeMap::from([("port".to_string(), "2222".to_string())]));
+        assert_eq!(bastion.global_rules, "Host {{ bastion }}\n  Port {{port}}");
+        assert_eq!(Fragment::parse(&bastion.path, &bastion.to_sshconf()), bastion);
+
+        let nas = Fragment::parse(
+            Path::new("nas.sshconf"),
+            "# REMOTE CONFIG BEGIN\nHost nas\n  ProxyJump {{bastion}}\n  Port {{ port }}\n\
+             # REMOTE CONFIG END\n",
+        );
+        // The port of the bastion is not visible to the NAS, which is left out.
+        let config = compose_to_string(&[bastion, nas], &[false, false]);
+        let lines: Vec<&str> = config.lines().filter(|line| !line.is_empty()).collect();
+        assert_eq!(lines, vec!["Host jump.example.com", "  Port 2222"]);
+    }
+
+    #[test]
+    fn splices_included_snippets() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("common.inc"),
+            "  ServerAliveInterval 30\n# INCLUDE keys.inc",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("keys.inc"), "  IdentityFile ~/.ssh/id_work\n").unwrap();
+        std::fs::write(dir.path().join("loop.inc"), "# INCLUDE loop.inc\n").unwrap();
+
+        let contents =
+            "# GLOBAL CONFIG BEGIN\nHost a\n  # INCLUDE common.inc\r\nHost b\n# INCLUDE keys.inc\n\
+                        # GLOBAL CONFIG END\n";
+        let path = dir.path().join("a.sshconf");
+        std::fs::write(&path, contents).unwrap();
+        let fragment = read_fragment(dir.path(), &path).unwrap();
+        assert_eq!(
+            fragment.global_rules,
+            "Host a\n  ServerAliveInterval 30\n  IdentityFile ~/.ssh/id_work\n\
+             Host b\n  IdentityFile ~/.ssh/id_work"
+        );
+        assert_eq!(
+            fragment.includes,
+            vec![dir.path().join("keys.inc"), dir.path().join("common.inc")]
+        );
+
+        assert!(splice_includes(dir.path(), "# INCLUDE loop.inc\n")
+            .unwrap_err()
+            .contains("includes itself"));
+        assert!(splice_includes(dir.path(), "# INCLUDE missing.inc\n").is_err());
+        std::fs::write(&path, "# INCLUDE missing.inc\n").unwrap();
+        assert_eq!(read_fragment(dir.path(), &path), None);
+    }
+
+    #[test]
+    fn layers_user_fragments_over_system_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let (user, system) = (dir.path().join("user"), dir.path().join("system"));
+        for path in [
+            "system/bastion.sshconf",
+            "system/work/proxy.sshconf",
+            "system/base.sshconf",
+            "user/a.sshconf",
+        ] {
+            let path = dir.path().join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "Host x\n").unwrap();
+        }
+        std::fs::create_dir_all(user.join("work")).unwrap();
+        std::fs::write(user.join("bastion.sshconf"), "Host mine\n").unwrap();
+        std::fs::write(user.join("work/proxy.sshconf"), "").unwrap();
+
+        let found = discover_layered(&user, &[&system]);
+        let expected =
+            [user.join("a.sshconf"), user.join("bastion.sshconf"), user.join("work/proxy.sshconf")];
+        assert_eq!(found, [expected.to_vec(), vec![system.join("base.sshconf")]].concat());
+        assert_eq!(discover_layered(&user, &[]), expected);
+
+        let legacy = dir.path().join("legacy");
+        std::fs::create_dir_all(&legacy).unwrap();
+        std::fs::write(legacy.join("base.sshconf"), "Host old\n").unwrap();
+        std::fs::write(legacy.join("a.sshconf"), "Host old\n").unwrap();
+        let found = discover_layered(&user, &[&legacy, &system, &user]);
+        assert_eq!(found, [expected.to_vec(), vec![legacy.join("base.sshconf")]].concat());
+    }
+
+    #[test]
+    fn fragments_round_trip_through_json() {
+        let contents = "# CONDITIONS BEGIN\nLocalSSID labnet\nOwner ops\n# CONDITIONS END\n\
+                        # LOCAL CONFIG BEGIN\nHost local\n# LOCAL CONFIG END";
+        let fragment = Fragment::parse(Path::new("test.sshconf"), contents);
+        assert_eq!(
+            fragment.conditions,
+            vec![Condition { key: "LocalSSID".to_string(), value: "labnet".to_string() }]
+        );
+        assert_eq!(fragment.metadata.get("Owner").map(String::as_str), Some("ops"));
+
+        let json = serde_json::to_string(&fragment).unwrap();
+        assert_eq!(serde_json::from_str::<Fragment>(&json).unwrap(), fragment);
+    }
+
+    #[test]
+    fn toml_fragments_match_the_marker_format() {
+        let markers = "# CONDITIONS BEGIN\nLocalGateway 192.168.1.1|00:11:22:33:44:55\n\
+                       LocalSSID home,wlan1:lab\nPriority -5\n# CONDITIONS END\n\
+                       # LOCAL CONFIG BEGIN\nHost nas\n  HostName 192.168.1.2\n\
+                       # LOCAL CONFIG END\n";
+        let toml = r#"
+            local = """
+            Host nas
+              HostName 192.168.1.2
+            """
+
+            [conditions]
+            LocalGateway = [{ ip = "192.168.1.1", mac = "00:11:22:33:44:55" }]
+            LocalSSID = ["home", "wlan1:lab"]
+            Priority = -5
+        "#
+        .lines()
+        .map(|line| line.strip_prefix("            ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+        let path = Path::new("test.sshconf");
+        assert_eq!(Fragment::parse_toml(path, &toml).unwrap(), Fragment::parse(path, markers));
+        assert!(Fragment::parse_toml(path, "[conditions]\nLocalPing = [[\"10.0.0.1\"]]").is_err());
+        assert!(Fragment::parse_toml(path, "locale = \"Host a\"").is_err());
+    }
+
+    #[test]
+    fn reads_yaml_front_matter() {
+        let contents = "---\r\nLocalSSID: [home, wlan1:lab]\r\nLocalGateway:\r\n\
+                        \x20 - ip: 192.168.1.1\r\n    mac: 00:11:22:33:44:55\r\n\
+                        Owner: ops\r\n---\r\n# CONDITIONS BEGIN\r\nLocalPing 10.0.0.1\r\n\
+                        Owner infra\r\n# CONDITIONS END\r\n\
+                        # LOCAL CONFIG BEGIN\r\nHost nas\r\n# LOCAL CONFIG END\r\n";
+        let fragment = Fragment::parse(Path::new("test.sshconf"), contents);
+        let conditions: Vec<String> =
+            fragment.conditions.iter().map(Condition::to_string).collect();
+        assert_eq!(
+            conditions,
+            [
+                "LocalGateway 192.168.1.1|00:11:22:33:44:55",
+                "LocalSSID home,wlan1:lab",
+                "LocalPing 10.0.0.1"
+            ]
+        );
+        assert_eq!(fragment.metadata.get("Owner").map(String::as_str), Some("infra"));
+        assert_eq!(fragment.local_rules, "Host nas");
+
+        // Unterminated or invalid front matter leaves the sections alone.
+        for contents in [
+            "---\nLocalSSID: home\n# LOCAL CONFIG BEGIN\nHost nas\n# LOCAL CONFIG END\n",
+            "---\nLocalSSID: [home\n---\n# LOCAL CONFIG BEGIN\nHost nas\n# LOCAL CONFIG END\n",
+        ] {
+            let fragment = Fragment::parse(Path::new("test.sshconf"), contents);
+            assert!(fragment.conditions.is_empty());
+            assert_eq!(fragment.local_rules, "Host nas");
+        }
+    }
+}
@@ -1,52 +1,646 @@
 //! # SSID
 //!
-//! This module contains the function to get the currently connected SSID of the machine.
+//! This module contains the functions to get the currently connected SSIDs of the machine.
 
-use std::{
-    process::Command,
-    str
-};
+use crate::error::{Error, Result};
+use crate::platform::{command, netsh_command, run};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs};
+use unicode_normalization::UnicodeNormalization;
 
-/// Get the currently connected SSID
-pub fn get_current_ssid() -> Result<String, &'static str> {
-    if cfg!(target_os = "windows")
-    {
-        let output = Command::new("netsh")
-            .args(["wlan", "show", "interfaces"])
-            .output()
-            .expect("Failed to execute command");
+/// A wireless interface and the SSID it is connected to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Connection {
+    pub interface: String,
+    pub ssid: String,
+}
 
-        let output_str = str::from_utf8(&output.stdout).unwrap();
-        for line in output_str.lines() {
-            if line.contains("SSID") && !line.contains("BSSID") {
-                return Ok(line.split(":").nth(1).unwrap().trim().to_string());
+impl fmt::Display for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.interface.is_empty() {
+            return write!(f, "{}", self.ssid);
+        }
+
+        write!(f, "{}:{}", self.interface, self.ssid)
+    }
+}
+
+/// Placeholder reported by macOS instead of the SSID without Location Services permission.
+const MACOS_REDACTED_SSID: &str = "<redacted>";
+
+/// Explanation printed when macOS hides the SSID.
+const MACOS_REDACTED_HELP: &str = "macOS is hiding the Wi-Fi SSID because this program has no \
+Location Services permission. Grant it to the app running sshconfgen (e.g. your terminal, or the \
+LaunchAgent's program) in System Settings > Privacy & Security > Location Services. Until then \
+LocalSSID conditions are skipped, and LocalGateway, LocalPing, and LocalArpPing conditions decide \
+which rules are used.";
+
+/// An entry of a `LocalSSID` list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SsidEntry {
+    /// An unquoted SSID, which may carry an `interface:` prefix
+    Plain(String),
+    /// A quoted SSID taken literally, optionally scoped to an interface with `interface:"ssid"`
+    Quoted { interface: Option<String>, ssid: String },
+}
+
+/// Parse a comma-separated `LocalSSID` list
+///
+/// Unquoted entries are trimmed. Double-quoted entries keep their surrounding whitespace and may
+/// contain commas, colons and `\"` escaped quotes.
+pub fn parse_ssid_list(value: &str) -> Vec<SsidEntry> {
+    let mut entries = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        let mut prefix = String::new();
+        let mut quoted = None;
+
+        while let Some(c) = chars.next() {
+            match c {
+                ',' => break,
+                '"' if quoted.is_none() => {
+                    let mut ssid = String::new();
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => ssid.extend(chars.next()),
+                            '"' => break,
+                            _ => ssid.push(c),
+                        }
+                    }
+                    quoted = Some(ssid);
+                }
+                // Anything between the closing quote and the next comma is ignored.
+                _ if quoted.is_some() => {}
+                _ => prefix.push(c),
+            }
+        }
+
+        let prefix = prefix.trim();
+        match quoted {
+            Some(ssid) => entries.push(SsidEntry::Quoted {
+                interface: prefix.strip_suffix(':').map(str::to_string),
+                ssid,
+            }),
+            None if !prefix.is_empty() => entries.push(SsidEntry::Plain(prefix.to_string())),
+            None => {}
+        }
+
+        if chars.peek().is_none() {
+            return entries;
+        }
+    }
+}
+
+/// Find the connection an entry of a `LocalSSID` list matches
+///
+/// A plain `a:b` entry is only treated as interface-scoped when an interface named `a` is
+/// connected, since SSIDs may contain colons themselves.
+pub fn find_match<'a>(entry: &SsidEntry, connections: &'a [Connection]) -> Option<&'a Connection> {
+    let (interface, ssid) = match entry {
+        SsidEntry::Plain(plain) => match plain.split_once(':') {
+            Some((interface, ssid)) if connections.iter().any(|c| c.interface == interface) => {
+                (Some(interface), ssid)
+            }
+            _ => (None, plain.as_str()),
+        },
+        SsidEntry::Quoted { interface, ssid } => (interface.as_deref(), ssid.as_str()),
+    };
+
+    connections.iter().find(|c| {
+        interface.is_none_or(|interface| c.interface == interface) && ssid_eq(&c.ssid, ssid)
+    })
+}
+
+/// Compare two SSIDs after Unicode NFC normalization
+///
+/// The same name may be reported precomposed by one tool and decomposed by another (or typed
+/// either way in a config file). Case and whitespace are significant.
+pub fn ssid_eq(a: &str, b: &str) -> bool {
+    a.nfc().eq(b.nfc())
+}
+
+/// Get every connected wireless interface along with its SSID
+pub fn get_connections() -> Result<Vec<Connection>> {
+    let settings = crate::settings::get();
+    if let Some(ssid_command) = &settings.ssid_command {
+        return get_custom_connections(ssid_command, settings.ssid_pattern.as_deref());
+    }
+
+    // Under WSL the Linux kernel only knows the virtual adapter.
+    let native_supported = crate::native::SSID_SUPPORTED && !crate::platform::is_wsl();
+    if crate::backend::use_native(settings.backend.ssid, command_tool(), native_supported) {
+        return crate::native::get_connections();
+    }
+
+    if cfg!(target_os = "windows") {
+        if !crate::backend::is_in_path("netsh") {
+            return crate::powershell::get_connections();
+        }
+
+        let output = run(&mut netsh_command(&["wlan", "show", "interfaces"]), "LocalSSID")?;
+
+        return Ok(parse_netsh_connections(&String::from_utf8_lossy(&output.stdout)));
+    } else if cfg!(target_os = "linux") {
+        if crate::platform::is_wsl() {
+            // The Linux side only sees a virtual adapter, ask the Windows host instead.
+            if !crate::backend::is_in_path("netsh.exe") {
+                return crate::powershell::get_connections();
+            }
+
+            let output = run(&mut netsh_command(&["wlan", "show", "interfaces"]), "LocalSSID")?;
+
+            return Ok(parse_netsh_connections(&String::from_utf8_lossy(&output.stdout)));
+        }
+
+        let mut connections = Vec::new();
+        for interface in get_linux_wireless_interfaces() {
+            let output = run(command("iwgetid").args(["-r", &interface]), "LocalSSID")?;
+
+            // iwgetid prints the raw SSID bytes, only the trailing newline is not part of it.
+            let ssid = strip_line_ending(&String::from_utf8_lossy(&output.stdout)).to_string();
+            if !ssid.is_empty() {
+                connections.push(Connection { interface, ssid });
+            }
+        }
+
+        return Ok(connections);
+    } else if cfg!(target_os = "android") {
+        if !crate::platform::is_termux() {
+            return Err(Error::Unsupported("SSID detection outside of Termux"));
+        }
+
+        // Requires the Termux:API app and the termux-api package.
+        let output = run(&mut command("termux-wifi-connectioninfo"), "LocalSSID")?;
+
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap_or_default();
+        return Ok(match info["ssid"].as_str().map(parse_android_ssid) {
+            Some(ssid) if !ssid.is_empty() => {
+                vec![Connection { interface: "wlan0".to_string(), ssid }]
+            }
+            _ => vec![],
+        });
+    } else if cfg!(target_os = "macos") {
+        let mut connections = Vec::new();
+        for interface in get_macos_wireless_interfaces() {
+            let output =
+                run(command("networksetup").args(["-getairportnetwork", &interface]), "LocalSSID")?;
+
+            // "Current Wi-Fi Network: <ssid>", the SSID may itself contain ": ".
+            let output_str = String::from_utf8_lossy(&output.stdout);
+            let ssid = output_str
+                .find(": ")
+                .map(|start| strip_line_ending(&output_str[start + 2..]).to_string());
+
+            match ssid {
+                Some(ssid) if ssid != MACOS_REDACTED_SSID => {
+                    connections.push(Connection { interface, ssid })
+                }
+                // Without Location Services permission the SSID is redacted, or the interface is
+                // reported as not associated even though it has an address.
+                _ if ssid.is_some() || is_macos_interface_up(&interface) => {
+                    crate::platform::warn_once("macos ssid redaction", MACOS_REDACTED_HELP);
+                    return Err(Error::Detection(
+                        "The SSID is hidden by macOS Location Services privacy",
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        return Ok(connections);
+    } else if cfg!(target_os = "freebsd") {
+        let output = run(command("ifconfig").args(["-l"]), "LocalSSID")?;
+
+        // Wireless interfaces are wlan(4) clones named wlan0, wlan1, ...
+        let output_str = String::from_utf8_lossy(&output.stdout);
+        let mut connections = Vec::new();
+        for interface in output_str.split_whitespace().filter(|name| name.starts_with("wlan")) {
+            let output = run(command("ifconfig").args([interface]), "LocalSSID")?;
+
+            // The status line reads "ssid <name> channel <n> ...".
+            let output_str = String::from_utf8_lossy(&output.stdout);
+            for line in output_str.lines() {
+                if let Some(rest) = line.trim_start().strip_prefix("ssid ") {
+                    connections.push(Connection {
+                        interface: interface.to_string(),
+                        ssid: parse_ifconfig_ssid(rest),
+                    });
+                }
+            }
+        }
+
+        return Ok(connections);
+    } else if cfg!(target_os = "openbsd") {
+        let output = run(&mut command("ifconfig"), "LocalSSID")?;
+
+        // Associated wireless interfaces report "ieee80211: nwid <name> chan <n> ...".
+        return Ok(parse_ifconfig_connections(
+            &String::from_utf8_lossy(&output.stdout),
+            "ieee80211: nwid ",
+        ));
+    } else if cfg!(target_os = "netbsd") {
+        let output = run(&mut command("ifconfig"), "LocalSSID")?;
+
+        // Wireless interfaces are named after their driver (iwn0, urtwn0, ...) and report
+        // "ssid <name> nwkey ..." inside their block.
+        return Ok(parse_ifconfig_connections(&String::from_utf8_lossy(&output.stdout), "ssid "));
+    } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+        let output = run(
+            command("dladm").args(["show-wifi", "-p", "-o", "link,status,essid"]),
+            "LocalSSID",
+        )?;
+
+        return Ok(parse_dladm_connections(&String::from_utf8_lossy(&output.stdout)));
+    }
+
+    Err(Error::Unsupported("SSID detection"))
+}
+
+/// Get the external tool the command backend uses to detect SSIDs on this platform
+fn command_tool() -> &'static str {
+    if cfg!(target_os = "windows") || crate::platform::is_wsl() {
+        "cmd"
+    } else if cfg!(target_os = "android") {
+        "termux-wifi-connectioninfo"
+    } else if cfg!(target_os = "linux") {
+        "iwgetid"
+    } else if cfg!(target_os = "macos") {
+        "networksetup"
+    } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+        "dladm"
+    } else {
+        "ifconfig"
+    }
+}
+
+/// Get the connections from a user-configured `ssid_command`
+///
+/// Without a pattern every non-empty output line is an SSID. With a pattern, each matching line
+/// yields its `ssid` capture group and the optional `interface` group.
+fn get_custom_connections(
+    ssid_command: &[String],
+    pattern: Option<&str>,
+) -> Result<Vec<Connection>> {
+    let output = run(&mut crate::settings::build_command(ssid_command, "", ""), "LocalSSID")?;
+
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let pattern = pattern.map(|pattern| Regex::new(pattern).unwrap());
+    let mut connections = Vec::new();
+
+    for line in output_str.lines() {
+        let line = strip_line_ending(line);
+        let connection = match &pattern {
+            Some(pattern) => pattern.captures(line).and_then(|caps| {
+                Some(Connection {
+                    interface: caps.name("interface").map_or("", |m| m.as_str()).to_string(),
+                    ssid: caps.name("ssid")?.as_str().to_string(),
+                })
+            }),
+            None => Some(Connection { interface: String::new(), ssid: line.to_string() }),
+        };
+
+        if let Some(connection) = connection.filter(|c| !c.ssid.is_empty()) {
+            connections.push(connection);
+        }
+    }
+
+    Ok(connections)
+}
+
+/// Get the connections from the output of `netsh wlan show interfaces`
+///
+/// The field labels are translated on non-English systems, so the output is parsed by layout:
+/// each adapter is an indented block of `label : value` lines whose first line is its name. Only
+/// the `SSID` label itself is the same in every language.
+fn parse_netsh_connections(output: &str) -> Vec<Connection> {
+    let mut connections = Vec::new();
+    let mut interface = String::new();
+    let mut new_block = true;
+
+    for line in output.lines() {
+        // Blank lines and unindented headers separate the adapter blocks.
+        if line.trim().is_empty() || !line.starts_with(char::is_whitespace) {
+            new_block = true;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        if new_block {
+            interface = value.trim().to_string();
+            new_block = false;
+        } else if key.trim() == "SSID" {
+            // Values are printed after "label : ", anything past that single space is the SSID.
+            let ssid = value.strip_prefix(' ').unwrap_or(value);
+            connections.push(Connection {
+                interface: interface.clone(),
+                ssid: strip_line_ending(ssid).to_string(),
+            });
+        }
+    }
+
+    connections
+}
+
+/// Get the BSSID of the access point a wireless interface is associated with
+///
+/// Only reported where a tool prints it without elevated privileges, `None` elsewhere.
+pub fn get_bssid(interface: &str) -> Option<String> {
+    let output = if cfg!(target_os = "windows") || crate::platform::is_wsl() {
+        if !crate::backend::is_in_path(if crate::platform::is_wsl() {
+            "netsh.exe"
+        } else {
+            "netsh"
+        }) {
+            return None;
+        }
+
+        let output = run(&mut netsh_command(&["wlan", "show", "interfaces"]), "LocalSSID").ok()?;
+        return parse_netsh_bssid(&String::from_utf8_lossy(&output.stdout), interface);
+    } else if cfg!(target_os = "linux") && crate::backend::is_in_path("iw") {
+        // "Connected to 00:11:22:33:44:55 (on wlan0)"
+        run(command("iw").args(["dev", interface, "link"]), "LocalSSID").ok()?
+    } else if cfg!(target_os = "macos") {
+        // "  BSSID : 00:11:22:33:44:55", redacted like the SSID without Location Services.
+        run(command("ipconfig").args(["getsummary", interface]), "LocalSSID").ok()?
+    } else {
+        return None;
+    };
+
+    let mac_re = Regex::new(r"^[0-9A-Fa-f]{1,2}(:[0-9A-Fa-f]{1,2}){5}$").unwrap();
+    String::from_utf8_lossy(&output.stdout).lines().find_map(|line| {
+        let line = line.trim();
+        let rest = line.strip_prefix("Connected to ").or_else(|| line.strip_prefix("BSSID : "))?;
+        rest.split_whitespace().next().filter(|bssid| mac_re.is_match(bssid)).map(str::to_string)
+    })
+}
+
+/// Get the BSSID of an adapter from the output of `netsh wlan show interfaces`
+///
+/// Blocks are laid out as in [`parse_netsh_connections`], the label is `BSSID` or `AP BSSID`
+/// depending on the Windows version.
+fn parse_netsh_bssid(output: &str, interface: &str) -> Option<String> {
+    let mut current = String::new();
+    let mut new_block = true;
+
+    for line in output.lines() {
+        if line.trim().is_empty() || !line.starts_with(char::is_whitespace) {
+            new_block = true;
+            continue;
+        }
+
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+
+        if new_block {
+            current = value.trim().to_string();
+            new_block = false;
+        } else if current == interface && key.trim().ends_with("BSSID") {
+            return Some(value.trim().to_string());
+        }
+    }
+
+    None
+}
+
+/// Get the names of the wireless interfaces on Linux
+///
+/// Wireless devices expose a `wireless` or `phy80211` entry under `/sys/class/net/<name>`.
+fn get_linux_wireless_interfaces() -> Vec<String> {
+    let mut interfaces: Vec<String> = fs::read_dir("/sys/class/net")
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| {
+                    let path = entry.path();
+                    path.join("wireless").exists() || path.join("phy80211").exists()
+                })
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    interfaces.sort();
+    interfaces
+}
+
+/// Get whether a macOS interface has an IPv4 address assigned
+fn is_macos_interface_up(interface: &str) -> bool {
+    run(command("ipconfig").args(["getifaddr", interface]), "LocalSSID")
+        .is_ok_and(|output| output.status.success() && !output.stdout.trim_ascii().is_empty())
+}
+
+/// Get the device names of the Wi-Fi hardware ports on macOS, defaulting to `en0`
+fn get_macos_wireless_interfaces() -> Vec<String> {
+    let output = match run(command("networksetup").args(["-listallhardwareports"]), "LocalSSID") {
+        Ok(output) => output,
+        Err(_) => return vec!["en0".to_string()],
+    };
+
+    // Blocks read "Hardware Port: Wi-Fi" followed by "Device: en0".
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let mut interfaces = Vec::new();
+    let mut is_wifi = false;
+    for line in output_str.lines() {
+        if let Some(port) = line.strip_prefix("Hardware Port: ") {
+            is_wifi = port == "Wi-Fi" || port == "AirPort";
+        } else if let Some(device) = line.strip_prefix("Device: ") {
+            if is_wifi {
+                interfaces.push(device.trim().to_string());
+            }
+        }
+    }
+
+    if interfaces.is_empty() {
+        interfaces.push("en0".to_string());
+    }
+
+    interfaces
+}
+
+/// Strip a single trailing line ending, keeping any other whitespace that is part of the SSID
+fn strip_line_ending(value: &str) -> &str {
+    let value = value.strip_suffix('\n').unwrap_or(value);
+    value.strip_suffix('\r').unwrap_or(value)
+}
+
+/// Get the connections from a BSD `ifconfig` listing of all interfaces
+///
+/// Interface blocks start unindented with "<name>: flags=...", associated wireless interfaces
+/// report the SSID on an indented line starting with `prefix`.
+fn parse_ifconfig_connections(output: &str, prefix: &str) -> Vec<Connection> {
+    let mut connections = Vec::new();
+    let mut interface = String::new();
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            interface = line.split(':').next().unwrap().to_string();
+        } else if let Some(rest) = line.trim_start().strip_prefix(prefix) {
+            connections
+                .push(Connection { interface: interface.clone(), ssid: parse_ifconfig_ssid(rest) });
+        }
+    }
+
+    connections
+}
+
+/// Get the connections from the parsable `dladm show-wifi -p -o link,status,essid` output
+///
+/// Fields are separated by colons, colons and backslashes inside a field are escaped with a
+/// backslash.
+fn parse_dladm_connections(output: &str) -> Vec<Connection> {
+    let mut connections = Vec::new();
+    for line in output.lines() {
+        let mut fields = vec![String::new()];
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => fields.last_mut().unwrap().extend(chars.next()),
+                ':' => fields.push(String::new()),
+                _ => fields.last_mut().unwrap().push(c),
             }
         }
 
-        return Ok("".to_string());
+        if let [interface, status, ssid] = fields.as_slice() {
+            if status == "connected" && !ssid.is_empty() {
+                connections.push(Connection { interface: interface.clone(), ssid: ssid.clone() });
+            }
+        }
     }
-    else if cfg!(target_os = "linux")
-    {
-        let output = Command::new("iwgetid")
-            .args(["-r"])
-            .output()
-            .expect("Failed to execute command");
 
-        return Ok(str::from_utf8(&output.stdout).unwrap().trim().to_string());
+    connections
+}
+
+/// Get the SSID from the remainder of a BSD `ifconfig` line following `ssid`/`nwid`
+///
+/// Names containing spaces are double-quoted and names with unprintable bytes are printed as
+/// `0x` followed by hex digits, otherwise the name runs up to the next space.
+fn parse_ifconfig_ssid(rest: &str) -> String {
+    if let Some(quoted) = rest.strip_prefix('"') {
+        return quoted.split('"').next().unwrap().to_string();
     }
-    else if cfg!(target_os = "macos") {
-        let output = Command::new("networksetup")
-            .args(["-getairportnetwork", "en0"])
-            .output()
-            .expect("Failed to execute command");
 
-        let output_str = str::from_utf8(&output.stdout).unwrap();
-        if let Some(start) = output_str.find(": ") {
-            return Ok(output_str[start + 2..].trim().to_string());
+    let token = rest.split(' ').next().unwrap();
+    if let Some(hex) = token.strip_prefix("0x") {
+        let bytes: Option<Vec<u8>> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect();
+
+        if let Some(bytes) = bytes {
+            return String::from_utf8_lossy(&bytes).to_string();
         }
+    }
+
+    token.to_string()
+}
 
-        return Ok("".to_string());
+/// Get the SSID from the `ssid` field reported by Termux:API
+///
+/// Android wraps UTF-8 SSIDs in double quotes and reports a placeholder when disconnected.
+fn parse_android_ssid(ssid: &str) -> String {
+    if ssid == "<unknown ssid>" {
+        return String::new();
     }
 
-    Err("Unsupported operating system")
-}
\ No newline at end of file
+    ssid.strip_prefix('"').and_then(|ssid| ssid.strip_suffix('"')).unwrap_or(ssid).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(interface: &str, ssid: &str) -> Connection {
+        Connection { interface: interface.to_string(), ssid: ssid.to_string() }
+    }
+
+    #[test]
+    fn parses_netsh_ssids_with_colons_spaces_and_unicode() {
+        let output = "\r\nThere are 2 interfaces on the system:\r\n\r\n\
+            \x20   Name                   : Wi-Fi\r\n\
+            \x20   SSID                   : Caf\u{e9} : Gäste \r\n\
+            \x20   BSSID                  : 00:11:22:33:44:55\r\n\r\n\
+            \x20   Nom                    : Wi-Fi 2\r\n\
+            \x20   SSID                   : 🐟 fish\r\n";
+
+        assert_eq!(
+            parse_netsh_connections(output),
+            vec![connection("Wi-Fi", "Café : Gäste "), connection("Wi-Fi 2", "🐟 fish")]
+        );
+    }
+
+    #[test]
+    fn parses_netsh_bssid_of_an_adapter() {
+        let output = "\r\n\x20   Name                   : Wi-Fi\r\n\
+            \x20   SSID                   : labnet\r\n\
+            \x20   AP BSSID               : 00:11:22:33:44:55\r\n\r\n\
+            \x20   Name                   : Wi-Fi 2\r\n\
+            \x20   SSID                   : other\r\n";
+
+        assert_eq!(parse_netsh_bssid(output, "Wi-Fi").as_deref(), Some("00:11:22:33:44:55"));
+        assert_eq!(parse_netsh_bssid(output, "Wi-Fi 2"), None);
+    }
+
+    #[test]
+    fn parses_quoted_and_hex_ifconfig_ssids() {
+        assert_eq!(parse_ifconfig_ssid("\" my net \" channel 6 (2437 MHz 11g)"), " my net ");
+        assert_eq!(parse_ifconfig_ssid("labnet channel 6"), "labnet");
+        assert_eq!(parse_ifconfig_ssid("0x436166c3a9 chan 1"), "Café");
+    }
+
+    #[test]
+    fn parses_escaped_dladm_fields() {
+        let output = "iwk0:connected:lab\\:net\nath0:disconnected:\n";
+        assert_eq!(parse_dladm_connections(output), vec![connection("iwk0", "lab:net")]);
+    }
+
+    #[test]
+    fn strips_android_quotes_and_placeholder() {
+        assert_eq!(parse_android_ssid("\"Zürich\""), "Zürich");
+        assert_eq!(parse_android_ssid("<unknown ssid>"), "");
+    }
+
+    #[test]
+    fn parses_quoted_ssid_lists() {
+        assert_eq!(
+            parse_ssid_list(r#"foo, bar5ghz, " spaced, out ", wlan1:"a \"b\"", wlan0:lab"#),
+            vec![
+                SsidEntry::Plain("foo".to_string()),
+                SsidEntry::Plain("bar5ghz".to_string()),
+                SsidEntry::Quoted { interface: None, ssid: " spaced, out ".to_string() },
+                SsidEntry::Quoted {
+                    interface: Some("wlan1".to_string()),
+                    ssid: "a \"b\"".to_string()
+                },
+                SsidEntry::Plain("wlan0:lab".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_with_normalization_and_interface_scope() {
+        let connections = vec![connection("wlan0", "Cafe\u{301}"), connection("wlan1", "lab:net")];
+
+        // Precomposed é in the config matches the decomposed form reported by the tool.
+        let entry = SsidEntry::Plain("Caf\u{e9}".to_string());
+        assert_eq!(find_match(&entry, &connections), Some(&connections[0]));
+
+        // "lab" is not an interface, so the colon is part of the SSID.
+        let entry = SsidEntry::Plain("lab:net".to_string());
+        assert_eq!(find_match(&entry, &connections), Some(&connections[1]));
+
+        let entry = SsidEntry::Plain("wlan0:lab:net".to_string());
+        assert_eq!(find_match(&entry, &connections), None);
+
+        let entry =
+            SsidEntry::Quoted { interface: Some("wlan1".to_string()), ssid: "lab:net".to_string() };
+        assert_eq!(find_match(&entry, &connections), Some(&connections[1]));
+
+        assert!(!ssid_eq("labnet", "labnet "));
+        assert!(!ssid_eq("LabNet", "labnet"));
+    }
+}
@@ -0,0 +1,162 @@
+//! # State
+//!
+//! This module contains the state file kept between runs, holding the last network fingerprint
+//! and the results of slow probes. A run scheduled every minute reuses those results while the
+//! network is unchanged, instead of probing unreachable hosts again. It also records the SSH
+//! config files sshconfgen manages, see [`crate::adopt`], and their last generation, see
+//! [`crate::status`].
+
+use crate::error::{Error, Result};
+use crate::fingerprint::Fingerprint;
+use crate::status::Generation;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Held while updating the state file, so concurrent generations of other `.ssh` directories
+/// don't lose each other's records
+static UPDATING: Mutex<()> = Mutex::new(());
+
+/// Seconds the result of each kind of probe is kept in the state file, 0 (the default) disables
+/// keeping it
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtlSettings {
+    pub ping: u64,
+    pub arp_ping: u64,
+    pub gateway: u64,
+}
+
+impl TtlSettings {
+    /// Get whether any probe result is kept
+    pub fn enabled(&self) -> bool {
+        self.ping > 0 || self.arp_ping > 0 || self.gateway > 0
+    }
+}
+
+/// The probe results kept between runs, only valid on the network they were observed on
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct State {
+    pub fingerprint: Option<Fingerprint>,
+    pub probes: BTreeMap<String, Probe>,
+    /// The SSH config files written by sshconfgen
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub managed: BTreeSet<PathBuf>,
+    /// The last generation of each SSH config file
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub generations: BTreeMap<PathBuf, Generation>,
+}
+
+/// The result of a probe and when it expires
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Probe {
+    pub result: String,
+    /// Seconds since the Unix epoch
+    pub expires: u64,
+}
+
+/// Get the directory of the state file and the files kept next to it, `sshconfgen` in the state
+/// directory of the user
+///
+/// `$XDG_STATE_HOME` is honored on every platform, otherwise `~/.local/state` is used.
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| crate::platform::home_dir().map(|home| home.join(".local").join("state")))?;
+
+    Some(state_dir.join("sshconfgen"))
+}
+
+/// Get the path of the state file in `state_dir`
+pub fn state_path(state_dir: &Path) -> PathBuf {
+    state_dir.join("state.json")
+}
+
+/// Load the state file of `state_dir` if any probe result is kept, a missing or unreadable file
+/// yields an empty state
+pub fn load(state_dir: &Path) -> Option<State> {
+    crate::settings::get().state_ttl.enabled().then(|| read(state_dir))
+}
+
+/// Read the state file of `state_dir`, a missing or unreadable file yields an empty state
+pub fn read(state_dir: &Path) -> State {
+    crate::file::read_file(&state_path(state_dir))
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Read the state file of `state_dir`, apply `update` to it and write it back
+pub fn update(state_dir: &Path, update: impl FnOnce(&mut State)) -> Result<()> {
+    let _updating = UPDATING.lock().unwrap_or_else(|e| e.into_inner());
+    let mut state = read(state_dir);
+    update(&mut state);
+    state.save(state_dir)
+}
+
+/// Get the current time in seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+impl State {
+    /// Forget every probe result if they were observed on another network
+    pub fn observe(&mut self, fingerprint: &Fingerprint) {
+        if self.fingerprint.as_ref() != Some(fingerprint) {
+            self.probes.clear();
+            self.fingerprint = Some(fingerprint.clone());
+        }
+    }
+
+    /// Get the result of a probe, unless it expired
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.probes
+            .get(key)
+            .filter(|probe| probe.expires > now())
+            .map(|probe| probe.result.as_str())
+    }
+
+    /// Keep the result of a probe for `ttl` seconds
+    pub fn insert(&mut self, key: String, result: String, ttl: u64) {
+        self.probes.insert(key, Probe { result, expires: now() + ttl });
+    }
+
+    /// Write the state file of `state_dir`, without the expired probe results
+    pub fn save(&mut self, state_dir: &Path) -> Result<()> {
+        let path = state_path(state_dir);
+        let now = now();
+        self.probes.retain(|_, probe| probe.expires > now);
+
+        std::fs::create_dir_all(state_dir).map_err(Error::io("create", state_dir))?;
+
+        let contents = serde_json::to_string_pretty(self).unwrap_or_default();
+        crate::file::replace_file(&path, |out| out.write_all(contents.as_bytes()))
+            .map_err(Error::io("write", &path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_results_of_other_networks_and_expired_ones() {
+        let home = Fingerprint { interface: Some("wlan0".to_string()), ..Fingerprint::default() };
+        let mut state = State::default();
+        state.observe(&home);
+        state.insert("ping:nas".to_string(), "true".to_string(), 60);
+        state.insert("ping:lab".to_string(), "false".to_string(), 0);
+
+        state.observe(&home);
+        assert_eq!(state.get("ping:nas"), Some("true"));
+        assert_eq!(state.get("ping:lab"), None);
+
+        state.observe(&Fingerprint::default());
+        assert_eq!(state.get("ping:nas"), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! # Status
+//!
+//! This module records each generation in the state file: when it ran, the profile it selected,
+//! the network fingerprint, the fragments it was generated from, and the files it wrote.
+//! `sshconfgen status` reads the record without detecting anything, and a one-shot run skips the
+//! generation when the fragments, the settings, the network and the written files are unchanged.
+//!
+//! Fragments with `LocalPing` or `LocalArpPing` conditions, encrypted sections or placeholders
+//! depend on more than the fingerprint, so configs generated from them are always regenerated.
+
+use crate::error::Result;
+use crate::fingerprint::Fingerprint;
+use crate::paths::Paths;
+use crate::sshconf::Fragment;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The conditions whose outcome the network fingerprint doesn't capture
+const PROBE_CONDITIONS: &[&str] = &["LocalPing", "LocalArpPing"];
+
+/// A generation as recorded in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Generation {
+    /// Seconds since the Unix epoch
+    pub time: u64,
+    pub profile: String,
+    pub fingerprint: Fingerprint,
+    /// The fragments, in processing order
+    pub fragments: Vec<PathBuf>,
+    /// The SHA-256 of the parsed fragments and the settings file, `None` if the config depends
+    /// on more than them and the fingerprint
+    pub inputs: Option<String>,
+    /// The SHA-256 of each file written
+    pub written: BTreeMap<PathBuf, String>,
+}
+
+/// Get the SHA-256 of the inputs of a generation from `fragments`, `None` if some fragment or
+/// named network depends on probes, secrets or decryption. The settings overridden by environment
+/// variables are part of the inputs.
+pub fn inputs(fragments: &[Fragment]) -> Option<String> {
+    let settings = crate::settings::get();
+    let probed = fragments
+        .iter()
+        .flat_map(|fragment| fragment.conditions.iter().map(|condition| condition.key.as_str()))
+        .chain(
+            settings
+                .networks
+                .iter()
+                .flat_map(|network| network.conditions.keys().map(String::as_str)),
+        )
+        .any(|key| PROBE_CONDITIONS.contains(&key));
+    let secret = fragments.iter().any(|fragment| {
+        let rules = [&fragment.global_rules, &fragment.local_rules, &fragment.remote_rules];
+        !fragment.encrypted.is_empty() || rules.iter().any(|rules| rules.contains("{{"))
+    });
+    if probed || secret {
+        return None;
+    }
+
+    let mut bytes = serde_json::to_vec(fragments).ok()?;
+    if let Some(contents) =
+        crate::settings::settings_path().and_then(|path| std::fs::read(path).ok())
+    {
+        bytes.extend(contents);
+    }
+    for (name, value) in crate::settings::env_overrides() {
+        bytes.extend(format!("{}={}\n", name, value).into_bytes());
+    }
+    Some(crate::source::sha256_hex(&bytes))
+}
+
+/// Get the SHA-256 of each of `files`, skipping the missing ones
+fn hashes(files: &[PathBuf]) -> BTreeMap<PathBuf, String> {
+    files
+        .iter()
+        .filter_map(|file| {
+            Some((file.clone(), crate::source::sha256_hex(&std::fs::read(file).ok()?)))
+        })
+        .collect()
+}
+
+/// Get the last generation of the SSH config of `paths`
+pub fn last(paths: &Paths) -> Option<Generation> {
+    crate::state::read(&paths.state_dir).generations.remove(&paths.config_file)
+}
+
+/// Get the last generation of the SSH config of `paths` if it had the same inputs on the same
+/// network, and the files it wrote are unchanged
+pub fn unchanged(paths: &Paths, inputs: &str, fingerprint: &Fingerprint) -> Option<Generation> {
+    let generation = last(paths)?;
+    let files: Vec<PathBuf> = generation.written.keys().cloned().collect();
+    let unchanged = generation.inputs.as_deref() == Some(inputs)
+        && generation.fingerprint == *fingerprint
+        && !files.is_empty()
+        && hashes(&files) == generation.written;
+    unchanged.then_some(generation)
+}
+
+/// Record a generation of the SSH config of `paths` that wrote `files`, forgetting the
+/// generations of config files that no longer exist
+pub fn record(paths: &Paths, generation: Generation, files: &[PathBuf]) -> Result<()> {
+    let generation = Generation { written: hashes(files), ..generation };
+    crate::state::update(&paths.state_dir, |state| {
+        state.generations.retain(|path, _| path.exists());
+        state.generations.insert(paths.config_file.clone(), generation);
+    })
+}
+
+impl Generation {
+    /// Check whether a file the generation wrote was changed since
+    pub fn modified(&self) -> bool {
+        !self.written.is_empty()
+            && hashes(&self.written.keys().cloned().collect::<Vec<_>>()) != self.written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depends_on_the_fragments_unless_probed() {
+        let fragment = Fragment {
+            path: "a.sshconf".into(),
+            global_rules: "Host a".to_string(),
+            ..Fragment::default()
+        };
+        let other = Fragment { global_rules: "Host b".to_string(), ..fragment.clone() };
+        let digest = inputs(std::slice::from_ref(&fragment));
+        assert!(digest.is_some());
+        assert_eq!(digest, inputs(std::slice::from_ref(&fragment)));
+        assert_ne!(digest, inputs(&[other]));
+
+        let condition = crate::sshconf::Condition {
+            key: "LocalPing".to_string(),
+            value: "10.0.0.1".to_string(),
+        };
+        assert_eq!(inputs(&[Fragment { conditions: vec![condition], ..fragment.clone() }]), None);
+        let template = Fragment { local_rules: "User {{ env(\"USER\") }}".to_string(), ..fragment };
+        assert_eq!(inputs(&[template]), None);
+    }
+}
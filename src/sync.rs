@@ -0,0 +1,243 @@
+//! # Sync
+//!
+//! This module pulls a git repository of shared fragments, e.g. the bastion and jump hosts of a
+//! team, before generating. The repository is either checked out as `config.d` itself, or checked
+//! out next to it and its fragments overlaid onto the local ones.
+
+use crate::error::{Error, Result};
+use crate::paths::Paths;
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The repository `sshconfgen sync` pulls
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncSettings {
+    /// The URL or path of the repository, anything `git clone` accepts
+    pub repository: String,
+    /// The branch to check out, defaults to the default branch of the repository
+    #[serde(default)]
+    pub branch: Option<String>,
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// Discard local changes to the synced fragments instead of keeping them
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Where the repository is checked out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// `config.d` is the checkout
+    #[default]
+    Into,
+    /// The checkout is `config.sync` in the `.ssh` directory, its fragments are copied to
+    /// `config.d` next to the local ones
+    Overlay,
+}
+
+/// What a sync changed in `config.d`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Fragments copied from the repository
+    pub written: Vec<PathBuf>,
+    /// Fragments removed from the repository, and from `config.d`
+    pub removed: Vec<PathBuf>,
+    /// Local fragments kept although the repository has a fragment of the same name, or no
+    /// longer has it, because they were edited or are not from the repository
+    pub conflicts: Vec<PathBuf>,
+}
+
+/// Get the checkout of the repository
+pub fn checkout_dir(paths: &Paths, mode: SyncMode) -> PathBuf {
+    match mode {
+        SyncMode::Into => paths.config_dir.clone(),
+        SyncMode::Overlay => paths.ssh_dir.join("config.sync"),
+    }
+}
+
+/// Pull the repository of shared fragments and fetch the remote bundles set in the settings
+/// into `config.d`
+pub fn sync_all(paths: &Paths) -> Result<()> {
+    if !paths.ssh_dir.is_dir() {
+        return Err(Error::MissingDirectory(paths.ssh_dir.clone()));
+    }
+
+    let settings = crate::settings::get();
+    if settings.sync.is_none() && settings.sources.is_empty() {
+        let message = "sync: nothing to sync, set [sync] or [[source]] in the settings file";
+        return Err(Error::Invalid(message.to_string()));
+    }
+
+    if let Some(sync_settings) = &settings.sync {
+        let report = sync(paths, sync_settings)?;
+        for path in &report.written {
+            verbose_println!("Synced {}", path.display());
+        }
+        for path in &report.removed {
+            verbose_println!("Removed {}", path.display());
+        }
+        for path in &report.conflicts {
+            let message =
+                format!("{} was changed locally, keeping it over the repository.", path.display());
+            crate::platform::warn_once(&path.to_string_lossy(), &message);
+        }
+    }
+
+    if !settings.sources.is_empty() {
+        fs::create_dir_all(&paths.config_dir).map_err(Error::io("create", &paths.config_dir))?;
+        for dir in crate::source::fetch_all(&paths.config_dir, &settings.sources)? {
+            verbose_println!("Unpacked {}", dir.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Pull the repository and bring `config.d` up to date
+///
+/// Commits made in the checkout that are not in the repository, or uncommitted changes the pull
+/// would overwrite, are an error unless `read_only` is set, in which case they are discarded.
+pub fn sync(paths: &Paths, settings: &SyncSettings) -> Result<Report> {
+    if !crate::backend::is_in_path("git") {
+        return Err(Error::ToolUnavailable("git".to_string()));
+    }
+
+    let checkout = checkout_dir(paths, settings.mode);
+    let previous = match settings.mode {
+        SyncMode::Into => BTreeMap::new(),
+        SyncMode::Overlay => read_fragments(&checkout),
+    };
+
+    update(&checkout, settings)?;
+
+    match settings.mode {
+        SyncMode::Into => Ok(Report::default()),
+        SyncMode::Overlay => {
+            overlay(&previous, &read_fragments(&checkout), &paths.config_dir, settings.read_only)
+        }
+    }
+}
+
+/// Clone the repository into `checkout`, or pull it if it is already checked out
+fn update(checkout: &Path, settings: &SyncSettings) -> Result<()> {
+    if !checkout.join(".git").exists() {
+        let is_empty = fs::read_dir(checkout).map_or(true, |mut entries| entries.next().is_none());
+        if !is_empty {
+            let message = format!(
+                "{} is not empty and not a git checkout, use mode = \"overlay\"",
+                checkout.display()
+            );
+            return Err(Error::Sync(message));
+        }
+
+        verbose_println!("Cloning {} into {}", settings.repository, checkout.display());
+        let mut args = vec!["clone", "--quiet"];
+        if let Some(branch) = &settings.branch {
+            args.extend(["--branch", branch]);
+        }
+        args.extend([settings.repository.as_str(), "."]);
+        fs::create_dir_all(checkout).map_err(Error::io("create", checkout))?;
+        return git(checkout, &args);
+    }
+
+    verbose_println!("Pulling {} into {}", settings.repository, checkout.display());
+    git(checkout, &["remote", "set-url", "origin", &settings.repository])?;
+    git(checkout, &["fetch", "--quiet", "origin"])?;
+
+    let upstream = match &settings.branch {
+        Some(branch) => format!("origin/{}", branch),
+        None => "@{upstream}".to_string(),
+    };
+    if settings.read_only {
+        return git(checkout, &["reset", "--quiet", "--hard", &upstream]);
+    }
+
+    run_git(checkout, &["merge", "--quiet", "--ff-only", &upstream]).map_err(|e| {
+        Error::Sync(format!(
+            "{}, commit or discard the local changes in {}, or set read_only",
+            e,
+            checkout.display()
+        ))
+    })
+}
+
+/// Copy the fragments of the checkout to `config_dir`, given the fragments it had before the
+/// pull, which tell the synced fragments that were edited locally apart from the others
+fn overlay(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+    config_dir: &Path,
+    read_only: bool,
+) -> Result<Report> {
+    let mut report = Report::default();
+
+    for (name, contents) in current {
+        let path = config_dir.join(name);
+        let local = crate::file::read_file(&path).ok();
+        if local.as_ref() == Some(contents) {
+            continue;
+        }
+
+        if local.is_some() && local.as_ref() != previous.get(name) && !read_only {
+            report.conflicts.push(path);
+            continue;
+        }
+
+        crate::file::replace_file(&path, |out| std::io::Write::write_all(out, contents.as_bytes()))
+            .map_err(Error::io("write", &path))?;
+        report.written.push(path);
+    }
+
+    for (name, contents) in previous.iter().filter(|(name, _)| !current.contains_key(*name)) {
+        let path = config_dir.join(name);
+        match crate::file::read_file(&path) {
+            Ok(local) if local == *contents || read_only => {
+                fs::remove_file(&path).map_err(Error::io("remove", &path))?;
+                report.removed.push(path);
+            }
+            Ok(_) => report.conflicts.push(path),
+            Err(_) => {}
+        }
+    }
+
+    Ok(report)
+}
+
+/// Read the fragments at the top of a checkout, by file name
+fn read_fragments(checkout: &Path) -> BTreeMap<String, String> {
+    crate::sshconf::fragments_in(checkout)
+        .into_iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_string_lossy().to_string();
+            Some((name, crate::file::read_file(&path).ok()?))
+        })
+        .collect()
+}
+
+/// Run git in `dir`, never prompting for credentials
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    run_git(dir, args).map_err(Error::Sync)
+}
+
+/// Run git in `dir`, the error is the message of git
+fn run_git(dir: &Path, args: &[&str]) -> std::result::Result<(), String> {
+    let output = crate::platform::command("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .map_err(|e| format!("git could not be run ({})", e))?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Err(format!("git {}: {}", args[0], stderr.trim()))
+}
@@ -0,0 +1,154 @@
+//! # Template
+//!
+//! This module renders the `{{ function("argument") }}` placeholders of rules sections when the
+//! config is composed, so values such as usernames or ports can be looked up in a secret store
+//! instead of being written in plaintext fragments. `{{ name }}`, short for `{{ var("name") }}`,
+//! is replaced with a variable of the `VARIABLES` blocks. Braces that aren't shaped like a
+//! placeholder, such as the Go templates of `docker ps --format '{{.Names}}'`, are kept as they
+//! are.
+//!
+//! ```text
+//! Host bastion
+//!   User {{ keychain("corp-bastion-user") }}
+//!   Port {{ bastion_port }}
+//! ```
+
+use crate::error::{Error, Result};
+use std::collections::BTreeMap;
+
+/// The function looking up a variable
+const VARIABLE_FUNCTION: &str = "var";
+
+/// Render the placeholders of `text` with `variables` and the secret stores, see [`render_with`]
+pub fn render(text: &str, variables: &BTreeMap<String, String>) -> Result<String> {
+    render_with(text, |function, argument| match function {
+        VARIABLE_FUNCTION => {
+            let value = variables.get(argument).cloned();
+            value.ok_or_else(|| Error::Invalid(format!("variable: {} is not defined", argument)))
+        }
+        _ => crate::secret::lookup(function, argument),
+    })
+}
+
+/// Check whether `name` is usable as the name of a variable: ASCII letters, digits, `_` and `-`
+pub fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Replace each placeholder of `text` with the value `resolve` returns for its function and
+/// argument. Values must be a single line, so a secret can't add config lines of its own. A `{{`
+/// not starting a placeholder is left in the text.
+pub fn render_with(
+    text: &str,
+    mut resolve: impl FnMut(&str, &str) -> Result<String>,
+) -> Result<String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("{{") {
+        let parsed = rest[start..].find("}}").and_then(|end| {
+            let end = end + start;
+            Some((end, parse_placeholder(&rest[start + 2..end])?))
+        });
+        let Some((end, (function, argument))) = parsed else {
+            rendered.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        };
+
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..end + 2];
+        let value = resolve(function, &argument)?;
+        let value = value.strip_suffix('\n').unwrap_or(&value);
+        let value = value.strip_suffix('\r').unwrap_or(value);
+        if value.contains(['\n', '\r']) {
+            return Err(Error::Invalid(format!("value of {}: contains a line break", placeholder)));
+        }
+
+        rendered.push_str(value);
+        rest = &rest[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Parse the inside of a placeholder, `function("argument")`, into the function name and the
+/// argument with its `\"` and `\\` escapes resolved. A bare `name` is a call of `var`.
+fn parse_placeholder(placeholder: &str) -> Option<(&str, String)> {
+    let placeholder = placeholder.trim();
+    if is_variable_name(placeholder) {
+        return Some((VARIABLE_FUNCTION, placeholder.to_string()));
+    }
+
+    let (function, rest) = placeholder.split_once('(')?;
+    let function = function.trim_end();
+    if function.is_empty() || !function.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+
+    let quoted = rest.strip_suffix(')')?.trim().strip_prefix('"')?.strip_suffix('"')?;
+    let mut argument = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => argument.push(chars.next()?),
+            '"' => return None,
+            c => argument.push(c),
+        }
+    }
+
+    Some((function, argument))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_test(text: &str) -> Result<String> {
+        render_with(text, |function, argument| match function {
+            "echo" => Ok(format!("{}\n", argument)),
+            "lines" => Ok("a\nb".to_string()),
+            _ => Err(Error::Invalid(format!("function {}", function))),
+        })
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(render_test("Host a\n  User b").unwrap(), "Host a\n  User b");
+        assert_eq!(
+            render_test("User {{ echo(\"alice\") }}\nPort {{echo( \"22\" )}}").unwrap(),
+            "User alice\nPort 22"
+        );
+        assert_eq!(render_test(r#"{{ echo("a \"b\" \\c") }}"#).unwrap(), r#"a "b" \c"#);
+
+        let variables = BTreeMap::from([("port".to_string(), "2222".to_string())]);
+        assert_eq!(
+            render("Port {{port}} {{ var(\"port\") }}", &variables).unwrap(),
+            "Port 2222 2222"
+        );
+        assert!(matches!(render("Port {{ bastion }}", &variables), Err(Error::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_failing_placeholders() {
+        for text in ["{{ bogus(\"a\") }}", "{{ lines(\"a\") }}"] {
+            assert!(matches!(render_test(text), Err(Error::Invalid(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn keeps_braces_that_are_not_placeholders() {
+        for text in [
+            "RemoteCommand docker ps --format '{{.Names}}\\t{{ .Status }}'",
+            "{{ echo(\"a\")",
+            "{{ echo(a) }}",
+            "{{ echo(\"a\" \"b\") }}",
+            "{{ (\"a\") }}",
+            "{{ a b }}",
+        ] {
+            assert_eq!(render_test(text).unwrap(), text);
+        }
+        assert_eq!(render_test("{{{{ echo(\"a\") }}").unwrap(), "{{a");
+    }
+}
@@ -0,0 +1,180 @@
+//! # Terminal
+//!
+//! This module exports the hosts of the generated SSH config as terminal profiles, regenerated
+//! along with the config, so the host picker of the terminal matches the active network:
+//!
+//! * iTerm2 dynamic profiles, in `~/Library/Application Support/iTerm2/DynamicProfiles`
+//! * Windows Terminal profiles, in the `Fragments` directory of Windows Terminal
+//!
+//! Each profile opens `ssh <host>` and is tabbed with the color set for the active profile.
+
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The terminal profiles written after each generation
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TerminalSettings {
+    /// Write iTerm2 dynamic profiles
+    pub iterm2: bool,
+    /// Write Windows Terminal profiles
+    pub windows_terminal: bool,
+    /// The tab color of each profile, as `#rrggbb`, e.g. `office = "#1f6feb"`
+    pub colors: BTreeMap<String, String>,
+}
+
+impl TerminalSettings {
+    /// Check that the colors are valid
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        match self.colors.iter().find(|(_, color)| parse_color(color).is_none()) {
+            Some((profile, color)) => {
+                Err(format!("terminal.colors.{}: {} is not a #rrggbb color", profile, color))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether any terminal profiles are written
+    pub fn enabled(&self) -> bool {
+        self.iterm2 || self.windows_terminal
+    }
+}
+
+/// Parse a `#rrggbb` color
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6 && hex.is_ascii())?;
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([component(0)?, component(2)?, component(4)?])
+}
+
+/// Get the hosts of an SSH config that can be connected to by name, leaving out patterns
+pub fn hosts(ssh_config: &str) -> Vec<String> {
+    let mut hosts: Vec<String> = Vec::new();
+    for line in ssh_config.lines() {
+        let mut words = line.split_whitespace();
+        if !words.next().is_some_and(|keyword| keyword.eq_ignore_ascii_case("host")) {
+            continue;
+        }
+
+        for host in words.filter(|host| !host.contains(['*', '?', '!'])) {
+            if !hosts.iter().any(|known| known == host) {
+                hosts.push(host.to_string());
+            }
+        }
+    }
+    hosts
+}
+
+/// Get the iTerm2 dynamic profiles of `hosts`
+pub fn iterm2_profiles(hosts: &[String], profile: &str, color: Option<&str>) -> Value {
+    let profiles: Vec<Value> = hosts
+        .iter()
+        .map(|host| {
+            let mut entry = json!({
+                "Name": host,
+                "Guid": format!("sshconfgen-{}", host),
+                "Custom Command": "Yes",
+                "Command": format!("ssh {}", host),
+                "Tags": ["sshconfgen", profile],
+            });
+            if let Some([red, green, blue]) = color.and_then(parse_color) {
+                entry["Use Tab Color"] = json!(true);
+                entry["Tab Color"] = json!({
+                    "Red Component": f64::from(red) / 255.0,
+                    "Green Component": f64::from(green) / 255.0,
+                    "Blue Component": f64::from(blue) / 255.0,
+                    "Color Space": "sRGB",
+                });
+            }
+            entry
+        })
+        .collect();
+
+    json!({ "Profiles": profiles })
+}
+
+/// Get the Windows Terminal profiles of `hosts`
+pub fn windows_terminal_profiles(hosts: &[String], color: Option<&str>) -> Value {
+    let profiles: Vec<Value> = hosts
+        .iter()
+        .map(|host| {
+            let mut entry = json!({ "name": host, "commandline": format!("ssh.exe {}", host) });
+            if let Some(color) = color {
+                entry["tabColor"] = json!(color);
+            }
+            entry
+        })
+        .collect();
+
+    json!({ "profiles": profiles })
+}
+
+/// Get the file of the iTerm2 dynamic profiles
+fn iterm2_path() -> Option<PathBuf> {
+    let dir =
+        crate::platform::home_dir()?.join("Library").join("Application Support").join("iTerm2");
+    Some(dir.join("DynamicProfiles").join("sshconfgen.json"))
+}
+
+/// Get the file of the Windows Terminal profiles, a fragment of the `sshconfgen` extension
+fn windows_terminal_path() -> Option<PathBuf> {
+    let dir = dirs::data_local_dir()?.join("Microsoft").join("Windows Terminal").join("Fragments");
+    Some(dir.join("sshconfgen").join("sshconfgen.json"))
+}
+
+/// Write the enabled terminal profiles of the hosts of `ssh_config`, generated on `profile`
+pub fn write(settings: &TerminalSettings, ssh_config: &str, profile: &str) -> Result<()> {
+    let hosts = hosts(ssh_config);
+    let color = settings.colors.get(profile).map(String::as_str);
+
+    let mut files = Vec::new();
+    if settings.iterm2 {
+        files.push((iterm2_path(), iterm2_profiles(&hosts, profile, color)));
+    }
+    if settings.windows_terminal {
+        files.push((windows_terminal_path(), windows_terminal_profiles(&hosts, color)));
+    }
+
+    for (path, profiles) in files {
+        let path = path.ok_or(Error::HomeNotFound)?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::io("create", dir))?;
+        }
+
+        let contents = serde_json::to_string_pretty(&profiles).unwrap_or_default();
+        crate::file::write_if_changed(&path, &contents)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_named_hosts() {
+        let config = "Host *\n  ServerAliveInterval 30\nHost git git.corp !bastion\n  User git\n\
+                      host bastion\nMatch host db* exec true\nHost git\n";
+        let hosts = hosts(config);
+        assert_eq!(hosts, ["git", "git.corp", "bastion"]);
+
+        let iterm2 = iterm2_profiles(&hosts, "office", Some("#ff8000"));
+        assert_eq!(iterm2["Profiles"][0]["Command"], "ssh git");
+        assert_eq!(iterm2["Profiles"][2]["Tags"], json!(["sshconfgen", "office"]));
+        assert_eq!(iterm2["Profiles"][1]["Tab Color"]["Red Component"], 1.0);
+
+        let windows_terminal = windows_terminal_profiles(&hosts, None);
+        assert_eq!(
+            windows_terminal["profiles"][1],
+            json!({ "name": "git.corp", "commandline": "ssh.exe git.corp" })
+        );
+
+        let colors = BTreeMap::from([("a".to_string(), "#12345g".to_string())]);
+        assert!(TerminalSettings { colors, ..TerminalSettings::default() }.validate().is_err());
+        assert_eq!(parse_color("#1f6feb"), Some([0x1f, 0x6f, 0xeb]));
+    }
+}
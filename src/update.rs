@@ -0,0 +1,304 @@
+//! # Update
+//!
+//! This module replaces the running binary with the latest release, for the installations made
+//! outside of package managers. The release endpoint is a URL serving:
+//!
+//! * the binaries, named `sshconfgen-<version>-<os>-<arch>`, with `.exe` on Windows, e.g.
+//!   `sshconfgen-0.2.0-linux-x86_64`
+//! * `SHA256SUMS`, their checksums as written by `sha256sum`
+//! * `SHA256SUMS.minisig`, the minisign signature of the checksums
+//!
+//! The checksums are checked against the release key set as `update.key`, and the downloaded
+//! binary against its checksum, before the binary is swapped in by a rename. The keys of
+//! `[signatures]` only vouch for fragments, never for binaries. Checksums that can't be verified,
+//! as no key is set or their signature is missing or invalid, are refused unless `allow_unsigned`
+//! is set.
+
+use crate::error::{Error, Result};
+use crate::signature::{Verification, SIGNATURE_EXTENSION};
+use crate::{is_verbose, verbose_println};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// The release endpoint used when none is set
+pub const DEFAULT_URL: &str = "https://github.com/collindutrow/sshconfgen/releases/latest/download";
+
+/// The file of the release endpoint listing the checksums of the binaries
+const CHECKSUMS_FILE: &str = "SHA256SUMS";
+
+/// Where releases are downloaded from
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateSettings {
+    /// The HTTPS release endpoint, [`DEFAULT_URL`] by default
+    pub url: Option<String>,
+    /// The minisign public key the checksums are signed with, in base64. Releases can't be
+    /// verified without one.
+    pub key: Option<String>,
+    /// Install releases whose checksums aren't signed by a trusted key, with a warning
+    pub allow_unsigned: bool,
+}
+
+impl UpdateSettings {
+    /// Get the release endpoint, without a trailing slash
+    pub fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_URL).trim_end_matches('/')
+    }
+
+    /// Get the key the checksums must be signed with, if set
+    fn public_key(&self) -> Option<minisign_verify::PublicKey> {
+        // The key was validated along with the settings.
+        minisign_verify::PublicKey::from_base64(self.key.as_deref()?.trim()).ok()
+    }
+
+    /// Check that the endpoint is an HTTPS URL and the key can be decoded
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if !self.url().starts_with("https://") {
+            return Err(format!("update.url {} must be an https:// URL", self.url()));
+        }
+
+        match &self.key {
+            Some(key) => minisign_verify::PublicKey::from_base64(key.trim())
+                .map(|_| ())
+                .map_err(|e| format!("update.key {}: {}", key, e)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A release of the binary for this platform
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub version: String,
+    /// The file name of the binary on the release endpoint
+    pub file_name: String,
+    /// The SHA-256 checksum of the binary, in hexadecimal
+    pub sha256: String,
+}
+
+/// Get the prefix and suffix of the file names of the binaries for this platform, around the
+/// version
+fn file_name_affixes() -> (&'static str, String) {
+    let suffix = format!(
+        "-{}-{}{}",
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        std::env::consts::EXE_SUFFIX
+    );
+    ("sshconfgen-", suffix)
+}
+
+/// Get the newest release for this platform listed in the contents of a `SHA256SUMS` file
+pub fn latest(checksums: &str) -> Option<Release> {
+    let (prefix, suffix) = file_name_affixes();
+    checksums
+        .lines()
+        .filter_map(|line| {
+            let (sha256, file_name) = line.split_once(char::is_whitespace)?;
+            // sha256sum marks the files it read in binary mode with a `*`.
+            let file_name = file_name.trim_start().trim_start_matches('*').trim_end();
+            let version = file_name.strip_prefix(prefix)?.strip_suffix(suffix.as_str())?;
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            Some(Release {
+                version: version.to_string(),
+                file_name: file_name.to_string(),
+                sha256: sha256.to_string(),
+            })
+        })
+        .max_by(|a, b| version_key(&a.version).cmp(&version_key(&b.version)))
+}
+
+/// Get the numeric components of a version, e.g. `[1, 10, 2]` for `1.10.2`, ignoring a leading
+/// `v` and any pre-release or build suffix
+fn version_key(version: &str) -> Vec<u64> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let version = version.split(['-', '+']).next().unwrap_or_default();
+    version.split('.').map(|component| component.parse().unwrap_or(0)).collect()
+}
+
+/// Check whether `version` is newer than `current`
+pub fn is_newer(version: &str, current: &str) -> bool {
+    version_key(version) > version_key(current)
+}
+
+/// Download and verify the checksums of the release endpoint, and get the latest release for this
+/// platform, if it is newer than the running binary
+pub fn check() -> Result<Option<Release>> {
+    let settings = &crate::settings::get().update;
+    let url = format!("{}/{}", settings.url(), CHECKSUMS_FILE);
+    let checksums = download(&url)?;
+    let signature = download(&format!("{}.{}", url, SIGNATURE_EXTENSION)).ok();
+
+    let signature = signature.map(|signature| String::from_utf8_lossy(&signature).to_string());
+    let problem = match settings.public_key() {
+        None => Some("can't be verified, update.key is not set".to_string()),
+        Some(key) => match crate::signature::verify(&checksums, signature.as_deref(), &[key]) {
+            Verification::Valid => {
+                verbose_println!("{} is signed", url);
+                None
+            }
+            Verification::Unsigned => Some("is not signed".to_string()),
+            Verification::Invalid(e) => Some(format!("has an invalid signature ({})", e)),
+        },
+    };
+    if let Some(problem) = problem {
+        if !settings.allow_unsigned {
+            return Err(Error::Update(format!("{} {}", url, problem)));
+        }
+        let message = format!("{} {}, it is only checked against its checksum.", url, problem);
+        crate::platform::warn_once("update", &message);
+    }
+
+    let checksums = String::from_utf8_lossy(&checksums);
+    let release = latest(&checksums).ok_or_else(|| {
+        let (prefix, suffix) = file_name_affixes();
+        Error::Update(format!("{} lists no {}<version>{} binary", url, prefix, suffix))
+    })?;
+    Ok(is_newer(&release.version, crate::VERSION).then_some(release))
+}
+
+/// Replace the running binary with the latest release if it is newer. Returns the release
+/// installed, if any.
+pub fn update() -> Result<Option<Release>> {
+    let Some(release) = check()? else {
+        return Ok(None);
+    };
+
+    let url = format!("{}/{}", crate::settings::get().update.url(), release.file_name);
+    verbose_println!("Downloading {}", url);
+    let binary = download(&url)?;
+    let checksum = crate::source::sha256_hex(&binary);
+    if !checksum.eq_ignore_ascii_case(&release.sha256) {
+        return Err(Error::Update(format!(
+            "{} has checksum {}, expected {}",
+            url, checksum, release.sha256
+        )));
+    }
+
+    let exe = std::env::current_exe()
+        .map_err(|e| Error::Update(format!("the binary can't be located ({})", e)))?;
+    // Replacing a symlink would leave the binary it points to behind.
+    let exe = fs::canonicalize(&exe).unwrap_or(exe);
+    replace_binary(&exe, &binary)?;
+    Ok(Some(release))
+}
+
+/// Download `url` to memory, through the directory of the state file rather than a temporary
+/// directory other users can write to
+fn download(url: &str) -> Result<Vec<u8>> {
+    let download_dir = crate::state::state_dir().ok_or(Error::HomeNotFound)?;
+    crate::source::download(url, &download_dir).map_err(|e| match e {
+        Error::Sync(message) => Error::Update(message),
+        e => e,
+    })
+}
+
+/// Write `binary` next to `exe` and rename it over `exe`, so the binary is never left half
+/// written. Windows can't replace a running binary, which is renamed out of the way to
+/// `<exe>.old` instead and removed by the next update.
+fn replace_binary(exe: &Path, binary: &[u8]) -> Result<()> {
+    let file_name = exe.file_name().unwrap_or_default().to_string_lossy();
+    let staging = exe.with_file_name(format!(".{}.{}.new", file_name, std::process::id()));
+    if let Err(e) = fs::write(&staging, binary) {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            let dir = exe.parent().unwrap_or(exe);
+            return Err(Error::Update(format!(
+                "{} is not writable, run it with sudo",
+                dir.display()
+            )));
+        }
+        return Err(Error::io("write", &staging)(e));
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(exe).map(|metadata| metadata.permissions().mode()).unwrap_or(0o755);
+        if let Err(e) = fs::set_permissions(&staging, fs::Permissions::from_mode(mode)) {
+            let _ = fs::remove_file(&staging);
+            return Err(Error::io("set permissions of", &staging)(e));
+        }
+    }
+
+    rename_over(&staging, exe).inspect_err(|_| {
+        let _ = fs::remove_file(&staging);
+    })
+}
+
+/// Rename `staging` over the running binary `exe`, which is moved to `<exe>.old` first
+#[cfg(windows)]
+fn rename_over(staging: &Path, exe: &Path) -> Result<()> {
+    let file_name = exe.file_name().unwrap_or_default().to_string_lossy();
+    let old = exe.with_file_name(format!("{}.old", file_name));
+    let _ = fs::remove_file(&old);
+    fs::rename(exe, &old).map_err(Error::io("rename", exe))?;
+    fs::rename(staging, exe).map_err(|e| {
+        let _ = fs::rename(&old, exe);
+        Error::io("rename", staging)(e)
+    })
+}
+
+#[cfg(not(windows))]
+fn rename_over(staging: &Path, exe: &Path) -> Result<()> {
+    fs::rename(staging, exe).map_err(Error::io("rename", staging))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_newest_release_of_this_platform() {
+        let (prefix, suffix) = file_name_affixes();
+        let line = |sha: &str, version: &str| {
+            format!("{}  {}{}{}\n", sha.repeat(64), prefix, version, suffix)
+        };
+        let checksums = [
+            line("a", "0.9.0"),
+            line("b", "0.10.0"),
+            format!("{}  sshconfgen-1.0.0-plan9-mips\n", "c".repeat(64)),
+            format!("{} *{}0.10.1-rc1{}\n", "d".repeat(63), prefix, suffix),
+        ]
+        .concat();
+
+        let release = latest(&checksums).unwrap();
+        assert_eq!(release.version, "0.10.0");
+        assert_eq!(release.sha256, "b".repeat(64));
+        assert_eq!(release.file_name, format!("sshconfgen-0.10.0{}", suffix));
+        assert_eq!(latest(""), None);
+
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(is_newer("v1.0.0", "0.10.0"));
+        assert!(!is_newer("0.0.1", crate::VERSION));
+        assert!(!is_newer("1.2.0-rc1", "1.2.0"));
+    }
+
+    #[test]
+    fn only_trusts_the_configured_key() {
+        assert!(UpdateSettings::default().public_key().is_none());
+
+        // The test vector of the minisign-verify crate.
+        let key = "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3";
+        let settings = UpdateSettings { key: Some(format!(" {}\n", key)), ..Default::default() };
+        assert!(settings.validate().is_ok());
+        assert!(settings.public_key().is_some());
+
+        let settings =
+            UpdateSettings { key: Some("RWQf6LRCGA9i5".to_string()), ..Default::default() };
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn replaces_the_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let exe = dir.path().join("sshconfgen");
+        fs::write(&exe, "old").unwrap();
+
+        replace_binary(&exe, b"new").unwrap();
+        assert_eq!(fs::read(&exe).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), if cfg!(windows) { 2 } else { 1 });
+    }
+}
@@ -0,0 +1,106 @@
+//! # VPN
+//!
+//! This module contains the functions to detect connected VPN (virtual tunnel) adapters, so they
+//! can be told apart from the physical network underneath.
+
+use crate::error::Result;
+use crate::platform::{command, run};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// A connected VPN adapter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Adapter {
+    pub name: String,
+    pub description: String,
+    /// The interface index, only known on Windows
+    pub index: Option<u32>,
+}
+
+/// Interface descriptions of common Windows VPN clients and the built-in VPN miniports.
+const WINDOWS_VPN_PATTERN: &str = concat!(
+    r"(?i)vpn|wireguard|wintun|tap-|openvpn|anyconnect|globalprotect|pangp|fortinet|fortissl|",
+    r"zscaler|juniper|pulse secure|tunnel|wan miniport"
+);
+
+/// Interface name prefixes of tunnel devices on macOS and the BSDs.
+const BSD_VPN_PREFIXES: [&str; 6] = ["utun", "ipsec", "ppp", "tun", "tap", "wg"];
+
+/// Get the VPN adapters that are currently up
+pub fn get_vpn_adapters() -> Result<Vec<Adapter>> {
+    if cfg!(target_os = "windows") || crate::platform::is_wsl() {
+        get_windows_vpn_adapters()
+    } else if cfg!(any(target_os = "linux", target_os = "android")) {
+        Ok(get_linux_vpn_adapters())
+    } else if cfg!(any(target_os = "illumos", target_os = "solaris")) {
+        let output = run(command("dladm").args(["show-iptun", "-p", "-o", "link"]), "LocalVPN")?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|name| Adapter { name: name.to_string(), description: String::new(), index: None })
+            .collect())
+    } else {
+        let output = run(command("ifconfig").args(["-l", "-u"]), "LocalVPN")?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .filter(|name| BSD_VPN_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+            .map(|name| Adapter { name: name.to_string(), description: String::new(), index: None })
+            .collect())
+    }
+}
+
+/// Get the Windows VPN adapters, which are the connected software adapters whose description
+/// matches a known VPN client
+fn get_windows_vpn_adapters() -> Result<Vec<Adapter>> {
+    let script =
+        "Get-NetAdapter | Where-Object { $_.Status -eq 'Up' -and -not $_.HardwareInterface } | \
+        Select-Object Name, InterfaceDescription, InterfaceIndex | ConvertTo-Json -Compress";
+    let adapters = crate::powershell::run_json(script, "LocalVPN")?;
+
+    let vpn_re = Regex::new(WINDOWS_VPN_PATTERN).unwrap();
+    Ok(adapters
+        .iter()
+        .filter_map(|adapter| {
+            Some(Adapter {
+                name: adapter["Name"].as_str()?.to_string(),
+                description: adapter["InterfaceDescription"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                index: adapter["InterfaceIndex"]
+                    .as_u64()
+                    .and_then(|index| u32::try_from(index).ok()),
+            })
+        })
+        .filter(|adapter| vpn_re.is_match(&adapter.description) || vpn_re.is_match(&adapter.name))
+        .collect())
+}
+
+/// Get the Linux tunnel devices (tun/tap, WireGuard, PPP) that are not down
+fn get_linux_vpn_adapters() -> Vec<Adapter> {
+    let mut adapters: Vec<Adapter> = fs::read_dir("/sys/class/net")
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .filter(|entry| {
+                    let path = entry.path();
+                    let link_type = fs::read_to_string(path.join("type")).unwrap_or_default();
+                    let operstate = fs::read_to_string(path.join("operstate")).unwrap_or_default();
+
+                    // ARPHRD_NONE (tun, WireGuard) and ARPHRD_PPP, tap devices have tun_flags.
+                    let is_tunnel = path.join("tun_flags").exists()
+                        || matches!(link_type.trim(), "65534" | "512");
+                    is_tunnel && operstate.trim() != "down"
+                })
+                .map(|entry| Adapter {
+                    name: entry.file_name().to_string_lossy().to_string(),
+                    description: String::new(),
+                    index: None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    adapters.sort_by(|a, b| a.name.cmp(&b.name));
+    adapters
+}
@@ -0,0 +1,41 @@
+//! # FFI
+//!
+//! Calls the C ABI the way a C caller would, on fragments without network conditions.
+
+use sshconfgen::ffi::*;
+use std::ffi::{CStr, CString};
+use std::fs;
+
+#[test]
+fn generates_through_the_c_abi() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("10-global.sshconf"),
+        "# GLOBAL CONFIG BEGIN\nHost everywhere\n# GLOBAL CONFIG END\n",
+    )
+    .unwrap();
+
+    let config_dir = CString::new(dir.path().to_str().unwrap()).unwrap();
+    // SAFETY: a valid string, and the result is freed once.
+    unsafe {
+        let config = sshconfgen_generate(config_dir.as_ptr());
+        assert!(!config.is_null());
+        assert_eq!(CStr::from_ptr(config).to_str().unwrap().trim_end(), "Host everywhere");
+        sshconfgen_string_free(config);
+    }
+    assert!(sshconfgen_last_error().is_null());
+}
+
+#[test]
+fn reports_errors_through_the_c_abi() {
+    let fragment = CString::new("# LOCAL CONFIG BEGIN\nHost lan\n# LOCAL CONFIG END\n").unwrap();
+    // SAFETY: NULL and valid strings.
+    unsafe {
+        assert_eq!(sshconfgen_evaluate(fragment.as_ptr()), 0);
+        assert_eq!(sshconfgen_evaluate(std::ptr::null()), -1);
+        assert_eq!(
+            CStr::from_ptr(sshconfgen_last_error()).to_str().unwrap(),
+            "Invalid fragment: NULL"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+//! # Parser
+//!
+//! Checks how fragments are split into sections, including malformed ones: overlapping and stray
+//! markers, mixed line endings, byte order marks, and large files.
+
+use proptest::prelude::*;
+use sshconfgen::{parse_sections, Fragment, SECTIONS};
+use std::path::Path;
+
+fn sections(contents: &str) -> [String; 4] {
+    parse_sections(Path::new("test.sshconf"), contents)
+}
+
+/// Wrap `body` in the markers of a section, with `newline` line endings
+fn section(name: &str, body: &str, newline: &str) -> String {
+    format!("# {name} BEGIN{newline}{body}{newline}# {name} END{newline}")
+}
+
+#[test]
+fn reads_well_formed_sections() {
+    let contents = "Comment outside of any section\n\
+                    # CONDITIONS BEGIN\nLocalSSID home\n# CONDITIONS END\n\
+                    # LOCAL CONFIG BEGIN\nHost nas\n  HostName 192.168.1.2\n# LOCAL CONFIG END\n";
+    let [conditions, global, local, remote] = sections(contents);
+    assert_eq!(conditions, "LocalSSID home");
+    assert_eq!(global, "");
+    assert_eq!(local, "Host nas\n  HostName 192.168.1.2");
+    assert_eq!(remote, "");
+}
+
+#[test]
+fn unterminated_sections_do_not_swallow_their_neighbors() {
+    let contents = "# LOCAL CONFIG BEGIN\nHost lost\n\
+                    # REMOTE CONFIG BEGIN\nHost remote\n# REMOTE CONFIG END\n\
+                    # GLOBAL CONFIG BEGIN\nHost also-lost\n";
+    let [_, global, local, remote] = sections(contents);
+    assert_eq!(local, "");
+    assert_eq!(remote, "Host remote");
+    assert_eq!(global, "");
+}
+
+#[test]
+fn ignores_stray_and_repeated_markers() {
+    let contents = "# LOCAL CONFIG END\n\
+                    # LOCAL CONFIG BEGIN\nHost first\n# REMOTE CONFIG END\n# LOCAL CONFIG END\n\
+                    # LOCAL CONFIG BEGIN\nHost second\n# LOCAL CONFIG END\n";
+    let [_, _, local, _] = sections(contents);
+    assert_eq!(local, "Host first");
+}
+
+#[test]
+fn markers_must_be_on_their_own_line() {
+    let contents = "# LOCAL CONFIG BEGIN\nHost a # LOCAL CONFIG END\n  #  LOCAL CONFIG END  \n";
+    let [_, _, local, _] = sections(contents);
+    assert_eq!(local, "Host a # LOCAL CONFIG END");
+}
+
+#[test]
+fn accepts_byte_order_marks_and_crlf() {
+    let lf = section("REMOTE CONFIG", "Host a\n  User b", "\n");
+    let crlf = format!("\u{feff}{}", section("REMOTE CONFIG", "Host a\r\n  User b", "\r\n"));
+    assert_eq!(sections(&lf), sections(&crlf));
+}
+
+#[test]
+fn flags_encrypted_rules_sections() {
+    let ciphertext = "-----BEGIN AGE ENCRYPTED FILE-----\nYWdlLWVuY3J5cHRpb24=\n\
+                      -----END AGE ENCRYPTED FILE-----";
+    let contents = format!(
+        "# CONDITIONS BEGIN (encrypted)\nLocalSSID lost\n# CONDITIONS END\n\
+         # LOCAL CONFIG BEGIN (encrypted)\n{ciphertext}\n# LOCAL CONFIG END\n\
+         # REMOTE CONFIG BEGIN\nHost remote\n# REMOTE CONFIG END (encrypted)\n# REMOTE CONFIG END\n"
+    );
+    let fragment = Fragment::parse(Path::new("secret.sshconf"), &contents);
+    assert!(fragment.conditions.is_empty());
+    assert_eq!(fragment.local_rules, ciphertext);
+    assert_eq!(fragment.remote_rules, "Host remote\n# REMOTE CONFIG END (encrypted)");
+    assert_eq!(fragment.encrypted.iter().collect::<Vec<_>>(), ["LOCAL CONFIG"]);
+
+    let reparsed = Fragment::parse(&fragment.path, &fragment.to_sshconf());
+    assert_eq!(reparsed, fragment);
+}
+
+#[test]
+fn falls_back_from_sections_for_newer_clients() {
+    let contents = "# LOCAL CONFIG BEGIN (openssh >= 99.0)\nHost a\n  SessionType none\n\
+                    # LOCAL CONFIG END\n# LOCAL CONFIG BEGIN\nHost a\n# LOCAL CONFIG END\n\
+                    # REMOTE CONFIG BEGIN (openssh >= 99.0) (encrypted)\nciphertext\n\
+                    # REMOTE CONFIG END\n\
+                    # REMOTE CONFIG BEGIN (openssh >= 8)\nHost b\n# REMOTE CONFIG END\n";
+    let fragment = Fragment::parse(Path::new("variants.sshconf"), contents);
+    assert_eq!(fragment.local_rules, "Host a");
+    assert_eq!(fragment.remote_rules, "");
+    assert!(fragment.encrypted.is_empty());
+}
+
+#[test]
+fn parses_large_fragments() {
+    let hosts = "Host bulk\n  HostName 10.0.0.1\n".repeat(200_000);
+    let contents =
+        section("CONDITIONS", "LocalSSID home", "\n") + &section("LOCAL CONFIG", &hosts, "\n");
+    let fragment = Fragment::parse(Path::new("large.sshconf"), &contents);
+    assert_eq!(fragment.conditions.len(), 1);
+    assert_eq!(fragment.local_rules.lines().count(), 400_000);
+}
+
+/// Section bodies: blank lines, and lines of text not starting with `#`, possibly mentioning a
+/// marker after some text
+fn body() -> impl Strategy<Value = String> {
+    prop::collection::vec("[ \t]*|[ \t]*[!-\"$-~][ -~\t]{0,40}( # LOCAL CONFIG END)?", 0..8)
+        .prop_map(|lines| lines.join("\n"))
+}
+
+proptest! {
+    #[test]
+    fn never_panics_on_arbitrary_input(contents in "\\PC*") {
+        let _ = Fragment::parse(Path::new("fuzz.sshconf"), &contents);
+    }
+
+    #[test]
+    fn never_panics_on_marker_soup(
+        lines in prop::collection::vec(prop_oneof![
+            "# (CONDITIONS|GLOBAL CONFIG|LOCAL CONFIG|REMOTE CONFIG) (BEGIN|END)",
+            "[ -~]{0,20}",
+            Just("\u{feff}".to_string()),
+            Just("\r".to_string()),
+        ], 0..40)
+    ) {
+        let _ = sections(&lines.join("\n"));
+    }
+
+    #[test]
+    fn recovers_every_well_formed_section(
+        bodies in prop::array::uniform4(body()),
+        crlf in any::<bool>(),
+        bom in any::<bool>(),
+    ) {
+        let newline = if crlf { "\r\n" } else { "\n" };
+        let mut contents = if bom { "\u{feff}".to_string() } else { String::new() };
+        for (name, body) in SECTIONS.iter().zip(&bodies) {
+            contents += &section(name, &body.replace('\n', newline), newline);
+        }
+
+        let parsed = sections(&contents);
+        for (parsed, body) in parsed.iter().zip(&bodies) {
+            prop_assert_eq!(parsed, body.trim());
+        }
+    }
+
+    #[test]
+    fn a_broken_section_only_loses_itself(
+        broken in 0..4usize,
+        bodies in prop::array::uniform4(body()),
+    ) {
+        // The broken section is missing its END marker, and comes first.
+        let mut contents = format!("# {} BEGIN\n{}\n", SECTIONS[broken], bodies[broken]);
+        for (index, name) in SECTIONS.iter().enumerate().filter(|(index, _)| *index != broken) {
+            contents += &section(name, &bodies[index], "\n");
+        }
+
+        let parsed = sections(&contents);
+        for (index, parsed) in parsed.iter().enumerate() {
+            let expected = if index == broken { "" } else { bodies[index].trim() };
+            prop_assert_eq!(parsed, expected);
+        }
+    }
+}
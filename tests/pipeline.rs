@@ -0,0 +1,474 @@
+//! # Pipeline
+//!
+//! Runs the full generation pipeline against temporary `.ssh` directories populated with the
+//! fragments in `tests/fixtures`, on a mocked network.
+
+use sshconfgen::generator::{Format, Generator, Monitor, Outcome, Report, Trigger};
+use sshconfgen::paths::Paths;
+use sshconfgen::provider::{
+    Adapter, Connection, DefaultRoute, NeighborProvider, PingProvider, Providers, RouteProvider,
+    SsidProvider, VpnProvider,
+};
+use sshconfgen::{Error, Result};
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::{fs, path::Path};
+use tempfile::TempDir;
+
+/// A network with a single Wi-Fi connection, and no reachable hosts or known neighbors
+struct Network {
+    ssid: &'static str,
+}
+
+impl SsidProvider for Network {
+    fn connections(&self) -> Result<Vec<Connection>> {
+        Ok(vec![Connection { interface: "wlan0".to_string(), ssid: self.ssid.to_string() }])
+    }
+}
+
+impl PingProvider for Network {
+    fn pingable(&self, _host: &str) -> bool {
+        false
+    }
+
+    fn arp_pingable(&self, _ip_address: &str) -> bool {
+        false
+    }
+}
+
+impl NeighborProvider for Network {
+    fn hw_address(&self, _ip_address: &str) -> Result<String> {
+        Err(Error::NotFound("MAC address"))
+    }
+}
+
+impl RouteProvider for Network {
+    fn default_routes(&self) -> Result<Vec<DefaultRoute>> {
+        Ok(vec![])
+    }
+}
+
+impl VpnProvider for Network {
+    fn vpn_adapters(&self) -> Result<Vec<Adapter>> {
+        Ok(vec![])
+    }
+}
+
+fn providers(ssid: &'static str) -> Providers {
+    Providers {
+        ssid: Arc::new(Network { ssid }),
+        ping: Arc::new(Network { ssid }),
+        neighbor: Arc::new(Network { ssid }),
+        routes: Arc::new(Network { ssid }),
+        vpn: Arc::new(Network { ssid }),
+    }
+}
+
+/// Generate the SSH config of `paths` and write it, as a new run of the binary would
+fn run(paths: &Paths, ssid: &'static str) -> Result<Report> {
+    Generator::new(paths.clone()).with_providers(providers(ssid)).generate()
+}
+
+/// Generate the SSH config of `paths` without writing it
+fn preview(paths: &Paths, ssid: &'static str) -> String {
+    Generator::new(paths.clone()).with_providers(providers(ssid)).preview().unwrap()
+}
+
+/// Create an `.ssh` directory with the fixture fragments in its `config.d`
+fn setup() -> (TempDir, Paths) {
+    let home = tempfile::tempdir().unwrap();
+    let paths = Paths::new(&home.path().join(".ssh"), &home.path().join("state"));
+    fs::create_dir_all(&paths.config_dir).unwrap();
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures");
+    for fixture in fs::read_dir(fixtures).unwrap() {
+        let fixture = fixture.unwrap().path();
+        fs::copy(&fixture, paths.config_dir.join(fixture.file_name().unwrap())).unwrap();
+    }
+
+    (home, paths)
+}
+
+/// Get the non-empty, trimmed lines of a file
+fn lines(path: &Path) -> Vec<String> {
+    fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Get the names of the files in a directory
+fn file_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn uses_local_rules_on_matching_network() {
+    let (_home, paths) = setup();
+    run(&paths, "homenet").unwrap();
+
+    assert_eq!(
+        lines(&paths.config_file),
+        vec![
+            "Host nas",
+            "User admin",
+            "HostName 192.168.1.10",
+            "Host lab",
+            "HostName 10.0.0.20",
+            "ProxyJump nas"
+        ]
+    );
+}
+
+#[test]
+fn uses_remote_rules_elsewhere() {
+    let (_home, paths) = setup();
+    run(&paths, "cafe").unwrap();
+
+    assert_eq!(
+        lines(&paths.config_file),
+        vec![
+            "Host nas",
+            "User admin",
+            "HostName nas.example.com",
+            "Port 2222",
+            "Host lab",
+            "HostName 10.0.0.20",
+            "ProxyJump nas",
+        ]
+    );
+}
+
+#[test]
+fn replaces_existing_config_without_leaving_backups() {
+    let (_home, paths) = setup();
+    run(&paths, "homenet").unwrap();
+    fs::write(&paths.config_file, "Host stale\n").unwrap();
+
+    run(&paths, "homenet").unwrap();
+
+    assert!(!lines(&paths.config_file).contains(&"Host stale".to_string()));
+    assert_eq!(file_names(&paths.ssh_dir), vec![".sshconfgen.lock", "config", "config.d"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn keeps_the_permissions_of_the_replaced_config() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let (_home, paths) = setup();
+    run(&paths, "homenet").unwrap();
+    fs::write(&paths.config_file, "Host stale\n").unwrap();
+    fs::set_permissions(&paths.config_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+    run(&paths, "homenet").unwrap();
+
+    assert!(!lines(&paths.config_file).contains(&"Host stale".to_string()));
+    assert_eq!(fs::metadata(&paths.config_file).unwrap().permissions().mode() & 0o777, 0o600);
+}
+
+#[test]
+fn waits_for_the_lock_of_another_generation() {
+    let (_home, paths) = setup();
+    let lock = paths.lock().unwrap();
+
+    let generation = {
+        let paths = paths.clone();
+        std::thread::spawn(move || run(&paths, "homenet").map(|report| report.outcome))
+    };
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert!(!paths.config_file.exists());
+
+    drop(lock);
+    generation.join().unwrap().unwrap();
+    assert!(lines(&paths.config_file).contains(&"HostName 192.168.1.10".to_string()));
+}
+
+#[test]
+fn adopts_hand_written_configs() {
+    let (_home, paths) = setup();
+    fs::write(&paths.config_file, "Host mine\n  User me\n").unwrap();
+
+    assert!(matches!(run(&paths, "homenet"), Err(Error::Unmanaged(_))));
+    assert_eq!(lines(&paths.config_file), vec!["Host mine", "User me"]);
+
+    let generator = Generator::new(paths.clone());
+    let adoption = generator.adopt(true).unwrap().unwrap();
+    assert_eq!(generator.backups(), std::slice::from_ref(&adoption.archive));
+    assert_eq!(lines(&paths.config_file), vec!["Host mine", "User me"]);
+    let fragment = adoption.fragment.unwrap();
+    assert_eq!(fragment, paths.config_dir.join("adopted.sshconf"));
+
+    run(&paths, "homenet").unwrap();
+    assert!(lines(&paths.config_file).contains(&"Host mine".to_string()));
+    assert_eq!(generator.adopt(true).unwrap(), None);
+
+    let archive = adoption.archive.file_name().unwrap().to_str().unwrap();
+    assert_eq!(generator.restore(Some(archive)).unwrap(), adoption.archive);
+    assert_eq!(fs::read_to_string(&paths.config_file).unwrap(), "Host mine\n  User me\n");
+}
+
+#[test]
+fn skips_unchanged_generations() {
+    let (_home, paths) = setup();
+    fs::remove_file(paths.config_dir.join("20-lab.sshconf")).unwrap();
+    let report = run(&paths, "homenet").unwrap();
+    assert_eq!(report.profile, "local");
+    assert_eq!(report.fragments, vec![paths.config_dir.join("10-home.sshconf")]);
+
+    let expected = lines(&paths.config_file);
+    let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options().write(true).open(&paths.config_file).unwrap().set_modified(old).unwrap();
+    assert_eq!(run(&paths, "homenet").unwrap().outcome, Outcome::Unchanged);
+    assert_eq!(fs::metadata(&paths.config_file).unwrap().modified().unwrap(), old);
+
+    fs::write(&paths.config_file, "Host edited\n").unwrap();
+    run(&paths, "homenet").unwrap();
+    assert_eq!(lines(&paths.config_file), expected);
+
+    assert_eq!(run(&paths, "cafe").unwrap().profile, "remote");
+}
+
+#[test]
+fn reports_what_each_generation_did() {
+    let (_home, paths) = setup();
+    fs::remove_file(paths.config_dir.join("20-lab.sshconf")).unwrap();
+    let (config_dir, config_file) = (paths.config_dir.clone(), paths.config_file.clone());
+    let mut generator = Generator::new(paths).with_providers(providers("homenet"));
+
+    let report = generator.generate().unwrap();
+    assert_eq!(report.outcome, Outcome::Written);
+    assert_eq!(report.profile, "local");
+    assert_eq!(report.local, vec![config_dir.join("10-home.sshconf")]);
+    assert_eq!(report.written, vec![config_file.clone()]);
+    assert_eq!(report.config, generator.preview().unwrap());
+
+    let report = generator.generate().unwrap();
+    assert_eq!(report.outcome, Outcome::Unchanged);
+    assert!(report.written.is_empty());
+
+    // Generating again comes out identical, the config isn't rewritten.
+    let modified = fs::metadata(&config_file).unwrap().modified().unwrap();
+    let report = generator.with_skip_unchanged(false).generate().unwrap();
+    assert_eq!(report.outcome, Outcome::Unchanged);
+    assert!(report.written.is_empty());
+    assert_eq!(fs::metadata(&config_file).unwrap().modified().unwrap(), modified);
+}
+
+#[test]
+fn previews_and_asks_before_writing() {
+    let (_home, paths) = setup();
+    let (config_file, lock_file) = (paths.config_file.clone(), paths.lock_file());
+    let generator = Generator::new(paths).with_providers(providers("homenet"));
+
+    let mut generator = generator.with_dry_run(true);
+    let report = generator.generate().unwrap();
+    assert_eq!(report.outcome, Outcome::Previewed);
+    assert_eq!(report.changes.len(), 1);
+    assert_eq!(report.changes[0].path, config_file);
+    assert!(report.timings.phases.iter().any(|phase| phase.name == "evaluation"));
+    assert!(!config_file.exists());
+    assert!(!lock_file.exists());
+
+    let asked = Arc::new(Mutex::new(Vec::new()));
+    let recorded = asked.clone();
+    let mut generator = generator.with_dry_run(false).with_interactive(move |planned, profile| {
+        recorded.lock().unwrap().push((planned.len(), profile.to_string()));
+        false
+    });
+    let report = generator.generate().unwrap();
+    assert_eq!(report.outcome, Outcome::Declined);
+    assert_eq!(*asked.lock().unwrap(), [(1, "local".to_string())]);
+    assert!(!config_file.exists());
+}
+
+#[test]
+fn keeps_existing_config_without_fragments() {
+    let (_home, paths) = setup();
+    fs::write(&paths.config_file, "Host stale\n").unwrap();
+    for fragment in fs::read_dir(&paths.config_dir).unwrap() {
+        fs::remove_file(fragment.unwrap().path()).unwrap();
+    }
+
+    run(&paths, "homenet").unwrap();
+
+    assert_eq!(lines(&paths.config_file), vec!["Host stale"]);
+}
+
+#[test]
+fn cache_follows_network_changes() {
+    let (_home, paths) = setup();
+    let mut generator = Generator::new(paths.clone()).with_providers(providers("homenet"));
+    let home = generator.preview().unwrap();
+    let mut generator = generator.with_providers(providers("cafe"));
+    let cafe = generator.preview().unwrap();
+
+    assert_eq!(home, preview(&paths, "homenet"));
+    assert_eq!(cafe, preview(&paths, "cafe"));
+    assert_ne!(home, cafe);
+}
+
+#[test]
+fn monitor_reports_profile_changes() {
+    let (_home, paths) = setup();
+    let home = paths.config_dir.join("10-home.sshconf");
+    let monitor = Monitor::new();
+    let changes = Arc::new(Mutex::new(Vec::new()));
+
+    let (subscriber, recorded) = (monitor.clone(), changes.clone());
+    let removed = home.clone();
+    monitor.subscribe(move |change| {
+        let mut recorded = recorded.lock().unwrap();
+        recorded.push(change.clone());
+        if recorded.len() == 1 {
+            fs::remove_file(&removed).unwrap();
+            subscriber.trigger(Trigger::Fragments);
+        } else {
+            subscriber.stop();
+        }
+    });
+
+    monitor.trigger(Trigger::Requested);
+    let mut generator = Generator::new(paths).with_providers(providers("homenet"));
+    monitor.run_generations(&mut generator, |_| {}).unwrap();
+
+    let changes = changes.lock().unwrap();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].activated, BTreeSet::from([home.clone()]));
+    assert_eq!(changes[0].profile.as_deref(), Some("local"));
+    assert_eq!(changes[1].triggers, BTreeSet::from([Trigger::Fragments]));
+    assert_eq!(changes[1].deactivated, BTreeSet::from([home]));
+    assert!(changes[1].local.is_empty());
+}
+
+#[test]
+fn exports_fragments_with_their_outcome() {
+    let (_home, paths) = setup();
+    let generator = Generator::new(paths).with_providers(providers("homenet"));
+    let exported = generator.export(true);
+
+    let outcomes: Vec<_> = exported
+        .fragments
+        .iter()
+        .map(|exported| {
+            (exported.fragment.path.file_name().unwrap().to_str().unwrap(), exported.matched)
+        })
+        .collect();
+    assert_eq!(
+        outcomes,
+        [("10-home.sshconf", Some(Some(true))), ("20-lab.sshconf", Some(Some(false)))]
+    );
+    assert!(exported.fingerprint.is_some());
+
+    let json = serde_json::to_value(generator.export(false)).unwrap();
+    assert_eq!(json["fragments"][0]["conditions"][0]["key"], "LocalSSID");
+    assert!(json["fragments"][0].get("matched").is_none());
+    assert!(json.get("fingerprint").is_none());
+}
+
+#[test]
+fn imports_definitions_as_fragments() {
+    let (home, paths) = setup();
+    let definitions = home.path().join("fragments.yaml");
+    fs::write(
+        &definitions,
+        "fragments:
+  - name: 30-office
+    conditions:
+      LocalSSID: [homenet, officenet]
+      Priority: 5
+    local:
+      - host: printer
+        options:
+          HostName: 10.0.0.9
+          Port: 2222
+          LocalForward: [8080 localhost:80, 8443 localhost:443]
+    remote: |
+      Host printer
+        ProxyJump bastion.example.com
+",
+    )
+    .unwrap();
+    let generator = Generator::new(paths.clone());
+    let imported = paths.config_dir.join("30-office.sshconf");
+    assert_eq!(
+        generator.import(&definitions, Format::Yaml, false).unwrap(),
+        std::slice::from_ref(&imported)
+    );
+
+    let config = preview(&paths, "officenet");
+    assert!(
+        config.contains("Host printer\n  HostName 10.0.0.9\n  LocalForward 8080 localhost:80\n")
+    );
+    assert!(config.contains("  LocalForward 8443 localhost:443\n  Port 2222\n"));
+    assert!(!config.contains("bastion"));
+
+    let exported = generator.export(false);
+    let parsed =
+        exported.fragments.iter().find(|exported| exported.fragment.path == imported).unwrap();
+    assert_eq!(parsed.fragment.priority(), 5);
+    assert_eq!(parsed.fragment.remote_rules, "Host printer\n  ProxyJump bastion.example.com");
+
+    assert!(matches!(generator.import(&definitions, Format::Yaml, false), Err(Error::Invalid(_))));
+    generator.import(&definitions, Format::Yaml, true).unwrap();
+
+    let invalid = home.path().join("invalid.json");
+    fs::write(&invalid, r#"{"fragments": [{"name": "../escape"}]}"#).unwrap();
+    assert!(generator.import(&invalid, Format::Json, false).is_err());
+}
+
+#[test]
+fn leaves_out_sections_that_cannot_be_decrypted() {
+    let (_home, paths) = setup();
+    let secret = "# CONDITIONS BEGIN\nLocalSSID homenet\n# CONDITIONS END\n\
+                  # GLOBAL CONFIG BEGIN\nHost public\n# GLOBAL CONFIG END\n\
+                  # LOCAL CONFIG BEGIN (encrypted)\nnot age ciphertext\n# LOCAL CONFIG END\n";
+    fs::write(paths.config_dir.join("30-secret.sshconf"), secret).unwrap();
+
+    let config = preview(&paths, "homenet");
+    assert!(config.contains("Host public"));
+    assert!(!config.contains("ciphertext"));
+}
+
+#[test]
+fn leaves_out_sections_whose_templates_fail() {
+    let (_home, paths) = setup();
+    let templated =
+        "# GLOBAL CONFIG BEGIN\nHost vault\n  User {{ nosuch(\"user\") }}\n# GLOBAL CONFIG END\n\
+                     # REMOTE CONFIG BEGIN\nHost plain\n# REMOTE CONFIG END\n";
+    fs::write(paths.config_dir.join("30-templated.sshconf"), templated).unwrap();
+
+    let config = preview(&paths, "homenet");
+    assert!(config.contains("Host plain"));
+    assert!(!config.contains("Host vault"));
+}
+
+#[test]
+fn keeps_go_templates_in_remote_commands() {
+    let (_home, paths) = setup();
+    let docker = "# REMOTE CONFIG BEGIN\nHost docker\n  RequestTTY yes\n\
+                  \x20 RemoteCommand docker ps --format '{{.Names}}'\n# REMOTE CONFIG END\n";
+    fs::write(paths.config_dir.join("30-docker.sshconf"), docker).unwrap();
+
+    let config = preview(&paths, "cafe");
+    assert!(config.contains("Host docker"));
+    assert!(config.contains("  RemoteCommand docker ps --format '{{.Names}}'\n"));
+}
+
+#[test]
+fn reports_missing_directories() {
+    let home = tempfile::tempdir().unwrap();
+    let paths = Paths::new(&home.path().join(".ssh"), &home.path().join("state"));
+
+    assert!(matches!(paths.check(), Err(Error::MissingDirectory(dir)) if dir == paths.ssh_dir));
+
+    fs::create_dir(&paths.ssh_dir).unwrap();
+    assert!(matches!(paths.check(), Err(Error::MissingDirectory(dir)) if dir == paths.config_dir));
+}
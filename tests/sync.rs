@@ -0,0 +1,181 @@
+//! # Sync
+//!
+//! Syncs temporary `.ssh` directories with a local git repository standing in for the shared
+//! repository of a team, and installs fragment bundles built locally. Skipped when git or tar is
+//! not installed.
+
+use sha2::{Digest, Sha256};
+use sshconfgen::generator::{
+    fetch_all, install_bundle, sync, Generator, Source, SyncMode, SyncReport, SyncSettings,
+};
+use sshconfgen::paths::Paths;
+use sshconfgen::{Error, Fragment};
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use tempfile::TempDir;
+
+/// Run git in `dir`
+fn git(dir: &Path, args: &[&str]) {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args([
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "-c",
+            "commit.gpgsign=false",
+        ])
+        .args(args)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success(), "git {:?}", args);
+}
+
+/// Write `contents` to `name` in the repository and commit it
+fn commit(repository: &Path, name: &str, contents: &str) {
+    fs::write(repository.join(name), contents).unwrap();
+    git(repository, &["add", "-A"]);
+    git(repository, &["commit", "--quiet", "-m", name]);
+}
+
+/// Get the fragments of `config.d`
+fn fragments(paths: &Paths) -> Vec<Fragment> {
+    let export = Generator::new(paths.clone()).export(false);
+    export.fragments.into_iter().map(|exported| exported.fragment).collect()
+}
+
+/// Create a repository with a fragment, and an empty `.ssh` directory
+fn setup() -> Option<(TempDir, Paths, SyncSettings)> {
+    if Command::new("git").arg("--version").output().is_err() {
+        return None;
+    }
+
+    let root = tempfile::tempdir().unwrap();
+    let repository = root.path().join("shared");
+    fs::create_dir(&repository).unwrap();
+    git(&repository, &["init", "--quiet", "--initial-branch=main"]);
+    commit(
+        &repository,
+        "10-bastion.sshconf",
+        "# GLOBAL CONFIG BEGIN\nHost bastion\n# GLOBAL CONFIG END\n",
+    );
+
+    let paths = Paths::new(&root.path().join(".ssh"), &root.path().join("state"));
+    fs::create_dir(&paths.ssh_dir).unwrap();
+
+    let settings = SyncSettings {
+        repository: repository.to_string_lossy().to_string(),
+        branch: Some("main".to_string()),
+        mode: SyncMode::Into,
+        read_only: false,
+    };
+    Some((root, paths, settings))
+}
+
+#[test]
+fn checks_the_repository_out_as_config_d() {
+    let Some((_root, paths, settings)) = setup() else { return };
+    let repository = Path::new(&settings.repository);
+
+    assert_eq!(sync(&paths, &settings).unwrap(), SyncReport::default());
+    assert!(paths.config_dir.join("10-bastion.sshconf").exists());
+
+    commit(
+        repository,
+        "20-jump.sshconf",
+        "# GLOBAL CONFIG BEGIN\nHost jump\n# GLOBAL CONFIG END\n",
+    );
+    sync(&paths, &settings).unwrap();
+    assert!(paths.config_dir.join("20-jump.sshconf").exists());
+
+    // A local commit diverging from the repository is kept, unless the checkout is read-only.
+    commit(&paths.config_dir, "10-bastion.sshconf", "edited locally");
+    commit(repository, "10-bastion.sshconf", "edited upstream");
+    assert!(matches!(sync(&paths, &settings), Err(Error::Sync(_))));
+
+    let settings = SyncSettings { read_only: true, ..settings };
+    sync(&paths, &settings).unwrap();
+    assert_eq!(
+        fs::read_to_string(paths.config_dir.join("10-bastion.sshconf")).unwrap(),
+        "edited upstream"
+    );
+}
+
+#[test]
+fn overlays_the_repository_onto_local_fragments() {
+    let Some((_root, paths, settings)) = setup() else { return };
+    let settings = SyncSettings { mode: SyncMode::Overlay, ..settings };
+    let repository = Path::new(&settings.repository);
+    fs::create_dir(&paths.config_dir).unwrap();
+    fs::write(paths.config_dir.join("00-local.sshconf"), "local").unwrap();
+
+    let bastion = paths.config_dir.join("10-bastion.sshconf");
+    let report = sync(&paths, &settings).unwrap();
+    assert_eq!(report.written, std::slice::from_ref(&bastion));
+    assert!(paths.config_dir.join("00-local.sshconf").exists());
+
+    // Locally edited fragments are conflicts and kept, untouched ones follow the repository.
+    commit(repository, "20-jump.sshconf", "jump");
+    commit(repository, "10-bastion.sshconf", "edited upstream");
+    fs::write(&bastion, "edited locally").unwrap();
+    let report = sync(&paths, &settings).unwrap();
+    assert_eq!(report.written, [paths.config_dir.join("20-jump.sshconf")]);
+    assert_eq!(report.conflicts, std::slice::from_ref(&bastion));
+    assert_eq!(fs::read_to_string(&bastion).unwrap(), "edited locally");
+
+    git(repository, &["rm", "--quiet", "20-jump.sshconf"]);
+    git(repository, &["commit", "--quiet", "-m", "remove"]);
+    let settings = SyncSettings { read_only: true, ..settings };
+    let report = sync(&paths, &settings).unwrap();
+    assert_eq!(report.written, std::slice::from_ref(&bastion));
+    assert_eq!(report.removed, [paths.config_dir.join("20-jump.sshconf")]);
+    assert_eq!(fs::read_to_string(&bastion).unwrap(), "edited upstream");
+}
+
+#[test]
+fn installs_verified_bundles() {
+    let root = tempfile::tempdir().unwrap();
+    let bundle_dir = root.path().join("bundle");
+    fs::create_dir(&bundle_dir).unwrap();
+    let fragment = "# GLOBAL CONFIG BEGIN\nHost intranet\n# GLOBAL CONFIG END\n";
+    fs::write(bundle_dir.join("30-intranet.sshconf"), fragment).unwrap();
+    let archive = root.path().join("intranet.tar.gz");
+    let packed =
+        Command::new("tar").arg("-czf").arg(&archive).arg("-C").arg(&bundle_dir).arg(".").output();
+    if !packed.is_ok_and(|output| output.status.success()) {
+        return;
+    }
+
+    let bundle = fs::read(&archive).unwrap();
+    let paths = Paths::new(&root.path().join(".ssh"), &root.path().join("state"));
+    fs::create_dir_all(&paths.config_dir).unwrap();
+    let mut source = Source {
+        url: "https://intranet.example.com/ssh/intranet.tar.gz".to_string(),
+        sha256: "0".repeat(64),
+        name: None,
+        signed: false,
+    };
+
+    assert!(matches!(
+        install_bundle(&paths.config_dir, &source, &bundle, None),
+        Err(Error::Sync(_))
+    ));
+    assert!(fragments(&paths).is_empty());
+
+    source.sha256 = Sha256::digest(&bundle).iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let dir = install_bundle(&paths.config_dir, &source, &bundle, None).unwrap();
+    assert_eq!(dir, paths.config_dir.join("sources").join("intranet"));
+    let fragments = fragments(&paths);
+    assert_eq!(fragments.len(), 1);
+    assert_eq!(fragments[0].global_rules, "Host intranet");
+
+    // Bundles already unpacked are not downloaded again, and unlisted ones are removed.
+    assert!(fetch_all(&paths.config_dir, std::slice::from_ref(&source)).unwrap().is_empty());
+    fetch_all(&paths.config_dir, &[]).unwrap();
+    assert!(!dir.exists());
+}